@@ -38,10 +38,13 @@ workdir_host_path="$(pwd)"
 if [ ! -z "$DOCK_HOSTPATHS" ] ; then
     # NOTE We only implement a subset of the full `DOCK_HOSTPATHS` functionality
     # in this script for simplicity, by assuming that `DOCK_HOSTPATHS` contains
-    # one mapping, and it maps to the current working directory.
+    # one mapping, and it maps to the current working directory. We also
+    # assume that neither path contains characters that `dock` percent-encodes,
+    # so that we only need to remove the version prefix, if any.
+    hostpaths="${DOCK_HOSTPATHS#v2:}"
 
     host_path_tgt=$(
-        echo "$DOCK_HOSTPATHS" \
+        echo "$hostpaths" \
             | sed 's/.*://'
     )
     if [ "$host_path_tgt" != "$workdir_host_path" ] ; then
@@ -51,7 +54,7 @@ if [ ! -z "$DOCK_HOSTPATHS" ] ; then
     fi
 
     workdir_host_path=$(
-        echo "$DOCK_HOSTPATHS" \
+        echo "$hostpaths" \
             | sed 's/:.*//'
     )
 fi
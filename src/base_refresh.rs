@@ -0,0 +1,391 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Periodic refreshes of the base images of environments.
+//!
+//! If `base_refresh` is set for an environment, then the base images of the
+//! environment are pulled, and the environment is rebuilt with `--pull`, once
+//! `base_refresh` has passed since they were last pulled. The time of the last
+//! pull is kept in the cache directory of the project.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Error as IoError;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::build_log;
+use crate::docker;
+use crate::dockerfile;
+use crate::dockerfile::Instruction;
+use crate::logging_process;
+use crate::logging_process::CmdLoggerMsg;
+use crate::logging_process::CommandLogger;
+use crate::logging_process::RunError;
+use crate::run_in;
+use crate::run_in::DockConfig;
+use crate::run_in::FindAndParseDockConfigError;
+
+pub const BASE_DIGESTS_LABEL: &str = "dock.base_digests";
+
+// `BaseRefresh` is the base refresh policy of the image `img_name`.
+pub struct BaseRefresh {
+    pub ttl: Duration,
+    pub img_prefix: String,
+    pub last_pull_path: PathBuf,
+}
+
+impl BaseRefresh {
+    // `new` returns the base refresh policy of the image `img_name` of an
+    // environment of `conf`, whose `base_refresh` is `raw_ttl`.
+    pub fn new(conf: &DockConfig, img_name: &str, raw_ttl: &str)
+        -> Result<Self, ParseTtlError>
+    {
+        let ttl = parse_ttl(raw_ttl)?;
+
+        Ok(Self{
+            ttl,
+            img_prefix: format!("{}/{}.", conf.organisation, conf.project),
+            last_pull_path: last_pull_path(
+                &conf.organisation,
+                &conf.project,
+                img_name,
+            ),
+        })
+    }
+
+    // `last_pull` returns the time that the base images were last pulled, if
+    // they have been pulled.
+    pub fn last_pull(&self) -> Option<SystemTime> {
+        let raw = fs::read_to_string(&self.last_pull_path).ok()?;
+        let secs = raw.trim().parse::<u64>().ok()?;
+
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        match self.last_pull() {
+            Some(last_pull) => {
+                now.duration_since(last_pull).unwrap_or_default() >= self.ttl
+            },
+            None => {
+                true
+            },
+        }
+    }
+
+    // `record_pull` records `now` as the time that the base images were last
+    // pulled.
+    pub fn record_pull(&self, now: SystemTime) -> Result<(), IoError> {
+        if let Some(dir) = self.last_pull_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        fs::write(&self.last_pull_path, format!("{}\n", secs.as_secs()))
+    }
+
+    // `prepare` returns the `docker build` arguments for the base images of
+    // `dockerfile`, and whether the base images were pulled, in which case
+    // `record_pull` should be called after a successful rebuild. The base
+    // images are pulled for `platform` if `base_refresh` has passed since they
+    // were last pulled, or if any of them aren't available locally. A failed
+    // pull isn't fatal, so that environments can be rebuilt offline.
+    pub fn prepare(
+        &self,
+        logger: &mut dyn CommandLogger,
+        dockerfile: &str,
+        platform: Option<&str>,
+        now: SystemTime,
+    )
+        -> Result<(Vec<String>, bool), RunError>
+    {
+        let bases = base_images(dockerfile, &self.img_prefix);
+
+        let missing = bases.iter().any(|base| !image_exists(base));
+
+        let mut args = vec![];
+        let mut pulled = false;
+        if !bases.is_empty() && (missing || self.is_expired(now)) {
+            pulled = true;
+            let platform_arg = platform.map(|p| format!("--platform={p}"));
+            for base in &bases {
+                let mut pull_args = vec![OsStr::new("pull")];
+                if let Some(arg) = &platform_arg {
+                    pull_args.push(OsStr::new(arg));
+                }
+                pull_args.push(OsStr::new(base));
+
+                let status = logging_process::run(
+                    logger,
+                    OsStr::new("docker"),
+                    &pull_args,
+                    Stdio::null(),
+                )?;
+
+                if !status.success() {
+                    let msg = format!(
+                        "Warning: couldn't pull '{base}'; using the local \
+                         base images\n",
+                    );
+                    logger.log(CmdLoggerMsg::StderrWrite(msg.as_bytes()));
+                    pulled = false;
+                    break;
+                }
+            }
+
+            if pulled {
+                args.push("--pull".to_string());
+            }
+        }
+
+        let digests: Vec<String> =
+            bases
+                .iter()
+                .filter_map(|base| local_digest(base))
+                .collect();
+        if !digests.is_empty() {
+            args.push(format!(
+                "--label={BASE_DIGESTS_LABEL}={}",
+                digests.join(","),
+            ));
+        }
+
+        Ok((args, pulled))
+    }
+}
+
+// `last_pull_path` returns the path of the file that records when the base
+// images of `img_name` were last pulled.
+fn last_pull_path(org: &str, proj: &str, img_name: &str) -> PathBuf {
+    let name = img_name.rsplit('/').next().unwrap_or(img_name);
+
+    build_log::project_cache_dir(org, proj)
+        .join("base_pulls")
+        .join(name)
+}
+
+fn image_exists(img: &str) -> bool {
+    docker::assert_run(["image", "inspect", "--format={{.Id}}", img]).is_ok()
+}
+
+// `local_digest` returns the repository digest of the local image `img`, if
+// it exists and was pulled from a registry.
+fn local_digest(img: &str) -> Option<String> {
+    let format_arg = "--format={{index .RepoDigests 0}}";
+    let output = docker::assert_run(["image", "inspect", format_arg, img])
+        .ok()?;
+
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if digest.is_empty() {
+        return None;
+    }
+
+    Some(digest)
+}
+
+// `base_images` returns the images used by the `FROM` instructions of
+// `dockerfile` that are pulled from a registry. Earlier stages of
+// `dockerfile`, `scratch`, images that depend on build arguments, and the
+// images of other environments, whose names start with `img_prefix`, are
+// skipped.
+pub fn base_images(dockerfile: &str, img_prefix: &str) -> Vec<String> {
+    let mut stages: Vec<&str> = vec![];
+    let mut imgs: Vec<String> = vec![];
+    let instrs = dockerfile::instructions(dockerfile);
+    for from in instrs.iter().filter_map(Instruction::parse_from) {
+        let img = from.img;
+        let is_base =
+            !stages.iter().any(|stage| stage.eq_ignore_ascii_case(img))
+                && !img.eq_ignore_ascii_case("scratch")
+                && !img.contains('$')
+                && !img.starts_with(img_prefix);
+        if is_base && !imgs.iter().any(|i| i == img) {
+            imgs.push(img.to_string());
+        }
+
+        if let Some(stage) = from.stage {
+            stages.push(stage);
+        }
+    }
+
+    imgs
+}
+
+// `parse_ttl` parses a duration in the form `<n><unit>`, where `<unit>` is
+// one of `s`, `m`, `h`, `d` or `w`.
+pub fn parse_ttl(raw: &str) -> Result<Duration, ParseTtlError> {
+    let invalid = || ParseTtlError::InvalidTtl{ttl: raw.to_string()};
+
+    let unit_start = raw.find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (raw_n, unit) = raw.split_at(unit_start);
+
+    let n = raw_n.parse::<u64>()
+        .map_err(|_| invalid())?;
+
+    let unit_secs =
+        match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+
+    let secs = n.checked_mul(unit_secs)
+        .ok_or_else(invalid)?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseTtlError {
+    #[snafu(display(
+        "'{}' isn't a valid duration; expected a number followed by `s`, \
+         `m`, `h`, `d` or `w`, such as `7d`",
+        ttl,
+    ))]
+    InvalidTtl{ttl: String},
+}
+
+// `OutdatedEnv` is an environment whose base images were last pulled more
+// than `ttl` ago, or have never been pulled.
+pub struct OutdatedEnv {
+    pub env_name: String,
+    pub age: Option<Duration>,
+    pub ttl: Duration,
+}
+
+// `outdated` returns the environments whose base images were last pulled more
+// than `base_refresh` ago, sorted by name. Environments without
+// `base_refresh` are skipped.
+pub fn outdated(dock_file_name: &str)
+    -> Result<Vec<OutdatedEnv>, OutdatedError>
+{
+    let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let now = SystemTime::now();
+
+    let mut outdated = vec![];
+    for (env_name, env) in &conf.environments {
+        let Some(raw_ttl) = &env.base_refresh else {
+            continue;
+        };
+
+        let img_name =
+            run_in::image_name(&conf.organisation, &conf.project, env_name);
+        let refresh = BaseRefresh::new(&conf, &img_name, raw_ttl)
+            .context(ParseBaseRefreshFailed{name: env_name})?;
+
+        if refresh.is_expired(now) {
+            let age =
+                refresh.last_pull()
+                    .map(|t| now.duration_since(t).unwrap_or_default());
+
+            outdated.push(OutdatedEnv{
+                env_name: env_name.clone(),
+                age,
+                ttl: refresh.ttl,
+            });
+        }
+    }
+    outdated.sort_unstable_by(|a, b| a.env_name.cmp(&b.env_name));
+
+    Ok(outdated)
+}
+
+#[derive(Debug, Snafu)]
+pub enum OutdatedError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display(
+        "Invalid `base_refresh` for '{}': {}",
+        name,
+        source,
+    ))]
+    ParseBaseRefreshFailed{source: ParseTtlError, name: String},
+}
+
+// `format_age` formats `d` using its largest whole unit, for example, `3d`.
+pub fn format_age(d: Duration) -> String {
+    let secs = d.as_secs();
+
+    let units = [
+        ("w", 7 * 24 * 60 * 60),
+        ("d", 24 * 60 * 60),
+        ("h", 60 * 60),
+        ("m", 60),
+    ];
+    for (unit, unit_secs) in units {
+        if secs >= unit_secs {
+            return format!("{}{unit}", secs / unit_secs);
+        }
+    }
+
+    format!("{secs}s")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ttl_accepts_units() {
+        let cases = [
+            ("30s", 30),
+            ("15m", 15 * 60),
+            ("12h", 12 * 60 * 60),
+            ("7d", 7 * 24 * 60 * 60),
+            ("2w", 14 * 24 * 60 * 60),
+        ];
+
+        for (raw, secs) in cases {
+            assert_eq!(parse_ttl(raw).unwrap(), Duration::from_secs(secs));
+        }
+    }
+
+    #[test]
+    fn parse_ttl_rejects_invalid_durations() {
+        for raw in ["", "7", "d", "7y", "-1d", "1.5d", "99999999999999999w"] {
+            assert!(parse_ttl(raw).is_err(), "'{raw}' was accepted");
+        }
+    }
+
+    #[test]
+    fn base_images_skips_stages_and_env_images() {
+        let dockerfile = "\
+            FROM --platform=linux/amd64 golang:1.22 AS build\n\
+            FROM build AS test\n\
+            FROM org/proj.base\n\
+            FROM ${BASE_IMG}\n\
+            FROM scratch\n\
+            from ubuntu:22.04\n\
+            FROM golang:1.22\n\
+            FROM \\\n\
+            \x20   debian:12\n\
+        ";
+
+        assert_eq!(
+            base_images(dockerfile, "org/proj."),
+            vec!["golang:1.22", "ubuntu:22.04", "debian:12"],
+        );
+    }
+}
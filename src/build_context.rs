@@ -0,0 +1,690 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Filtered build contexts.
+//!
+//! A filtered context contains only the files of a context directory that
+//! match the `context_include`, `context_exclude` and `context_git_tracked`
+//! settings of an environment. `dock` passes the list of these files to `tar`,
+//! and passes the resulting tar stream to `docker build -`, so that excluded
+//! files are never sent to the Docker daemon.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::io;
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::process::Child;
+use std::process::ChildStdout;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use sha2::Digest;
+use sha2::Sha256;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+// `DOCKERFILE_NAME` is the path of the Dockerfile in a filtered context. The
+// Dockerfile of an environment isn't usually in its context directory, so it's
+// added to the context under this name.
+pub const DOCKERFILE_NAME: &str = ".dock.Dockerfile";
+
+// `DOCKERIGNORE_NAME` is the path of the `.dockerignore` file in a filtered
+// context. A `.dockerignore` file that lists `DOCKERFILE_NAME` is added to the
+// context so that the Dockerfile isn't copied into the image by instructions
+// such as `COPY . /app`, which would also mean that every change to the
+// Dockerfile would invalidate the cache of that instruction.
+const DOCKERIGNORE_NAME: &str = ".dockerignore";
+
+// `SIZE_WARNING_THRESHOLD` is the size, in bytes, above which a warning is
+// printed about the size of a filtered context.
+pub const SIZE_WARNING_THRESHOLD: u64 = 100 * 1024 * 1024;
+
+// `ContextFilter` selects the files of a context directory to send to the
+// Docker daemon. A file is selected if it matches one of the `include`
+// patterns (or `include` is empty), doesn't match any of the `exclude`
+// patterns, and is tracked by Git (if `git_tracked` is `true`).
+pub struct ContextFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub git_tracked: bool,
+}
+
+impl ContextFilter {
+    fn includes(&self, path: &Path) -> bool {
+        let included =
+            self.include.is_empty()
+                || self.include.iter().any(|p| glob_matches(p, path));
+
+        included && !self.excludes(path)
+    }
+
+    fn excludes(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|p| glob_matches(p, path))
+    }
+}
+
+// `ContextFiles` are the files selected from the directory at `root`. `paths`
+// are relative to `root`, and include the ancestor directories of selected
+// files, so that their ownership and permissions are preserved.
+pub struct ContextFiles {
+    pub root: PathBuf,
+    pub paths: Vec<PathBuf>,
+    pub num_files: usize,
+    pub size: u64,
+}
+
+impl ContextFiles {
+    // `summary` returns a short description of the number and size of the
+    // files in the context.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} files, {}",
+            self.num_files,
+            format_size(self.size),
+        )
+    }
+}
+
+// `list` returns the files in `root` that are selected by `filter`.
+pub fn list(root: &Path, filter: &ContextFilter)
+    -> Result<ContextFiles, ListError>
+{
+    let candidates =
+        if filter.git_tracked {
+            git_tracked_paths(root)?
+        } else {
+            let mut paths = vec![];
+            walk(root, Path::new(""), filter, &mut paths)?;
+
+            paths
+        };
+
+    let mut paths = BTreeSet::new();
+    let mut num_files = 0;
+    let mut size = 0;
+    for path in candidates {
+        if !filter.includes(&path) {
+            continue;
+        }
+
+        // Tracked files may have been deleted from the working tree, and
+        // submodules are tracked as directories, so we skip both.
+        let full_path = root.join(&path);
+        let Ok(metadata) = fs::symlink_metadata(&full_path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            continue;
+        }
+
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() {
+                paths.insert(ancestor.to_path_buf());
+            }
+        }
+        if paths.insert(path) {
+            num_files += 1;
+            size += metadata.len();
+        }
+    }
+
+    Ok(ContextFiles{
+        root: root.to_path_buf(),
+        paths: paths.into_iter().collect(),
+        num_files,
+        size,
+    })
+}
+
+// `dockerignore_filter` returns a filter that excludes the files of the
+// context directory at `root` that are ignored by its `.dockerignore` file, if
+// it has one.
+pub fn dockerignore_filter(root: &Path) -> Result<ContextFilter, IoError> {
+    match fs::read_to_string(root.join(DOCKERIGNORE_NAME)) {
+        Ok(content) => {
+            Ok(parse_dockerignore(&content))
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Ok(parse_dockerignore(""))
+        },
+        Err(err) => {
+            Err(err)
+        },
+    }
+}
+
+// `parse_dockerignore` returns a filter that excludes the files matched by the
+// `.dockerignore` file `content`. Exceptions (patterns that start with `!`)
+// aren't supported by `ContextFilter`, so no files are excluded if `content`
+// uses them; this means that the filter may select more files than are sent
+// to the Docker daemon, but never fewer.
+fn parse_dockerignore(content: &str) -> ContextFilter {
+    let mut exclude = vec![];
+    for line in content.lines() {
+        let pattern = line.trim();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            continue;
+        }
+        if pattern.starts_with('!') {
+            exclude.clear();
+            break;
+        }
+        exclude.push(pattern.to_string());
+    }
+
+    ContextFilter{include: vec![], exclude, git_tracked: false}
+}
+
+// `digest` returns the SHA-256 digest of the paths, modes and contents of
+// `files`, which changes when a file is added to, removed from or changed in
+// the context.
+pub fn digest(files: &ContextFiles) -> Result<String, IoError> {
+    let mut hasher = Sha256::new();
+    for path in &files.paths {
+        let src = files.root.join(path);
+        let metadata = fs::symlink_metadata(&src)?;
+
+        // We hash the digest of the content of each file, rather than its
+        // content, so that the boundaries between files are unambiguous.
+        let mut content_hasher = Sha256::new();
+        if metadata.file_type().is_symlink() {
+            content_hasher.update(fs::read_link(&src)?.as_os_str().as_bytes());
+        } else if metadata.is_file() {
+            io::copy(&mut File::open(&src)?, &mut content_hasher)?;
+        }
+
+        hasher.update(path.as_os_str().as_bytes());
+        hasher.update(format!(
+            "\0{:o}\0{:x}\0",
+            metadata.mode(),
+            content_hasher.finalize(),
+        ));
+    }
+
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+// `walk` appends the paths of the files under `root.join(dir)` to `paths`,
+// relative to `root`. Directories that are excluded by `filter` aren't
+// walked, so that large excluded directories are skipped cheaply.
+fn walk(
+    root: &Path,
+    dir: &Path,
+    filter: &ContextFilter,
+    paths: &mut Vec<PathBuf>,
+)
+    -> Result<(), ListError>
+{
+    let full_dir = root.join(dir);
+    let entries = fs::read_dir(&full_dir)
+        .context(ReadDirFailed{path: full_dir.clone()})?;
+
+    for entry in entries {
+        let entry = entry
+            .context(ReadDirFailed{path: full_dir.clone()})?;
+        let path = dir.join(entry.file_name());
+
+        let file_type = entry.file_type()
+            .context(ReadDirFailed{path: full_dir.clone()})?;
+
+        if file_type.is_dir() {
+            if !filter.excludes(&path) {
+                walk(root, &path, filter, paths)?;
+            }
+        } else {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn git_tracked_paths(dir: &Path) -> Result<Vec<PathBuf>, ListError> {
+    let output =
+        Command::new("git")
+            .args(["ls-files", "-z", "--cached"])
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .context(ListTrackedFilesFailed)?;
+
+    if !output.status.success() {
+        return Err(ListError::ListTrackedFilesUnsuccessful);
+    }
+
+    let paths =
+        output.stdout
+            .split(|b| *b == 0)
+            .filter(|p| !p.is_empty())
+            .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+            .collect();
+
+    Ok(paths)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ListError {
+    #[snafu(display("Couldn't read '{}': {}", path.display(), source))]
+    ReadDirFailed{source: IoError, path: PathBuf},
+    #[snafu(display("Couldn't list Git-tracked files: {}", source))]
+    ListTrackedFilesFailed{source: IoError},
+    #[snafu(display(
+        "`git ls-files` returned an unsuccessful status (is the context in a \
+         Git repository?)",
+    ))]
+    ListTrackedFilesUnsuccessful,
+}
+
+// `glob_matches` returns whether `pattern` matches `path`, or one of the
+// ancestors of `path`, so that a pattern that matches a directory also matches
+// its contents. Patterns are relative to the context directory, and are split
+// into components by `/`; `**` matches any number of components, and `*` and
+// `?` match any number of characters, and a single character, within a
+// component.
+pub fn glob_matches(pattern: &str, path: &Path) -> bool {
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let pattern: Vec<&[u8]> =
+        pattern
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(str::as_bytes)
+            .collect();
+
+    let path: Vec<&[u8]> =
+        path.iter()
+            .map(OsStr::as_bytes)
+            .collect();
+
+    (1..=path.len()).any(|n| components_match(&pattern, &path[..n]))
+}
+
+fn components_match(pattern: &[&[u8]], path: &[&[u8]]) -> bool {
+    match pattern.split_first() {
+        None => {
+            path.is_empty()
+        },
+        Some((&b"**", rest)) => {
+            (0..=path.len()).any(|i| components_match(rest, &path[i..]))
+        },
+        Some((p, rest)) => {
+            match path.split_first() {
+                Some((c, path_rest)) => {
+                    component_matches(p, c)
+                        && components_match(rest, path_rest)
+                },
+                None => {
+                    false
+                },
+            }
+        },
+    }
+}
+
+fn component_matches(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => {
+            s.is_empty()
+        },
+        Some((b'*', rest)) => {
+            (0..=s.len()).any(|i| component_matches(rest, &s[i..]))
+        },
+        Some((b'?', rest)) => {
+            !s.is_empty() && component_matches(rest, &s[1..])
+        },
+        Some((p, rest)) => {
+            s.first() == Some(p) && component_matches(rest, &s[1..])
+        },
+    }
+}
+
+// `ContextTar` is a `tar` process that writes a filtered context to its
+// STDOUT.
+pub struct ContextTar {
+    tar: Child,
+    file_list: Vec<u8>,
+    // `_stage_dir` is kept until `tar` has finished, because `tar` reads the
+    // files that are added to the context from it.
+    _stage_dir: StageDir,
+}
+
+impl ContextTar {
+    // `take_stdout` returns the STDOUT of `tar`, which the context is written
+    // to.
+    pub fn take_stdout(&mut self) -> Option<ChildStdout> {
+        self.tar.stdout.take()
+    }
+
+    // `finish` passes the list of files to `tar` and waits for it to finish.
+    // STDOUT of `tar` must be read while `finish` is running, because `tar`
+    // may block on writing its output before it has read all of its input.
+    pub fn finish(mut self) -> Result<(), IoError> {
+        let mut tar_stdin = self.tar.stdin.take()
+            .ok_or_else(|| IoError::other("couldn't bind to `tar` STDIN"))?;
+
+        let write_result = tar_stdin.write_all(&self.file_list);
+        // `tar` only stops reading the list of files when STDIN is closed.
+        drop(tar_stdin);
+
+        let status = self.tar.wait()?;
+
+        write_result?;
+
+        if !status.success() {
+            let msg = "`tar` returned an unsuccessful status";
+
+            return Err(IoError::other(msg));
+        }
+
+        Ok(())
+    }
+}
+
+// `StageDir` is a directory that contains the files that `spawn_tar` adds to
+// a context. The directory is removed when the `StageDir` is dropped.
+struct StageDir {
+    path: PathBuf,
+}
+
+impl StageDir {
+    // `create` creates a new `StageDir` that is only accessible by the current
+    // user.
+    fn create() -> Result<Self, IoError> {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir()
+            .join(format!("dock-context-{}-{}", process::id(), id));
+
+        let mut builder = DirBuilder::new();
+        builder.mode(0o700);
+
+        if let Err(err) = builder.create(&path) {
+            if err.kind() != ErrorKind::AlreadyExists {
+                return Err(err);
+            }
+
+            // The directory may have been left behind by an earlier process
+            // with the same ID.
+            fs::remove_dir_all(&path)?;
+            builder.create(&path)?;
+        }
+
+        Ok(Self{path})
+    }
+}
+
+impl Drop for StageDir {
+    fn drop(&mut self) {
+        // NOTE We ignore the result because the directory is in the temporary
+        // directory, so it will be cleaned up eventually anyway.
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// `spawn_tar` starts `tar` to write `files`, followed by `dockerfile` as
+// `DOCKERFILE_NAME` and a `.dockerignore` file that ignores it, to its STDOUT
+// as a tar archive. If `files` contains a `.dockerignore` file then
+// `DOCKERFILE_NAME` is appended to it.
+pub fn spawn_tar(files: &ContextFiles, dockerfile: &Path)
+    -> Result<ContextTar, IoError>
+{
+    // We pass the list of paths to `tar` explicitly, and without recursion, so
+    // that only the selected files are included.
+    let mut dockerignore = None;
+    let mut file_list = vec![];
+    for path in &files.paths {
+        if path == Path::new(DOCKERIGNORE_NAME) {
+            dockerignore = Some(fs::read(files.root.join(path))?);
+            continue;
+        }
+        file_list.extend(path.as_os_str().as_bytes());
+        file_list.push(0);
+    }
+
+    // The Dockerfile and the `.dockerignore` file are written to a separate
+    // directory so that they can be added to the archive under their own
+    // names.
+    let stage_dir = StageDir::create()?;
+    fs::copy(dockerfile, stage_dir.path.join(DOCKERFILE_NAME))?;
+    fs::write(
+        stage_dir.path.join(DOCKERIGNORE_NAME),
+        dockerignore_content(dockerignore),
+    )?;
+
+    let tar =
+        Command::new("tar")
+            .arg("--create")
+            .arg("--no-recursion")
+            .arg("--null")
+            .arg("--file=-")
+            // NOTE `--directory` applies to the paths that follow it,
+            // including the paths from `--files-from`, so the order of these
+            // arguments matters.
+            .arg("--directory")
+            .arg(&files.root)
+            .arg("--files-from=-")
+            .arg("--directory")
+            .arg(&stage_dir.path)
+            .arg(DOCKERFILE_NAME)
+            .arg(DOCKERIGNORE_NAME)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+    Ok(ContextTar{tar, file_list, _stage_dir: stage_dir})
+}
+
+// `dockerignore_content` returns the content of the `.dockerignore` file of a
+// filtered context, given the content of the `.dockerignore` file of the
+// context directory, if any. The generated file also ignores itself, so that
+// it isn't copied into the image either.
+fn dockerignore_content(existing: Option<Vec<u8>>) -> Vec<u8> {
+    let Some(mut content) = existing else {
+        return format!("{DOCKERFILE_NAME}\n{DOCKERIGNORE_NAME}\n")
+            .into_bytes();
+    };
+
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        content.push(b'\n');
+    }
+    content.extend_from_slice(DOCKERFILE_NAME.as_bytes());
+    content.push(b'\n');
+
+    content
+}
+
+// `format_size` returns `size` in bytes as a human-readable string.
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+
+    let mut unit = 0;
+    let mut unit_bytes: u64 = 1;
+    while size / unit_bytes >= 1024 && unit < units.len() - 1 {
+        unit_bytes *= 1024;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        return format!("{size} B");
+    }
+
+    // We use integer arithmetic, rounding to the nearest tenth, so that large
+    // sizes aren't affected by floating-point imprecision.
+    let unit_bytes = u128::from(unit_bytes);
+    let tenths = (u128::from(size) * 10 + unit_bytes / 2) / unit_bytes;
+
+    format!("{}.{} {}", tenths / 10, tenths % 10, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) patterns and paths where the pattern matches the path, a
+    //     component of it or one of its ancestors
+    //     AND (2) patterns and paths where the pattern doesn't match
+    // When `glob_matches` is called for each pattern and path
+    // Then (A) the matching pairs match
+    //     AND (B) the other pairs don't match
+    fn test_glob_matches_components() {
+        // (1)
+        let matches = [
+            ("src", "src/main.rs"),
+            ("src/*.rs", "src/main.rs"),
+            ("**/*.rs", "src/bin/main.rs"),
+            ("**/*.rs", "main.rs"),
+            ("**/target", "a/target/debug/dock"),
+            ("./Cargo.?oml", "Cargo.toml"),
+            ("src/**", "src/bin/main.rs"),
+        ];
+        // (2)
+        let mismatches = [
+            ("src/*.rs", "src/bin/main.rs"),
+            ("Cargo.toml", "a/Cargo.toml"),
+        ];
+
+        // (A)
+        for (pattern, path) in matches {
+            assert!(
+                glob_matches(pattern, Path::new(path)),
+                "'{pattern}' didn't match '{path}'",
+            );
+        }
+        // (B)
+        for (pattern, path) in mismatches {
+            assert!(
+                !glob_matches(pattern, Path::new(path)),
+                "'{pattern}' matched '{path}'",
+            );
+        }
+    }
+
+    #[test]
+    // Given (1) a `ContextFilter` that includes `src`
+    //     AND (2) the filter excludes `**/*.bak`
+    // When `includes` is called with different paths
+    // Then (A) files in `src` are included
+    //     AND (B) excluded files in `src` aren't included
+    //     AND (C) files outside `src` aren't included
+    fn test_filter_applies_exclude_after_include() {
+        // (1) (2)
+        let filter = ContextFilter{
+            include: vec!["src".to_string()],
+            exclude: vec!["**/*.bak".to_string()],
+            git_tracked: false,
+        };
+
+        // (A)
+        assert!(filter.includes(Path::new("src/main.rs")));
+        // (B)
+        assert!(!filter.includes(Path::new("src/main.rs.bak")));
+        // (C)
+        assert!(!filter.includes(Path::new("README.md")));
+    }
+
+    #[test]
+    // Given (1) a `.dockerignore` file that ignores `/target` and `*.log`
+    //     AND (2) the `.dockerignore` file has a comment and a blank line
+    // When `parse_dockerignore` is called with the file
+    // Then (A) files in `target` are excluded
+    //     AND (B) log files in the context directory are excluded
+    //     AND (C) other files aren't excluded
+    fn test_parse_dockerignore_excludes_ignored_files() {
+        // (1) (2)
+        let content = "# Build output.\n/target\n\n*.log\n";
+
+        let filter = parse_dockerignore(content);
+
+        // (A)
+        assert!(!filter.includes(Path::new("target/debug/dock")));
+        // (B)
+        assert!(!filter.includes(Path::new("build.log")));
+        // (C)
+        assert!(filter.includes(Path::new("src/main.rs")));
+        assert!(filter.includes(Path::new("src/build.log")));
+    }
+
+    #[test]
+    // Given (1) a `.dockerignore` file that ignores `target`
+    //     AND (2) the `.dockerignore` file has an exception for
+    //         `target/keep`
+    // When `parse_dockerignore` is called with the file
+    // Then (A) no files are excluded
+    fn test_parse_dockerignore_ignores_nothing_with_exceptions() {
+        // (1) (2)
+        let content = "target\n!target/keep\n";
+
+        let filter = parse_dockerignore(content);
+
+        // (A)
+        assert!(filter.includes(Path::new("target/keep")));
+        assert!(filter.includes(Path::new("target/debug/dock")));
+    }
+
+    #[test]
+    // Given (1) the context directory doesn't have a `.dockerignore` file
+    // When `dockerignore_content` is called
+    // Then (A) the generated file ignores the Dockerfile and itself
+    fn test_dockerignore_content_without_existing_file() {
+        // (1)
+        let existing = None;
+
+        let content = dockerignore_content(existing);
+
+        // (A)
+        assert_eq!(content, b".dock.Dockerfile\n.dockerignore\n");
+    }
+
+    #[test]
+    // Given (1) the context directory has a `.dockerignore` file
+    //     AND (2) the file doesn't end with a newline
+    // When `dockerignore_content` is called
+    // Then (A) the Dockerfile is appended to the file on a new line
+    fn test_dockerignore_content_with_existing_file() {
+        // (1) (2)
+        let existing = Some(b"target".to_vec());
+
+        let content = dockerignore_content(existing);
+
+        // (A)
+        assert_eq!(content, b"target\n.dock.Dockerfile\n");
+    }
+
+    #[test]
+    // Given (1) sizes in bytes, KiB, MiB and beyond GiB
+    // When `format_size` is called for each size
+    // Then (A) each size is formatted with a binary unit, to one decimal place
+    fn test_format_size_uses_binary_units() {
+        // (1)
+        let cases = [
+            (512, "512 B"),
+            (1536, "1.5 KiB"),
+            (100 * 1024 * 1024, "100.0 MiB"),
+            (3 * 1024 * 1024 * 1024 * 1024, "3072.0 GiB"),
+        ];
+
+        for (size, expected) in cases {
+            let formatted = format_size(size);
+
+            // (A)
+            assert_eq!(formatted, expected);
+        }
+    }
+}
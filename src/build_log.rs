@@ -0,0 +1,187 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+use std::fs as std_fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::path::PathBuf;
+
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::cmd_loggers::Stream;
+use crate::run_in;
+use crate::run_in::FindAndParseDockConfigError;
+
+// `project_cache_dir` returns the directory that `dock` uses to store files
+// for the project `proj` of `org`, outside of the project directory.
+pub fn project_cache_dir(org: &str, proj: &str) -> PathBuf {
+    let cache_dir =
+        match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+            (Some(dir), _) if !dir.is_empty() => {
+                PathBuf::from(dir)
+            },
+            (_, Some(home)) if !home.is_empty() => {
+                PathBuf::from(home).join(".cache")
+            },
+            _ => {
+                env::temp_dir()
+            },
+        };
+
+    cache_dir.join("dock").join(org).join(proj)
+}
+
+// `last_build_log_path` returns the path of the log of the last failed build
+// of `env_name`.
+pub fn last_build_log_path(org: &str, proj: &str, env_name: &str) -> PathBuf {
+    project_cache_dir(org, proj)
+        .join("logs")
+        .join(format!("{env_name}.last-build.log"))
+}
+
+// `find_last_build_log` returns the path of the log of the last failed build
+// of `env_name`, as defined in the Dock file.
+pub fn find_last_build_log(dock_file_name: &str, env_name: &str)
+    -> Result<PathBuf, FindLastBuildLogError>
+{
+    let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    conf.environments.get(env_name)
+        .context(EnvironmentNotFound{name: env_name})?;
+
+    let path =
+        last_build_log_path(&conf.organisation, &conf.project, env_name);
+    if !path.exists() {
+        return Err(FindLastBuildLogError::NoBuildLog{
+            name: env_name.to_string(),
+        });
+    }
+
+    Ok(path)
+}
+
+#[derive(Debug, Snafu)]
+pub enum FindLastBuildLogError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+    #[snafu(display("No failed build of '{}' has been logged", name))]
+    NoBuildLog{name: String},
+}
+
+// `write` writes the output in `chunks` to `path`, in the order that it was
+// captured, replacing any existing log.
+pub fn write(path: &Path, chunks: &[(Stream, Vec<u8>)])
+    -> Result<(), IoError>
+{
+    if let Some(dir) = path.parent() {
+        std_fs::create_dir_all(dir)?;
+    }
+
+    let log: Vec<u8> =
+        chunks
+            .iter()
+            .flat_map(|(_, bs)| bs.iter().copied())
+            .collect();
+
+    std_fs::write(path, log)
+}
+
+// `read_tail` returns the last `n` relevant lines of the log at `path`.
+pub fn read_tail(path: &Path, n: usize) -> Result<Vec<String>, IoError> {
+    let log = std_fs::read(path)?;
+
+    Ok(tail(&String::from_utf8_lossy(&log), n))
+}
+
+fn tail(log: &str, n: usize) -> Vec<String> {
+    let lines: Vec<&str> = log.lines().filter(|l| is_relevant(l)).collect();
+    let start = lines.len().saturating_sub(n);
+
+    lines[start..].iter().map(ToString::to_string).collect()
+}
+
+// `is_relevant` returns `false` for lines of `docker build` output that report
+// progress, rather than the output of the build steps, because these are
+// rarely useful for diagnosing a failed build.
+fn is_relevant(line: &str) -> bool {
+    let line = line.trim_end();
+    if line.trim().is_empty() {
+        return false;
+    }
+
+    if line.starts_with(" ---> ")
+        || line.starts_with("Removing intermediate container ")
+    {
+        return false;
+    }
+
+    // BuildKit prefixes each line with the number of the step that it's for.
+    let Some(rest) = line.strip_prefix('#') else {
+        return true;
+    };
+    let Some((id, msg)) = rest.split_once(' ') else {
+        return true;
+    };
+    if !id.chars().all(|c| c.is_ascii_digit()) {
+        return true;
+    }
+
+    let progress_prefixes = [
+        "DONE ",
+        "CACHED",
+        "sha256:",
+        "resolve ",
+        "extracting ",
+        "transferring ",
+    ];
+
+    !progress_prefixes.iter().any(|prefix| msg.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) a BuildKit build log that contains progress lines and blank
+    //         lines
+    // When `tail` is called with the log and a count of 2
+    // Then (A) the last 2 lines that aren't progress lines or blank are
+    //         returned
+    fn test_tail_skips_progress_lines() {
+        // (1)
+        let log = "\
+            #5 [2/2] RUN exit 2\n\
+            #5 0.215 oops\n\
+            #5 DONE 0.3s\n\
+            \n\
+            #6 CACHED\n\
+            ERROR: failed to solve\n\
+        ";
+
+        let lines = tail(log, 2);
+
+        // (A)
+        assert_eq!(
+            lines,
+            vec![
+                "#5 0.215 oops".to_string(),
+                "ERROR: failed to solve".to_string(),
+            ],
+        );
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::logging_process::CmdLoggerMsg;
+use crate::logging_process::CommandLogger;
+
+const MAX_INSTRUCTION_LEN: usize = 40;
+
+// `Step` is a build step reported in the output of `docker build`.
+#[derive(Debug, PartialEq)]
+pub struct Step {
+    pub stage: Option<String>,
+    pub n: usize,
+    pub total: usize,
+    pub instruction: String,
+}
+
+impl Display for Step {
+    // The instruction is abbreviated so that the step fits on a single line.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.stage {
+            Some(stage) => {
+                write!(f, "[{} {}/{}]", stage, self.n, self.total)?;
+            },
+            None => {
+                write!(f, "[{}/{}]", self.n, self.total)?;
+            },
+        }
+
+        write!(f, " {}", abbreviate(&self.instruction, MAX_INSTRUCTION_LEN))
+    }
+}
+
+// `parse_step` returns the build step announced by `line`, if any. This
+// supports the output of the legacy builder (`Step 2/5 : RUN make`) and the
+// plain output of BuildKit (`#7 [build 2/5] RUN make`).
+pub fn parse_step(line: &str) -> Option<Step> {
+    let line = line.trim_end();
+
+    if let Some(rest) = line.strip_prefix("Step ") {
+        let (counts, instruction) = rest.split_once(" : ")?;
+        let (n, total) = parse_counts(counts)?;
+
+        return Some(Step{
+            stage: None,
+            n,
+            total,
+            instruction: instruction.to_string(),
+        });
+    }
+
+    let rest = line.strip_prefix('#')?;
+    let (id, rest) = rest.split_once(' ')?;
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (label, instruction) = rest.strip_prefix('[')?.split_once("] ")?;
+    let (stage, counts) =
+        match label.rsplit_once(' ') {
+            Some((stage, counts)) => (Some(stage.to_string()), counts),
+            None => (None, label),
+        };
+    let (n, total) = parse_counts(counts)?;
+
+    Some(Step{stage, n, total, instruction: instruction.to_string()})
+}
+
+fn parse_counts(counts: &str) -> Option<(usize, usize)> {
+    let (n, total) = counts.split_once('/')?;
+
+    Some((n.parse().ok()?, total.parse().ok()?))
+}
+
+// `abbreviate` returns the first line of `s`, truncated to `max_len`
+// characters.
+fn abbreviate(s: &str, max_len: usize) -> String {
+    let first_line = s.lines().next().unwrap_or("");
+    if first_line.chars().count() <= max_len {
+        return first_line.to_string();
+    }
+
+    let mut abbrev: String =
+        first_line.chars().take(max_len.saturating_sub(3)).collect();
+    abbrev.push_str("...");
+
+    abbrev
+}
+
+// `StepCmdLogger` passes messages to `logger`, and calls `on_step` with each
+// build step found in the output of the logged commands.
+pub struct StepCmdLogger<'a, F> {
+    logger: &'a mut dyn CommandLogger,
+    on_step: F,
+    stdout_buf: Vec<u8>,
+    stderr_buf: Vec<u8>,
+}
+
+impl<'a, F> StepCmdLogger<'a, F>
+where
+    F: FnMut(Step),
+{
+    pub fn new(logger: &'a mut dyn CommandLogger, on_step: F) -> Self {
+        Self{logger, on_step, stdout_buf: vec![], stderr_buf: vec![]}
+    }
+}
+
+impl<F> CommandLogger for StepCmdLogger<'_, F>
+where
+    F: FnMut(Step),
+{
+    fn log(&mut self, msg: CmdLoggerMsg) {
+        // The legacy builder writes steps to STDOUT, whereas BuildKit writes
+        // them to STDERR, so we buffer lines from both.
+        match msg {
+            CmdLoggerMsg::StdoutWrite(bs) => {
+                scan_lines(&mut self.stdout_buf, bs, &mut self.on_step);
+            },
+            CmdLoggerMsg::StderrWrite(bs) => {
+                scan_lines(&mut self.stderr_buf, bs, &mut self.on_step);
+            },
+            CmdLoggerMsg::Start => {
+                self.stdout_buf.clear();
+                self.stderr_buf.clear();
+            },
+            _ => {
+            },
+        }
+
+        self.logger.log(msg);
+    }
+}
+
+// `scan_lines` appends `bs` to `buf`, and calls `on_step` for each complete
+// line in `buf` that announces a build step.
+fn scan_lines<F>(buf: &mut Vec<u8>, bs: &[u8], on_step: &mut F)
+where
+    F: FnMut(Step),
+{
+    buf.extend_from_slice(bs);
+
+    while let Some(i) = buf.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = buf.drain(..=i).collect();
+        if let Some(step) = parse_step(&String::from_utf8_lossy(&line)) {
+            on_step(step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmd_loggers::CapturingCmdLogger;
+
+    use super::*;
+
+    #[test]
+    fn parses_legacy_steps() {
+        let step = parse_step("Step 2/5 : RUN make\n");
+
+        assert_eq!(
+            step,
+            Some(Step{
+                stage: None,
+                n: 2,
+                total: 5,
+                instruction: "RUN make".to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    fn parses_buildkit_steps() {
+        assert_eq!(
+            parse_step("#7 [build 2/5] RUN make"),
+            Some(Step{
+                stage: Some("build".to_string()),
+                n: 2,
+                total: 5,
+                instruction: "RUN make".to_string(),
+            }),
+        );
+        assert_eq!(
+            parse_step("#4 [1/3] FROM docker.io/library/alpine"),
+            Some(Step{
+                stage: None,
+                n: 1,
+                total: 3,
+                instruction: "FROM docker.io/library/alpine".to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    fn ignores_other_lines() {
+        assert_eq!(parse_step("#1 [internal] load build definition"), None);
+        assert_eq!(parse_step("#7 0.512 [1/2] compiling"), None);
+        assert_eq!(parse_step("#7 DONE 0.3s"), None);
+        assert_eq!(parse_step(" ---> Running in 0123456789ab"), None);
+        assert_eq!(parse_step("Step 2/x : RUN make"), None);
+    }
+
+    #[test]
+    fn abbreviates_long_instructions() {
+        let step = Step{
+            stage: None,
+            n: 1,
+            total: 2,
+            instruction: format!("RUN {}", "a".repeat(50)),
+        };
+
+        let expected = format!("[1/2] RUN {}...", "a".repeat(33));
+        assert_eq!(step.to_string(), expected);
+    }
+
+    #[test]
+    fn logger_finds_steps_split_across_writes() {
+        let mut inner = CapturingCmdLogger::new();
+        let mut steps = vec![];
+        {
+            let mut logger =
+                StepCmdLogger::new(&mut inner, |step| steps.push(step.n));
+
+            logger.log(CmdLoggerMsg::StdoutWrite(b"Step 1/2 : FR"));
+            logger.log(CmdLoggerMsg::StdoutWrite(b"OM a\nStep 2/2 : RUN b\n"));
+        }
+
+        assert_eq!(steps, vec![1, 2]);
+        assert_eq!(inner.chunks.len(), 2);
+    }
+}
@@ -0,0 +1,538 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Management of the cache volumes of environments.
+//!
+//! Cache volumes are accessed through containers of the image of their
+//! environment, so that they're mounted at the same path, and with the same
+//! permissions, as they are for `dock run-in`.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::clean;
+use crate::clean::ListError;
+use crate::clean::RemoveError;
+use crate::clean::Resource;
+use crate::clean::ResourceKind;
+use crate::cmd_loggers::StdoutWritingCmdLogger;
+use crate::docker;
+use crate::gc;
+use crate::labels;
+use crate::logging_process;
+use crate::logging_process::CommandLogger;
+use crate::logging_process::RunError;
+use crate::run_in;
+use crate::run_in::BuildOpts;
+use crate::run_in::CacheVolumeNaming;
+use crate::run_in::FindAndParseDockConfigError;
+use crate::run_in::PrepareRunInCacheVolumesArgsError;
+use crate::run_in::RunInError;
+
+// `CacheVolumeInfo` describes the cache volume `name` of `env_name`. `size` is
+// the size reported by `docker`, if it's known, and `last_use` is the time
+// that `env_name` was last used, if it was recorded.
+pub struct CacheVolumeInfo {
+    pub env_name: String,
+    pub name: String,
+    pub exists: bool,
+    pub size: Option<String>,
+    pub last_use: Option<SystemTime>,
+}
+
+// `list` returns the cache volumes of the environments defined in the Dock
+// file, sorted by environment and cache volume name.
+pub fn list(dock_file_name: &str)
+    -> Result<Vec<CacheVolumeInfo>, ListCacheVolumesError>
+{
+    let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let existing = clean::list(&["volume", "ls", "--format={{.Name}}"])
+        .context(ListVolumesFailed)?;
+    let sizes = clean::volume_sizes();
+
+    let project = labels::project_name(&conf.organisation, &conf.project);
+
+    let mut env_names: Vec<&String> = conf.environments.keys().collect();
+    env_names.sort_unstable();
+
+    let mut vols = vec![];
+    for env_name in env_names {
+        let Some(cache_volumes) = &conf.environments[env_name].cache_volumes
+        else {
+            continue;
+        };
+
+        let last_use = gc::last_use(&project, env_name);
+        let prefix = run_in::cache_vol_name_prefix(
+            &conf.organisation,
+            &conf.project,
+            env_name,
+        );
+
+        let mut names: Vec<&String> = cache_volumes.keys().collect();
+        names.sort_unstable();
+
+        for name in names {
+            let vol_name = run_in::cache_vol_name(&prefix, name);
+
+            vols.push(CacheVolumeInfo{
+                env_name: env_name.clone(),
+                name: name.clone(),
+                exists: existing.contains(&vol_name),
+                size: sizes.get(&vol_name).cloned(),
+                last_use,
+            });
+        }
+    }
+
+    Ok(vols)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ListCacheVolumesError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Couldn't list volumes: {}", source))]
+    ListVolumesFailed{source: ListError},
+}
+
+// `CacheVolumeRef` refers to the cache volume `name` of `env_name`.
+pub struct CacheVolumeRef<'a> {
+    pub env_name: &'a str,
+    pub name: &'a str,
+}
+
+// `Target` is a cache volume, along with the details needed to access it
+// through a container of the image of its environment.
+struct Target {
+    vol_name_prefix: String,
+    vol_name: String,
+    path: PathBuf,
+    labels: Vec<String>,
+    img: String,
+    platform: Option<String>,
+}
+
+// `prepare_target` rebuilds the environment of `vol`, as `dock run-in` would,
+// so that its image can be used to access `vol`, and returns the details
+// needed to access `vol`.
+fn prepare_target(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    vol: &CacheVolumeRef,
+    opts: &BuildOpts,
+    show_rebuild_spinner: bool,
+)
+    -> Result<Target, CacheVolumeError>
+{
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseConfigFailed{dock_file_name})?;
+
+    let env = conf.environments.get(vol.env_name)
+        .context(EnvironmentNotFound{name: vol.env_name})?;
+
+    let path = env.cache_volumes.as_ref()
+        .and_then(|vols| vols.get(vol.name))
+        .context(CacheVolumeNotFound{
+            env_name: vol.env_name,
+            name: vol.name,
+        })?;
+
+    run_in::rebuild_with_deps(
+        logger,
+        &dock_dir,
+        &conf,
+        vol.env_name,
+        opts,
+        show_rebuild_spinner,
+    )
+        .context(RebuildFailed)?;
+
+    let img_name =
+        run_in::platform_image_name(&conf, vol.env_name, env, opts.platform);
+    let vol_name_prefix = run_in::cache_vol_name_prefix(
+        &conf.organisation,
+        &conf.project,
+        vol.env_name,
+    );
+
+    Ok(Target{
+        vol_name: run_in::cache_vol_name(&vol_name_prefix, vol.name),
+        vol_name_prefix,
+        path: path.clone(),
+        labels: labels::label_args(&conf, &dock_dir, vol.env_name),
+        img: img_name + ":latest",
+        platform:
+            run_in::env_platform(env, opts.platform).map(str::to_string),
+    })
+}
+
+// `reset` replaces the cache volume `vol` with an empty cache volume.
+pub fn reset(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    vol: &CacheVolumeRef,
+    opts: &BuildOpts,
+    show_rebuild_spinner: bool,
+)
+    -> Result<(), CacheVolumeError>
+{
+    let target = prepare_target(
+        logger,
+        dock_file_name,
+        vol,
+        opts,
+        show_rebuild_spinner,
+    )?;
+
+    recreate(logger, &target, vol.name)?;
+
+    Ok(())
+}
+
+// `recreate` removes the cache volume of `target`, if it exists, and creates
+// it again, and returns the `--mount` argument for it.
+fn recreate(logger: &mut dyn CommandLogger, target: &Target, name: &str)
+    -> Result<String, CacheVolumeError>
+{
+    let vol_name = &target.vol_name;
+    if volume_exists(vol_name) {
+        let resource = Resource{
+            kind: ResourceKind::Volume,
+            name: vol_name.clone(),
+            size: None,
+        };
+        clean::remove(logger, &resource)
+            .context(RemoveVolumeFailed{vol_name})?;
+    }
+
+    let vol_naming = CacheVolumeNaming{
+        name_prefix: &target.vol_name_prefix,
+        labels: &target.labels,
+    };
+    let mount_arg = run_in::prepare_cache_volume(
+        logger,
+        &vol_naming,
+        name,
+        &target.path,
+        &target.img,
+    )
+        .context(PrepareCacheVolumeFailed{vol_name})?;
+
+    Ok(mount_arg)
+}
+
+// `export` writes the contents of the cache volume `vol` to `archive`, as a
+// gzipped tarball.
+pub fn export(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    vol: &CacheVolumeRef,
+    archive: &Path,
+    opts: &BuildOpts,
+    show_rebuild_spinner: bool,
+)
+    -> Result<(), CacheVolumeError>
+{
+    let target = prepare_target(
+        logger,
+        dock_file_name,
+        vol,
+        opts,
+        show_rebuild_spinner,
+    )?;
+
+    let vol_name = &target.vol_name;
+    if !volume_exists(vol_name) {
+        return Err(CacheVolumeError::VolumeNotCreated{
+            vol_name: vol_name.clone(),
+        });
+    }
+
+    let path = target.path.display().to_string();
+    let mount_arg = format!(
+        "--mount=type=volume,src={vol_name},dst={path},readonly",
+    );
+    let tar_args = ["-czf", "-", "-C", &path, "."];
+    let args = tar_run_args(&target, false, Some(&mount_arg), &tar_args);
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+
+    // The tarball is written to STDOUT of the container, instead of a bind
+    // mount, so that the archive is owned by the current user.
+    let mut file = File::create(archive)
+        .context(CreateArchiveFailed{path: archive})?;
+    let mut writing_logger = StdoutWritingCmdLogger::new(logger, &mut file);
+    let status = logging_process::run(
+        &mut writing_logger,
+        OsStr::new("docker"),
+        &args,
+        Stdio::null(),
+    )
+        .context(RunTarFailed{vol_name})?;
+
+    if let Some(source) = writing_logger.err {
+        // We ignore the result of the removal because the write error is more
+        // relevant.
+        let _ = fs::remove_file(archive);
+
+        return Err(CacheVolumeError::WriteArchiveFailed{
+            source,
+            path: archive.to_path_buf(),
+        });
+    }
+
+    if !status.success() {
+        let _ = fs::remove_file(archive);
+
+        return Err(tar_unsuccessful(&target, status));
+    }
+
+    Ok(())
+}
+
+// `import` replaces the contents of the cache volume `vol` with the contents
+// of `archive`, which should have been created by `export`.
+pub fn import(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    vol: &CacheVolumeRef,
+    archive: &Path,
+    opts: &BuildOpts,
+    show_rebuild_spinner: bool,
+)
+    -> Result<(), CacheVolumeError>
+{
+    // We open `archive` before the cache volume is replaced so that the cache
+    // volume is kept if `archive` can't be read.
+    let file = File::open(archive)
+        .context(OpenArchiveFailed{path: archive})?;
+
+    let target = prepare_target(
+        logger,
+        dock_file_name,
+        vol,
+        opts,
+        show_rebuild_spinner,
+    )?;
+
+    // We check that `archive` can be read by `tar` before the cache volume is
+    // replaced so that the cache volume is kept if `archive` is corrupt, or if
+    // the image doesn't contain `tar`.
+    check_archive(logger, &target, file, archive)?;
+
+    let file = File::open(archive)
+        .context(OpenArchiveFailed{path: archive})?;
+
+    let mount_arg = recreate(logger, &target, vol.name)?;
+
+    let path = target.path.display().to_string();
+    let tar_args = ["-xzf", "-", "-C", &path];
+    let args = tar_run_args(&target, true, Some(&mount_arg), &tar_args);
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+
+    let vol_name = &target.vol_name;
+    let status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &args,
+        Stdio::from(file),
+    )
+        .context(RunTarFailed{vol_name})?;
+
+    if !status.success() {
+        return Err(tar_unsuccessful(&target, status));
+    }
+
+    Ok(())
+}
+
+// `check_archive` lists the contents of `file`, which was opened from
+// `archive`, using `tar` in a container of the image of `target`, and returns
+// an error if `tar` can't be found or if `file` can't be read as a gzipped
+// tarball.
+fn check_archive(
+    logger: &mut dyn CommandLogger,
+    target: &Target,
+    file: File,
+    archive: &Path,
+)
+    -> Result<(), CacheVolumeError>
+{
+    let args = tar_run_args(target, true, None, &["-tzf", "-"]);
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+
+    let vol_name = &target.vol_name;
+    let status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &args,
+        Stdio::from(file),
+    )
+        .context(RunTarFailed{vol_name})?;
+
+    if status.code() == Some(COMMAND_NOT_FOUND_EXIT_CODE) {
+        return Err(tar_unsuccessful(target, status));
+    }
+
+    if !status.success() {
+        return Err(CacheVolumeError::InvalidArchive{
+            path: archive.to_path_buf(),
+        });
+    }
+
+    Ok(())
+}
+
+// `COMMAND_NOT_FOUND_EXIT_CODE` is the exit code that `docker run` returns if
+// the command can't be found in the image.
+const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 127;
+
+// `tar_unsuccessful` returns the error for a `tar` run for `target` that
+// exited with `status`.
+fn tar_unsuccessful(target: &Target, status: ExitStatus) -> CacheVolumeError {
+    if status.code() == Some(COMMAND_NOT_FOUND_EXIT_CODE) {
+        return CacheVolumeError::TarNotFound{
+            img: target.img.clone(),
+        };
+    }
+
+    CacheVolumeError::TarUnsuccessful{vol_name: target.vol_name.clone()}
+}
+
+// `tar_run_args` returns the `docker` arguments for running `tar` with
+// `tar_args` in a container of the image of `target`, with the cache volume
+// mounted using `mount_arg`, if it's given. `tar` is run as `root` so that it
+// can read and preserve the ownership of every file in the cache volume.
+fn tar_run_args(
+    target: &Target,
+    interactive: bool,
+    mount_arg: Option<&str>,
+    tar_args: &[&str],
+)
+    -> Vec<String>
+{
+    let mut args = vec![
+        "run".to_string(),
+        "--rm".to_string(),
+        "--user=root".to_string(),
+        "--entrypoint=tar".to_string(),
+    ];
+
+    if interactive {
+        args.push("--interactive".to_string());
+    }
+
+    if let Some(platform) = &target.platform {
+        args.push(format!("--platform={platform}"));
+    }
+
+    args.extend(target.labels.iter().cloned());
+    args.push(labels::container_marker_arg());
+    if let Some(mount_arg) = mount_arg {
+        args.push(mount_arg.to_string());
+    }
+    args.push(target.img.clone());
+    args.extend(tar_args.iter().map(ToString::to_string));
+
+    args
+}
+
+fn volume_exists(vol_name: &str) -> bool {
+    docker::assert_run(["volume", "inspect", vol_name]).is_ok()
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum CacheVolumeError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+    #[snafu(display(
+        "Dock environment '{}' doesn't define the cache volume '{}'",
+        env_name,
+        name,
+    ))]
+    CacheVolumeNotFound{env_name: String, name: String},
+    #[snafu(display("{}", source))]
+    RebuildFailed{source: RunInError},
+    #[snafu(display(
+        "The cache volume '{}' hasn't been created yet",
+        vol_name,
+    ))]
+    VolumeNotCreated{vol_name: String},
+    #[snafu(display(
+        "Couldn't remove the cache volume '{}': {}",
+        vol_name,
+        source,
+    ))]
+    RemoveVolumeFailed{source: RemoveError, vol_name: String},
+    #[snafu(display(
+        "Couldn't create the cache volume '{}': {}",
+        vol_name,
+        source,
+    ))]
+    PrepareCacheVolumeFailed{
+        source: PrepareRunInCacheVolumesArgsError,
+        vol_name: String,
+    },
+    #[snafu(display("Couldn't open '{}': {}", path.display(), source))]
+    OpenArchiveFailed{source: IoError, path: PathBuf},
+    #[snafu(display("Couldn't create '{}': {}", path.display(), source))]
+    CreateArchiveFailed{source: IoError, path: PathBuf},
+    #[snafu(display("Couldn't write to '{}': {}", path.display(), source))]
+    WriteArchiveFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't run `tar` for the cache volume '{}': {}",
+        vol_name,
+        source,
+    ))]
+    RunTarFailed{source: RunError, vol_name: String},
+    #[snafu(display(
+        "`tar` was unsuccessful for the cache volume '{}'; run with \
+         `--debug` for more details",
+        vol_name,
+    ))]
+    TarUnsuccessful{vol_name: String},
+    #[snafu(display(
+        "`tar` couldn't be found in '{}'; cache volumes are accessed by \
+         running `tar` in the image of their environment, so `tar` (with \
+         `gzip` support) must be installed in the image",
+        img,
+    ))]
+    TarNotFound{img: String},
+    #[snafu(display(
+        "`tar` couldn't read '{}', so the cache volume wasn't changed; run \
+         with `--debug` for more details",
+        path.display(),
+    ))]
+    InvalidArchive{path: PathBuf},
+}
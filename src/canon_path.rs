@@ -23,7 +23,7 @@ use std::str;
 use snafu::OptionExt;
 use snafu::Snafu;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AbsPath {
     components: Vec<OsString>,
 }
@@ -80,6 +80,45 @@ impl AbsPath {
 
         p
     }
+
+    /// Returns `p` as an `AbsPath`, resolving it against `self` if it's
+    /// relative. `.` and `..` components are resolved lexically, so symbolic
+    /// links aren't followed.
+    pub fn resolve(&self, p: &Path) -> Result<Self, ResolvePathError> {
+        let mut components =
+            if p.has_root() {
+                vec![]
+            } else {
+                self.components.clone()
+            };
+
+        for component in p.components() {
+            match component {
+                Component::Normal(c) => {
+                    components.push(c.to_os_string());
+                },
+                Component::ParentDir => {
+                    components.pop()
+                        .context(TraversalAboveRoot)?;
+                },
+                Component::CurDir | Component::RootDir => {
+                },
+                Component::Prefix(_) => {
+                    return Err(ResolvePathError::PrefixInPath);
+                },
+            }
+        }
+
+        Ok(Self{components})
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum ResolvePathError {
+    #[snafu(display("The path refers to the parent of `/`"))]
+    TraversalAboveRoot,
+    #[snafu(display("The path contained a Windows path prefix"))]
+    PrefixInPath,
 }
 
 impl TryFrom<PathBuf> for AbsPath {
@@ -150,7 +189,7 @@ pub enum NewAbsPathError {
     SpecialComponentInAbsPath,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RelPath {
     components: Vec<OsString>,
 }
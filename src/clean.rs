@@ -2,85 +2,615 @@
 // Use of this source code is governed by an MIT
 // licence that can be found in the LICENCE file.
 
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::process::Stdio;
 use std::str;
+use std::str::Utf8Error;
 
+use serde::Deserialize;
 use snafu::ResultExt;
 use snafu::Snafu;
 
+use crate::cmd_loggers::Stream;
+use crate::cmd_loggers::TeeCmdLogger;
+use crate::docker;
+use crate::docker::AssertRunError;
+use crate::history;
+use crate::history::ListPrevTagsError;
+use crate::labels;
 use crate::logging_process;
 use crate::logging_process::CommandLogger;
 use crate::logging_process::RunError;
 use crate::run_in;
+use crate::run_in::DockConfig;
+use crate::run_in::DEFAULT_CACHE_TAG;
 use crate::run_in::FindAndParseDockConfigError;
 
-pub fn clean(
-    logger: &mut dyn CommandLogger,
-    dock_file_name: &str,
-    remove_images: bool,
-    remove_volumes: bool,
-)
-    -> Result<(), CleanError>
+// `ResourceKind` is a kind of Docker resource that `clean` removes. Kinds are
+// ordered in the order that their resources should be removed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ResourceKind {
+    Container,
+    Image,
+    Volume,
+}
+
+impl ResourceKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Container => "container",
+            Self::Image => "image",
+            Self::Volume => "volume",
+        }
+    }
+}
+
+// `Resource` is a Docker resource that `clean` removes. `size` is the size
+// reported by `docker`, if it's known.
+#[derive(Debug)]
+pub struct Resource {
+    pub kind: ResourceKind,
+    pub name: String,
+    pub size: Option<String>,
+}
+
+// `Selection` selects the resources that `clean` removes.
+pub struct Selection<'a> {
+    // `env_names` selects the resources of the named environments, which
+    // don't need to be defined in the Dock file. The resources of all
+    // environments are selected if `env_names` is empty.
+    pub env_names: &'a [&'a str],
+    // `volume_names` selects the cache volumes with the given names, as
+    // defined in `cache_volumes`. All cache volumes are selected if
+    // `volume_names` is empty.
+    pub volume_names: &'a [&'a str],
+    pub containers: bool,
+    pub images: bool,
+    pub volumes: bool,
+}
+
+// `plan` returns the resources of the project that are selected by
+// `selection`, in the order that they should be removed. Containers come
+// first, because images and volumes can't be removed while they're used by a
+// container. Only stopped containers that were created by `dock` are
+// included, so that `clean` doesn't stop commands that are in progress.
+//
+// Resources are found using their labels, so that the resources of
+// environments that have been renamed or removed are also found. Resources
+// that were created before `dock` labelled its resources are found using the
+// names of the environments that are defined in the Dock file.
+pub fn plan(dock_file_name: &str, selection: &Selection)
+    -> Result<Vec<Resource>, CleanError>
 {
     let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
         .context(FindAndParseDockConfigFailed{dock_file_name})?;
 
-    for (env_name, env) in conf.environments {
-        if remove_volumes {
-            let cache_vol_name_prefix = run_in::cache_vol_name_prefix(
-                &conf.organisation,
-                &conf.project,
-                &env_name,
-            );
-
-            if let Some(vols) = env.cache_volumes {
-                for (vol_name, _) in vols {
-                    let name = run_in::cache_vol_name(
-                        &cache_vol_name_prefix,
-                        &vol_name,
-                    );
-
-                    let prog = OsStr::new("docker");
-                    let raw_rm_args = &["volume", "rm", name.as_str()];
-                    let rm_args = run_in::new_os_strs(raw_rm_args);
-                    let _ = logging_process::run(
-                        logger,
-                        prog,
-                        &rm_args,
-                        Stdio::null(),
-                    )
-                        .context(RemoveVolumeFailed{name})?;
-                }
+    // Label filters are combined with "and", so environments are listed
+    // separately.
+    let env_filters: Vec<Option<String>> =
+        if selection.env_names.is_empty() {
+            vec![None]
+        } else {
+            selection.env_names
+                .iter()
+                .map(|env_name| {
+                    let label = labels::ENV_LABEL;
+
+                    Some(format!("--filter=label={label}={env_name}"))
+                })
+                .collect()
+        };
+
+    let mut env_names: Vec<&String> =
+        conf.environments
+            .keys()
+            .filter(|env_name| {
+                selection.env_names.is_empty()
+                    || selection.env_names.contains(&env_name.as_str())
+            })
+            .collect();
+    env_names.sort_unstable();
+
+    let scope = Scope{
+        conf: &conf,
+        project_filter:
+            labels::project_filter(&conf.organisation, &conf.project),
+        env_filters,
+        env_names,
+    };
+
+    let mut resources = vec![];
+
+    if selection.containers {
+        resources.extend(plan_containers(&scope)?);
+    }
+
+    if selection.images {
+        resources.extend(plan_images(&scope)?);
+    }
+
+    if selection.volumes {
+        resources.extend(plan_volumes(&scope, selection.volume_names)?);
+    }
+
+    // Resources may be found both by label and by name.
+    let mut seen = HashSet::new();
+    resources.retain(|r| seen.insert((r.kind, r.name.clone())));
+
+    Ok(resources)
+}
+
+// `Scope` describes where `plan` looks for resources. `env_filters` are the
+// label filters for the selected environments, where `None` selects all
+// environments, and `env_names` are the selected environments that are
+// defined in the Dock file.
+struct Scope<'a> {
+    conf: &'a DockConfig,
+    project_filter: String,
+    env_filters: Vec<Option<String>>,
+    env_names: Vec<&'a String>,
+}
+
+// `plan_containers` returns the stopped containers in `scope` that were
+// created by `dock`.
+fn plan_containers(scope: &Scope) -> Result<Vec<Resource>, CleanError> {
+    let marker_filter = format!("--filter=label={}", labels::CONTAINER_LABEL);
+
+    let mut resources = vec![];
+    for env_filter in &scope.env_filters {
+        let mut args = vec![
+            "container",
+            "ls",
+            "--all",
+            "--size",
+            "--format={{.ID}}\t{{.Size}}",
+            "--filter=status=created",
+            "--filter=status=exited",
+            &scope.project_filter,
+            &marker_filter,
+        ];
+        args.extend(env_filter.as_deref());
+
+        let lines = list(&args)
+            .context(ListLabelledContainersFailed)?;
+        for line in lines {
+            let (id, size) = split_line(&line);
+            resources.push(Resource{
+                kind: ResourceKind::Container,
+                name: id.to_string(),
+                size,
+            });
+        }
+    }
+
+    Ok(resources)
+}
+
+// `plan_images` returns the images in `scope` that were built by `dock`.
+fn plan_images(scope: &Scope) -> Result<Vec<Resource>, CleanError> {
+    let format_arg = "--format={{.Repository}}:{{.Tag}}\t{{.ID}}\t{{.Size}}";
+
+    let mut lines = vec![];
+    for env_filter in &scope.env_filters {
+        let mut args = vec!["image", "ls", format_arg, &scope.project_filter];
+        args.extend(env_filter.as_deref());
+
+        let env_lines = list(&args)
+            .context(ListLabelledImagesFailed)?;
+        lines.extend(env_lines);
+    }
+
+    // Images that other tools build from `dock` images inherit their labels,
+    // so we only keep the labelled images that are marked as having been
+    // built by `dock`.
+    let markers = image_markers(&lines)
+        .context(InspectLabelledImagesFailed)?;
+    lines.retain(|line| {
+        let (name, rest) = split_line(line);
+        let (id, _) = split_line(rest.as_deref().unwrap_or_default());
+        let marker = markers.get(id).map_or("", String::as_str);
+
+        labels::is_marked_image(marker, name)
+    });
+
+    let conf = scope.conf;
+    let mut unlabelled_names = vec![];
+    for env_name in &scope.env_names {
+        let img_name =
+            run_in::image_name(&conf.organisation, &conf.project, env_name);
+        unlabelled_names.extend(unlabelled_img_names(img_name)?);
+    }
+    // `docker image ls` lists all images if no filters are given, so we only
+    // list unlabelled images if there are any names to look for.
+    if !unlabelled_names.is_empty() {
+        let reference_filters: Vec<String> =
+            unlabelled_names
+                .iter()
+                .map(|name| format!("--filter=reference={name}"))
+                .collect();
+        let mut args = vec!["image", "ls", format_arg];
+        args.extend(reference_filters.iter().map(String::as_str));
+
+        let unlabelled_lines = list(&args)
+            .context(ListUnlabelledImagesFailed)?;
+        lines.extend(unlabelled_lines);
+    }
+
+    let mut resources = vec![];
+    for line in lines {
+        let (name, rest) = split_line(&line);
+        let (id, size) = split_line(rest.as_deref().unwrap_or_default());
+
+        // Untagged images can only be removed by ID.
+        let name =
+            if name == "<none>:<none>" {
+                id
+            } else {
+                name
+            };
+
+        resources.push(Resource{
+            kind: ResourceKind::Image,
+            name: name.to_string(),
+            size,
+        });
+    }
+
+    Ok(resources)
+}
+
+// `plan_volumes` returns the cache volumes in `scope` that are selected by
+// `volume_names`, where all cache volumes are selected if `volume_names` is
+// empty.
+fn plan_volumes(scope: &Scope, volume_names: &[&str])
+    -> Result<Vec<Resource>, CleanError>
+{
+    let conf = scope.conf;
+    let is_selected_volume = |env_name: &str, name: &str| {
+        let prefix = run_in::cache_vol_name_prefix(
+            &conf.organisation,
+            &conf.project,
+            env_name,
+        );
+
+        volume_names.is_empty()
+            || volume_names
+                .iter()
+                .any(|vol| name == run_in::cache_vol_name(&prefix, vol))
+    };
+
+    let mut resources = vec![];
+
+    let format_arg = format!(
+        "--format={{{{.Name}}}}\t{{{{.Label \"{}\"}}}}",
+        labels::ENV_LABEL,
+    );
+    for env_filter in &scope.env_filters {
+        let mut args =
+            vec!["volume", "ls", &format_arg, &scope.project_filter];
+        args.extend(env_filter.as_deref());
+
+        let lines = list(&args)
+            .context(ListLabelledVolumesFailed)?;
+        for line in lines {
+            let (name, env_name) = split_line(&line);
+            let env_name = env_name.unwrap_or_default();
+
+            if is_selected_volume(&env_name, name) {
+                resources.push(Resource{
+                    kind: ResourceKind::Volume,
+                    name: name.to_string(),
+                    size: None,
+                });
             }
         }
+    }
+
+    let mut unlabelled_names = vec![];
+    for env_name in &scope.env_names {
+        let env = &conf.environments[*env_name];
+        let prefix = run_in::cache_vol_name_prefix(
+            &conf.organisation,
+            &conf.project,
+            env_name,
+        );
 
-        if remove_images {
-            let img_name = run_in::image_name(
-                &conf.organisation,
-                &conf.project,
-                &env_name,
-            );
-            let name = img_name + ":latest";
-            // TODO Handle cache image.
-
-            let prog = OsStr::new("docker");
-            let raw_rmi_args = &["rmi", name.as_str()];
-            let rmi_args = run_in::new_os_strs(raw_rmi_args);
-            let _ = logging_process::run(
-                logger,
-                prog,
-                &rmi_args,
-                Stdio::null(),
-            )
-                .context(RemoveImageFailed{name})?;
+        for vol_name in env.cache_volumes.iter().flat_map(|v| v.keys()) {
+            let name = run_in::cache_vol_name(&prefix, vol_name);
+            if is_selected_volume(env_name, &name) {
+                unlabelled_names.push(name);
+            }
         }
     }
+    // `docker volume ls` doesn't support exact name filters, so we list all
+    // volumes and look for the unlabelled names among them.
+    if !unlabelled_names.is_empty() {
+        let names = list(&["volume", "ls", "--format={{.Name}}"])
+            .context(ListUnlabelledVolumesFailed)?;
+
+        for name in names {
+            if unlabelled_names.contains(&name) {
+                resources.push(Resource{
+                    kind: ResourceKind::Volume,
+                    name,
+                    size: None,
+                });
+            }
+        }
+    }
+
+    Ok(resources)
+}
+
+// `split_line` splits `line` at its first tab.
+fn split_line(line: &str) -> (&str, Option<String>) {
+    match line.split_once('\t') {
+        Some((first, rest)) => (first, Some(rest.to_string())),
+        None => (line, None),
+    }
+}
+
+// `image_markers` returns the values of the image marker labels of the images
+// in `lines`, which are in the format used by `plan` for listing images,
+// indexed by image ID.
+fn image_markers(lines: &[String])
+    -> Result<HashMap<String, String>, ListError>
+{
+    let mut ids: Vec<&str> =
+        lines
+            .iter()
+            .filter_map(|line| line.split('\t').nth(1))
+            .collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    // `docker image inspect` fails if no images are given.
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let format_arg = format!(
+        "--format={{{{index .Config.Labels \"{}\"}}}}",
+        labels::IMAGE_LABEL,
+    );
+    let mut args = vec!["image", "inspect", &format_arg];
+    args.extend(&ids);
+
+    // `docker image inspect` outputs the images in the order that they're
+    // given.
+    let markers = list(&args)?;
+
+    Ok(ids.into_iter().map(str::to_string).zip(markers).collect())
+}
+
+// `unlabelled_img_names` returns the names of the images of `img_name` that
+// may have been created before `dock` labelled its images, which are the
+// current image, the images for other platforms, their previous images, and
+// their cache images, which are left behind if a rebuild is interrupted.
+// Cache images that use a tag other than the default cache tag aren't
+// included, because their tags aren't known.
+fn unlabelled_img_names(img_name: String) -> Result<Vec<String>, CleanError> {
+    // Images built for other platforms have their own names, so we remove
+    // their images too.
+    let mut img_names = platform_img_names(&img_name)
+        .context(ListPlatformImagesFailed{img_name: img_name.clone()})?;
+    img_names.insert(0, img_name);
+
+    let mut names = vec![];
+    for img_name in img_names {
+        let prev_imgs = history::prev_imgs(&img_name)
+            .context(ListPrevImagesFailed{img_name: img_name.clone()})?;
+        names.push(format!("{img_name}:latest"));
+        names.push(format!("{img_name}:{DEFAULT_CACHE_TAG}"));
+        names.extend(prev_imgs);
+    }
+
+    Ok(names)
+}
+
+// `list` runs `docker` with `args`, which should list resources in a format
+// with one resource per line, and returns the listed resources.
+pub fn list(args: &[&str]) -> Result<Vec<String>, ListError> {
+    let output = docker::assert_run(args)
+        .context(ListFailed)?;
+
+    let stdout = str::from_utf8(&output.stdout)
+        .context(ListOutputNotUtf8)?;
+
+    Ok(stdout.lines().map(str::to_string).collect())
+}
+
+#[derive(Debug, Snafu)]
+pub enum ListError {
+    #[snafu(display("{}", source))]
+    ListFailed{source: AssertRunError},
+    #[snafu(display("`docker` output wasn't UTF-8: {}", source))]
+    ListOutputNotUtf8{source: Utf8Error},
+}
+
+// `add_volume_sizes` sets the sizes of the volumes in `resources`, if they
+// can be found.
+pub fn add_volume_sizes(resources: &mut [Resource]) {
+    let sizes = volume_sizes();
+
+    for resource in resources {
+        if resource.kind == ResourceKind::Volume {
+            resource.size = sizes.get(&resource.name).cloned();
+        }
+    }
+}
+
+// `volume_sizes` returns the sizes of the volumes on the host, as reported by
+// `docker`, or an empty map if they can't be found. Volume sizes are only
+// reported by `docker system df`, which can be slow, so they're only found
+// when they're needed.
+pub fn volume_sizes() -> HashMap<String, String> {
+    #[derive(Deserialize)]
+    struct DiskUsage {
+        #[serde(rename = "Volumes", default)]
+        volumes: Vec<VolumeUsage>,
+    }
+
+    #[derive(Deserialize)]
+    struct VolumeUsage {
+        #[serde(rename = "Name")]
+        name: String,
+        #[serde(rename = "Size")]
+        size: String,
+    }
+
+    let args = ["system", "df", "--verbose", "--format={{json .}}"];
+    let Ok(output) = docker::assert_run(args) else {
+        return HashMap::new();
+    };
+
+    // `docker` outputs JSON, which can be parsed as YAML.
+    let usage: DiskUsage =
+        match serde_yaml::from_slice(&output.stdout) {
+            Ok(usage) => usage,
+            Err(_) => return HashMap::new(),
+        };
+
+    usage.volumes
+        .into_iter()
+        .map(|vol| (vol.name, vol.size))
+        .collect()
+}
+
+// `CleanSummary` is the number of each kind of resource removed by
+// `remove_all`.
+#[derive(Debug, Default)]
+pub struct CleanSummary {
+    pub containers: usize,
+    pub images: usize,
+    pub volumes: usize,
+}
+
+// `RemoveFailure` is a resource that couldn't be removed by `remove_all`.
+#[derive(Debug)]
+pub struct RemoveFailure {
+    pub resource: Resource,
+    pub source: RemoveError,
+}
+
+// `remove_all` removes `resources`, in order, and returns the number of each
+// kind of resource that was removed, along with the resources that couldn't
+// be removed. A resource that couldn't be removed doesn't prevent the
+// remaining resources from being removed.
+pub fn remove_all(logger: &mut dyn CommandLogger, resources: Vec<Resource>)
+    -> (CleanSummary, Vec<RemoveFailure>)
+{
+    let mut summary = CleanSummary::default();
+    let mut failures = vec![];
+    for resource in resources {
+        match remove(logger, &resource) {
+            Ok(()) => {
+                let count =
+                    match resource.kind {
+                        ResourceKind::Container => &mut summary.containers,
+                        ResourceKind::Image => &mut summary.images,
+                        ResourceKind::Volume => &mut summary.volumes,
+                    };
+                *count += 1;
+            },
+            Err(source) => {
+                failures.push(RemoveFailure{resource, source});
+            },
+        }
+    }
+
+    (summary, failures)
+}
+
+// `remove` removes `resource`. The STDERR of `docker` is returned as the
+// reason for an unsuccessful removal.
+pub fn remove(logger: &mut dyn CommandLogger, resource: &Resource)
+    -> Result<(), RemoveError>
+{
+    let name = resource.name.as_str();
+    let args =
+        match resource.kind {
+            ResourceKind::Container => vec!["rm", name],
+            ResourceKind::Image => vec!["rmi", name],
+            ResourceKind::Volume => vec!["volume", "rm", name],
+        };
+
+    // We capture the output of the command so that the reason for a failure,
+    // such as the resource being in use, can be reported.
+    let mut tee = TeeCmdLogger::new(logger);
+    let status = logging_process::run(
+        &mut tee,
+        OsStr::new("docker"),
+        &run_in::new_os_strs(&args),
+        Stdio::null(),
+    )
+        .context(RunRemoveFailed)?;
+
+    if !status.success() {
+        let stderr: Vec<u8> =
+            tee.capture.chunks
+                .into_iter()
+                .filter(|(stream, _)| matches!(stream, Stream::Stderr))
+                .flat_map(|(_, chunk)| chunk)
+                .collect();
+
+        return Err(RemoveError::RemoveUnsuccessful{
+            stderr: String::from_utf8_lossy(&stderr).trim().to_string(),
+        });
+    }
 
     Ok(())
 }
 
+#[derive(Debug, Snafu)]
+pub enum RemoveError {
+    #[snafu(display("Couldn't run `docker`: {}", source))]
+    RunRemoveFailed{source: RunError},
+    #[snafu(display("{}", stderr))]
+    RemoveUnsuccessful{stderr: String},
+}
+
+// `platform_img_names` returns the names of the images of `img_name` that were
+// built for platforms other than the configured platform of its environment.
+fn platform_img_names(img_name: &str)
+    -> Result<Vec<String>, ListPlatformImgNamesError>
+{
+    let reference_filter = format!(
+        "--filter=reference={}",
+        run_in::platform_image_names_pattern(img_name),
+    );
+    let label_filter = format!("--filter=label={}", run_in::PLATFORM_LABEL);
+    let args = [
+        "image",
+        "ls",
+        "--format={{.Repository}}",
+        &reference_filter,
+        &label_filter,
+    ];
+    let output = docker::assert_run(args)
+        .context(ListImagesFailed)?;
+
+    let stdout = str::from_utf8(&output.stdout)
+        .context(ListImagesOutputNotUtf8)?;
+
+    let mut names: Vec<String> = stdout.lines().map(str::to_string).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    Ok(names)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ListPlatformImgNamesError {
+    #[snafu(display("Couldn't list images: {}", source))]
+    ListImagesFailed{source: AssertRunError},
+    #[snafu(display("`docker image ls` output wasn't UTF-8: {}", source))]
+    ListImagesOutputNotUtf8{source: Utf8Error},
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Snafu)]
 pub enum CleanError {
@@ -93,14 +623,34 @@ pub enum CleanError {
         source: FindAndParseDockConfigError,
         dock_file_name: String,
     },
-    #[snafu(display("Couldn't remove volume '{}': {}", name, source))]
-    RemoveVolumeFailed{
-        source: RunError,
-        name: String,
+    #[snafu(display("Couldn't list containers: {}", source))]
+    ListLabelledContainersFailed{source: ListError},
+    #[snafu(display("Couldn't list images: {}", source))]
+    ListLabelledImagesFailed{source: ListError},
+    #[snafu(display("Couldn't inspect images: {}", source))]
+    InspectLabelledImagesFailed{source: ListError},
+    #[snafu(display("Couldn't list images: {}", source))]
+    ListUnlabelledImagesFailed{source: ListError},
+    #[snafu(display("Couldn't list volumes: {}", source))]
+    ListLabelledVolumesFailed{source: ListError},
+    #[snafu(display("Couldn't list volumes: {}", source))]
+    ListUnlabelledVolumesFailed{source: ListError},
+    #[snafu(display(
+        "Couldn't list previous images of '{}': {}",
+        img_name,
+        source,
+    ))]
+    ListPrevImagesFailed{
+        source: ListPrevTagsError,
+        img_name: String,
     },
-    #[snafu(display("Couldn't remove image '{}': {}", name, source))]
-    RemoveImageFailed{
-        source: RunError,
-        name: String,
+    #[snafu(display(
+        "Couldn't list platform-specific images of '{}': {}",
+        img_name,
+        source,
+    ))]
+    ListPlatformImagesFailed{
+        source: ListPlatformImgNamesError,
+        img_name: String,
     },
 }
@@ -41,6 +41,59 @@ impl CommandLogger for CapturingCmdLogger {
     }
 }
 
+// `TeeCmdLogger` passes messages to `logger`, and also captures the output of
+// the logged commands in `capture`.
+pub struct TeeCmdLogger<'a> {
+    logger: &'a mut dyn CommandLogger,
+    pub capture: CapturingCmdLogger,
+}
+
+impl<'a> TeeCmdLogger<'a> {
+    pub fn new(logger: &'a mut dyn CommandLogger) -> Self {
+        Self{logger, capture: CapturingCmdLogger::new()}
+    }
+}
+
+impl CommandLogger for TeeCmdLogger<'_> {
+    fn log(&mut self, msg: CmdLoggerMsg) {
+        self.capture.log(msg);
+        self.logger.log(msg);
+    }
+}
+
+// `StdoutWritingCmdLogger` writes the STDOUT of the logged commands to `w`,
+// instead of passing it to `logger`, which receives all other messages. The
+// first error that occurs while writing to `w` is stored in `err`, after which
+// STDOUT is discarded.
+pub struct StdoutWritingCmdLogger<'a> {
+    logger: &'a mut dyn CommandLogger,
+    w: &'a mut dyn Write,
+    pub err: Option<IoError>,
+}
+
+impl<'a> StdoutWritingCmdLogger<'a> {
+    pub fn new(logger: &'a mut dyn CommandLogger, w: &'a mut dyn Write)
+        -> Self
+    {
+        Self{logger, w, err: None}
+    }
+}
+
+impl CommandLogger for StdoutWritingCmdLogger<'_> {
+    fn log(&mut self, msg: CmdLoggerMsg) {
+        match msg {
+            CmdLoggerMsg::StdoutWrite(bytes) => {
+                if self.err.is_none() {
+                    self.err = self.w.write_all(bytes).err();
+                }
+            },
+            _ => {
+                self.logger.log(msg);
+            },
+        }
+    }
+}
+
 pub struct TimingPrefixingCmdLogger<'a> {
     logger: PrefixingCmdLogger<'a>,
     duration_prefix: &'a [u8],
@@ -0,0 +1,337 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Sources of build contexts.
+//!
+//! The `context` of an environment can be a directory, relative to the
+//! directory containing the Dock file or absolute, or a subdirectory of a Git
+//! repository, in the form `git+<url>#<ref>:<subdir>`. Git repositories are
+//! cloned into the cache directory of the project, and are updated each time
+//! that the context is resolved.
+
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use sha2::Digest;
+use sha2::Sha256;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::build_log;
+use crate::canon_path::AbsPath;
+use crate::canon_path::NewAbsPathError;
+use crate::canon_path::NewRelPathError;
+use crate::canon_path::RelPath;
+use crate::canon_path::ResolvePathError;
+use crate::init;
+use crate::init::GitCloneToError;
+use crate::init::GitTemplatesSource;
+use crate::run_in::AssertRunError;
+
+const GIT_PREFIX: &str = "git+";
+
+#[derive(Debug, PartialEq)]
+pub enum ContextSource {
+    Dir(AbsPath),
+    Git{url: String, reference: String, subdir: RelPath},
+}
+
+// `parse` returns the source of the context defined by `raw`. Relative paths
+// are resolved against `dock_dir`, and must start with `.` or `..`.
+pub fn parse(dock_dir: &AbsPath, raw: &str)
+    -> Result<ContextSource, ParseContextSourceError>
+{
+    if let Some(rest) = raw.strip_prefix(GIT_PREFIX) {
+        let (url, rest) = rest.rsplit_once('#')
+            .ok_or(ParseContextSourceError::MissingGitRef)?;
+
+        let (reference, subdir) =
+            match rest.split_once(':') {
+                Some((reference, subdir)) => (reference, subdir),
+                None => (rest, "."),
+            };
+
+        if url.is_empty() || reference.is_empty() {
+            return Err(ParseContextSourceError::MissingGitRef);
+        }
+
+        let subdir = RelPath::try_from(PathBuf::from(subdir))
+            .context(GitSubdirToRelPathFailed{subdir})?;
+
+        return Ok(ContextSource::Git{
+            url: url.to_string(),
+            reference: reference.to_string(),
+            subdir,
+        });
+    }
+
+    let path = Path::new(raw);
+    let first = path.components().next();
+    let is_explicit = matches!(
+        first,
+        Some(Component::CurDir | Component::ParentDir | Component::RootDir),
+    );
+    if !is_explicit {
+        return Err(ParseContextSourceError::AmbiguousPath{
+            path: raw.to_string(),
+        });
+    }
+
+    let path = dock_dir.resolve(path)
+        .context(ResolveContextPathFailed{path: raw})?;
+
+    Ok(ContextSource::Dir(path))
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseContextSourceError {
+    #[snafu(display(
+        "Git contexts must be in the form `git+<url>#<ref>[:<subdir>]`",
+    ))]
+    MissingGitRef,
+    #[snafu(display(
+        "Couldn't convert the subdirectory '{}' to a relative path: {}",
+        subdir,
+        source,
+    ))]
+    GitSubdirToRelPathFailed{source: NewRelPathError, subdir: String},
+    #[snafu(display(
+        "The context path '{}' must start with `.`, `..` or `/`",
+        path,
+    ))]
+    AmbiguousPath{path: String},
+    #[snafu(display(
+        "Couldn't resolve the context path '{}': {}",
+        path,
+        source,
+    ))]
+    ResolveContextPathFailed{source: ResolvePathError, path: String},
+}
+
+// `ResolvedContext` is the local directory of a context source. `commit` is
+// the commit that was checked out, if the source is a Git repository.
+pub struct ResolvedContext {
+    pub path: AbsPath,
+    pub commit: Option<String>,
+}
+
+// `resolve` returns the local directory of `source`, fetching it into the
+// cache directory of the project `proj` of `org` if it's a Git repository.
+pub fn resolve(source: &ContextSource, org: &str, proj: &str)
+    -> Result<ResolvedContext, ResolveContextError>
+{
+    let resolved =
+        match source {
+            ContextSource::Dir(path) => {
+                ResolvedContext{path: path.clone(), commit: None}
+            },
+            ContextSource::Git{url, reference, subdir} => {
+                let cache_dir =
+                    build_log::project_cache_dir(org, proj).join("contexts");
+
+                let (clone_dir, commit) =
+                    fetch_git_context(&cache_dir, url, reference)
+                        .context(FetchGitContextFailed)?;
+
+                let clone_dir = AbsPath::try_from(clone_dir.clone())
+                    .context(CloneDirToAbsPathFailed{path: clone_dir})?;
+
+                ResolvedContext{
+                    path: clone_dir.concat(subdir),
+                    commit: Some(commit),
+                }
+            },
+        };
+
+    if !PathBuf::from(resolved.path.clone()).is_dir() {
+        return Err(ResolveContextError::ContextDirNotFound{
+            path: resolved.path,
+        });
+    }
+
+    Ok(resolved)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ResolveContextError {
+    #[snafu(display("Couldn't fetch the Git context: {}", source))]
+    FetchGitContextFailed{source: FetchGitContextError},
+    #[snafu(display(
+        "Couldn't use '{}' as the context directory: {}",
+        path.display(),
+        source,
+    ))]
+    CloneDirToAbsPathFailed{source: NewAbsPathError, path: PathBuf},
+    #[snafu(display(
+        "The context directory '{}' doesn't exist",
+        path.display_lossy(),
+    ))]
+    ContextDirNotFound{path: AbsPath},
+}
+
+// `fetch_git_context` returns the directory of the clone of `reference` of
+// the repository at `url`, and the commit that's checked out. The clone is
+// kept in `cache_dir`, so that later fetches only need to update it. If an
+// existing clone can't be updated then it's used as-is, so that cached
+// contexts can be used offline.
+fn fetch_git_context(cache_dir: &Path, url: &str, reference: &str)
+    -> Result<(PathBuf, String), FetchGitContextError>
+{
+    let key = format!("{:x}", Sha256::digest(format!("{url}#{reference}")));
+    let clone_dir = cache_dir.join(&key[..16]);
+
+    if clone_dir.join(".git").is_dir() {
+        let fetch_args = ["fetch", "--depth=1", "origin", reference];
+        let checkout_args = ["checkout", "--quiet", "--detach", "FETCH_HEAD"];
+
+        let update_result =
+            init::assert_run_in_dir(&clone_dir, "git", fetch_args)
+                .and_then(|_| {
+                    init::assert_run_in_dir(&clone_dir, "git", checkout_args)
+                });
+
+        if let Err(e) = update_result {
+            eprintln!(
+                "Warning: couldn't update the cached clone of '{url}' at \
+                 '{reference}'; using the cached version: {e}",
+            );
+        }
+    } else {
+        // We clone into a temporary directory first so that a failed clone
+        // doesn't leave a partial clone in the cache.
+        let tmp_dir = cache_dir.join(format!("{key}.{}.tmp", process::id()));
+        fs::create_dir_all(&tmp_dir)
+            .context(CreateCloneDirFailed{path: tmp_dir.clone()})?;
+
+        let source =
+            GitTemplatesSource::new(url.to_string(), reference.to_string());
+        let clone_result = source.clone_to(&tmp_dir);
+        if let Err(source) = clone_result {
+            let _ = fs::remove_dir_all(&tmp_dir);
+
+            return Err(FetchGitContextError::CloneFailed{source});
+        }
+
+        // Another process may have cloned the same repository in the
+        // meantime, in which case we use its clone.
+        if fs::rename(&tmp_dir, &clone_dir).is_err() {
+            let _ = fs::remove_dir_all(&tmp_dir);
+        }
+    }
+
+    let rev_parse_args = ["rev-parse", "HEAD"];
+    let output = init::assert_run_in_dir(&clone_dir, "git", rev_parse_args)
+        .context(GetCommitFailed)?;
+
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    Ok((clone_dir, commit))
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum FetchGitContextError {
+    #[snafu(display(
+        "Couldn't create '{}': {}",
+        path.display(),
+        source,
+    ))]
+    CreateCloneDirFailed{source: IoError, path: PathBuf},
+    #[snafu(display("{}", source))]
+    CloneFailed{source: GitCloneToError},
+    #[snafu(display("Couldn't get the checked out commit: {}", source))]
+    GetCommitFailed{source: AssertRunError},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) the Dock directory `/src/proj`
+    //     AND (2) context paths that start with `./`, `../` and `/`
+    // When `parse` is called with each context path
+    // Then (A) each path is resolved to a local directory, relative to the
+    //         Dock directory if it's relative
+    fn test_parse_resolves_relative_and_absolute_paths() {
+        // (1)
+        let dock_dir = AbsPath::parse("/src/proj")
+            .expect("couldn't parse the Dock directory");
+        // (2)
+        let cases = [
+            ("./docker", "/src/proj/docker"),
+            ("../docker-common", "/src/docker-common"),
+            ("/opt/ctx/../docker", "/opt/docker"),
+        ];
+
+        for (raw, expected) in cases {
+            let source = parse(&dock_dir, raw)
+                .expect("couldn't parse the context source");
+
+            // (A)
+            let expected = AbsPath::parse(expected)
+                .expect("couldn't parse the expected path");
+            assert_eq!(source, ContextSource::Dir(expected));
+        }
+    }
+
+    #[test]
+    // Given (1) the Dock directory `/src/proj`
+    // When `parse` is called with a path that doesn't start with `./`, `../`
+    //     or `/`
+    //     AND `parse` is called with a path outside the root directory
+    // Then (A) an `AmbiguousPath` error is returned for the first path
+    //     AND (B) a `ResolveContextPathFailed` error is returned for the
+    //         second path
+    fn test_parse_rejects_ambiguous_paths() {
+        // (1)
+        let dock_dir = AbsPath::parse("/src/proj")
+            .expect("couldn't parse the Dock directory");
+
+        let ambiguous_result = parse(&dock_dir, "docker");
+        let outside_result = parse(&dock_dir, "../../../..");
+
+        // (A)
+        assert!(matches!(
+            ambiguous_result,
+            Err(ParseContextSourceError::AmbiguousPath{..}),
+        ));
+        // (B)
+        assert!(matches!(
+            outside_result,
+            Err(ParseContextSourceError::ResolveContextPathFailed{..}),
+        ));
+    }
+
+    #[test]
+    // Given (1) the Dock directory `/src/proj`
+    // When `parse` is called with a `git+` source that has a reference and a
+    //     subdirectory
+    // Then (A) the URL, reference and subdirectory of the source are returned
+    fn test_parse_splits_git_sources() {
+        // (1)
+        let dock_dir = AbsPath::parse("/src/proj")
+            .expect("couldn't parse the Dock directory");
+
+        let source =
+            parse(&dock_dir, "git+https://host/repo.git#v1.2:./docker")
+                .expect("couldn't parse the context source");
+
+        // (A)
+        let subdir = RelPath::try_from(PathBuf::from("./docker"))
+            .expect("couldn't create the expected subdirectory");
+        assert_eq!(
+            source,
+            ContextSource::Git{
+                url: "https://host/repo.git".to_string(),
+                reference: "v1.2".to_string(),
+                subdir,
+            },
+        );
+    }
+}
@@ -0,0 +1,600 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::fmt::Write;
+use std::fs as std_fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::build_log;
+use crate::build_progress;
+use crate::build_progress::Step;
+use crate::build_progress::StepCmdLogger;
+use crate::canon_path::AbsPath;
+use crate::canon_path::NewAbsPathError;
+use crate::docker;
+use crate::docker::AssertRunError;
+use crate::dockerfile;
+use crate::dockerfile::Instruction;
+use crate::lockfile;
+use crate::lockfile::ReadError as ReadLockFileError;
+use crate::logging_process::CommandLogger;
+use crate::run_in;
+use crate::run_in::BuildDebugImgError;
+use crate::run_in::BuildOpts;
+use crate::run_in::DockConfig;
+use crate::run_in::DockEnvironmentConfig;
+use crate::run_in::FindAndParseDockConfigError;
+use crate::run_in::RunInError;
+use crate::spinner;
+use crate::spinner::SpinError;
+use crate::spinner::Status;
+
+// `HISTORY_PATH` is the path of the shell history file in the debug container.
+const HISTORY_PATH: &str = "/tmp/.dock_history";
+
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+// `debug_build_failure` rebuilds `env_name`, and, if the rebuild fails,
+// starts an interactive shell in the last layer that was built successfully
+// before the failed step. The command of the failed step is added to the
+// shell history, so that it can be rerun and investigated.
+pub fn debug_build_failure(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    maybe_env_name: Option<&str>,
+    opts: &BuildOpts,
+    show_spinner: bool,
+)
+    -> Result<ExitStatus, DebugBuildFailureError>
+{
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let env_name = maybe_env_name.unwrap_or(&conf.default_shell_env);
+
+    let rebuild_result = run_in::rebuild_with_deps(
+        logger,
+        &dock_dir,
+        &conf,
+        env_name,
+        opts,
+        show_spinner,
+    );
+
+    let err =
+        match rebuild_result {
+            Ok(()) => {
+                return Err(DebugBuildFailureError::RebuildSucceeded{
+                    name: env_name.to_string(),
+                });
+            },
+            Err(err) => {
+                err
+            },
+        };
+
+    // The failed rebuild may be of an environment that `env_name` depends
+    // on, in which case we debug that environment instead.
+    let Some((failed_env_name, log_path)) = err.build_log_path() else {
+        return Err(DebugBuildFailureError::RebuildFailed{source: err});
+    };
+    let failed_env_name = failed_env_name.to_string();
+    let log_path = log_path.to_path_buf();
+
+    let env = conf.environments.get(&failed_env_name)
+        .context(EnvironmentNotFound{name: &failed_env_name})?;
+
+    let Failure{dockerfile, index: failed, instr: failed_instr} =
+        find_failure(&dock_dir, &failed_env_name, log_path)?;
+
+    let DebugFiles{dockerfile_path: debug_dockerfile_path, history_path} =
+        write_debug_files(
+            &dock_dir,
+            &conf,
+            &failed_env_name,
+            &dockerfile,
+            &failed_instr,
+            opts.platform,
+        )?;
+
+    let img_name = run_in::platform_image_name(
+        &conf,
+        &failed_env_name,
+        env,
+        opts.platform,
+    );
+    let debug_img = format!("{img_name}:debug");
+    let platform = run_in::env_platform(env, opts.platform);
+
+    let build = |logger: &mut dyn CommandLogger| {
+        run_in::build_debug_img(
+            logger,
+            &dock_dir,
+            &conf,
+            &failed_env_name,
+            debug_dockerfile_path,
+            &debug_img,
+            platform,
+        )
+    };
+
+    let result =
+        if show_spinner {
+            let msg = format!("Building the layer before line {}", failed + 1);
+            let msg = format!("{msg} of '{failed_env_name}.Dockerfile'");
+            let status = Status::new(msg.clone());
+            let mut step_logger = StepCmdLogger::new(logger, |step| {
+                status.set(format!("{msg} {step}"));
+            });
+
+            spinner::spin_status(&status, || build(&mut step_logger))
+                .context(SpinFailed)?
+        } else {
+            build(logger)
+        };
+    let status = result
+        .context(BuildDebugImgFailed)?;
+
+    if !status.success() {
+        return Err(DebugBuildFailureError::BuildDebugImgUnsuccessful{
+            img: debug_img,
+        });
+    }
+
+    eprintln!(
+        "Starting a shell before `{}` (line {} of '{}.Dockerfile').",
+        failed_instr.raw,
+        failed_instr.line + 1,
+        failed_env_name,
+    );
+    if failed_instr.run_command().is_some_and(|cmd| !cmd.is_empty()) {
+        eprintln!("The failed command is in the shell history.");
+    }
+
+    let status = run_debug_shell(env, &debug_img, &history_path, platform)?;
+
+    docker::assert_run(["rmi", &debug_img])
+        .context(RemoveDebugImgFailed{img: debug_img})?;
+
+    Ok(status)
+}
+
+// `Failure` is the instruction that failed in a build.
+struct Failure {
+    // `dockerfile` is the content of the Dockerfile that was built.
+    dockerfile: String,
+    // `index` is the index of `instr` in the instructions of `dockerfile`.
+    index: usize,
+    instr: Instruction,
+}
+
+// `find_failure` returns the instruction of the Dockerfile of `env_name` that
+// failed, according to the build log at `log_path`.
+fn find_failure(dock_dir: &AbsPath, env_name: &str, log_path: PathBuf)
+    -> Result<Failure, DebugBuildFailureError>
+{
+    let log = std_fs::read(&log_path)
+        .context(ReadBuildLogFailed{path: log_path.clone()})?;
+    let step = failed_step(&String::from_utf8_lossy(&log))
+        .context(FailedStepNotFound{path: log_path})?;
+
+    let dockerfile_path = run_in::dockerfile_path(dock_dir, env_name);
+    let dockerfile = std_fs::read(PathBuf::from(dockerfile_path.clone()))
+        .context(ReadDockerfileFailed{path: dockerfile_path})?;
+    let dockerfile = String::from_utf8_lossy(&dockerfile).into_owned();
+
+    let mut instrs = dockerfile::instructions(&dockerfile);
+    let index = failed_instruction(&instrs, &step)
+        .context(FailedInstructionNotFound{
+            instruction: step.instruction.clone(),
+        })?;
+
+    let instr = instrs.swap_remove(index);
+    if instr.keyword().eq_ignore_ascii_case("FROM") {
+        return Err(DebugBuildFailureError::NoLayerBeforeFailure{
+            instruction: instr.raw,
+        });
+    }
+
+    Ok(Failure{dockerfile, index, instr})
+}
+
+// `DebugFiles` are the files that are written for debugging a failed build.
+struct DebugFiles {
+    // `dockerfile_path` is the path of the Dockerfile of the debug image.
+    dockerfile_path: AbsPath,
+    // `history_path` is the path of the shell history of the debug container.
+    history_path: PathBuf,
+}
+
+// `write_debug_files` writes the files for debugging the failure of
+// `failed_instr` in the Dockerfile of `env_name`, whose content is
+// `dockerfile`.
+fn write_debug_files(
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    dockerfile: &str,
+    failed_instr: &Instruction,
+    platform_override: Option<&str>,
+)
+    -> Result<DebugFiles, DebugBuildFailureError>
+{
+    // We build the Dockerfile up to, but not including, the failed
+    // instruction. The steps before the failed instruction are usually
+    // cached, so this is usually quick.
+    let debug_dir =
+        build_log::project_cache_dir(&conf.organisation, &conf.project)
+            .join("debug");
+    std_fs::create_dir_all(&debug_dir)
+        .context(WriteDebugFileFailed{path: debug_dir.clone()})?;
+
+    let debug_dockerfile =
+        dockerfile
+            .lines()
+            .take(failed_instr.line)
+            .fold(String::new(), |mut debug_dockerfile, line| {
+                // Writing to a `String` doesn't fail.
+                let _ = writeln!(debug_dockerfile, "{line}");
+
+                debug_dockerfile
+            });
+    // The debug image is built from the same pinned base images as the
+    // environment, if the project has a lock file.
+    let pins =
+        lockfile::read(dock_dir)
+            .context(ReadLockFileFailed)?
+            .and_then(|mut lock| lock.environments.remove(env_name))
+            .unwrap_or_default();
+    let debug_dockerfile = lockfile::pin(&debug_dockerfile, &pins);
+    let debug_dockerfile = run_in::replace_platform_images(
+        conf,
+        &debug_dockerfile,
+        platform_override,
+    );
+    let debug_dockerfile_path =
+        debug_dir.join(format!("{env_name}.Dockerfile"));
+    std_fs::write(&debug_dockerfile_path, debug_dockerfile)
+        .context(WriteDebugFileFailed{path: debug_dockerfile_path.clone()})?;
+
+    let history_path = debug_dir.join(format!("{env_name}.history"));
+    let history = failed_instr.run_command().unwrap_or_default();
+    std_fs::write(&history_path, format!("{history}\n"))
+        .context(WriteDebugFileFailed{path: history_path.clone()})?;
+
+    let dockerfile_path = AbsPath::try_from(debug_dockerfile_path)
+        .context(DebugDockerfileAsAbsPathFailed)?;
+
+    Ok(DebugFiles{dockerfile_path, history_path})
+}
+
+// `run_debug_shell` runs an interactive shell in `debug_img`, using the shell
+// history at `history_path`.
+fn run_debug_shell(
+    env: &DockEnvironmentConfig,
+    debug_img: &str,
+    history_path: &Path,
+    platform: Option<&str>,
+)
+    -> Result<ExitStatus, DebugBuildFailureError>
+{
+    let shell =
+        env.shell
+            .as_ref()
+            .map_or(DEFAULT_SHELL.into(), |shell| shell.display().to_string());
+
+    // We don't mount the project directory or the environment's volumes,
+    // so that the container matches the build environment of the failed
+    // step.
+    let mut run_cmd = Command::new("docker");
+    run_cmd
+        .args([
+            "run",
+            "--rm",
+            "--interactive",
+            "--tty",
+            &format!("--entrypoint={shell}"),
+            &format!("--env=HISTFILE={HISTORY_PATH}"),
+        ])
+        .arg(format!("--volume={}:{HISTORY_PATH}", history_path.display()));
+    if let Some(platform) = platform {
+        run_cmd.arg(format!("--platform={platform}"));
+    }
+
+    run_cmd
+        .arg(debug_img)
+        .status()
+        .context(RunShellFailed)
+}
+
+#[derive(Debug, Snafu)]
+pub enum DebugBuildFailureError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+    #[snafu(display("{}", source))]
+    RebuildFailed{source: RunInError},
+    #[snafu(display(
+        "The rebuild of '{}' succeeded, so there's no failure to debug",
+        name,
+    ))]
+    RebuildSucceeded{name: String},
+    #[snafu(display(
+        "Couldn't read the build log '{}': {}",
+        path.display(),
+        source,
+    ))]
+    ReadBuildLogFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't find the failed step in the build log '{}'",
+        path.display(),
+    ))]
+    FailedStepNotFound{path: PathBuf},
+    #[snafu(display("{}", source))]
+    ReadLockFileFailed{source: ReadLockFileError},
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    ReadDockerfileFailed{source: IoError, path: AbsPath},
+    #[snafu(display(
+        "Couldn't find the failed step `{}` in the Dockerfile",
+        instruction,
+    ))]
+    FailedInstructionNotFound{instruction: String},
+    #[snafu(display(
+        "The build failed at `{}`, so there's no layer to debug",
+        instruction,
+    ))]
+    NoLayerBeforeFailure{instruction: String},
+    #[snafu(display("Couldn't write '{}': {}", path.display(), source))]
+    WriteDebugFileFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't get the debug Dockerfile path as an absolute path: {}",
+        source,
+    ))]
+    DebugDockerfileAsAbsPathFailed{source: NewAbsPathError},
+    #[snafu(display("{}", source))]
+    SpinFailed{source: SpinError},
+    #[snafu(display("{}", source))]
+    BuildDebugImgFailed{source: BuildDebugImgError},
+    #[snafu(display("Build of '{}' returned an unsuccessful status", img))]
+    BuildDebugImgUnsuccessful{img: String},
+    #[snafu(display("Couldn't run the debug shell: {}", source))]
+    RunShellFailed{source: IoError},
+    #[snafu(display("Couldn't remove '{}': {}", img, source))]
+    RemoveDebugImgFailed{source: AssertRunError, img: String},
+}
+
+// `failed_step` returns the build step that failed in `log`. BuildKit runs
+// independent steps in parallel, and reports cached steps as they're reached,
+// so the header of another step may be printed after the header of the failed
+// step. As such, the failed step is found using the `#<n> ERROR` line that
+// BuildKit prints for it, and the last step announced in `log` is used for the
+// legacy builder, which runs steps in order.
+fn failed_step(log: &str) -> Option<Step> {
+    let Some(id) = log.lines().find_map(error_step_id) else {
+        return log.lines().rev().find_map(build_progress::parse_step);
+    };
+
+    let header_prefix = format!("#{id} [");
+
+    log.lines()
+        .filter(|line| line.starts_with(&header_prefix))
+        .find_map(build_progress::parse_step)
+}
+
+// `error_step_id` returns `<n>` if `line` is in the form `#<n> ERROR: <msg>`,
+// which is how BuildKit reports that step `<n>` failed.
+fn error_step_id(line: &str) -> Option<&str> {
+    let (id, rest) = line.strip_prefix('#')?.split_once(' ')?;
+    let is_id = !id.is_empty() && id.chars().all(|c| c.is_ascii_digit());
+    if !is_id || !rest.starts_with("ERROR") {
+        return None;
+    }
+
+    Some(id)
+}
+
+// `failed_instruction` returns the index of the instruction in `instrs` that
+// `step` refers to. If `step` names a stage then only the instructions of that
+// stage are considered.
+fn failed_instruction(instrs: &[Instruction], step: &Step) -> Option<usize> {
+    let stage_starts: Vec<usize> =
+        instrs
+            .iter()
+            .enumerate()
+            .filter(|(_, instr)| instr.keyword().eq_ignore_ascii_case("FROM"))
+            .map(|(i, _)| i)
+            .collect();
+
+    let (start, end) =
+        match &step.stage {
+            Some(stage) => {
+                let n = stage_index(instrs, &stage_starts, stage)?;
+                let end = stage_starts.get(n + 1).copied();
+
+                (stage_starts[n], end.unwrap_or(instrs.len()))
+            },
+            None => {
+                (0, instrs.len())
+            },
+        };
+
+    let want = dockerfile::normalise(&step.instruction);
+    let matches = |i: &usize| {
+        instrs[*i].normalised().eq_ignore_ascii_case(&want)
+    };
+
+    // The step number identifies the instruction exactly for the legacy
+    // builder, and for BuildKit if the stage doesn't contain instructions
+    // that aren't reported as steps, so we check it first.
+    let numbered = start + step.n.saturating_sub(1);
+    if numbered < end && matches(&numbered) {
+        return Some(numbered);
+    }
+
+    (start..end).find(matches)
+}
+
+// `stage_index` returns the index of the stage called `stage`, which is
+// either the name given to the stage using `AS`, or `stage-<n>`.
+fn stage_index(
+    instrs: &[Instruction],
+    stage_starts: &[usize],
+    stage: &str,
+)
+    -> Option<usize>
+{
+    let named =
+        stage_starts.iter().position(|&i| {
+            let words: Vec<&str> = instrs[i].raw.split_whitespace().collect();
+            let n = words.len();
+
+            n >= 2
+                && words[n - 2].eq_ignore_ascii_case("AS")
+                && words[n - 1] == stage
+        });
+    if named.is_some() {
+        return named;
+    }
+
+    let n: usize = stage.strip_prefix("stage-")?.parse().ok()?;
+
+    (n < stage_starts.len()).then_some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCKERFILE: &str = "\
+        # syntax=docker/dockerfile:1\n\
+        FROM alpine AS build\n\
+        \n\
+        RUN apk add \\\n\
+        \x20   make\n\
+        RUN make\n\
+        \n\
+        FROM alpine\n\
+        RUN make\n\
+    ";
+
+    #[test]
+    // Given (1) a multi-stage Dockerfile where both stages contain the same
+    //         instruction
+    //     AND (2) BuildKit steps that name each stage
+    // When `failed_instruction` is called with each step
+    // Then (A) the instruction of the unnamed stage is returned for the step
+    //         of `stage-1`
+    //     AND (B) the instruction of the `build` stage is returned for the
+    //         step of `build`
+    fn test_failed_instruction_uses_stage() {
+        // (1)
+        let instrs = dockerfile::instructions(DOCKERFILE);
+        // (2)
+        let stage_step =
+            build_progress::parse_step("#9 [stage-1 2/2] RUN make")
+                .expect("couldn't parse the `stage-1` step");
+        let build_step = build_progress::parse_step("#8 [build 3/3] RUN make")
+            .expect("couldn't parse the `build` step");
+
+        let stage_instr = failed_instruction(&instrs, &stage_step);
+        let build_instr = failed_instruction(&instrs, &build_step);
+
+        // (A)
+        assert_eq!(stage_instr, Some(4));
+        // (B)
+        assert_eq!(build_instr, Some(2));
+    }
+
+    #[test]
+    // Given (1) a multi-stage Dockerfile where both stages contain the same
+    //         instruction
+    //     AND (2) a legacy builder step, which is numbered across stages
+    // When `failed_instruction` is called with the step
+    // Then (A) the instruction with the step's number is returned
+    fn test_failed_instruction_uses_legacy_step_number() {
+        // (1)
+        let instrs = dockerfile::instructions(DOCKERFILE);
+        // (2)
+        let step = build_progress::parse_step("Step 5/5 : RUN make")
+            .expect("couldn't parse the step");
+
+        let instr = failed_instruction(&instrs, &step);
+
+        // (A)
+        assert_eq!(instr, Some(4));
+    }
+
+    #[test]
+    // Given (1) BuildKit output of a multi-stage build where step `#8` fails
+    //     AND (2) the headers of steps of the other stage are printed after
+    //         the header of `#8`
+    // When `failed_step` is called with the output
+    // Then (A) the step of `#8` is returned
+    fn test_failed_step_uses_error_line() {
+        // (1) (2)
+        let log = "\
+            #7 [build 2/3] RUN apk add make\n\
+            #7 CACHED\n\
+            #8 [build 3/3] RUN make\n\
+            #9 [stage-1 2/2] RUN make\n\
+            #8 0.412 make: *** No targets specified and no makefile found.\n\
+            #9 CANCELED\n\
+            #10 [stage-1 1/2] COPY --from=build /a /a\n\
+            #10 CACHED\n\
+            #8 ERROR: process \"/bin/sh -c make\" did not complete \
+            successfully: exit code: 2\n\
+        ";
+
+        let step = failed_step(log);
+
+        // (A)
+        assert_eq!(
+            step,
+            Some(Step{
+                stage: Some("build".to_string()),
+                n: 3,
+                total: 3,
+                instruction: "RUN make".to_string(),
+            }),
+        );
+    }
+
+    #[test]
+    // Given (1) legacy builder output where the second step fails
+    // When `failed_step` is called with the output
+    // Then (A) the last step announced is returned
+    fn test_failed_step_uses_last_legacy_step() {
+        // (1)
+        let log = "\
+            Step 1/2 : FROM alpine\n\
+            Step 2/2 : RUN make\n\
+            make: *** No targets specified and no makefile found.\n\
+        ";
+
+        let step = failed_step(log);
+
+        // (A)
+        assert_eq!(step.map(|step| step.n), Some(2));
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use snafu::Snafu;
+
+// `FlagSpec` describes a flag accepted by `docker build`.
+struct FlagSpec {
+    long: &'static str,
+    short: Option<char>,
+    takes_value: bool,
+}
+
+const fn flag(long: &'static str, takes_value: bool) -> FlagSpec {
+    FlagSpec{long, short: None, takes_value}
+}
+
+const fn short_flag(long: &'static str, short: char, takes_value: bool)
+    -> FlagSpec
+{
+    FlagSpec{long, short: Some(short), takes_value}
+}
+
+// `FLAGS` contains the flags accepted by `docker build`, covering both the
+// legacy builder and BuildKit (`docker buildx build`).
+const FLAGS: &[FlagSpec] = &[
+    flag("add-host", true),
+    flag("allow", true),
+    flag("annotation", true),
+    flag("attest", true),
+    flag("build-arg", true),
+    flag("build-context", true),
+    flag("builder", true),
+    flag("cache-from", true),
+    flag("cache-to", true),
+    flag("call", true),
+    flag("cgroup-parent", true),
+    flag("check", false),
+    flag("compress", false),
+    flag("cpu-period", true),
+    flag("cpu-quota", true),
+    short_flag("cpu-shares", 'c', true),
+    flag("cpuset-cpus", true),
+    flag("cpuset-mems", true),
+    flag("disable-content-trust", false),
+    short_flag("file", 'f', true),
+    flag("force-rm", false),
+    flag("iidfile", true),
+    flag("isolation", true),
+    flag("label", true),
+    flag("load", false),
+    short_flag("memory", 'm', true),
+    flag("memory-swap", true),
+    flag("metadata-file", true),
+    flag("network", true),
+    flag("no-cache", false),
+    flag("no-cache-filter", true),
+    short_flag("output", 'o', true),
+    flag("platform", true),
+    flag("progress", true),
+    flag("provenance", true),
+    flag("pull", false),
+    flag("push", false),
+    short_flag("quiet", 'q', false),
+    flag("rm", false),
+    flag("sbom", true),
+    flag("secret", true),
+    flag("security-opt", true),
+    flag("shm-size", true),
+    flag("squash", false),
+    flag("ssh", true),
+    short_flag("tag", 't', true),
+    flag("target", true),
+    flag("ulimit", true),
+];
+
+// `Flag` is a flag parsed from `docker build` arguments. `raw` is the
+// argument that the flag was parsed from, which is used when reporting
+// problems with the flag.
+#[derive(Debug, PartialEq)]
+pub struct Flag<'a> {
+    pub name: &'static str,
+    pub value: Option<&'a str>,
+    pub raw: &'a str,
+}
+
+// `BuildArgs` is the parsed form of the arguments to `docker build`.
+#[derive(Debug)]
+pub struct BuildArgs<'a> {
+    pub flags: Vec<Flag<'a>>,
+    pub context: Option<&'a str>,
+}
+
+impl<'a> BuildArgs<'a> {
+    // `value_of` returns the last value given for the flag called `name`,
+    // which is the value used by `docker build` for flags that take a single
+    // value.
+    pub fn value_of(&self, name: &str) -> Option<&'a str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|f| f.name == name)
+            .and_then(|f| f.value)
+    }
+
+    pub fn file(&self) -> Option<&'a str> {
+        self.value_of("file")
+    }
+
+    // `find_any` returns the first flag whose name is in `names`.
+    pub fn find_any(&self, names: &[&str]) -> Option<&Flag<'a>> {
+        self.flags
+            .iter()
+            .find(|f| names.contains(&f.name))
+    }
+}
+
+// `parse` parses `args` in the same way as `docker build`, where flags may be
+// interspersed with the context path, short flags may be combined (e.g.
+// `-qt img`), and values may be given in the same argument as their flag
+// (e.g. `--tag=img`, `-timg`) or in the next argument. Arguments after `--`
+// aren't treated as flags.
+pub fn parse<'a>(args: &[&'a str]) -> Result<BuildArgs<'a>, ParseError> {
+    let mut flags = vec![];
+    let mut positionals = vec![];
+    let mut only_positionals = false;
+
+    let mut args = args.iter();
+    while let Some(&raw) = args.next() {
+        if only_positionals || raw == "-" || !raw.starts_with('-') {
+            positionals.push(raw);
+        } else if raw == "--" {
+            only_positionals = true;
+        } else if let Some(long) = raw.strip_prefix("--") {
+            let (name, inline_value) =
+                match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (long, None),
+                };
+
+            let spec = FLAGS.iter().find(|spec| spec.long == name)
+                .ok_or_else(|| ParseError::UnknownFlag{
+                    flag: raw.to_string(),
+                })?;
+
+            let value =
+                if !spec.takes_value {
+                    // Boolean flags may be given an explicit value, as in
+                    // `--pull=false`.
+                    inline_value
+                } else if inline_value.is_some() {
+                    inline_value
+                } else if let Some(&next) = args.next() {
+                    Some(next)
+                } else {
+                    return Err(ParseError::MissingValue{
+                        flag: raw.to_string(),
+                    });
+                };
+
+            flags.push(Flag{name: spec.long, value, raw});
+        } else {
+            let shorts = &raw[1..];
+            for (i, c) in shorts.char_indices() {
+                let spec = FLAGS.iter().find(|spec| spec.short == Some(c))
+                    .ok_or_else(|| ParseError::UnknownFlag{
+                        flag: format!("-{c}"),
+                    })?;
+
+                if !spec.takes_value {
+                    flags.push(Flag{name: spec.long, value: None, raw});
+                    continue;
+                }
+
+                // The rest of the argument is the value of a short flag that
+                // takes a value, if it isn't empty.
+                let rest = &shorts[i + c.len_utf8()..];
+                let value =
+                    if !rest.is_empty() {
+                        rest
+                    } else if let Some(&next) = args.next() {
+                        next
+                    } else {
+                        return Err(ParseError::MissingValue{
+                            flag: format!("-{c}"),
+                        });
+                    };
+
+                flags.push(Flag{name: spec.long, value: Some(value), raw});
+                break;
+            }
+        }
+    }
+
+    if positionals.len() > 1 {
+        return Err(ParseError::MultipleContexts{
+            contexts: positionals.iter().map(ToString::to_string).collect(),
+        });
+    }
+
+    Ok(BuildArgs{flags, context: positionals.first().copied()})
+}
+
+#[derive(Debug, PartialEq, Snafu)]
+pub enum ParseError {
+    #[snafu(display("unknown `docker build` flag: `{}`", flag))]
+    UnknownFlag{flag: String},
+    #[snafu(display("`{}` requires a value", flag))]
+    MissingValue{flag: String},
+    #[snafu(display(
+        "expected a single build context, got {}",
+        contexts.join(", "),
+    ))]
+    MultipleContexts{contexts: Vec<String>},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) `docker build` args where `--label` takes `-t` as its value
+    // When `parse` is called with the args
+    // Then (A) the value of `label` is `-t`
+    //     AND (B) `tag` isn't found as a flag
+    //     AND (C) the context is `.`
+    fn test_value_of_other_flag_isnt_a_flag() {
+        // (1)
+        let raw_args = ["--label", "-t", "."];
+
+        let args = parse(&raw_args)
+            .expect("couldn't parse args");
+
+        // (A)
+        assert_eq!(args.value_of("label"), Some("-t"));
+        // (B)
+        assert_eq!(args.find_any(&["tag"]), None);
+        // (C)
+        assert_eq!(args.context, Some("."));
+    }
+
+    #[test]
+    // Given (1) `docker build` args that combine `-q` and `-t` into `-qt`
+    // When `parse` is called with the args
+    // Then (A) the flags are `quiet` and `tag`, with `tag` taking `img`
+    fn test_combined_short_flags() {
+        // (1)
+        let raw_args = ["-qt", "img", "."];
+
+        let args = parse(&raw_args)
+            .expect("couldn't parse args");
+
+        // (A)
+        assert_eq!(
+            args.flags,
+            vec![
+                Flag{name: "quiet", value: None, raw: "-qt"},
+                Flag{name: "tag", value: Some("img"), raw: "-qt"},
+            ],
+        );
+    }
+
+    #[test]
+    // Given (1) `docker build` args with values attached to `-f` and
+    //     `--target`
+    // When `parse` is called with the args
+    // Then (A) the file is `a.Dockerfile`
+    //     AND (B) the target is `dev`
+    //     AND (C) the context is `-`
+    fn test_inline_values() {
+        // (1)
+        let raw_args = ["-fa.Dockerfile", "--target=dev", "-"];
+
+        let args = parse(&raw_args)
+            .expect("couldn't parse args");
+
+        // (A)
+        assert_eq!(args.file(), Some("a.Dockerfile"));
+        // (B)
+        assert_eq!(args.value_of("target"), Some("dev"));
+        // (C)
+        assert_eq!(args.context, Some("-"));
+    }
+
+    #[test]
+    // Given (1) `docker build` args where `-dir` follows `--`
+    // When `parse` is called with the args
+    // Then (A) the context is `-dir`
+    fn test_context_after_double_dash() {
+        // (1)
+        let raw_args = ["--no-cache", "--", "-dir"];
+
+        let args = parse(&raw_args)
+            .expect("couldn't parse args");
+
+        // (A)
+        assert_eq!(args.context, Some("-dir"));
+    }
+
+    #[test]
+    // Given (1) `docker build` args with an unknown long flag
+    // When `parse` is called with the args
+    // Then (A) the result is `Err(ParseError::UnknownFlag)`
+    fn test_unknown_long_flag_fails() {
+        // (1)
+        let raw_args = ["--nope"];
+
+        let result = parse(&raw_args);
+
+        // (A)
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::UnknownFlag{flag: "--nope".to_string()},
+        );
+    }
+
+    #[test]
+    // Given (1) `docker build` args where `-z` is combined with `-q`
+    // When `parse` is called with the args
+    // Then (A) the result is `Err(ParseError::UnknownFlag)` for `-z`
+    fn test_unknown_combined_short_flag_fails() {
+        // (1)
+        let raw_args = ["-qz"];
+
+        let result = parse(&raw_args);
+
+        // (A)
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::UnknownFlag{flag: "-z".to_string()},
+        );
+    }
+
+    #[test]
+    // Given (1) `docker build` args that end with `-f`
+    // When `parse` is called with the args
+    // Then (A) the result is `Err(ParseError::MissingValue)`
+    fn test_missing_value_fails() {
+        // (1)
+        let raw_args = [".", "-f"];
+
+        let result = parse(&raw_args);
+
+        // (A)
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::MissingValue{flag: "-f".to_string()},
+        );
+    }
+
+    #[test]
+    // Given (1) `docker build` args with two contexts
+    // When `parse` is called with the args
+    // Then (A) the result is `Err(ParseError::MultipleContexts)`
+    fn test_multiple_contexts_fails() {
+        // (1)
+        let raw_args = ["a", "b"];
+
+        let result = parse(&raw_args);
+
+        // (A)
+        assert_eq!(
+            result.unwrap_err(),
+            ParseError::MultipleContexts{
+                contexts: vec!["a".to_string(), "b".to_string()],
+            },
+        );
+    }
+}
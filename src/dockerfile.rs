@@ -0,0 +1,313 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::fmt::Write;
+
+// `Instruction` is an instruction in a Dockerfile. `line` is the index of the
+// line that the instruction starts on, `num_lines` is the number of lines that
+// it spans, and `raw` is the instruction with line continuations removed.
+#[derive(Debug, PartialEq)]
+pub struct Instruction {
+    pub line: usize,
+    pub num_lines: usize,
+    pub raw: String,
+}
+
+impl Instruction {
+    pub fn keyword(&self) -> &str {
+        self.raw.split_whitespace().next().unwrap_or("")
+    }
+
+    // `normalised` returns `raw` with runs of whitespace replaced by a single
+    // space, which is how `docker build` reports instructions.
+    pub fn normalised(&self) -> String {
+        normalise(&self.raw)
+    }
+
+    // `run_command` returns the command run by a `RUN` instruction, without
+    // any flags. Commands in exec form are converted to shell form.
+    pub fn run_command(&self) -> Option<String> {
+        let (keyword, rest) = self.raw.split_once(char::is_whitespace)?;
+        if !keyword.eq_ignore_ascii_case("RUN") {
+            return None;
+        }
+
+        let mut rest = rest.trim_start();
+        while rest.starts_with("--") {
+            rest =
+                match rest.split_once(char::is_whitespace) {
+                    Some((_, after)) => after.trim_start(),
+                    None => "",
+                };
+        }
+
+        if rest.starts_with('[') {
+            if let Ok(args) = serde_yaml::from_str::<Vec<String>>(rest) {
+                return Some(args.join(" "));
+            }
+        }
+
+        Some(rest.to_string())
+    }
+
+    // `parse_from` returns the image and stage name of a `FROM` instruction.
+    // Flags, such as `--platform`, are skipped.
+    pub fn parse_from(&self) -> Option<FromInstruction<'_>> {
+        let mut words = words(&self.raw).into_iter();
+
+        let (_, keyword) = words.next()?;
+        if !keyword.eq_ignore_ascii_case("FROM") {
+            return None;
+        }
+
+        let mut words = words.skip_while(|(_, w)| w.starts_with("--"));
+        let (img_start, img) = words.next()?;
+
+        let has_stage = words.next()
+            .is_some_and(|(_, w)| w.eq_ignore_ascii_case("AS"));
+        let stage =
+            if has_stage {
+                words.next().map(|(_, stage)| stage)
+            } else {
+                None
+            };
+
+        Some(FromInstruction{img, stage, img_start})
+    }
+}
+
+// `FromInstruction` is a parsed `FROM` instruction. `img_start` is the byte
+// offset of `img` in the `raw` field of the instruction.
+pub struct FromInstruction<'a> {
+    pub img: &'a str,
+    pub stage: Option<&'a str>,
+    img_start: usize,
+}
+
+pub fn normalise(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// `instructions` returns the instructions in `dockerfile`, skipping comments
+// and blank lines.
+pub fn instructions(dockerfile: &str) -> Vec<Instruction> {
+    let mut instrs = vec![];
+    let mut cur: Option<Instruction> = None;
+
+    for (i, line) in dockerfile.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('#') || (cur.is_none() && trimmed.is_empty()) {
+            continue;
+        }
+
+        let (part, continues) =
+            match trimmed.strip_suffix('\\') {
+                Some(part) => (part.trim_end(), true),
+                None => (trimmed, false),
+            };
+
+        let instr = cur.get_or_insert_with(|| Instruction{
+            line: i,
+            num_lines: 0,
+            raw: String::new(),
+        });
+        if !instr.raw.is_empty() && !part.is_empty() {
+            instr.raw.push(' ');
+        }
+        instr.raw.push_str(part);
+        instr.num_lines = i + 1 - instr.line;
+
+        if !continues {
+            instrs.extend(cur.take());
+        }
+    }
+    instrs.extend(cur);
+
+    instrs
+}
+
+// `from_images` returns the images used by the `FROM` instructions of
+// `dockerfile`, in order.
+pub fn from_images(dockerfile: &str) -> Vec<String> {
+    instructions(dockerfile)
+        .iter()
+        .filter_map(|instr| {
+            instr.parse_from().map(|from| from.img.to_string())
+        })
+        .collect()
+}
+
+// `replace_from_images` returns `dockerfile` with the image of each `FROM`
+// instruction replaced by the result of `replace`, if it returns `Some`.
+// Instructions that are replaced are written on a single line, followed by
+// blank lines in place of their continuation lines, so that the other
+// instructions stay on the same lines.
+pub fn replace_from_images<F>(dockerfile: &str, mut replace: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut lines: Vec<String> =
+        dockerfile
+            .lines()
+            .map(str::to_string)
+            .collect();
+
+    for instr in instructions(dockerfile) {
+        let Some(from) = instr.parse_from() else {
+            continue;
+        };
+        let Some(img) = replace(from.img) else {
+            continue;
+        };
+
+        let img_end = from.img_start + from.img.len();
+        let indent_len = lines[instr.line].len()
+            - lines[instr.line].trim_start().len();
+        let replaced = format!(
+            "{}{}{img}{}",
+            &lines[instr.line][..indent_len],
+            &instr.raw[..from.img_start],
+            &instr.raw[img_end..],
+        );
+
+        lines[instr.line] = replaced;
+        for line in &mut lines[instr.line + 1..instr.line + instr.num_lines] {
+            line.clear();
+        }
+    }
+
+    lines
+        .iter()
+        .fold(String::new(), |mut dockerfile, line| {
+            // Writing to a `String` doesn't fail.
+            let _ = writeln!(dockerfile, "{line}");
+
+            dockerfile
+        })
+}
+
+// `words` returns the whitespace-separated words of `s`, along with their byte
+// offsets in `s`.
+fn words(s: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in s.char_indices() {
+        if c.is_whitespace() {
+            if let Some(j) = start.take() {
+                words.push((j, &s[j..i]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(j) = start {
+        words.push((j, &s[j..]));
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCKERFILE: &str = "\
+        # syntax=docker/dockerfile:1\n\
+        FROM alpine AS build\n\
+        \n\
+        RUN apk add \\\n\
+        \x20   make\n\
+        RUN make\n\
+        \n\
+        FROM alpine\n\
+        RUN make\n\
+    ";
+
+    #[test]
+    fn instructions_join_continuations() {
+        let instrs = instructions(DOCKERFILE);
+
+        let instr = |line, num_lines, raw: &str| {
+            Instruction{line, num_lines, raw: raw.to_string()}
+        };
+        assert_eq!(
+            instrs,
+            vec![
+                instr(1, 1, "FROM alpine AS build"),
+                instr(3, 2, "RUN apk add make"),
+                instr(5, 1, "RUN make"),
+                instr(7, 1, "FROM alpine"),
+                instr(8, 1, "RUN make"),
+            ],
+        );
+    }
+
+    #[test]
+    fn run_command_strips_flags_and_exec_form() {
+        let instr = |raw: &str| {
+            Instruction{line: 0, num_lines: 1, raw: raw.to_string()}
+        };
+
+        let run = instr("RUN --mount=type=cache,target=/c make all");
+        assert_eq!(run.run_command(), Some("make all".to_string()));
+
+        let run = instr(r#"RUN ["make", "all"]"#);
+        assert_eq!(run.run_command(), Some("make all".to_string()));
+
+        assert_eq!(instr("COPY a b").run_command(), None);
+    }
+
+    #[test]
+    // Given (1) a Dockerfile with `FROM` instructions that use flags, stage
+    //     names and line continuations
+    // When `from_images` is called with the Dockerfile
+    // Then (A) the images of all `FROM` instructions are returned
+    fn test_from_images_handles_continuations() {
+        // (1)
+        let dockerfile = "\
+            FROM --platform=linux/amd64 \\\n\
+            \x20   golang:1.22 AS build\n\
+            from build as test\n\
+            FROM \\\n\
+            \x20   # The base image.\n\
+            \x20   ubuntu:22.04\n\
+        ";
+
+        let imgs = from_images(dockerfile);
+
+        // (A)
+        assert_eq!(imgs, vec!["golang:1.22", "build", "ubuntu:22.04"]);
+    }
+
+    #[test]
+    // Given (1) a Dockerfile with a `FROM` instruction that spans two lines
+    //     AND (2) a `FROM` instruction on a single line
+    // When `replace_from_images` is called to replace both images
+    // Then (A) the instructions are replaced on their first lines
+    //     AND (B) the continuation line is replaced by a blank line
+    //     AND (C) the other lines are unchanged
+    fn test_replace_from_images_keeps_lines() {
+        // (1) (2)
+        let dockerfile = "\
+            FROM --platform=linux/amd64 \\\n\
+            \x20   golang:1.22 AS build\n\
+            RUN echo golang:1.22\n\
+            \x20 from ubuntu:22.04\n\
+        ";
+
+        let replaced =
+            replace_from_images(dockerfile, |img| Some(format!("{img}@x")));
+
+        assert_eq!(
+            replaced,
+            // (A) (B) (C)
+            "\
+                FROM --platform=linux/amd64 golang:1.22@x AS build\n\
+                \n\
+                RUN echo golang:1.22\n\
+                \x20 from ubuntu:22.04@x\n\
+            ",
+        );
+    }
+}
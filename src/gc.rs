@@ -0,0 +1,832 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Garbage collection of the Docker resources of all projects on the host.
+//!
+//! Resources are found using the labels that `dock` adds to the resources that
+//! it creates. Resources that were created before `dock` labelled its
+//! resources are found using the `<org>/<project>.<env>` naming convention for
+//! images and the `<org>.<project>.<env>.cache.<name>` naming convention for
+//! cache volumes, but only for projects that `dock` has a cache directory for,
+//! so that resources of other tools that happen to follow these conventions
+//! aren't removed. The resources of each environment are removed together.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::base_refresh;
+use crate::build_log;
+use crate::clean;
+use crate::clean::ListError;
+use crate::clean::Resource;
+use crate::clean::ResourceKind;
+use crate::labels;
+
+// `GcOpts` are the criteria for removing the resources of an environment.
+// Environments that match any of the criteria are removed.
+pub struct GcOpts {
+    // `max_age` selects environments that haven't been used for longer than
+    // `max_age`.
+    pub max_age: Option<Duration>,
+    // `max_size` selects the least recently used environments until the total
+    // size of the remaining environments is at most `max_size` bytes.
+    pub max_size: Option<u64>,
+    // `missing_dir` selects environments whose project directory no longer
+    // exists.
+    pub missing_dir: bool,
+}
+
+// `GcEnv` is an environment of a project on the host, along with its
+// resources. `last_use` is the time that the environment was last run, or the
+// time that its newest resource was created if it hasn't been run since
+// `dock` started recording its use. `size` is the total size of the images
+// and cache volumes of the environment, as far as it can be found; images may
+// share layers, so this may overestimate the space that removing the
+// environment would free.
+pub struct GcEnv {
+    pub project: String,
+    pub env_name: String,
+    pub dir: Option<String>,
+    pub last_use: Option<SystemTime>,
+    pub size: u64,
+    pub resources: Vec<Resource>,
+}
+
+// `PruneReason` is the reason that the resources of an environment are
+// removed.
+pub enum PruneReason {
+    MissingDir,
+    Unused(Duration),
+    OverBudget,
+}
+
+impl fmt::Display for PruneReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingDir => {
+                write!(f, "project directory is missing")
+            },
+            Self::Unused(age) => {
+                write!(f, "unused for {}", base_refresh::format_age(*age))
+            },
+            Self::OverBudget => {
+                write!(f, "over size budget")
+            },
+        }
+    }
+}
+
+pub struct Prune {
+    pub env: GcEnv,
+    pub reason: PruneReason,
+}
+
+// `plan` returns the environments on the host whose resources should be
+// removed according to `opts`, sorted by project and environment name.
+pub fn plan(opts: &GcOpts) -> Result<Vec<Prune>, GcError> {
+    let now = SystemTime::now();
+
+    let mut pruned = vec![];
+    let mut kept = vec![];
+    for env in find_envs()? {
+        let age =
+            env.last_use.map(|t| now.duration_since(t).unwrap_or_default());
+        let missing_dir =
+            env.dir.as_ref().is_some_and(|dir| !Path::new(dir).exists());
+
+        let reason =
+            if opts.missing_dir && missing_dir {
+                Some(PruneReason::MissingDir)
+            } else {
+                match (opts.max_age, age) {
+                    (Some(max_age), Some(age)) if age > max_age => {
+                        Some(PruneReason::Unused(age))
+                    },
+                    _ => {
+                        None
+                    },
+                }
+            };
+
+        match reason {
+            Some(reason) => pruned.push(Prune{env, reason}),
+            None => kept.push(env),
+        }
+    }
+
+    if let Some(max_size) = opts.max_size {
+        // Environments whose last use is unknown are removed first.
+        kept.sort_by_key(|env| env.last_use);
+
+        let mut total: u64 = kept.iter().map(|env| env.size).sum();
+        for env in kept {
+            if total <= max_size {
+                break;
+            }
+            total -= env.size;
+            pruned.push(Prune{env, reason: PruneReason::OverBudget});
+        }
+    }
+
+    pruned.sort_by(|a, b| {
+        (&a.env.project, &a.env.env_name)
+            .cmp(&(&b.env.project, &b.env.env_name))
+    });
+
+    Ok(pruned)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum GcError {
+    #[snafu(display("Couldn't list containers: {}", source))]
+    ListContainersFailed{source: ListError},
+    #[snafu(display("Couldn't list images: {}", source))]
+    ListImagesFailed{source: ListError},
+    #[snafu(display("Couldn't inspect images: {}", source))]
+    InspectImagesFailed{source: ListError},
+    #[snafu(display("Couldn't list volumes: {}", source))]
+    ListVolumesFailed{source: ListError},
+    #[snafu(display("Couldn't inspect volumes: {}", source))]
+    InspectVolumesFailed{source: ListError},
+}
+
+// `find_envs` returns the environments of all projects on the host that have
+// resources, sorted by project and environment name.
+fn find_envs() -> Result<Vec<GcEnv>, GcError> {
+    let mut envs = BTreeMap::new();
+
+    let label_fields = format!(
+        "{{{{.Label \"{}\"}}}}\t{{{{.Label \"{}\"}}}}\t{{{{.Label \"{}\"}}}}",
+        labels::PROJECT_LABEL,
+        labels::ENV_LABEL,
+        labels::DIR_LABEL,
+    );
+
+    // Only stopped containers that were created by `dock` are removed, so that
+    // commands that are in progress aren't stopped.
+    let format_arg = format!("--format={{{{.ID}}}}\t{label_fields}");
+    let marker_filter = format!("--filter=label={}", labels::CONTAINER_LABEL);
+    let args = [
+        "container",
+        "ls",
+        "--all",
+        &format_arg,
+        "--filter=status=created",
+        "--filter=status=exited",
+        &marker_filter,
+    ];
+    let lines = clean::list(&args)
+        .context(ListContainersFailed)?;
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [id, project, env_name, dir] = fields[..] else {
+            continue;
+        };
+
+        let env = entry(&mut envs, project, env_name, Some(dir));
+        env.resources.push(Resource{
+            kind: ResourceKind::Container,
+            name: id.to_string(),
+            size: None,
+        });
+    }
+
+    add_images(&mut envs)?;
+
+    add_volumes(&mut envs)?;
+
+    for env in envs.values_mut() {
+        let last_run = last_use(&env.project, &env.env_name);
+        env.last_use = env.last_use.max(last_run);
+
+        // The directory that was recorded when the environment was last used
+        // on this host takes precedence over the directories in labels.
+        if let Some(dir) = recorded_dir(&env.project) {
+            env.dir = Some(dir);
+        }
+    }
+
+    Ok(envs.into_values().collect())
+}
+
+// `entry` returns the environment `env_name` of `project` in `envs`, adding it
+// if it doesn't exist.
+fn entry<'a>(
+    envs: &'a mut BTreeMap<(String, String), GcEnv>,
+    project: &str,
+    env_name: &str,
+    dir: Option<&str>,
+)
+    -> &'a mut GcEnv
+{
+    let key = (project.to_string(), env_name.to_string());
+    let env = envs.entry(key).or_insert_with(|| GcEnv{
+        project: project.to_string(),
+        env_name: env_name.to_string(),
+        dir: None,
+        last_use: None,
+        size: 0,
+        resources: vec![],
+    });
+
+    if let (None, Some(dir)) = (&env.dir, dir) {
+        if !dir.is_empty() {
+            env.dir = Some(dir.to_string());
+        }
+    }
+
+    env
+}
+
+fn add_images(envs: &mut BTreeMap<(String, String), GcEnv>)
+    -> Result<(), GcError>
+{
+    let project_filter = format!("--filter=label={}", labels::PROJECT_LABEL);
+    let args = [
+        "image",
+        "ls",
+        "--no-trunc",
+        "--format={{.ID}}",
+        &project_filter,
+    ];
+    let mut candidate_ids = clean::list(&args)
+        .context(ListImagesFailed)?;
+
+    let format_arg = "--format={{.Repository}}:{{.Tag}}\t{{.ID}}";
+    let args = ["image", "ls", "--no-trunc", format_arg];
+    let mut img_names: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let lines = clean::list(&args)
+        .context(ListImagesFailed)?;
+    for line in lines {
+        let Some((name, id)) = line.split_once('\t') else {
+            continue;
+        };
+
+        // Untagged images can only be removed by ID.
+        let names = img_names.entry(id.to_string()).or_default();
+        if name != "<none>:<none>" {
+            names.push(name.to_string());
+        }
+
+        if parse_dock_img_name(name).is_some() {
+            candidate_ids.push(id.to_string());
+        }
+    }
+    candidate_ids.sort_unstable();
+    candidate_ids.dedup();
+
+    // `docker image inspect` fails if no images are given.
+    if candidate_ids.is_empty() {
+        return Ok(());
+    }
+
+    let format_arg = format!(
+        "--format={{{{.Id}}}}\t{{{{.Created}}}}\t{{{{.Size}}}}\t\
+         {{{{len .RepoDigests}}}}\t{{{{index .Config.Labels \"{}\"}}}}\t{}",
+        labels::IMAGE_LABEL,
+        label_index_fields(".Config.Labels"),
+    );
+    let mut args = vec!["image", "inspect", &format_arg];
+    args.extend(candidate_ids.iter().map(String::as_str));
+
+    let lines = clean::list(&args)
+        .context(InspectImagesFailed)?;
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [id, created, size, num_digests, marker, project, env_name, dir] =
+            fields[..]
+        else {
+            continue;
+        };
+
+        let mut names = img_names.remove(id).unwrap_or_default();
+
+        // Images that other tools build from `dock` images inherit their
+        // labels, so labelled images are only removed if they're marked as
+        // having been built by `dock`, and only by the names that `dock` gave
+        // them. Images that were labelled before `dock` marked its images are
+        // treated as unlabelled images.
+        if !marker.is_empty() && !names.is_empty() {
+            names.retain(|name| labels::is_marked_image(marker, name));
+            if names.is_empty() {
+                continue;
+            }
+        }
+
+        let (project, env_name, dir) =
+            if marker.is_empty() {
+                // Unlabelled images are only removed if they were built
+                // locally, to avoid removing images that were pulled from a
+                // registry and only happen to match the naming convention.
+                if num_digests != "0" {
+                    continue;
+                }
+                let Some((project, env_name)) =
+                    names.iter().find_map(|name| parse_dock_img_name(name))
+                else {
+                    continue;
+                };
+
+                (project, env_name, None)
+            } else if num_digests == "0" {
+                (project.to_string(), env_name.to_string(), Some(dir))
+            } else {
+                // Images that were pushed to or pulled from a registry may
+                // have been built on another host, so their directory label
+                // isn't necessarily a directory on this host.
+                (project.to_string(), env_name.to_string(), None)
+            };
+
+        let env = entry(envs, &project, &env_name, dir);
+        env.size += size.parse::<u64>().unwrap_or_default();
+        env.last_use = env.last_use.max(parse_timestamp(created));
+
+        let names =
+            if names.is_empty() {
+                vec![id.to_string()]
+            } else {
+                names
+            };
+        for name in names {
+            env.resources.push(Resource{
+                kind: ResourceKind::Image,
+                name,
+                size: None,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn add_volumes(envs: &mut BTreeMap<(String, String), GcEnv>)
+    -> Result<(), GcError>
+{
+    let project_filter = format!("--filter=label={}", labels::PROJECT_LABEL);
+    let args = ["volume", "ls", "--format={{.Name}}", &project_filter];
+    let mut candidates = clean::list(&args)
+        .context(ListVolumesFailed)?;
+
+    let args = ["volume", "ls", "--format={{.Name}}"];
+    let names = clean::list(&args)
+        .context(ListVolumesFailed)?;
+    for name in names {
+        if parse_dock_vol_name(&name).is_some() {
+            candidates.push(name);
+        }
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    // `docker volume inspect` fails if no volumes are given.
+    if candidates.is_empty() {
+        return Ok(());
+    }
+
+    let format_arg = format!(
+        "--format={{{{.Name}}}}\t{{{{.CreatedAt}}}}\t{}",
+        label_index_fields(".Labels"),
+    );
+    let mut args = vec!["volume", "inspect", &format_arg];
+    args.extend(candidates.iter().map(String::as_str));
+
+    let sizes = clean::volume_sizes();
+
+    let lines = clean::list(&args)
+        .context(InspectVolumesFailed)?;
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, created, project, env_name, dir] = fields[..] else {
+            continue;
+        };
+
+        let (project, env_name, dir) =
+            if project.is_empty() {
+                let Some((project, env_name)) = parse_dock_vol_name(name)
+                else {
+                    continue;
+                };
+
+                (project, env_name, None)
+            } else {
+                (project.to_string(), env_name.to_string(), Some(dir))
+            };
+
+        let size = sizes.get(name).and_then(|s| parse_size(s).ok());
+
+        let env = entry(envs, &project, &env_name, dir);
+        env.size += size.unwrap_or_default();
+        env.last_use = env.last_use.max(parse_timestamp(created));
+        env.resources.push(Resource{
+            kind: ResourceKind::Volume,
+            name: name.to_string(),
+            size: None,
+        });
+    }
+
+    Ok(())
+}
+
+// `label_index_fields` returns a tab-separated template for `docker inspect`
+// that outputs the project, environment and directory labels of the `labels`
+// field.
+fn label_index_fields(labels: &str) -> String {
+    [labels::PROJECT_LABEL, labels::ENV_LABEL, labels::DIR_LABEL]
+        .iter()
+        .map(|label| format!("{{{{index {labels} \"{label}\"}}}}"))
+        .collect::<Vec<String>>()
+        .join("\t")
+}
+
+// `parse_dock_img_name` returns the project and environment of the image
+// `name` if it follows the naming convention of `dock` and belongs to a
+// project that `dock` has a cache directory for.
+fn parse_dock_img_name(name: &str) -> Option<(String, String)> {
+    parse_img_name(name).filter(|(project, _)| has_cache_dir(project))
+}
+
+// `parse_dock_vol_name` returns the project and environment of the volume
+// `name` if it follows the naming convention of `dock` and belongs to a
+// project that `dock` has a cache directory for.
+fn parse_dock_vol_name(name: &str) -> Option<(String, String)> {
+    parse_vol_name(name).filter(|(project, _)| has_cache_dir(project))
+}
+
+// `has_cache_dir` returns whether the `dock` cache directory of `project`
+// exists.
+fn has_cache_dir(project: &str) -> bool {
+    let Some((org, proj)) = project.split_once('/') else {
+        return false;
+    };
+
+    build_log::project_cache_dir(org, proj).is_dir()
+}
+
+// `parse_img_name` returns the project and environment of the image `name`,
+// which is in the form `<org>/<project>.<env>:<tag>`.
+fn parse_img_name(name: &str) -> Option<(String, String)> {
+    let (repo, _) = name.rsplit_once(':')?;
+    let (org, rest) = repo.split_once('/')?;
+    let (proj, env_name) = rest.split_once('.')?;
+
+    // Names that contain a registry host, such as `localhost:5000/a.b:c`,
+    // weren't built by `dock`.
+    let is_valid =
+        [org, proj, env_name]
+            .iter()
+            .all(|part| !part.is_empty() && !part.contains(['/', ':']));
+    if !is_valid {
+        return None;
+    }
+
+    Some((labels::project_name(org, proj), env_name.to_string()))
+}
+
+// `parse_vol_name` returns the project and environment of the cache volume
+// `name`, which is in the form `<org>.<project>.<env>.cache.<name>`.
+fn parse_vol_name(name: &str) -> Option<(String, String)> {
+    let (prefix, vol_name) = name.rsplit_once(".cache.")?;
+    let mut parts = prefix.splitn(3, '.');
+    let (org, proj, env_name) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let is_valid =
+        [org, proj, env_name, vol_name]
+            .iter()
+            .all(|part| !part.is_empty());
+    if !is_valid {
+        return None;
+    }
+
+    Some((labels::project_name(org, proj), env_name.to_string()))
+}
+
+// `record_use` records `now` as the time that `env_name` of the project `proj`
+// of `org` was last used.
+pub fn record_use(org: &str, proj: &str, env_name: &str, now: SystemTime)
+    -> Result<(), IoError>
+{
+    let path = last_use_path(org, proj, env_name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    fs::write(path, format!("{}\n", secs.as_secs()))
+}
+
+// `last_use` returns the time that `env_name` of `project` was last used, if
+// it was recorded.
+pub fn last_use(project: &str, env_name: &str) -> Option<SystemTime> {
+    let (org, proj) = project.split_once('/')?;
+    let raw = fs::read_to_string(last_use_path(org, proj, env_name)).ok()?;
+    let secs = raw.trim().parse::<u64>().ok()?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// `last_use_path` returns the path of the file that records when `env_name`
+// of the project `proj` of `org` was last used.
+pub fn last_use_path(org: &str, proj: &str, env_name: &str) -> PathBuf {
+    build_log::project_cache_dir(org, proj)
+        .join("last_use")
+        .join(env_name)
+}
+
+// `record_dir` records `dir` as the directory that contains the Dock file of
+// the project `proj` of `org` on this host.
+pub fn record_dir(org: &str, proj: &str, dir: &str) -> Result<(), IoError> {
+    let path = dir_path(org, proj);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, format!("{dir}\n"))
+}
+
+// `recorded_dir` returns the directory that contains the Dock file of
+// `project` on this host, if it was recorded.
+fn recorded_dir(project: &str) -> Option<String> {
+    let (org, proj) = project.split_once('/')?;
+    let raw = fs::read_to_string(dir_path(org, proj)).ok()?;
+    let dir = raw.trim_end_matches('\n');
+
+    if dir.is_empty() {
+        return None;
+    }
+
+    Some(dir.to_string())
+}
+
+// `dir_path` returns the path of the file that records the directory that
+// contains the Dock file of the project `proj` of `org`.
+pub fn dir_path(org: &str, proj: &str) -> PathBuf {
+    build_log::project_cache_dir(org, proj).join("dir")
+}
+
+// `parse_timestamp` parses an RFC 3339 timestamp, as output by
+// `docker inspect`, such as `2024-05-01T12:34:56.789Z`.
+fn parse_timestamp(raw: &str) -> Option<SystemTime> {
+    let (date, time) = raw.split_once('T')?;
+
+    let mut date_parts = date.splitn(3, '-').map(str::parse::<i64>);
+    let year = date_parts.next()?.ok()?;
+    let month = date_parts.next()?.ok()?;
+    let day = date_parts.next()?.ok()?;
+
+    let (time, offset_secs) =
+        if let Some(time) = time.strip_suffix('Z') {
+            (time, 0)
+        } else {
+            let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (sign, offset) = offset.split_at(1);
+            let (hours, mins) = offset.split_once(':')?;
+            let secs =
+                hours.parse::<i64>().ok()? * 60 * 60
+                    + mins.parse::<i64>().ok()? * 60;
+
+            (time, if sign == "-" { -secs } else { secs })
+        };
+
+    // Fractions of a second are ignored.
+    let time = time.split('.').next()?;
+    let mut time_parts = time.splitn(3, ':').map(str::parse::<i64>);
+    let hours = time_parts.next()?.ok()?;
+    let mins = time_parts.next()?.ok()?;
+    let secs = time_parts.next()?.ok()?;
+
+    let unix_secs =
+        days_from_civil(year, month, day) * 24 * 60 * 60
+            + hours * 60 * 60
+            + mins * 60
+            + secs
+            - offset_secs;
+
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(unix_secs).ok()?))
+}
+
+// `days_from_civil` returns the number of days from the Unix epoch to the
+// given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
+
+// `parse_size` parses a size in the form `<n><unit>`, where `<unit>` is one of
+// `B`, `kB`, `MB`, `GB` or `TB`, as output by `docker`, or one of `KiB`,
+// `MiB`, `GiB` or `TiB`, as output by `build_context::format_size`. A space
+// is allowed between `<n>` and `<unit>`.
+pub fn parse_size(raw: &str) -> Result<u64, ParseSizeError> {
+    let invalid = || ParseSizeError::InvalidSize{size: raw.to_string()};
+
+    let unit_start = raw.find(|c: char| !c.is_ascii_digit() && c != '.')
+        .ok_or_else(invalid)?;
+    let (raw_n, unit) = raw.split_at(unit_start);
+    let unit = unit.trim_start();
+
+    let unit_bytes: u64 =
+        match unit.to_ascii_uppercase().as_str() {
+            "B" => 1,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            "TB" => 1_000_000_000_000,
+            "KIB" => 1 << 10,
+            "MIB" => 1 << 20,
+            "GIB" => 1 << 30,
+            "TIB" => 1 << 40,
+            _ => return Err(invalid()),
+        };
+
+    scale_decimal(raw_n, unit_bytes)
+        .ok_or_else(invalid)
+}
+
+// `scale_decimal` returns the decimal number `raw_n`, such as `1.5`,
+// multiplied by `factor`, rounded down, or `None` if `raw_n` isn't a valid
+// decimal number or if the result doesn't fit in a `u64`.
+fn scale_decimal(raw_n: &str, factor: u64) -> Option<u64> {
+    let (raw_int, raw_frac) = raw_n.split_once('.').unwrap_or((raw_n, ""));
+    if raw_int.is_empty() && raw_frac.is_empty() {
+        return None;
+    }
+
+    let parse_digits = |digits: &str| {
+        if digits.is_empty() {
+            Some(0)
+        } else {
+            digits.parse::<u64>().ok()
+        }
+    };
+    let int = parse_digits(raw_int)?;
+    let frac = parse_digits(raw_frac)?;
+    let frac_scale = 10_u64.checked_pow(u32::try_from(raw_frac.len()).ok()?)?;
+
+    int.checked_mul(factor)?
+        .checked_add(frac.checked_mul(factor)? / frac_scale)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseSizeError {
+    #[snafu(display(
+        "'{}' isn't a valid size; expected a number followed by `B`, `kB`, \
+         `MB`, `GB`, `TB`, `KiB`, `MiB`, `GiB` or `TiB`, such as `20GB`",
+        size,
+    ))]
+    InvalidSize{size: String},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) timestamps in UTC and with offsets, which all refer to the
+    //     same instant, except for the Unix epoch
+    // When `parse_timestamp` is called for each timestamp
+    // Then (A) each timestamp is parsed as the expected instant
+    fn test_parse_timestamp_accepts_offsets() {
+        // (1)
+        let cases = [
+            ("1970-01-01T00:00:00Z", 0),
+            ("2024-05-01T12:34:56.123456789Z", 1_714_566_896),
+            ("2024-05-01T13:34:56+01:00", 1_714_566_896),
+            ("2024-05-01T10:34:56-02:00", 1_714_566_896),
+        ];
+
+        for (raw, secs) in cases {
+            let result = parse_timestamp(raw);
+
+            // (A)
+            assert_eq!(
+                result,
+                Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                "'{raw}' was parsed incorrectly",
+            );
+        }
+    }
+
+    #[test]
+    // Given (1) sizes with decimal and binary units, with and without
+    //     fractions and spaces
+    // When `parse_size` is called for each size
+    // Then (A) each size is parsed as the expected number of bytes
+    fn test_parse_size_accepts_units() {
+        // (1)
+        let cases = [
+            ("0B", 0),
+            ("512B", 512),
+            ("1.5kB", 1_500),
+            ("1.234GB", 1_234_000_000),
+            ("20GB", 20_000_000_000),
+            ("1TB", 1_000_000_000_000),
+            ("1.5 KiB", 1_536),
+            ("20GiB", 20 * 1024 * 1024 * 1024),
+        ];
+
+        for (raw, bytes) in cases {
+            let result = parse_size(raw);
+
+            // (A)
+            assert!(
+                matches!(result, Ok(b) if b == bytes),
+                "'{raw}' was parsed incorrectly: {result:?}",
+            );
+        }
+    }
+
+    #[test]
+    // Given (1) sizes without numbers or units, with unknown units, with
+    //     malformed numbers, or that don't fit in a `u64`
+    // When `parse_size` is called for each size
+    // Then (A) each size is rejected
+    fn test_parse_size_rejects_invalid_sizes() {
+        // (1)
+        let cases = [
+            "",
+            "20",
+            "GB",
+            ".GB",
+            "1.2.3GB",
+            "20GIGS",
+            "N/A",
+            "99999999999TB",
+        ];
+
+        for raw in cases {
+            let result = parse_size(raw);
+
+            // (A)
+            assert!(result.is_err(), "'{raw}' was accepted");
+        }
+    }
+
+    #[test]
+    // Given (1) an image name that follows the naming convention of `dock`
+    //     AND (2) image names that don't follow it
+    // When `parse_img_name` is called for each name
+    // Then (A) the project and environment of (1) are returned
+    //     AND (B) nothing is returned for (2)
+    fn test_parse_img_name_follows_naming_convention() {
+        // (1)
+        let name = "org/proj.build:latest";
+        // (2)
+        let others = [
+            "ubuntu:22.04",
+            "library/ubuntu:22.04",
+            "localhost:5000/proj.build:x",
+        ];
+
+        // (A)
+        assert_eq!(
+            parse_img_name(name),
+            Some(("org/proj".to_string(), "build".to_string())),
+        );
+        // (B)
+        for other in others {
+            assert_eq!(parse_img_name(other), None, "'{other}' was parsed");
+        }
+    }
+
+    #[test]
+    // Given (1) a volume name that follows the naming convention of `dock`
+    //     AND (2) volume names that don't follow it
+    // When `parse_vol_name` is called for each name
+    // Then (A) the project and environment of (1) are returned
+    //     AND (B) nothing is returned for (2)
+    fn test_parse_vol_name_follows_naming_convention() {
+        // (1)
+        let name = "org.proj.build.cache.cargo";
+        // (2)
+        let others = ["org.proj.cache.cargo", "my_volume"];
+
+        // (A)
+        assert_eq!(
+            parse_vol_name(name),
+            Some(("org/proj".to_string(), "build".to_string())),
+        );
+        // (B)
+        for other in others {
+            assert_eq!(parse_vol_name(other), None, "'{other}' was parsed");
+        }
+    }
+}
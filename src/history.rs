@@ -0,0 +1,287 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::collections::HashMap;
+use std::str;
+use std::str::Utf8Error;
+
+use serde_yaml::Error as SerdeYamlError;
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::docker;
+use crate::docker::AssertRunError;
+use crate::run_in;
+use crate::run_in::FindAndParseDockConfigError;
+
+pub const DOCKERFILE_HASH_LABEL: &str = "dock.dockerfile_hash";
+
+const PREV_TAG_PREFIX: &str = "prev-";
+
+fn prev_img(img_name: &str, n: usize) -> String {
+    format!("{img_name}:{PREV_TAG_PREFIX}{n}")
+}
+
+// `rotate` keeps `cache_img`, which is the image that was superseded by a
+// successful rebuild of `img_name`, as `<img_name>:prev-1`, and shifts the
+// existing previous images of `img_name` back by one, so that at most `depth`
+// previous images are kept.
+pub fn rotate(img_name: &str, cache_img: &str, depth: usize)
+    -> Result<(), RotateError>
+{
+    let mut prevs = prev_tag_numbers(img_name)
+        .context(ListPrevTagsFailed)?;
+
+    // We shift the oldest images first, so that each tag is moved before it's
+    // overwritten by the next-newest image.
+    prevs.sort_unstable();
+    for n in prevs.into_iter().rev() {
+        let img = prev_img(img_name, n);
+        if n < depth {
+            docker::assert_run(["tag", &img, &prev_img(img_name, n + 1)])
+                .context(ShiftPrevImageFailed{img: img.clone()})?;
+        }
+        docker::assert_run(["rmi", &img])
+            .context(RemovePrevImageFailed{img})?;
+    }
+
+    if depth > 0 {
+        let prev = prev_img(img_name, 1);
+        docker::assert_run(["tag", cache_img, &prev])
+            .context(TagPrevImageFailed{img: prev})?;
+    }
+
+    Ok(())
+}
+
+// `prev_imgs` returns the previous images of `img_name` that exist.
+pub fn prev_imgs(img_name: &str) -> Result<Vec<String>, ListPrevTagsError> {
+    let nums = prev_tag_numbers(img_name)?;
+
+    Ok(nums.into_iter().map(|n| prev_img(img_name, n)).collect())
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum RotateError {
+    #[snafu(display("Couldn't list previous images: {}", source))]
+    ListPrevTagsFailed{source: ListPrevTagsError},
+    #[snafu(display("Couldn't shift previous image '{}': {}", img, source))]
+    ShiftPrevImageFailed{source: AssertRunError, img: String},
+    #[snafu(display("Couldn't remove previous image '{}': {}", img, source))]
+    RemovePrevImageFailed{source: AssertRunError, img: String},
+    #[snafu(display("Couldn't tag previous image '{}': {}", img, source))]
+    TagPrevImageFailed{source: AssertRunError, img: String},
+}
+
+// `prev_tag_numbers` returns `n` for each `<img_name>:prev-<n>` tag that
+// exists.
+fn prev_tag_numbers(img_name: &str) -> Result<Vec<usize>, ListPrevTagsError> {
+    let output =
+        docker::assert_run(["image", "ls", "--format={{.Tag}}", img_name])
+            .context(ListTagsFailed)?;
+
+    let stdout = str::from_utf8(&output.stdout)
+        .context(ListTagsOutputNotUtf8)?;
+
+    let nums =
+        stdout
+            .lines()
+            .filter_map(|tag| tag.strip_prefix(PREV_TAG_PREFIX))
+            .filter_map(|n| n.parse().ok())
+            .collect();
+
+    Ok(nums)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ListPrevTagsError {
+    #[snafu(display("Couldn't list image tags: {}", source))]
+    ListTagsFailed{source: AssertRunError},
+    #[snafu(display("Image tags weren't valid UTF-8: {}", source))]
+    ListTagsOutputNotUtf8{source: Utf8Error},
+}
+
+pub struct Entry {
+    pub tag: String,
+    pub id: String,
+    pub created: String,
+    pub dockerfile_hash: Option<String>,
+}
+
+// `history` returns the current image for `env_name`, followed by its previous
+// images, from newest to oldest. `platform` selects the images that were built
+// for a platform other than the configured platform of the environment.
+pub fn history(dock_file_name: &str, env_name: &str, platform: Option<&str>)
+    -> Result<Vec<Entry>, HistoryError>
+{
+    let img_name = env_image_name(dock_file_name, env_name, platform)
+        .context(EnvImageNameFailed)?;
+
+    let mut prevs = prev_tag_numbers(&img_name)
+        .context(ListPrevImagesFailed)?;
+    prevs.sort_unstable();
+
+    let mut tags = vec!["latest".to_string()];
+    tags.extend(prevs.iter().map(|n| format!("{PREV_TAG_PREFIX}{n}")));
+
+    let mut entries = vec![];
+    for tag in tags {
+        let img = format!("{img_name}:{tag}");
+        let format_arg =
+            "--format={{.Id}}\t{{.Created}}\t{{json .Config.Labels}}";
+
+        // `latest` may not exist, for example, if the environment has never
+        // been built.
+        let output =
+            match docker::assert_run(["image", "inspect", format_arg, &img]) {
+                Ok(output) => {
+                    output
+                },
+                Err(AssertRunError::NonZeroExit{..}) if tag == "latest" => {
+                    continue;
+                },
+                Err(source) => {
+                    return Err(HistoryError::InspectImageFailed{source, img});
+                },
+            };
+
+        let stdout = str::from_utf8(&output.stdout)
+            .context(InspectOutputNotUtf8{img: img.clone()})?;
+
+        let mut fields = stdout.trim_end().splitn(3, '\t');
+        let (id, created, raw_labels) =
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(id), Some(created), Some(labels)) => {
+                    (id, created, labels)
+                },
+                _ => {
+                    return Err(HistoryError::UnexpectedInspectOutput{img});
+                },
+            };
+
+        let labels: Option<HashMap<String, String>> =
+            serde_yaml::from_str(raw_labels)
+                .context(ParseLabelsFailed{img})?;
+
+        entries.push(Entry{
+            tag,
+            id: id.to_string(),
+            created: created.to_string(),
+            dockerfile_hash: labels
+                .and_then(|mut labels| labels.remove(DOCKERFILE_HASH_LABEL)),
+        });
+    }
+
+    Ok(entries)
+}
+
+#[derive(Debug, Snafu)]
+pub enum HistoryError {
+    #[snafu(display("{}", source))]
+    EnvImageNameFailed{source: EnvImageNameError},
+    #[snafu(display("Couldn't list previous images: {}", source))]
+    ListPrevImagesFailed{source: ListPrevTagsError},
+    #[snafu(display("Couldn't inspect image '{}': {}", img, source))]
+    InspectImageFailed{source: AssertRunError, img: String},
+    #[snafu(display(
+        "Output of inspecting '{}' wasn't valid UTF-8: {}",
+        img,
+        source,
+    ))]
+    InspectOutputNotUtf8{source: Utf8Error, img: String},
+    #[snafu(display("Unexpected output from inspecting '{}'", img))]
+    UnexpectedInspectOutput{img: String},
+    #[snafu(display("Couldn't parse the labels of '{}': {}", img, source))]
+    ParseLabelsFailed{source: SerdeYamlError, img: String},
+}
+
+// `rollback` swaps the current image for `env_name` with its `n`th previous
+// image, so that a rollback can be undone by running it again. `platform`
+// selects the images that were built for a platform other than the configured
+// platform of the environment.
+pub fn rollback(
+    dock_file_name: &str,
+    env_name: &str,
+    platform: Option<&str>,
+    n: usize,
+)
+    -> Result<(), RollbackError>
+{
+    let img_name = env_image_name(dock_file_name, env_name, platform)
+        .context(RollbackEnvImageNameFailed)?;
+
+    let cur = format!("{img_name}:latest");
+    let prev = prev_img(&img_name, n);
+    // `swap` temporarily holds the current image while `cur` is replaced.
+    let swap = format!("{img_name}:rollback");
+
+    docker::assert_run(["image", "inspect", &prev])
+        .context(PrevImageNotFound{img: prev.clone()})?;
+
+    let cur_exists = docker::assert_run(["image", "inspect", &cur]).is_ok();
+    if cur_exists {
+        docker::assert_run(["tag", &cur, &swap])
+            .context(RollbackTagFailed{src: cur.clone(), dst: swap.clone()})?;
+    }
+
+    docker::assert_run(["tag", &prev, &cur])
+        .context(RollbackTagFailed{src: prev.clone(), dst: cur})?;
+
+    if cur_exists {
+        docker::assert_run(["tag", &swap, &prev])
+            .context(RollbackTagFailed{src: swap.clone(), dst: prev})?;
+        docker::assert_run(["rmi", &swap])
+            .context(RemoveSwapImageFailed{img: swap})?;
+    } else {
+        docker::assert_run(["rmi", &prev])
+            .context(RemoveSwapImageFailed{img: prev})?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+pub enum RollbackError {
+    #[snafu(display("{}", source))]
+    RollbackEnvImageNameFailed{source: EnvImageNameError},
+    #[snafu(display("Previous image '{}' wasn't found: {}", img, source))]
+    PrevImageNotFound{source: AssertRunError, img: String},
+    #[snafu(display("Couldn't tag '{}' as '{}': {}", src, dst, source))]
+    RollbackTagFailed{source: AssertRunError, src: String, dst: String},
+    #[snafu(display("Couldn't remove '{}': {}", img, source))]
+    RemoveSwapImageFailed{source: AssertRunError, img: String},
+}
+
+fn env_image_name(
+    dock_file_name: &str,
+    env_name: &str,
+    platform: Option<&str>,
+)
+    -> Result<String, EnvImageNameError>
+{
+    let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let env = conf.environments.get(env_name)
+        .context(EnvironmentNotFound{name: env_name})?;
+
+    Ok(run_in::platform_image_name(&conf, env_name, env, platform))
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnvImageNameError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+}
@@ -0,0 +1,790 @@
+// Copyright 2022-2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Tracking of the host paths that are bind-mounted into a container.
+//!
+//! `dock` records the bind mounts that it creates in the `DOCK_HOSTPATHS`
+//! environment variable of the container, so that an instance of `dock`
+//! running inside that container can map its own paths back to paths on the
+//! Docker host.
+//!
+//! Two encodings of `DOCK_HOSTPATHS` are supported. The legacy encoding is a
+//! `:`-separated list of alternating outer and inner paths, which can't
+//! represent paths that contain `:`. The current encoding starts with a
+//! version prefix (`v2:`), and percent-encodes each path before joining them
+//! in the same way.
+//!
+//! If `DOCK_HOSTPATHS` isn't set but `dock` is running inside a container,
+//! then `dock` attempts to discover the host paths by inspecting its own
+//! container using the Docker socket. This allows nested bind mounts to be
+//! used in containers that weren't started by `dock`, such as CI agents. The
+//! sources of volumes mounted into the container are also paths on the host,
+//! so they're discovered in the same way as bind mounts.
+
+use std::env;
+use std::env::VarError;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io::Error as IoError;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str;
+use std::str::Utf8Error;
+
+use serde::Deserialize;
+use serde_yaml::Error as SerdeYamlError;
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::canon_path::AbsPath;
+use crate::canon_path::NewAbsPathError;
+use crate::canon_path::RelPath;
+use crate::docker;
+use crate::docker::AssertRunError;
+use crate::logging_process::CmdLoggerMsg;
+use crate::logging_process::CommandLogger;
+use crate::trie::InsertError;
+use crate::trie::Trie;
+
+pub const DOCK_HOSTPATHS_VAR_NAME: &str = "DOCK_HOSTPATHS";
+
+pub const DOCK_HOSTPATHS_DISCOVERY_VAR_NAME: &str = "DOCK_HOSTPATHS_DISCOVERY";
+
+const V2_PREFIX: &str = "v2:";
+
+#[derive(Debug)]
+pub struct Hostpaths {
+    host_paths: Trie<OsString, AbsPath>,
+}
+
+impl Hostpaths {
+    fn new() -> Hostpaths {
+        Self{host_paths: Trie::new()}
+    }
+
+    // NOTE Mappings may overlap (e.g. `/app` and `/app/target` may both be
+    // mapped), in which case `lookup` uses the mapping for the longest
+    // matching prefix, in the same way as the kernel resolves nested mounts.
+    fn insert(&mut self, outer_path: AbsPath, inner_path: &AbsPath)
+        -> Result<(), HostpathInsertError>
+    {
+        match self.host_paths.insert(inner_path, outer_path.clone()) {
+            Ok(()) => {
+                Ok(())
+            },
+            Err(InsertError::EmptyKey) => {
+                // TODO These parameters can be added at a higher level.
+                Err(HostpathInsertError::EmptyInnerPath{outer_path})
+            },
+        }
+    }
+
+    pub fn lookup(&self, path: &AbsPath) -> Option<AbsPath> {
+        let (prefix, host_dir) = self.host_paths.value_at_prefix(path)?;
+
+        let rel_path: Vec<OsString> =
+            path
+                .iter()
+                .skip(prefix.len())
+                .cloned()
+                .collect();
+
+        let host_path = host_dir.concat(&RelPath::from(rel_path));
+
+        Some(host_path)
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum HostpathInsertError {
+    #[snafu(display(
+        "The path '{}' maps to an empty path",
+        outer_path.display_lossy(),
+    ))]
+    EmptyInnerPath{outer_path: AbsPath},
+}
+
+impl TryFrom<Vec<(PathBuf, PathBuf)>> for Hostpaths {
+    type Error = HostpathFromPairsError;
+
+    fn try_from(pairs: Vec<(PathBuf, PathBuf)>) -> Result<Self, Self::Error> {
+        let mut hps = Hostpaths::new();
+
+        for (outer_path, inner_path) in pairs {
+            let abs_outer_path = AbsPath::try_from(outer_path.clone())
+                .with_context(|| ParseOuterPathFailed{
+                    outer_path: outer_path.clone(),
+                    inner_path: inner_path.clone(),
+                })?;
+
+            let abs_inner_path = AbsPath::try_from(inner_path.clone())
+                .with_context(|| ParseInnerPathFailed{
+                    outer_path: outer_path.clone(),
+                    inner_path: inner_path.clone(),
+                })?;
+
+            hps.insert(abs_outer_path, &abs_inner_path)
+                .context(HostpathInsertFailed{outer_path, inner_path})?;
+        }
+
+        Ok(hps)
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum HostpathFromPairsError {
+    #[snafu(display(
+        "Couldn't parse '{}' as an absolute path (mapped to '{}'): {}",
+        outer_path.display(),
+        inner_path.display(),
+        source,
+    ))]
+    ParseOuterPathFailed{
+        source: NewAbsPathError,
+        outer_path: PathBuf,
+        inner_path: PathBuf,
+    },
+    #[snafu(display(
+        "Couldn't parse '{}' as an absolute path (mapped from '{}'): {}",
+        inner_path.display(),
+        outer_path.display(),
+        source,
+    ))]
+    ParseInnerPathFailed{
+        source: NewAbsPathError,
+        outer_path: PathBuf,
+        inner_path: PathBuf,
+    },
+    #[snafu(display(
+        "Couldn't add hostpath mapping '{}' to '{}' to hostpaths: {}",
+        outer_path.display(),
+        inner_path.display(),
+        source,
+    ))]
+    HostpathInsertFailed{
+        source: HostpathInsertError,
+        outer_path: PathBuf,
+        inner_path: PathBuf,
+    },
+}
+
+/// Returns the `Hostpaths` defined by `$DOCK_HOSTPATHS`. If
+/// `$DOCK_HOSTPATHS` isn't set then the `Hostpaths` are discovered from the
+/// mounts of the current container, unless discovery has been disabled using
+/// `$DOCK_HOSTPATHS_DISCOVERY`. `None` is returned if no `Hostpaths` could be
+/// found.
+pub fn hostpaths(logger: &mut dyn CommandLogger)
+    -> Result<Option<Hostpaths>, HostpathsError>
+{
+    let raw_hostpaths =
+        match env::var(DOCK_HOSTPATHS_VAR_NAME) {
+            Ok(v) => {
+                v
+            },
+            Err(VarError::NotPresent) => {
+                let discovery = discovery_mode()
+                    .context(GetDiscoveryModeFailed)?;
+
+                if let DiscoveryMode::Off = discovery {
+                    return Ok(None);
+                }
+
+                return discover_hostpaths(logger, &discovery)
+                    .context(DiscoverHostpathsFailed);
+            },
+            Err(VarError::NotUnicode(value)) => {
+                return Err(HostpathsError::EnvVarIsNotUnicode{value});
+            },
+        };
+
+    let pairs = parse(&raw_hostpaths)
+        .context(ParseFailed{hostpaths: raw_hostpaths.clone()})?;
+
+    let hostpaths = Hostpaths::try_from(pairs)
+        .context(CreateHostpathsFailed)?;
+
+    Ok(Some(hostpaths))
+}
+
+#[derive(Debug, Snafu)]
+pub enum HostpathsError {
+    #[snafu(display(
+        "The value of '${}' isn't unicode",
+        DOCK_HOSTPATHS_VAR_NAME,
+    ))]
+    EnvVarIsNotUnicode{value: OsString},
+    #[snafu(display(
+        "Couldn't parse '${}': {}",
+        DOCK_HOSTPATHS_VAR_NAME,
+        source,
+    ))]
+    ParseFailed{source: ParseError, hostpaths: String},
+    #[snafu(display(
+        "Couldn't create hostpaths from '${}': {}",
+        DOCK_HOSTPATHS_VAR_NAME,
+        source,
+    ))]
+    CreateHostpathsFailed{source: HostpathFromPairsError},
+    #[snafu(display("{}", source))]
+    GetDiscoveryModeFailed{source: DiscoveryModeError},
+    #[snafu(display("Couldn't discover hostpaths: {}", source))]
+    DiscoverHostpathsFailed{source: DiscoverHostpathsError},
+}
+
+enum DiscoveryMode {
+    // `Auto` discovers hostpaths if `dock` is running in a container, and
+    // ignores errors that occur during discovery.
+    Auto,
+    // `Required` always discovers hostpaths, and fails if they can't be
+    // discovered.
+    Required,
+    Off,
+}
+
+fn discovery_mode() -> Result<DiscoveryMode, DiscoveryModeError> {
+    let raw_mode =
+        match env::var(DOCK_HOSTPATHS_DISCOVERY_VAR_NAME) {
+            Ok(v) => {
+                v
+            },
+            Err(VarError::NotPresent) => {
+                return Ok(DiscoveryMode::Auto);
+            },
+            Err(VarError::NotUnicode(value)) => {
+                return Err(DiscoveryModeError::ModeIsNotUnicode{value});
+            },
+        };
+
+    match raw_mode.as_str() {
+        "auto" => Ok(DiscoveryMode::Auto),
+        "required" => Ok(DiscoveryMode::Required),
+        "off" => Ok(DiscoveryMode::Off),
+        _ => Err(DiscoveryModeError::UnknownMode{mode: raw_mode}),
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum DiscoveryModeError {
+    #[snafu(display(
+        "The value of '${}' isn't unicode",
+        DOCK_HOSTPATHS_DISCOVERY_VAR_NAME,
+    ))]
+    ModeIsNotUnicode{value: OsString},
+    #[snafu(display(
+        "Unknown value '{}' for '${}' (expected 'auto', 'required' or 'off')",
+        mode,
+        DOCK_HOSTPATHS_DISCOVERY_VAR_NAME,
+    ))]
+    UnknownMode{mode: String},
+}
+
+fn discover_hostpaths(logger: &mut dyn CommandLogger, mode: &DiscoveryMode)
+    -> Result<Option<Hostpaths>, DiscoverHostpathsError>
+{
+    if let DiscoveryMode::Auto = mode {
+        if !in_container() {
+            return Ok(None);
+        }
+
+        // We treat discovery as best-effort in `Auto` mode, because `dock`
+        // may be running in a container that doesn't have access to the
+        // Docker daemon that started it (e.g. when a separate Docker daemon
+        // is run inside the container), in which case paths in the current
+        // container are assumed to be paths on the Docker host. The error is
+        // logged so that it's included in the debug output.
+        let hostpaths =
+            match try_discover_hostpaths() {
+                Ok(hostpaths) => {
+                    Some(hostpaths)
+                },
+                Err(err) => {
+                    let msg = format!(
+                        "Couldn't discover host paths, so paths are assumed \
+                         to be paths on the host: {err}\n",
+                    );
+                    logger.log(CmdLoggerMsg::StderrWrite(msg.as_bytes()));
+
+                    None
+                },
+            };
+
+        return Ok(hostpaths);
+    }
+
+    let hostpaths = try_discover_hostpaths()?;
+
+    Ok(Some(hostpaths))
+}
+
+fn in_container() -> bool {
+    CONTAINER_MARKER_FILES
+        .iter()
+        .any(|p| Path::new(p).exists())
+}
+
+const CONTAINER_MARKER_FILES: &[&str] = &["/.dockerenv", "/run/.containerenv"];
+
+fn try_discover_hostpaths() -> Result<Hostpaths, DiscoverHostpathsError> {
+    let container_id = own_container_id()
+        .context(GetContainerIdFailed)?;
+
+    let output =
+        docker::assert_run([
+            "inspect",
+            "--type=container",
+            "--format={{json .Mounts}}",
+            &container_id,
+        ])
+            .context(InspectContainerFailed{
+                container_id: container_id.clone(),
+            })?;
+
+    let raw_mounts = str::from_utf8(&output.stdout)
+        .context(InspectOutputAsUtf8Failed)?;
+
+    let pairs = mount_pairs(raw_mounts)
+        .context(ParseMountsFailed)?;
+
+    let hostpaths = Hostpaths::try_from(pairs)
+        .context(CreateDiscoveredHostpathsFailed)?;
+
+    Ok(hostpaths)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum DiscoverHostpathsError {
+    #[snafu(display(
+        "Couldn't get the ID of the current container: {}",
+        source,
+    ))]
+    GetContainerIdFailed{source: OwnContainerIdError},
+    #[snafu(display(
+        "Couldn't inspect the current container ('{}'): {}",
+        container_id,
+        source,
+    ))]
+    InspectContainerFailed{source: AssertRunError, container_id: String},
+    #[snafu(display(
+        "Couldn't convert the container mounts to UTF-8: {}",
+        source,
+    ))]
+    InspectOutputAsUtf8Failed{source: Utf8Error},
+    #[snafu(display("Couldn't parse the container mounts: {}", source))]
+    ParseMountsFailed{source: SerdeYamlError},
+    #[snafu(display("{}", source))]
+    CreateDiscoveredHostpathsFailed{source: HostpathFromPairsError},
+}
+
+// `own_container_id` returns the ID of the current container. This is read
+// from the mount information of the current process, because the files that
+// Docker generates for each container (e.g. `/etc/hostname`) are bind-mounted
+// from a directory named after the ID of the container. The hostname is used
+// as a fallback because it's the short form of the container ID by default.
+fn own_container_id() -> Result<String, OwnContainerIdError> {
+    let mountinfo = fs::read_to_string(MOUNTINFO_PATH)
+        .context(ReadMountinfoFailed)?;
+
+    if let Some(id) = container_id_from_mountinfo(&mountinfo) {
+        return Ok(id);
+    }
+
+    let hostname = fs::read_to_string(HOSTNAME_PATH)
+        .context(ReadHostnameFailed)?;
+
+    Ok(hostname.trim().to_string())
+}
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+const HOSTNAME_PATH: &str = "/etc/hostname";
+
+#[derive(Debug, Snafu)]
+pub enum OwnContainerIdError {
+    #[snafu(display("Couldn't read '{}': {}", MOUNTINFO_PATH, source))]
+    ReadMountinfoFailed{source: IoError},
+    #[snafu(display("Couldn't read '{}': {}", HOSTNAME_PATH, source))]
+    ReadHostnameFailed{source: IoError},
+}
+
+fn container_id_from_mountinfo(mountinfo: &str) -> Option<String> {
+    let marker = "/containers/";
+
+    for line in mountinfo.lines() {
+        let mut rest = line;
+        while let Some(i) = rest.find(marker) {
+            rest = &rest[i + marker.len()..];
+
+            let id: String =
+                rest
+                    .chars()
+                    .take_while(char::is_ascii_hexdigit)
+                    .collect();
+
+            let is_dir = rest[id.len()..].starts_with('/');
+            if id.len() == CONTAINER_ID_LEN && is_dir {
+                return Some(id);
+            }
+        }
+    }
+
+    None
+}
+
+const CONTAINER_ID_LEN: usize = 64;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerMount {
+    #[serde(rename = "Type")]
+    kind: String,
+    source: PathBuf,
+    destination: PathBuf,
+}
+
+// `mount_pairs` returns the host and container paths of the bind mounts and
+// volumes in `raw_mounts`, which is the JSON rendering of the `Mounts` field
+// output by `docker inspect`. The `Source` of a volume is the directory of the
+// volume on the host, such as `/var/lib/docker/volumes/<name>/_data`, which
+// is empty for volumes whose driver doesn't store them on the host. We use
+// `serde_yaml` to parse this JSON because JSON is a subset of YAML.
+fn mount_pairs(raw_mounts: &str)
+    -> Result<Vec<(PathBuf, PathBuf)>, SerdeYamlError>
+{
+    let mounts: Option<Vec<DockerMount>> = serde_yaml::from_str(raw_mounts)?;
+
+    let pairs =
+        mounts
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|m| m.kind == "bind" || m.kind == "volume")
+            .filter(|m| !m.source.as_os_str().is_empty())
+            .map(|m| (m.source, m.destination))
+            .collect();
+
+    Ok(pairs)
+}
+
+/// Returns the value of `$DOCK_HOSTPATHS` that describes `mappings`, where
+/// each mapping is a pair of an outer path and an inner path.
+pub fn render(mappings: &[(PathBuf, PathBuf)]) -> String {
+    let mut rendered = V2_PREFIX.to_string();
+
+    let encoded: Vec<String> =
+        mappings
+            .iter()
+            .flat_map(|(outer, inner)| {
+                [encode_path(outer), encode_path(inner)]
+            })
+            .collect();
+
+    rendered.push_str(&encoded.join(":"));
+
+    rendered
+}
+
+fn parse(raw_hostpaths: &str) -> Result<Vec<(PathBuf, PathBuf)>, ParseError> {
+    if let Some(raw_v2) = raw_hostpaths.strip_prefix(V2_PREFIX) {
+        if raw_v2.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut paths = vec![];
+        for raw_path in raw_v2.split(':') {
+            let path = decode_path(raw_path)
+                .context(DecodePathFailed{path: raw_path.to_string()})?;
+
+            paths.push(path);
+        }
+
+        return pairs(paths)
+            .context(UnmatchedHostpath);
+    }
+
+    // Paths in the legacy encoding always start with `/`, so any other prefix
+    // indicates that a newer version of `dock` set `$DOCK_HOSTPATHS`.
+    if !raw_hostpaths.starts_with('/') {
+        let version =
+            match raw_hostpaths.split_once(':') {
+                Some((version, _)) => version.to_string(),
+                None => raw_hostpaths.to_string(),
+            };
+
+        return Err(ParseError::UnsupportedVersion{version});
+    }
+
+    let paths =
+        raw_hostpaths
+            .split(':')
+            .map(PathBuf::from)
+            .collect();
+
+    pairs(paths)
+        .context(UnmatchedHostpath)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseError {
+    #[snafu(display("Couldn't decode '{}': {}", path, source))]
+    DecodePathFailed{source: DecodeError, path: String},
+    #[snafu(display("The value has an unmatched hostpath"))]
+    UnmatchedHostpath,
+    #[snafu(display("Unsupported encoding version '{}'", version))]
+    UnsupportedVersion{version: String},
+}
+
+fn pairs<T: Debug>(xs: Vec<T>) -> Option<Vec<(T, T)>> {
+    if xs.len() % 2 == 1 {
+        return None;
+    }
+
+    let mut pairs = Vec::with_capacity(xs.len() / 2);
+
+    let mut iter = xs.into_iter();
+    while let (Some(a), Some(b)) = (iter.next(), iter.next()) {
+        pairs.push((a, b));
+    }
+
+    Some(pairs)
+}
+
+// `encode_path` percent-encodes `%`, `:` and all bytes that aren't printable
+// ASCII characters, so that the result can be safely joined with `:` and
+// passed as the value of an environment variable.
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+
+    for b in path.as_os_str().as_bytes() {
+        let c = char::from(*b);
+        if c == '%' || c == ':' || !c.is_ascii_graphic() {
+            encoded.push('%');
+            encoded.push_str(&hex_byte(*b));
+        } else {
+            encoded.push(c);
+        }
+    }
+
+    encoded
+}
+
+fn hex_byte(b: u8) -> String {
+    format!("{b:02X}")
+}
+
+fn decode_path(s: &str) -> Result<PathBuf, DecodeError> {
+    let bytes = s.as_bytes();
+
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let b =
+            bytes.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if let Some(b) = b {
+            decoded.push(b);
+        } else {
+            return Err(DecodeError::InvalidEscape{pos: i});
+        }
+
+        i += 3;
+    }
+
+    Ok(PathBuf::from(OsString::from_vec(decoded)))
+}
+
+#[derive(Debug, Snafu)]
+pub enum DecodeError {
+    #[snafu(display("Invalid escape sequence at position {}", pos))]
+    InvalidEscape{pos: usize},
+}
+
+pub fn apply_hostpath(maybe_hostpaths: Option<&Hostpaths>, path: &AbsPath)
+    -> Option<AbsPath>
+{
+    if let Some(hps) = maybe_hostpaths {
+        hps.lookup(path)
+    } else {
+        Some(path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) a mapping between paths that contain `:` and `%`
+    // When the mapping is rendered and then parsed
+    // Then (A) the parsed mapping is the same as the original mapping
+    fn test_render_and_parse_paths_with_special_chars() {
+        // (1)
+        let mappings = vec![
+            (PathBuf::from("/host/a:b"), PathBuf::from("/app/100%")),
+            (PathBuf::from("/host/c d"), PathBuf::from("/app/c d")),
+        ];
+
+        let rendered = render(&mappings);
+
+        let result = parse(&rendered)
+            .expect("couldn't parse rendered hostpaths");
+
+        // (A)
+        assert_eq!(result, mappings);
+    }
+
+    #[test]
+    // Given (1) a value in the legacy encoding
+    // When `parse` is called
+    // Then (A) the result contains the legacy mappings
+    fn test_parse_legacy_encoding() {
+        // (1)
+        let raw = "/host/a:/app:/host/b:/app/b";
+
+        let result = parse(raw)
+            .expect("couldn't parse legacy hostpaths");
+
+        // (A)
+        assert_eq!(
+            result,
+            vec![
+                (PathBuf::from("/host/a"), PathBuf::from("/app")),
+                (PathBuf::from("/host/b"), PathBuf::from("/app/b")),
+            ],
+        );
+    }
+
+    #[test]
+    // Given (1) a value with an unknown version prefix
+    // When `parse` is called
+    // Then (A) the result is `Err(ParseError::UnsupportedVersion)`
+    fn test_parse_unsupported_version() {
+        // (1)
+        let raw = "v3:%2Fa:%2Fb";
+
+        let result = parse(raw);
+
+        // (A)
+        assert!(matches!(result, Err(ParseError::UnsupportedVersion{..})));
+    }
+
+    #[test]
+    // Given (1) `Hostpaths` that map `/host/a` to `/app`
+    //     AND (2) the `Hostpaths` map `/host/b` to `/app/target`
+    // When `lookup` is called with `/app/target/debug`
+    // Then (A) the result is `/host/b/debug`
+    fn test_lookup_uses_longest_mapping() {
+        let hps = Hostpaths::try_from(vec![
+            // (1)
+            (PathBuf::from("/host/a"), PathBuf::from("/app")),
+            // (2)
+            (PathBuf::from("/host/b"), PathBuf::from("/app/target")),
+        ])
+            .expect("couldn't create hostpaths");
+        let path = AbsPath::parse("/app/target/debug")
+            .expect("couldn't parse path");
+
+        let result = hps.lookup(&path);
+
+        // (A)
+        assert_eq!(
+            result.map(|p| p.display_lossy()),
+            Some("/host/b/debug".to_string()),
+        );
+    }
+
+    #[test]
+    // Given (1) mount information that contains the Docker-generated
+    //     `/etc/hostname` of a container
+    // When `container_id_from_mountinfo` is called
+    // Then (A) the result is the ID of the container
+    fn test_container_id_from_mountinfo() {
+        let id = "0123456789abcdef".repeat(4);
+        // (1)
+        let mountinfo = format!(
+            "600 550 0:50 / / rw,relatime - overlay overlay rw\n\
+             610 600 259:1 /var/lib/docker/containers/{id}/hostname \
+             /etc/hostname rw,relatime - ext4 /dev/root rw\n",
+        );
+
+        let result = container_id_from_mountinfo(&mountinfo);
+
+        // (A)
+        assert_eq!(result, Some(id));
+    }
+
+    #[test]
+    // Given (1) the JSON rendering of a container's bind mount and volume
+    //     AND (2) a volume without a source and a `tmpfs` mount
+    // When `mount_pairs` is called
+    // Then (A) the result contains the paths of the bind mount and the volume
+    fn test_mount_pairs_maps_binds_and_volumes() {
+        // (1)
+        let raw_mounts = r#"[
+            {
+                "Type": "bind",
+                "Source": "/home/user/proj",
+                "Destination": "/app",
+                "Mode": "",
+                "RW": true,
+                "Propagation": "rprivate"
+            },
+            {
+                "Type": "volume",
+                "Name": "cache",
+                "Source": "/var/lib/docker/volumes/cache/_data",
+                "Destination": "/cache",
+                "Driver": "local",
+                "Mode": "z",
+                "RW": true,
+                "Propagation": ""
+            },
+            {
+                "Type": "volume",
+                "Name": "remote",
+                "Source": "",
+                "Destination": "/remote",
+                "Driver": "nfs",
+                "Mode": "z",
+                "RW": true,
+                "Propagation": ""
+            },
+            {
+                "Type": "tmpfs",
+                "Source": "",
+                "Destination": "/tmp",
+                "Mode": "",
+                "RW": true,
+                "Propagation": ""
+            }
+        ]"#;
+
+        let result = mount_pairs(raw_mounts)
+            .expect("couldn't parse mounts");
+
+        // (A)
+        assert_eq!(
+            result,
+            vec![
+                (PathBuf::from("/home/user/proj"), PathBuf::from("/app")),
+                (
+                    PathBuf::from("/var/lib/docker/volumes/cache/_data"),
+                    PathBuf::from("/cache"),
+                ),
+            ],
+        );
+    }
+}
@@ -151,11 +151,11 @@ pub struct GitTemplatesSource {
 }
 
 impl GitTemplatesSource {
-    fn new(url: String, reference: String) -> Self {
+    pub fn new(url: String, reference: String) -> Self {
         Self{url, reference}
     }
 
-    fn clone_to(&self, dir: &Path) -> Result<(), GitCloneToError> {
+    pub fn clone_to(&self, dir: &Path) -> Result<(), GitCloneToError> {
         // This optimised flow for cloning a single reference is taken from
         // <https://stackoverflow.com/a/71911631>.
 
@@ -438,7 +438,7 @@ pub enum WalkError<E: 'static + Debug + Display + StdError> {
 }
 
 // TODO Mostly duplicated from `crate::run_in`.
-fn assert_run_in_dir<I, S>(dir: &Path, prog: &str, args: I)
+pub fn assert_run_in_dir<I, S>(dir: &Path, prog: &str, args: I)
     -> Result<Output, AssertRunError>
 where
     I: IntoIterator<Item = S>,
@@ -0,0 +1,151 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use snafu::Snafu;
+
+// `interpolate` replaces each `${NAME}` in `s` with the value returned by
+// `lookup` for `NAME`, and each `$$` with `$`. Other uses of `$` are left
+// unchanged.
+pub fn interpolate<F, E>(s: &str, mut lookup: F)
+    -> Result<String, InterpolateError<E>>
+where
+    F: FnMut(&str) -> Result<Option<String>, E>,
+    E: std::error::Error + 'static,
+{
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}')
+                .ok_or(InterpolateError::UnterminatedVar)?;
+
+            let name = &after[..end];
+            if name.is_empty() {
+                return Err(InterpolateError::EmptyVarName);
+            }
+
+            let value = lookup(name)
+                .map_err(|source| InterpolateError::LookupFailed{
+                    source,
+                    name: name.to_string(),
+                })?
+                .ok_or_else(|| InterpolateError::UndefinedVar{
+                    name: name.to_string(),
+                })?;
+
+            out.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+#[derive(Debug, Snafu)]
+pub enum InterpolateError<E>
+where
+    E: std::error::Error + 'static
+{
+    #[snafu(display("`${{` isn't terminated by `}}`"))]
+    UnterminatedVar,
+    #[snafu(display("`${{}}` doesn't contain a variable name"))]
+    EmptyVarName,
+    #[snafu(display("`{}` isn't defined", name))]
+    UndefinedVar{name: String},
+    #[snafu(display("Couldn't get the value of `{}`: {}", name, source))]
+    LookupFailed{source: E, name: String},
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Error as IoError;
+
+    use super::*;
+
+    // `lookup` only defines `A`, as `1`.
+    fn lookup(name: &str) -> Option<String> {
+        (name == "A").then(|| "1".to_string())
+    }
+
+    // `interpolate_with_lookup` interpolates `s` using `lookup`, which can't
+    // fail.
+    fn interpolate_with_lookup(s: &str)
+        -> Result<String, InterpolateError<IoError>>
+    {
+        interpolate(s, |name| Ok(lookup(name)))
+    }
+
+    #[test]
+    // Given (1) `lookup` defines `A` as `1`
+    // When `interpolate` is called with `x${A}y${A}`
+    // Then (A) both references to `A` are replaced by `1`
+    fn test_interpolate_replaces_vars() {
+        let out = interpolate_with_lookup("x${A}y${A}")
+            .expect("couldn't interpolate");
+
+        // (A)
+        assert_eq!(out, "x1y1");
+    }
+
+    #[test]
+    // Given (1) `lookup` defines `A` as `1`
+    // When `interpolate` is called with `$$A $A ${A}$`
+    // Then (A) `$$` is replaced by `$`
+    //     AND (B) `$` that isn't followed by `{` is unchanged
+    //     AND (C) `${A}` is replaced by `1`
+    fn test_interpolate_escapes_dollars() {
+        let out = interpolate_with_lookup("$$A $A ${A}$")
+            .expect("couldn't interpolate");
+
+        // (A) (B) (C)
+        assert_eq!(out, "$A $A 1$");
+    }
+
+    #[test]
+    // Given (1) `lookup` doesn't define `B`
+    // When `interpolate` is called with `${B}`
+    // Then (A) an `UndefinedVar` error is returned for `B`
+    fn test_interpolate_rejects_undefined_var() {
+        let err = interpolate_with_lookup("${B}")
+            .expect_err("interpolation succeeded");
+
+        // (A)
+        assert!(
+            matches!(err, InterpolateError::UndefinedVar{name} if name == "B"),
+        );
+    }
+
+    #[test]
+    // Given (1) `lookup` defines `A` as `1`
+    // When `interpolate` is called with `${A`
+    // Then (A) an `UnterminatedVar` error is returned
+    fn test_interpolate_rejects_unterminated_var() {
+        let err = interpolate_with_lookup("${A")
+            .expect_err("interpolation succeeded");
+
+        // (A)
+        assert!(matches!(err, InterpolateError::UnterminatedVar));
+    }
+
+    #[test]
+    // Given (1) `lookup` defines `A` as `1`
+    // When `interpolate` is called with `${}`
+    // Then (A) an `EmptyVarName` error is returned
+    fn test_interpolate_rejects_empty_var_name() {
+        let err = interpolate_with_lookup("${}")
+            .expect_err("interpolation succeeded");
+
+        // (A)
+        assert!(matches!(err, InterpolateError::EmptyVarName));
+    }
+}
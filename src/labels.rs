@@ -0,0 +1,128 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Labels that identify the Docker resources created by `dock`.
+//!
+//! Every image, container and volume that `dock` creates for an environment
+//! is labelled with the project, the environment and the directory of the
+//! Dock file, so that the resources of a project can be found even after the
+//! environment that they were created for has been renamed or removed. `dock`
+//! doesn't create networks.
+//!
+//! Containers and images inherit the labels of the image that they're created
+//! from, so the containers and images that `dock` creates are also marked, so
+//! that they can be distinguished from the ones that other tools create from
+//! `dock` images.
+
+use crate::canon_path::AbsPath;
+use crate::run_in::DockConfig;
+
+pub const PROJECT_LABEL: &str = "dock.project";
+pub const ENV_LABEL: &str = "dock.env";
+pub const DIR_LABEL: &str = "dock.dir";
+pub const CONTAINER_LABEL: &str = "dock.container";
+pub const IMAGE_LABEL: &str = "dock.image";
+
+// `label_args` returns the `--label` arguments for the resources that are
+// created for `env_name`, which are accepted by `docker build`, `docker run`,
+// `docker create` and `docker volume create`.
+pub fn label_args(conf: &DockConfig, dock_dir: &AbsPath, env_name: &str)
+    -> Vec<String>
+{
+    let project = project_name(&conf.organisation, &conf.project);
+
+    vec![
+        format!("--label={PROJECT_LABEL}={project}"),
+        format!("--label={ENV_LABEL}={env_name}"),
+        format!("--label={DIR_LABEL}={}", dock_dir.display_lossy()),
+    ]
+}
+
+// `container_marker_arg` returns the `--label` argument that marks containers
+// that are created by `dock`. Containers inherit the labels of their image, so
+// this distinguishes the containers created by `dock` from the containers that
+// are created from `dock` images by other tools.
+pub fn container_marker_arg() -> String {
+    format!("--label={CONTAINER_LABEL}=true")
+}
+
+// `image_marker_arg` returns the `--label` argument that marks the images of
+// `img_name` that are built by `dock`. Unlike containers, images can't be
+// marked by the presence of a label, because an image that another tool builds
+// from a `dock` image inherits the label, so the marker records the name of
+// the image instead; see `is_marked_image`.
+pub fn image_marker_arg(img_name: &str) -> String {
+    format!("--label={IMAGE_LABEL}={img_name}")
+}
+
+// `is_marked_image` returns whether the image named `name`, whose image marker
+// label has the value `marker`, was built by `dock`. An image that's built
+// from a `dock` image by another tool inherits the marker, but isn't named
+// after it. Names in a registry, such as the names of images that were pulled
+// by `dock pull`, are named after the marker following the registry host.
+// Untagged images can't be checked in this way, so they're assumed to have
+// been built by `dock` if they have a marker.
+pub fn is_marked_image(marker: &str, name: &str) -> bool {
+    if marker.is_empty() {
+        return false;
+    }
+    if name == "<none>:<none>" {
+        return true;
+    }
+
+    let Some((repo, _)) = name.rsplit_once(':') else {
+        return false;
+    };
+
+    repo == marker
+        || repo
+            .strip_suffix(marker)
+            .is_some_and(|registry| registry.ends_with('/'))
+}
+
+// `project_name` returns the value of the project label for the project
+// `proj` of `org`.
+pub fn project_name(org: &str, proj: &str) -> String {
+    format!("{org}/{proj}")
+}
+
+// `project_filter` returns a `--filter` argument for `docker ... ls` that
+// matches the resources of the project `proj` of `org`.
+pub fn project_filter(org: &str, proj: &str) -> String {
+    format!("--filter=label={PROJECT_LABEL}={}", project_name(org, proj))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) an image that was built by `dock` as `org/proj.env`
+    //     AND (2) an image that another tool built from it as `app`
+    // When `is_marked_image` is called for each image
+    // Then (A) only the image that was built by `dock` is marked
+    fn test_is_marked_image_rejects_inherited_marker() {
+        // (1) (2)
+        let marker = "org/proj.env";
+
+        // (A)
+        assert!(is_marked_image(marker, "org/proj.env:latest"));
+        assert!(is_marked_image(marker, "localhost:5000/org/proj.env:abc"));
+        assert!(!is_marked_image(marker, "app:latest"));
+        assert!(!is_marked_image(marker, "org/proj.env-linux-arm64:latest"));
+    }
+
+    #[test]
+    // Given (1) an untagged image
+    // When `is_marked_image` is called with and without a marker
+    // Then (A) the image is only marked if it has a marker
+    fn test_is_marked_image_accepts_untagged_image_with_marker() {
+        // (1)
+        let name = "<none>:<none>";
+
+        // (A)
+        assert!(is_marked_image("org/proj.env", name));
+        assert!(!is_marked_image("", name));
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+use std::fs::DirBuilder;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Error as IoError;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+use nix::errno::Errno;
+use nix::fcntl;
+use nix::fcntl::FlockArg;
+use nix::unistd;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+const REBUILT_MARKER: &str = "rebuilt\n";
+
+// `ImageLock` is an advisory lock on the rebuild of a Docker image, which is
+// shared between all `dock` processes on the current host. The lock is
+// released when the `ImageLock` is dropped.
+pub struct ImageLock {
+    file: File,
+    rebuilt_by_other: bool,
+}
+
+impl ImageLock {
+    // `lock` blocks until the rebuild lock for `img` is acquired, and calls
+    // `on_wait` before blocking if the lock is held by another process.
+    pub fn lock<F>(img: &str, on_wait: F) -> Result<Self, LockError>
+    where
+        F: FnOnce(),
+    {
+        let dir = lock_dir();
+        // The lock directory is only accessible by the current user, because
+        // the fallback directory is in a directory that's shared between
+        // users.
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .context(CreateLockDirFailed{path: dir.clone()})?;
+
+        let path = dir.join(lock_file_name(img));
+        let mut file =
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .context(OpenLockFileFailed{path: path.clone()})?;
+
+        let fd = file.as_raw_fd();
+        let waited =
+            match fcntl::flock(fd, FlockArg::LockExclusiveNonblock) {
+                Ok(()) => {
+                    false
+                },
+                Err(Errno::EWOULDBLOCK) => {
+                    on_wait();
+                    fcntl::flock(fd, FlockArg::LockExclusive)
+                        .context(LockFailed{path: path.clone()})?;
+
+                    true
+                },
+                Err(source) => {
+                    return Err(LockError::LockFailed{source, path});
+                },
+            };
+
+        // The process that held the lock before us leaves a marker in the
+        // lock file if it rebuilt the image successfully. We only trust the
+        // marker if we waited for that process, because otherwise it could
+        // have been left by a rebuild that happened long ago.
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .context(ReadLockFileFailed{path: path.clone()})?;
+
+        let rebuilt_by_other = waited && contents == REBUILT_MARKER;
+
+        // We keep the marker if we're going to reuse the rebuilt image, so
+        // that other waiting processes can also reuse it.
+        if !rebuilt_by_other {
+            file.set_len(0)
+                .context(ResetLockFileFailed{path})?;
+        }
+
+        Ok(Self{file, rebuilt_by_other})
+    }
+
+    // `rebuilt_by_other` returns `true` if another process successfully
+    // rebuilt the image while we were waiting for the lock.
+    pub fn rebuilt_by_other(&self) -> bool {
+        self.rebuilt_by_other
+    }
+
+    // `mark_rebuilt` records that the image was rebuilt successfully, for the
+    // next process waiting for the lock.
+    pub fn mark_rebuilt(&mut self) -> Result<(), IoError> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(REBUILT_MARKER.as_bytes())
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum LockError {
+    #[snafu(display(
+        "Couldn't create the lock directory '{}': {}",
+        path.display(),
+        source,
+    ))]
+    CreateLockDirFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't open the lock file '{}': {}",
+        path.display(),
+        source,
+    ))]
+    OpenLockFileFailed{source: IoError, path: PathBuf},
+    #[snafu(display("Couldn't lock '{}': {}", path.display(), source))]
+    LockFailed{source: Errno, path: PathBuf},
+    #[snafu(display(
+        "Couldn't read the lock file '{}': {}",
+        path.display(),
+        source,
+    ))]
+    ReadLockFileFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't reset the lock file '{}': {}",
+        path.display(),
+        source,
+    ))]
+    ResetLockFileFailed{source: IoError, path: PathBuf},
+}
+
+// `lock_dir` returns the directory that lock files are stored in. The
+// fallback directory is named after the current user, so that users sharing a
+// host don't try to use each other's lock files.
+fn lock_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => {
+            PathBuf::from(dir).join("dock").join("locks")
+        },
+        _ => {
+            env::temp_dir().join(format!("dock-locks-{}", unistd::getuid()))
+        },
+    }
+}
+
+// `lock_file_name` escapes the characters of `img` that can't be used in file
+// names, along with `%`, so that distinct images have distinct lock files.
+fn lock_file_name(img: &str) -> String {
+    let mut name = String::new();
+    for c in img.chars() {
+        match c {
+            '/' => name.push_str("%2F"),
+            '%' => name.push_str("%25"),
+            _ => name.push(c),
+        }
+    }
+    name.push_str(".lock");
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Given (1) an image name that contains `/`
+    //     AND (2) an image name that contains an escaped `/`
+    // When `lock_file_name` is called with each image name
+    // Then (A) `/` is escaped as `%2F`
+    //     AND (B) `%` is escaped as `%25`, so that the lock file names of the
+    //         images don't collide
+    fn test_lock_file_name_escapes_slashes() {
+        // (1)
+        let slash_img = "org/proj.env";
+        // (2)
+        let escaped_img = "a%2Fb";
+
+        let slash_name = lock_file_name(slash_img);
+        let escaped_name = lock_file_name(escaped_img);
+
+        // (A)
+        assert_eq!(slash_name, "org%2Fproj.env.lock");
+        // (B)
+        assert_eq!(escaped_name, "a%252Fb.lock");
+    }
+}
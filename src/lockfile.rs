@@ -0,0 +1,447 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Pinning of the base images of environments to digests.
+//!
+//! `dock lock` resolves the base images of each environment to digests and
+//! writes them to `dock.lock`, next to the Dock file. If `dock.lock` exists
+//! then environments are rebuilt from a copy of their Dockerfile in which the
+//! base images are pinned to these digests.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs as std_fs;
+use std::io::ErrorKind;
+use std::io::Error as IoError;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde_yaml::Error as SerdeYamlError;
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::base_refresh;
+use crate::build_log;
+use crate::canon_path::AbsPath;
+use crate::canon_path::NewAbsPathError;
+use crate::cmd_loggers::Stream;
+use crate::cmd_loggers::TeeCmdLogger;
+use crate::dockerfile;
+use crate::logging_process;
+use crate::logging_process::CommandLogger;
+use crate::logging_process::RunError;
+use crate::run_in;
+use crate::run_in::DockConfig;
+use crate::run_in::FindAndParseDockConfigError;
+
+pub const LOCK_FILE_NAME: &str = "dock.lock";
+
+const LOCK_FILE_HEADER: &str =
+    "# This file is generated by `dock lock`; don't edit it by hand.\n";
+
+// `LockFile` maps the base images of each environment to the digests that
+// they're pinned to.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LockFile {
+    pub environments: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn lock_file_path(dock_dir: &AbsPath) -> PathBuf {
+    PathBuf::from(dock_dir.clone()).join(LOCK_FILE_NAME)
+}
+
+// `read` returns the lock file in `dock_dir`, or `None` if it doesn't exist.
+pub fn read(dock_dir: &AbsPath) -> Result<Option<LockFile>, ReadError> {
+    let path = lock_file_path(dock_dir);
+
+    let raw =
+        match std_fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(ReadError::ReadFailed{source, path}),
+        };
+
+    let lock_file = serde_yaml::from_str(&raw)
+        .context(ParseFailed{path})?;
+
+    Ok(Some(lock_file))
+}
+
+#[derive(Debug, Snafu)]
+pub enum ReadError {
+    #[snafu(display("Couldn't read '{}': {}", path.display(), source))]
+    ReadFailed{source: IoError, path: PathBuf},
+    #[snafu(display("Couldn't parse '{}': {}", path.display(), source))]
+    ParseFailed{source: SerdeYamlError, path: PathBuf},
+}
+
+// `env_base_images` returns the base images of each environment of `conf`
+// that can be pinned, which are the base images that aren't already pinned
+// to a digest.
+fn env_base_images(dock_dir: &AbsPath, conf: &DockConfig)
+    -> Result<BTreeMap<String, BTreeSet<String>>, EnvBaseImagesError>
+{
+    let img_prefix = format!("{}/{}.", conf.organisation, conf.project);
+
+    let mut env_imgs = BTreeMap::new();
+    for env_name in conf.environments.keys() {
+        let path = run_in::dockerfile_path(dock_dir, env_name);
+        let dockerfile = std_fs::read(PathBuf::from(path.clone()))
+            .context(ReadDockerfileFailed{path})?;
+        let dockerfile = String::from_utf8_lossy(&dockerfile);
+
+        let imgs: BTreeSet<String> =
+            base_refresh::base_images(&dockerfile, &img_prefix)
+                .into_iter()
+                .filter(|img| !img.contains('@'))
+                .collect();
+
+        env_imgs.insert(env_name.clone(), imgs);
+    }
+
+    Ok(env_imgs)
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnvBaseImagesError {
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    ReadDockerfileFailed{source: IoError, path: AbsPath},
+}
+
+// `lock` pins the base images of all environments to their current digests,
+// by pulling them, and writes the pins to the lock file. It returns the
+// digests of the pinned images.
+pub fn lock(logger: &mut dyn CommandLogger, dock_file_name: &str)
+    -> Result<BTreeMap<String, String>, LockError>
+{
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let env_imgs = env_base_images(&dock_dir, &conf)
+        .context(LockEnvBaseImagesFailed)?;
+
+    // Images that are used by more than one environment are only resolved
+    // once, so that all environments are pinned to the same digest.
+    let mut digests = BTreeMap::new();
+    let mut lock_file = LockFile::default();
+    for (env_name, imgs) in env_imgs {
+        let env = &conf.environments[&env_name];
+
+        let mut pins = BTreeMap::new();
+        for img in imgs {
+            if !digests.contains_key(&img) {
+                let digest = resolve(logger, &img, env.platform.as_deref())
+                    .context(ResolveFailed{img: &img, name: &env_name})?;
+
+                digests.insert(img.clone(), digest);
+            }
+            pins.insert(img.clone(), digests[&img].clone());
+        }
+
+        lock_file.environments.insert(env_name, pins);
+    }
+
+    let path = lock_file_path(&dock_dir);
+    let yaml = serde_yaml::to_string(&lock_file)
+        .context(SerialiseFailed)?;
+    std_fs::write(&path, format!("{LOCK_FILE_HEADER}{yaml}\n"))
+        .context(WriteFailed{path})?;
+
+    Ok(digests)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum LockError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("{}", source))]
+    LockEnvBaseImagesFailed{source: EnvBaseImagesError},
+    #[snafu(display(
+        "Couldn't resolve '{}' (used by '{}'): {}",
+        img,
+        name,
+        source,
+    ))]
+    ResolveFailed{source: ResolveError, img: String, name: String},
+    #[snafu(display("Couldn't serialise the lock file: {}", source))]
+    SerialiseFailed{source: SerdeYamlError},
+    #[snafu(display("Couldn't write '{}': {}", path.display(), source))]
+    WriteFailed{source: IoError, path: PathBuf},
+}
+
+// `resolve` pulls `img` for `platform` and returns the digest that it was
+// pulled at. For multi-platform images this is the digest of the image index,
+// so the same pin can be used for all platforms.
+fn resolve(
+    logger: &mut dyn CommandLogger,
+    img: &str,
+    platform: Option<&str>,
+)
+    -> Result<String, ResolveError>
+{
+    let platform_arg = platform.map(|p| format!("--platform={p}"));
+    let mut pull_args = vec![OsStr::new("pull")];
+    if let Some(arg) = &platform_arg {
+        pull_args.push(OsStr::new(arg));
+    }
+    pull_args.push(OsStr::new(img));
+
+    // We capture the output of `docker pull` because it's the only place that
+    // the pulled digest is reported unambiguously; the `RepoDigests` of the
+    // local image may also contain the digests of other tags of the same
+    // repository.
+    let mut tee = TeeCmdLogger::new(logger);
+    let status = logging_process::run(
+        &mut tee,
+        OsStr::new("docker"),
+        &pull_args,
+        Stdio::null(),
+    )
+        .context(RunPullFailed)?;
+
+    if !status.success() {
+        return Err(ResolveError::PullUnsuccessful);
+    }
+
+    let stdout: Vec<u8> =
+        tee.capture.chunks
+            .into_iter()
+            .filter(|(stream, _)| matches!(stream, Stream::Stdout))
+            .flat_map(|(_, chunk)| chunk)
+            .collect();
+
+    pulled_digest(&String::from_utf8_lossy(&stdout))
+        .context(DigestNotFound)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ResolveError {
+    #[snafu(display("Couldn't run `docker pull`: {}", source))]
+    RunPullFailed{source: RunError},
+    #[snafu(display("`docker pull` was unsuccessful"))]
+    PullUnsuccessful,
+    #[snafu(display("`docker pull` didn't report a digest"))]
+    DigestNotFound,
+}
+
+// `pulled_digest` returns the digest reported in the output of `docker pull`.
+fn pulled_digest(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Digest: "))
+        .map(|digest| digest.trim().to_string())
+}
+
+// `check` returns descriptions of the ways in which the lock file of the
+// project is out of date with the Dockerfiles of its environments. The
+// registry isn't queried, so newer digests for the pinned images aren't
+// reported.
+pub fn check(dock_file_name: &str) -> Result<Vec<String>, CheckError> {
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(CheckFindAndParseDockConfigFailed{dock_file_name})?;
+
+    let lock_file = read(&dock_dir)
+        .context(CheckReadFailed)?
+        .context(LockFileNotFound)?;
+
+    let env_imgs = env_base_images(&dock_dir, &conf)
+        .context(CheckEnvBaseImagesFailed)?;
+
+    let no_pins = BTreeMap::new();
+    let mut problems = vec![];
+    for (env_name, imgs) in &env_imgs {
+        let pins = lock_file.environments.get(env_name).unwrap_or(&no_pins);
+
+        for img in imgs {
+            if !pins.contains_key(img) {
+                problems.push(format!("'{img}' of '{env_name}' isn't pinned"));
+            }
+        }
+        for img in pins.keys() {
+            if !imgs.contains(img) {
+                problems.push(format!(
+                    "'{img}' is pinned for '{env_name}' but isn't used",
+                ));
+            }
+        }
+    }
+    for env_name in lock_file.environments.keys() {
+        if !env_imgs.contains_key(env_name) {
+            problems.push(format!(
+                "'{env_name}' is pinned but isn't defined",
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum CheckError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    CheckFindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("{}", source))]
+    CheckReadFailed{source: ReadError},
+    #[snafu(display(
+        "'{}' doesn't exist; run `dock lock` to create it",
+        LOCK_FILE_NAME,
+    ))]
+    LockFileNotFound,
+    #[snafu(display("{}", source))]
+    CheckEnvBaseImagesFailed{source: EnvBaseImagesError},
+}
+
+// `pin_dockerfile` writes a copy of the Dockerfile at `dockerfile_path`, in
+// which the base images are pinned to the digests in the lock file in
+// `dock_dir`, and returns its path. It returns `None` if there is no lock
+// file. A warning is printed for each base image that isn't pinned.
+pub fn pin_dockerfile(
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    img_name: &str,
+    dockerfile_path: &AbsPath,
+)
+    -> Result<Option<AbsPath>, PinDockerfileError>
+{
+    let lock_file = read(dock_dir)
+        .context(PinReadFailed)?;
+    let Some(lock_file) = lock_file else {
+        return Ok(None);
+    };
+
+    let dockerfile = std_fs::read(PathBuf::from(dockerfile_path.clone()))
+        .context(PinReadDockerfileFailed{path: dockerfile_path.clone()})?;
+    let dockerfile = String::from_utf8_lossy(&dockerfile);
+
+    let no_pins = BTreeMap::new();
+    let pins = lock_file.environments.get(env_name).unwrap_or(&no_pins);
+
+    let img_prefix = format!("{}/{}.", conf.organisation, conf.project);
+    for img in base_refresh::base_images(&dockerfile, &img_prefix) {
+        if !img.contains('@') && !pins.contains_key(&img) {
+            eprintln!(
+                "Warning: '{img}' of '{env_name}' isn't pinned in \
+                 '{LOCK_FILE_NAME}'; run `dock lock` to pin it",
+            );
+        }
+    }
+
+    // The pinned Dockerfile is named after the image, rather than the
+    // environment, so that builds for different platforms don't share it.
+    let name = img_name.rsplit('/').next().unwrap_or(img_name);
+    let pinned_dir =
+        build_log::project_cache_dir(&conf.organisation, &conf.project)
+            .join("pinned");
+    let pinned_path = pinned_dir.join(format!("{name}.Dockerfile"));
+
+    std_fs::create_dir_all(&pinned_dir)
+        .context(WritePinnedDockerfileFailed{path: pinned_dir})?;
+    std_fs::write(&pinned_path, pin(&dockerfile, pins))
+        .context(WritePinnedDockerfileFailed{path: pinned_path.clone()})?;
+
+    let pinned_path = AbsPath::try_from(pinned_path)
+        .context(PinnedDockerfileAsAbsPathFailed)?;
+
+    Ok(Some(pinned_path))
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum PinDockerfileError {
+    #[snafu(display("{}", source))]
+    PinReadFailed{source: ReadError},
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    PinReadDockerfileFailed{source: IoError, path: AbsPath},
+    #[snafu(display(
+        "Couldn't write the pinned Dockerfile to '{}': {}",
+        path.display(),
+        source,
+    ))]
+    WritePinnedDockerfileFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't get the path of the pinned Dockerfile as an absolute path: \
+         {}",
+        source,
+    ))]
+    PinnedDockerfileAsAbsPathFailed{source: NewAbsPathError},
+}
+
+// `pin` returns `dockerfile` with the images of its `FROM` instructions that
+// are in `pins` replaced with `<image>@<digest>`.
+pub fn pin(dockerfile: &str, pins: &BTreeMap<String, String>) -> String {
+    dockerfile::replace_from_images(dockerfile, |img| {
+        pins.get(img).map(|digest| format!("{img}@{digest}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_replaces_pinned_from_images() {
+        let dockerfile = "\
+            FROM --platform=linux/amd64 golang:1.22 AS build\n\
+            RUN echo golang:1.22\n\
+            FROM build AS test\n\
+            from ubuntu:22.04\n\
+            FROM debian:12\n\
+        ";
+        let pins = BTreeMap::from([
+            ("golang:1.22".to_string(), "sha256:aaaa".to_string()),
+            ("ubuntu:22.04".to_string(), "sha256:bbbb".to_string()),
+        ]);
+
+        assert_eq!(
+            pin(dockerfile, &pins),
+            "\
+                FROM --platform=linux/amd64 golang:1.22@sha256:aaaa AS build\n\
+                RUN echo golang:1.22\n\
+                FROM build AS test\n\
+                from ubuntu:22.04@sha256:bbbb\n\
+                FROM debian:12\n\
+            ",
+        );
+    }
+
+    #[test]
+    fn pulled_digest_finds_digest_line() {
+        let output = "\
+            22.04: Pulling from library/ubuntu\n\
+            Digest: sha256:0123abcd\n\
+            Status: Image is up to date for ubuntu:22.04\n\
+        ";
+
+        assert_eq!(pulled_digest(output), Some("sha256:0123abcd".to_string()));
+    }
+}
@@ -18,6 +18,7 @@ pub trait CommandLogger {
     fn log(&mut self, msg: CmdLoggerMsg);
 }
 
+#[derive(Clone, Copy)]
 pub enum CmdLoggerMsg<'a> {
     Cmd(&'a [&'a OsStr]),
     Start,
@@ -3,48 +3,70 @@
 // licence that can be found in the LICENCE file.
 
 use std::env;
+use std::fs as std_fs;
 use std::io;
 use std::io::Error as IoError;
-use std::io::StderrLock;
-use std::io::Stdout;
+use std::io::IsTerminal;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
 use std::process::ExitStatus;
 use std::str;
+use std::thread;
+use std::time::SystemTime;
 
 use clap::Arg;
 use clap::ArgMatches;
 use clap::Command;
 
+mod base_refresh;
+mod build_context;
+mod build_log;
+mod build_progress;
+mod cache;
 mod canon_path;
 mod clean;
 mod cmd_loggers;
+mod context_source;
+mod debug_build;
 mod docker;
+mod docker_build_args;
+mod dockerfile;
 mod fs;
+mod gc;
+mod history;
+mod hostpaths;
 mod init;
+mod interpolate;
+mod labels;
+mod lock;
+mod lockfile;
 mod logging_process;
 mod option;
 mod rebuild;
+mod rebuild_all;
+mod registry;
 mod run_in;
 mod spinner;
+mod staged_run;
 mod trie;
 
 use cmd_loggers::CapturingCmdLogger;
 use cmd_loggers::Prefixer;
 use cmd_loggers::PrefixingCmdLogger;
-use cmd_loggers::Stream;
 use cmd_loggers::TimingPrefixingCmdLogger;
 use init::FileAction;
 use init::FileActionLogger;
 use init::InitError;
+use rebuild::Builder;
+use rebuild_all::Outcome;
 use run_in::Args;
+use run_in::BuildOpts;
 use run_in::CmdLoggers;
+use run_in::DEFAULT_CACHE_TAG;
 use run_in::Rebuild;
 use run_in::RebuildAction;
-use run_in::RebuildForRunInError;
-use run_in::RunInError;
 
 const DEFAULT_TEMPLATES_SOURCE: &str = env!("DOCK_DEFAULT_TEMPLATES_SOURCE");
 
@@ -54,13 +76,27 @@ const COMMAND_ARGS_FLAG: &str = "docker-args";
 const ENV_FLAG: &str = "env";
 const DEBUG_FLAG: &str = "debug";
 const TTY_FLAG: &str = "tty";
+const HERMETIC_FLAG: &str = "hermetic";
+const ARTIFACT_FLAG: &str = "artifact";
 const SKIP_REBUILD_FLAG: &str = "skip-rebuild";
+const DRY_RUN_FLAG: &str = "dry-run";
 const SOURCE_FLAG: &str = "source";
 const TEMPLATE_FLAG: &str = "template";
 const SKIP_IMAGES_FLAG: &str = "skip-images";
 const SKIP_VOLUMES_FLAG: &str = "skip-volumes";
-
-const DEFAULT_CACHE_TAG: &str = "cached";
+const PREV_FLAG: &str = "prev";
+const JOBS_FLAG: &str = "jobs";
+const LAST_BUILD_FLAG: &str = "last-build";
+const BUILD_FAILURE_FLAG: &str = "build-failure";
+const PLATFORM_FLAG: &str = "platform";
+const CHECK_FLAG: &str = "check";
+const VOLUME_FLAG: &str = "volume";
+const YES_FLAG: &str = "yes";
+const MAX_AGE_FLAG: &str = "max-age";
+const MAX_SIZE_FLAG: &str = "max-size";
+const MISSING_DIR_FLAG: &str = "missing-dir";
+const CACHE_NAME_FLAG: &str = "name";
+const ARCHIVE_FLAG: &str = "archive";
 
 #[allow(clippy::too_many_lines)]
 fn main() {
@@ -82,6 +118,34 @@ fn main() {
          tag will be replaced by `{CACHE_TAG_FLAG}` for the duration of the \
          rebuild.",
     );
+    let rebuild_all_about: &str = &format!(
+        "Rebuild the environments defined in `{dock_file_name}`",
+    );
+    let logs_about: &str =
+        "Show the output of the last failed rebuild of an environment";
+    let rollback_about: &str =
+        "Restore a previous image of an environment";
+    let history_about: &str =
+        "List the current and previous images of an environment";
+    let push_about: &str =
+        "Push environment images to the registry of the project";
+    let pull_about: &str =
+        "Pull environment images from the registry of the project";
+    let outdated_about: &str =
+        "List environments whose base images are older than `base_refresh`";
+    let lock_about: &str =
+        "Pin the base images of the environments to digests in `dock.lock`";
+    let gc_about: &str =
+        "Remove the Docker resources of unused environments of all projects";
+    let cache_about: &str = &format!(
+        "Manage the cache volumes of the environments defined in \
+         `{dock_file_name}`",
+    );
+    let platform_long_help: &str =
+        "The platform to build and run the environment for, in the form \
+         `os/arch[/variant]`, overriding the `platform` of the environment. \
+         Images built for a platform other than the configured platform are \
+         kept separately from the images for the configured platform.";
     let clean_about: &str =
         "Remove Docker resources associated with the environments defined in \
          {dock_file_name}";
@@ -101,6 +165,14 @@ fn main() {
                             .default_value(DEFAULT_CACHE_TAG)
                             .help("The tag for the cache image")
                             .long_help(cache_tag_long_help),
+                        Arg::new(DRY_RUN_FLAG)
+                            .long(DRY_RUN_FLAG)
+                            .help("Print the build without running it")
+                            .long_help(
+                                "Print the Dockerfile, context and `docker \
+                                 build` command that would be used for the \
+                                 rebuild, without running it.",
+                            ),
                         Arg::new(TAGGED_IMG_FLAG)
                             .required(true)
                             .help("The tagged name for the new image")
@@ -112,6 +184,38 @@ fn main() {
                             .multiple_occurrences(true)
                             .help("Arguments to pass to `docker build`"),
                     ]),
+                Command::new("rebuild-all")
+                    .about(rebuild_all_about)
+                    .args(&[
+                        Arg::new(CACHE_TAG_FLAG)
+                            .long(CACHE_TAG_FLAG)
+                            .default_value(DEFAULT_CACHE_TAG)
+                            .help("The tag for the cache image")
+                            .long_help(cache_tag_long_help),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform to build for")
+                            .long_help(platform_long_help),
+                        Arg::new(JOBS_FLAG)
+                            .short('j')
+                            .long(JOBS_FLAG)
+                            .takes_value(true)
+                            .help("The number of rebuilds to run at a time")
+                            .long_help(
+                                "The maximum number of environments to \
+                                 rebuild at a time. Defaults to the number \
+                                 of CPUs.",
+                            ),
+                        Arg::new(ENV_FLAG)
+                            .multiple_occurrences(true)
+                            .help("The environments to rebuild")
+                            .long_help(
+                                "The environments to rebuild, along with the \
+                                 environments that they depend on. All \
+                                 environments are rebuilt if none are given.",
+                            ),
+                    ]),
                 Command::new("run-in")
                     .trailing_var_arg(true)
                     .about(run_about)
@@ -133,6 +237,33 @@ fn main() {
                             .short('R')
                             .long(SKIP_REBUILD_FLAG)
                             .help("Don't rebuild before running"),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform to build and run for")
+                            .long_help(platform_long_help),
+                        Arg::new(HERMETIC_FLAG)
+                            .long(HERMETIC_FLAG)
+                            .help("Copy the project instead of mounting it")
+                            .long_help(
+                                "Copy the Git-tracked files of the project \
+                                 into the container instead of bind-mounting \
+                                 the project directory, and copy the \
+                                 environment's `artifacts` out of the \
+                                 container after the command finishes.",
+                            ),
+                        Arg::new(ARTIFACT_FLAG)
+                            .long(ARTIFACT_FLAG)
+                            .takes_value(true)
+                            .multiple_occurrences(true)
+                            .help("Copy a path out of the container")
+                            .long_help(
+                                "Copy a path out of the container after the \
+                                 command finishes, in the form \
+                                 `<container-path>:<host-path>[:optional]`, \
+                                 where `<host-path>` is relative to the \
+                                 directory containing the Dock file.",
+                            ),
                         Arg::new(ENV_FLAG)
                             .required(true)
                             .help("The environment to run"),
@@ -152,6 +283,21 @@ fn main() {
                             .short('R')
                             .long(SKIP_REBUILD_FLAG)
                             .help("Don't rebuild before running"),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform to build and run for")
+                            .long_help(platform_long_help),
+                        Arg::new(BUILD_FAILURE_FLAG)
+                            .long(BUILD_FAILURE_FLAG)
+                            .conflicts_with(SKIP_REBUILD_FLAG)
+                            .help("Start a shell before a failed build step")
+                            .long_help(
+                                "Rebuild the environment and, if the rebuild \
+                                 fails, start a shell in the last layer that \
+                                 was built before the failed step, with the \
+                                 failed command in the shell history.",
+                            ),
                         Arg::new(ENV_FLAG)
                             .help("The environment to run"),
                     ]),
@@ -194,6 +340,219 @@ fn main() {
                                 "Don't remove volumes associated with the \
                                  current project",
                             ),
+                        Arg::new(VOLUME_FLAG)
+                            .long(VOLUME_FLAG)
+                            .takes_value(true)
+                            .multiple_occurrences(true)
+                            .help("Only remove the named cache volume")
+                            .long_help(
+                                "Only remove the cache volume with the given \
+                                 name, as defined in `cache_volumes`; \
+                                 containers and images aren't removed. Can \
+                                 be given more than once.",
+                            ),
+                        Arg::new(DRY_RUN_FLAG)
+                            .long(DRY_RUN_FLAG)
+                            .help("Print the resources without removing them"),
+                        Arg::new(YES_FLAG)
+                            .short('y')
+                            .long(YES_FLAG)
+                            .help("Remove the resources without confirmation")
+                            .long_help(
+                                "Remove the resources without confirmation. \
+                                 Confirmation is only requested if STDIN is a \
+                                 terminal.",
+                            ),
+                        Arg::new(ENV_FLAG)
+                            .multiple_occurrences(true)
+                            .help("The environments to clean")
+                            .long_help(
+                                "The environments to clean, which don't need \
+                                 to be defined in the Dock file. All \
+                                 environments are cleaned if none are given.",
+                            ),
+                    ]),
+                Command::new("rollback")
+                    .about(rollback_about)
+                    .args(&[
+                        Arg::new(PREV_FLAG)
+                            .long(PREV_FLAG)
+                            .takes_value(true)
+                            .default_value("1")
+                            .help("The previous image to restore")
+                            .long_help(
+                                "The number of the previous image to \
+                                 restore, as listed by `dock history`. The \
+                                 current image takes the place of the \
+                                 restored image, so running the same \
+                                 rollback again undoes it.",
+                            ),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform of the images to roll back")
+                            .long_help(platform_long_help),
+                        Arg::new(ENV_FLAG)
+                            .required(true)
+                            .help("The environment to roll back"),
+                    ]),
+                Command::new("logs")
+                    .about(logs_about)
+                    .args(&[
+                        Arg::new(LAST_BUILD_FLAG)
+                            .long(LAST_BUILD_FLAG)
+                            .required(true)
+                            .help("Show the log of the last failed rebuild"),
+                        Arg::new(ENV_FLAG)
+                            .required(true)
+                            .help("The environment to show logs for"),
+                    ]),
+                Command::new("history")
+                    .about(history_about)
+                    .args(&[
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform of the images to list")
+                            .long_help(platform_long_help),
+                        Arg::new(ENV_FLAG)
+                            .required(true)
+                            .help("The environment to list images for"),
+                    ]),
+                Command::new("outdated")
+                    .about(outdated_about),
+                Command::new("lock")
+                    .about(lock_about)
+                    .args(&[
+                        Arg::new(DEBUG_FLAG)
+                            .short('D')
+                            .long(DEBUG_FLAG)
+                            .help("Output debugging information"),
+                        Arg::new(CHECK_FLAG)
+                            .long(CHECK_FLAG)
+                            .help("Check that `dock.lock` is up to date")
+                            .long_help(
+                                "Check that `dock.lock` pins every base \
+                                 image of every environment, and nothing \
+                                 else, without updating it. The registry \
+                                 isn't queried, so newer digests for the \
+                                 pinned images aren't reported.",
+                            ),
+                    ]),
+                Command::new("push")
+                    .about(push_about)
+                    .args(&[
+                        Arg::new(DEBUG_FLAG)
+                            .short('D')
+                            .long(DEBUG_FLAG)
+                            .help("Output debugging information"),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform of the images to push")
+                            .long_help(platform_long_help),
+                        Arg::new(ENV_FLAG)
+                            .multiple_occurrences(true)
+                            .help("The environments to push")
+                            .long_help(
+                                "The environments to push. All environments \
+                                 are pushed if none are given.",
+                            ),
+                    ]),
+                Command::new("pull")
+                    .about(pull_about)
+                    .args(&[
+                        Arg::new(DEBUG_FLAG)
+                            .short('D')
+                            .long(DEBUG_FLAG)
+                            .help("Output debugging information"),
+                        Arg::new(PLATFORM_FLAG)
+                            .long(PLATFORM_FLAG)
+                            .takes_value(true)
+                            .help("The platform of the images to pull")
+                            .long_help(platform_long_help),
+                        Arg::new(ENV_FLAG)
+                            .multiple_occurrences(true)
+                            .help("The environments to pull")
+                            .long_help(
+                                "The environments to pull, along with the \
+                                 environments that they depend on. All \
+                                 environments are pulled if none are given.",
+                            ),
+                    ]),
+                Command::new("cache")
+                    .about(cache_about)
+                    .subcommand_required(true)
+                    .arg_required_else_help(true)
+                    .subcommands([
+                        Command::new("ls")
+                            .about(
+                                "List the cache volumes with their sizes and \
+                                 last use",
+                            ),
+                        Command::new("reset")
+                            .about("Replace a cache volume with an empty one")
+                            .args(cache_volume_args()),
+                        Command::new("export")
+                            .about("Write a cache volume to a tarball")
+                            .args(cache_volume_args())
+                            .arg(
+                                Arg::new(ARCHIVE_FLAG)
+                                    .required(true)
+                                    .help("The path of the tarball to write"),
+                            ),
+                        Command::new("import")
+                            .about(
+                                "Replace a cache volume with the contents of \
+                                 a tarball",
+                            )
+                            .args(cache_volume_args())
+                            .arg(
+                                Arg::new(ARCHIVE_FLAG)
+                                    .required(true)
+                                    .help("The path of the tarball to read"),
+                            ),
+                    ]),
+                Command::new("gc")
+                    .about(gc_about)
+                    .args(&[
+                        Arg::new(DEBUG_FLAG)
+                            .short('D')
+                            .long(DEBUG_FLAG)
+                            .help("Output debugging information"),
+                        Arg::new(MAX_AGE_FLAG)
+                            .long(MAX_AGE_FLAG)
+                            .takes_value(true)
+                            .help(
+                                "Remove environments that haven't been used \
+                                 for this long, such as `30d`",
+                            ),
+                        Arg::new(MAX_SIZE_FLAG)
+                            .long(MAX_SIZE_FLAG)
+                            .takes_value(true)
+                            .help(
+                                "Remove the least recently used environments \
+                                 until the rest fit in this size, such as \
+                                 `50GB`",
+                            ),
+                        Arg::new(MISSING_DIR_FLAG)
+                            .long(MISSING_DIR_FLAG)
+                            .help(
+                                "Remove environments whose project directory \
+                                 no longer exists",
+                            ),
+                        Arg::new(DRY_RUN_FLAG)
+                            .long(DRY_RUN_FLAG)
+                            .help("Print the report without removing it"),
+                        Arg::new(YES_FLAG)
+                            .short('y')
+                            .long(YES_FLAG)
+                            .help("Remove the resources without confirmation")
+                            .long_help(
+                                "Remove the resources without confirmation. \
+                                 Confirmation is only requested if STDIN is a \
+                                 terminal.",
+                            ),
                     ]),
             ])
             .get_matches();
@@ -201,6 +560,25 @@ fn main() {
     handle_arg_matches(&args, dock_file_name);
 }
 
+// `cache_volume_args` returns the arguments of the `cache` subcommands that
+// operate on a single cache volume.
+fn cache_volume_args<'a>() -> [Arg<'a>; 3] {
+    [
+        Arg::new(DEBUG_FLAG)
+            .short('D')
+            .long(DEBUG_FLAG)
+            .help("Output debugging information"),
+        Arg::new(ENV_FLAG)
+            .required(true)
+            .help("The environment that defines the cache volume"),
+        Arg::new(CACHE_NAME_FLAG)
+            .required(true)
+            .help(
+                "The name of the cache volume, as defined in `cache_volumes`",
+            ),
+    ]
+}
+
 fn handle_arg_matches(args: &ArgMatches, dock_file_name: &str) {
     match args.subcommand() {
         Some(("rebuild", sub_args)) => {
@@ -214,13 +592,24 @@ fn handle_arg_matches(args: &ArgMatches, dock_file_name: &str) {
                 sub_args.value_of(TAGGED_IMG_FLAG).unwrap(),
                 sub_args.value_of(CACHE_TAG_FLAG).unwrap(),
                 &docker_args,
+                sub_args.is_present(DRY_RUN_FLAG),
             );
             process::exit(exit_code);
         },
+        Some(("rebuild-all", sub_args)) => {
+            let exit_code = rebuild_all(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
         Some(("run-in", sub_args)) => {
             let exit_code = run_in(dock_file_name, sub_args);
             process::exit(exit_code);
         },
+        Some(("shell", sub_args))
+            if sub_args.is_present(BUILD_FAILURE_FLAG) =>
+        {
+            let exit_code = debug_build_failure(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
         Some(("shell", sub_args)) => {
             let exit_code = shell(dock_file_name, Some(sub_args));
             process::exit(exit_code);
@@ -233,6 +622,42 @@ fn handle_arg_matches(args: &ArgMatches, dock_file_name: &str) {
             let exit_code = clean(dock_file_name, sub_args);
             process::exit(exit_code);
         },
+        Some(("rollback", sub_args)) => {
+            let exit_code = rollback(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("logs", sub_args)) => {
+            let exit_code = logs(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("history", sub_args)) => {
+            let exit_code = history(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("outdated", _)) => {
+            let exit_code = outdated(dock_file_name);
+            process::exit(exit_code);
+        },
+        Some(("lock", sub_args)) => {
+            let exit_code = lock(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("push", sub_args)) => {
+            let exit_code = push(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("pull", sub_args)) => {
+            let exit_code = pull(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
+        Some(("gc", sub_args)) => {
+            let exit_code = gc(sub_args);
+            process::exit(exit_code);
+        },
+        Some(("cache", sub_args)) => {
+            let exit_code = cache(dock_file_name, sub_args);
+            process::exit(exit_code);
+        },
         Some((arg_name, sub_args)) => {
             // All subcommands defined in `args_defn` should be handled here,
             // so matching an unhandled command shouldn't happen.
@@ -247,12 +672,40 @@ fn handle_arg_matches(args: &ArgMatches, dock_file_name: &str) {
     }
 }
 
-fn rebuild(target_img: &str, cache_tag: &str, docker_args: &[&str]) -> i32 {
-    if let Some(i) = index_of_first_unsupported_flag(docker_args) {
-        eprintln!("unsupported argument: `{}`", docker_args[i]);
+fn rebuild(
+    target_img: &str,
+    cache_tag: &str,
+    docker_args: &[&str],
+    dry_run: bool,
+)
+    -> i32
+{
+    let build_args =
+        match docker_build_args::parse(docker_args) {
+            Ok(build_args) => {
+                build_args
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                return 1;
+            },
+        };
+
+    // `dock rebuild` manages the tag of the new image, and always removes
+    // intermediate containers, so these flags can't be overridden.
+    if let Some(flag) = build_args.find_any(&["tag", "force-rm"]) {
+        eprintln!("unsupported argument: `{}`", flag.raw);
         return 1;
     }
 
+    let context =
+        if let Some(context) = build_args.context {
+            context
+        } else {
+            eprintln!("a build context must be provided");
+            return 1;
+        };
+
     let target_img_parts =
         target_img.split(':').collect::<Vec<&str>>();
 
@@ -268,6 +721,25 @@ fn rebuild(target_img: &str, cache_tag: &str, docker_args: &[&str]) -> i32 {
 
     let cache_img = new_tagged_img_name(img_name, cache_tag);
 
+    if dry_run {
+        // `docker build` reads the Dockerfile from the context if `--file`
+        // isn't provided.
+        let dockerfile = build_args.file().unwrap_or("Dockerfile");
+        let build_cmd: Vec<String> =
+            rebuild::build_args(Builder::Docker, target_img, docker_args)
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+
+        println!("image: {target_img}");
+        println!("cache image: {cache_img}");
+        println!("dockerfile: {dockerfile}");
+        println!("context: {context}");
+        println!("command: docker {}", build_cmd.join(" "));
+
+        return 0;
+    }
+
     let rebuild_result = rebuild::rebuild_with_streaming_output(
         target_img,
         &cache_img,
@@ -297,26 +769,97 @@ fn exit_code_from_exit_status(status: ExitStatus) -> i32 {
     }
 }
 
-fn index_of_first_unsupported_flag(args: &[&str]) -> Option<usize> {
-    // Note that this is a naive approach to checking whether the tag flag is
-    // present, as it has the potential to give a false positive in the case
-    // where the tag string is passed as a value to another flag. However, we
-    // take this approach for simplicity, under the assumption that the case of
-    // a tag string being passed as a value is unlikely. This functionality
-    // would need to be refined if this assumption doesn't hold in practice.
-    for (i, arg) in args.iter().enumerate() {
-        let matched =
-            arg == &"--force-rm"
-            || arg == &"-t"
-            || arg == &"--tag"
-            || arg.starts_with("--tag=");
+fn rebuild_all(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let jobs =
+        match args.value_of(JOBS_FLAG) {
+            None => {
+                thread::available_parallelism().map_or(1, usize::from)
+            },
+            Some(raw_jobs) => {
+                match raw_jobs.parse::<usize>() {
+                    Ok(n) if n > 0 => {
+                        n
+                    },
+                    _ => {
+                        eprintln!("`{JOBS_FLAG}` must be a positive integer");
+                        return 1;
+                    },
+                }
+            },
+        };
+
+    let env_names: Vec<&str> =
+        match args.values_of(ENV_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
+
+    let opts = BuildOpts{
+        cache_tag: args.value_of(CACHE_TAG_FLAG).unwrap(),
+        platform: args.value_of(PLATFORM_FLAG),
+        pull_only: false,
+    };
+
+    let results = rebuild_all::rebuild_all(
+        dock_file_name,
+        &env_names,
+        &opts,
+        jobs,
+        io::stdout().is_terminal(),
+    );
+    let results =
+        match results {
+            Ok(results) => {
+                results
+            },
+            Err(err) => {
+                eprintln!("{err}");
+                return 1;
+            },
+        };
+
+    let width =
+        results
+            .iter()
+            .map(|r| r.env_name.len())
+            .chain(["ENVIRONMENT".len()])
+            .max()
+            .unwrap_or(0);
+
+    println!("{:<width$}  {:<8} DURATION", "ENVIRONMENT", "STATUS");
+    for result in &results {
+        let (status, duration) =
+            match &result.outcome {
+                Outcome::Rebuilt{duration} => ("rebuilt", Some(duration)),
+                Outcome::Failed{duration, ..} => ("failed", Some(duration)),
+                Outcome::Skipped{..} => ("skipped", None),
+            };
+        let duration =
+            duration.map_or("-".to_string(), |d| {
+                rebuild_all::format_duration(*d)
+            });
+
+        println!("{:<width$}  {:<8} {}", result.env_name, status, duration);
+    }
 
-        if matched {
-            return Some(i);
+    let mut exit_code = 0;
+    for result in &results {
+        if let Outcome::Failed{msg, log_path, ..} = &result.outcome {
+            eprintln!();
+            if let Some(log_path) = log_path {
+                print_build_log_tail(log_path);
+            }
+            eprintln!("{msg}");
+            if log_path.is_some() {
+                print_build_log_hint(&result.env_name);
+            }
+        }
+        if !matches!(result.outcome, Outcome::Rebuilt{..}) {
+            exit_code = 1;
         }
     }
 
-    None
+    exit_code
 }
 
 fn run_in(dock_file_name: &str, arg_matches: &ArgMatches) -> i32 {
@@ -333,7 +876,19 @@ fn run_in(dock_file_name: &str, arg_matches: &ArgMatches) -> i32 {
 
     let cache_tag = arg_matches.value_of(CACHE_TAG_FLAG).unwrap();
 
-    let args = &Args{docker: &docker_args, command: &cmd_args};
+    let artifacts: Vec<&str> =
+        match arg_matches.values_of(ARTIFACT_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
+
+    let args = &Args{
+        docker: &docker_args,
+        command: &cmd_args,
+        hermetic: arg_matches.is_present(HERMETIC_FLAG),
+        artifacts: &artifacts,
+        platform: arg_matches.value_of(PLATFORM_FLAG),
+    };
 
     handle_run_in(dock_file_name, Some(arg_matches), args, None, cache_tag)
 }
@@ -368,12 +923,9 @@ fn handle_run_in(
 
     let mut stdout = io::stdout();
     // TODO We would ideally get the lock on `stdout` here, but this blocks the
-    // global access to STDOUT that is currently done in `spinner::spin()`.
-    // This should be replaced with a mechanism to pass the reference to
-    // `stdout` to that function, when time allows.
-
-    let stderr = io::stderr();
-    let mut stderr = stderr.lock();
+    // global access to STDOUT that is currently done in
+    // `spinner::spin_status()`. This should be replaced with a mechanism to
+    // pass the reference to `stdout` to that function, when time allows.
 
     let mut logger =
         if debug {
@@ -409,52 +961,48 @@ fn handle_run_in(
             exit_code_from_exit_status(exit_status)
         },
         Err(err) => {
-            match (err, logger) {
-                (
-                    RunInError::RebuildForRunInFailed{
-                        source: RebuildForRunInError::RebuildUnsuccessful{..},
-                    },
-                    CmdLoggers::Capturing(logger),
-                ) => {
-                    let chunks = &logger.chunks;
+            let build_log = err.build_log_path();
 
-                    write_streams(&mut stdout, &mut stderr, chunks);
-                },
-                (e, _) => {
-                    eprintln!("{e}");
-                },
-            };
+            // The output of the rebuild has already been printed if the
+            // logger isn't capturing it.
+            if let (Some((_, log_path)), CmdLoggers::Capturing(_)) =
+                (build_log, &logger)
+            {
+                print_build_log_tail(log_path);
+            }
+
+            eprintln!("{err}");
+
+            if let Some((env_name, _)) = build_log {
+                print_build_log_hint(env_name);
+            }
 
             1
         },
     }
 }
 
-fn write_streams(
-    mut stdout: &mut Stdout,
-    mut stderr: &mut StderrLock,
-    chunks: &[(Stream, Vec<u8>)],
-) {
-    for (stream, bs) in chunks {
-        let out =
-            match stream {
-                Stream::Stdout => &mut stdout as &mut dyn Write,
-                Stream::Stderr => &mut stderr as &mut dyn Write,
-            };
-
-        if let Err(e) = out.write_all(bs) {
-            eprintln!("couldn't write stream ({stream:?}): {e}");
-            return;
-        }
-    }
+const BUILD_LOG_TAIL_LINES: usize = 20;
 
-    if let Err(e) = stdout.flush() {
-        eprintln!("couldn't flush STDOUT: {e}");
+// `print_build_log_tail` prints the end of the build log at `log_path`, which
+// usually contains the cause of the build failure.
+fn print_build_log_tail(log_path: &Path) {
+    match build_log::read_tail(log_path, BUILD_LOG_TAIL_LINES) {
+        Ok(lines) => {
+            for line in lines {
+                eprintln!("{line}");
+            }
+        },
+        Err(e) => {
+            eprintln!("couldn't read '{}': {e}", log_path.display());
+        },
     }
+}
 
-    if let Err(e) = stderr.flush() {
-        eprintln!("couldn't flush STDERR: {e}");
-    }
+fn print_build_log_hint(env_name: &str) {
+    eprintln!(
+        "Run `dock logs {env_name} --{LAST_BUILD_FLAG}` to view the full log",
+    );
 }
 
 fn shell(dock_file_name: &str, args: Option<&ArgMatches>) -> i32 {
@@ -465,12 +1013,58 @@ fn shell(dock_file_name: &str, args: Option<&ArgMatches>) -> i32 {
             // TODO Add tests for `--network=host`.
             docker: &["--interactive", "--tty", "--network=host"],
             command: &[],
+            hermetic: false,
+            artifacts: &[],
+            platform: args.and_then(|args| args.value_of(PLATFORM_FLAG)),
         },
         Some(Path::new("/bin/sh").to_path_buf()),
         DEFAULT_CACHE_TAG,
     )
 }
 
+fn debug_build_failure(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let mut stdout = io::stdout();
+
+    let debug = args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    let result = debug_build::debug_build_failure(
+        &mut logger,
+        dock_file_name,
+        args.value_of(ENV_FLAG),
+        &BuildOpts{
+            cache_tag: DEFAULT_CACHE_TAG,
+            platform: args.value_of(PLATFORM_FLAG),
+            pull_only: false,
+        },
+        !debug,
+    );
+
+    match result {
+        Ok(exit_status) => {
+            exit_code_from_exit_status(exit_status)
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
 fn init(dock_file_name: &str, args: &ArgMatches) -> i32 {
     let raw_source = args.value_of(SOURCE_FLAG).unwrap();
     let source =
@@ -553,23 +1147,789 @@ fn clean(dock_file_name: &str, args: &ArgMatches) -> i32 {
             CmdLoggers::Capturing(CapturingCmdLogger::new())
         };
 
-    let result = clean::clean(
-        &mut logger,
-        dock_file_name,
-        !args.is_present(SKIP_IMAGES_FLAG),
-        !args.is_present(SKIP_VOLUMES_FLAG),
-    );
+    let env_names: Vec<&str> =
+        match args.values_of(ENV_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
 
-    // TODO Check if the prefixing command logger has an error.
+    let volume_names: Vec<&str> =
+        match args.values_of(VOLUME_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
 
-    match result {
-        Ok(()) => {
-            0
-        },
-        Err(err) => {
-            eprintln!("{err}");
+    // `--volume` only selects cache volumes, so containers and images are
+    // skipped when it's given.
+    let only_volumes = !volume_names.is_empty();
+    let selection = clean::Selection{
+        env_names: &env_names,
+        volume_names: &volume_names,
+        containers: !only_volumes,
+        images: !only_volumes && !args.is_present(SKIP_IMAGES_FLAG),
+        volumes: !args.is_present(SKIP_VOLUMES_FLAG),
+    };
 
-            1
-        },
+    let mut resources =
+        match clean::plan(dock_file_name, &selection) {
+            Ok(resources) => {
+                resources
+            },
+            Err(err) => {
+                eprintln!("{err}");
+
+                return 1;
+            },
+        };
+
+    let dry_run = args.is_present(DRY_RUN_FLAG);
+    let confirm =
+        !dry_run
+            && !resources.is_empty()
+            && !args.is_present(YES_FLAG)
+            && io::stdin().is_terminal();
+
+    if dry_run || confirm {
+        clean::add_volume_sizes(&mut resources);
+        print_resources(&resources);
+    }
+
+    if dry_run {
+        return 0;
     }
+
+    if confirm {
+        if let Some(exit_code) = cancelled_removal_exit_code() {
+            return exit_code;
+        }
+    }
+
+    let (summary, failures) = clean::remove_all(&mut logger, resources);
+
+    // TODO Check if the prefixing command logger has an error.
+
+    report_removal(&summary, &failures)
+}
+
+// `cancelled_removal_exit_code` asks for confirmation before resources are
+// removed, and returns the exit code to exit with if the removal wasn't
+// confirmed.
+fn cancelled_removal_exit_code() -> Option<i32> {
+    let confirmed =
+        match confirm_removal() {
+            Ok(confirmed) => {
+                confirmed
+            },
+            Err(err) => {
+                eprintln!("Couldn't read confirmation: {err}");
+
+                return Some(1);
+            },
+        };
+
+    if !confirmed {
+        eprintln!("Cancelled; no resources were removed");
+
+        return Some(1);
+    }
+
+    None
+}
+
+// `report_removal` prints `summary` and `failures`, and returns the exit code
+// for the removal.
+fn report_removal(
+    summary: &clean::CleanSummary,
+    failures: &[clean::RemoveFailure],
+)
+    -> i32
+{
+    println!(
+        "Removed {}, {} and {}",
+        count(summary.containers, "container"),
+        count(summary.images, "image"),
+        count(summary.volumes, "volume"),
+    );
+
+    for failure in failures {
+        eprintln!(
+            "Couldn't remove {} '{}': {}",
+            failure.resource.kind.name(),
+            failure.resource.name,
+            failure.source,
+        );
+    }
+
+    i32::from(!failures.is_empty())
+}
+
+// `print_resources` prints `resources` as a table.
+fn print_resources(resources: &[clean::Resource]) {
+    let name_width =
+        resources
+            .iter()
+            .map(|r| r.name.len())
+            .chain(["NAME".len()])
+            .max()
+            .unwrap_or_default();
+
+    println!("{:<9}  {:<name_width$}  SIZE", "RESOURCE", "NAME");
+    for r in resources {
+        println!(
+            "{:<9}  {:<name_width$}  {}",
+            r.kind.name(),
+            r.name,
+            r.size.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+// `confirm_removal` asks the user to confirm the removal of the resources
+// printed by `print_resources`, and returns whether they confirmed it.
+fn confirm_removal() -> Result<bool, IoError> {
+    print!("Remove these resources? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// `count` returns `n` followed by `noun`, which is pluralised if `n` isn't 1.
+fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("{n} {noun}")
+    } else {
+        format!("{n} {noun}s")
+    }
+}
+
+fn outdated(dock_file_name: &str) -> i32 {
+    let envs =
+        match base_refresh::outdated(dock_file_name) {
+            Ok(envs) => {
+                envs
+            },
+            Err(err) => {
+                eprintln!("{err}");
+                return 1;
+            },
+        };
+
+    if envs.is_empty() {
+        return 0;
+    }
+
+    let width =
+        envs
+            .iter()
+            .map(|env| env.env_name.len())
+            .chain(["ENVIRONMENT".len()])
+            .max()
+            .unwrap_or(0);
+
+    println!("{:<width$}  {:<10} BASE_REFRESH", "ENVIRONMENT", "LAST PULL");
+    for env in &envs {
+        let last_pull =
+            env.age.map_or("never".to_string(), |age| {
+                format!("{} ago", base_refresh::format_age(age))
+            });
+
+        println!(
+            "{:<width$}  {:<10} {}",
+            env.env_name,
+            last_pull,
+            base_refresh::format_age(env.ttl),
+        );
+    }
+
+    0
+}
+
+fn lock(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    if args.is_present(CHECK_FLAG) {
+        let problems =
+            match lockfile::check(dock_file_name) {
+                Ok(problems) => {
+                    problems
+                },
+                Err(err) => {
+                    eprintln!("{err}");
+                    return 1;
+                },
+            };
+
+        if problems.is_empty() {
+            return 0;
+        }
+
+        eprintln!("'{}' is out of date:", lockfile::LOCK_FILE_NAME);
+        for problem in problems {
+            eprintln!("  {problem}");
+        }
+        eprintln!("Run `dock lock` to update it");
+
+        return 1;
+    }
+
+    let mut stdout = io::stdout();
+
+    let debug = args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    match lockfile::lock(&mut logger, dock_file_name) {
+        Ok(digests) => {
+            for (img, digest) in digests {
+                println!("Pinned '{img}' to '{digest}'");
+            }
+
+            0
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
+fn push(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let mut stdout = io::stdout();
+
+    let debug = args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    let env_names: Vec<&str> =
+        match args.values_of(ENV_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
+
+    let result = registry::push_envs(
+        &mut logger,
+        dock_file_name,
+        &env_names,
+        args.value_of(PLATFORM_FLAG),
+    );
+
+    match result {
+        Ok(imgs) => {
+            for img in imgs {
+                println!("Pushed '{img}'");
+            }
+
+            0
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
+fn pull(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let mut stdout = io::stdout();
+
+    let debug = args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    let env_names: Vec<&str> =
+        match args.values_of(ENV_FLAG) {
+            Some(vs) => vs.collect(),
+            None => vec![],
+        };
+
+    let result = registry::pull_envs(
+        &mut logger,
+        dock_file_name,
+        &env_names,
+        &BuildOpts{
+            cache_tag: DEFAULT_CACHE_TAG,
+            platform: args.value_of(PLATFORM_FLAG),
+            pull_only: true,
+        },
+    );
+
+    match result {
+        Ok(()) => {
+            0
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
+fn rollback(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let env_name = args.value_of(ENV_FLAG).unwrap();
+
+    let raw_prev = args.value_of(PREV_FLAG).unwrap();
+    let prev =
+        match raw_prev.parse::<usize>() {
+            Ok(n) if n > 0 => {
+                n
+            },
+            _ => {
+                eprintln!("`{PREV_FLAG}` must be a positive integer");
+                return 1;
+            },
+        };
+
+    let platform = args.value_of(PLATFORM_FLAG);
+
+    match history::rollback(dock_file_name, env_name, platform, prev) {
+        Ok(()) => {
+            0
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
+fn logs(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let env_name = args.value_of(ENV_FLAG).unwrap();
+
+    let log_path =
+        match build_log::find_last_build_log(dock_file_name, env_name) {
+            Ok(log_path) => {
+                log_path
+            },
+            Err(err) => {
+                eprintln!("{err}");
+                return 1;
+            },
+        };
+
+    // We open the log in the user's pager if we're writing to a terminal,
+    // because build logs are usually too long to read otherwise.
+    let pager = env::var("PAGER").ok().filter(|pager| !pager.is_empty());
+    if let (Some(pager), true) = (pager, io::stdout().is_terminal()) {
+        // `PAGER` may contain arguments, so we run it using the shell.
+        let status =
+            process::Command::new("sh")
+                .args(["-c", &format!("{pager} \"$1\""), "sh"])
+                .arg(&log_path)
+                .status();
+
+        return match status {
+            Ok(status) => {
+                exit_code_from_exit_status(status)
+            },
+            Err(err) => {
+                eprintln!("couldn't run `{pager}`: {err}");
+
+                1
+            },
+        };
+    }
+
+    let result =
+        std_fs::File::open(&log_path)
+            .and_then(|mut f| io::copy(&mut f, &mut io::stdout()));
+    if let Err(err) = result {
+        eprintln!("couldn't read '{}': {err}", log_path.display());
+        return 1;
+    }
+
+    0
+}
+
+fn history(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let env_name = args.value_of(ENV_FLAG).unwrap();
+    let platform = args.value_of(PLATFORM_FLAG);
+
+    let entries =
+        match history::history(dock_file_name, env_name, platform) {
+            Ok(entries) => {
+                entries
+            },
+            Err(err) => {
+                eprintln!("{err}");
+                return 1;
+            },
+        };
+
+    println!(
+        "{:<10} {:<12} {:<30} DOCKERFILE HASH",
+        "TAG",
+        "IMAGE ID",
+        "CREATED",
+    );
+    for entry in entries {
+        let hash =
+            entry.dockerfile_hash
+                .as_deref()
+                .map_or("", short_digest);
+
+        println!(
+            "{:<10} {:<12} {:<30} {}",
+            entry.tag,
+            short_digest(&entry.id),
+            entry.created,
+            hash,
+        );
+    }
+
+    0
+}
+
+// `short_digest` returns the abbreviated form of a digest like
+// `sha256:<hex>`, as used by the `docker` CLI.
+fn short_digest(digest: &str) -> &str {
+    let hex =
+        match digest.split_once(':') {
+            Some((_, hex)) => hex,
+            None => digest,
+        };
+
+    hex.get(..12).unwrap_or(hex)
+}
+
+fn gc(args: &ArgMatches) -> i32 {
+    let max_age =
+        match args.value_of(MAX_AGE_FLAG).map(base_refresh::parse_ttl) {
+            Some(Ok(max_age)) => {
+                Some(max_age)
+            },
+            Some(Err(err)) => {
+                eprintln!("Invalid `--{MAX_AGE_FLAG}`: {err}");
+
+                return 1;
+            },
+            None => {
+                None
+            },
+        };
+
+    let max_size =
+        match args.value_of(MAX_SIZE_FLAG).map(gc::parse_size) {
+            Some(Ok(max_size)) => {
+                Some(max_size)
+            },
+            Some(Err(err)) => {
+                eprintln!("Invalid `--{MAX_SIZE_FLAG}`: {err}");
+
+                return 1;
+            },
+            None => {
+                None
+            },
+        };
+
+    let missing_dir = args.is_present(MISSING_DIR_FLAG);
+
+    if max_age.is_none() && max_size.is_none() && !missing_dir {
+        eprintln!(
+            "At least one of `--{MAX_AGE_FLAG}`, `--{MAX_SIZE_FLAG}` or \
+             `--{MISSING_DIR_FLAG}` is required",
+        );
+
+        return 1;
+    }
+
+    let mut stdout = io::stdout();
+
+    let debug = args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    let opts = gc::GcOpts{max_age, max_size, missing_dir};
+    let pruned =
+        match gc::plan(&opts) {
+            Ok(pruned) => {
+                pruned
+            },
+            Err(err) => {
+                eprintln!("{err}");
+
+                return 1;
+            },
+        };
+
+    if pruned.is_empty() {
+        println!("No environments to remove");
+
+        return 0;
+    }
+
+    print_prunes(&pruned);
+
+    if args.is_present(DRY_RUN_FLAG) {
+        return 0;
+    }
+
+    if !args.is_present(YES_FLAG) && io::stdin().is_terminal() {
+        if let Some(exit_code) = cancelled_removal_exit_code() {
+            return exit_code;
+        }
+    }
+
+    // Containers are removed first, because images and volumes can't be
+    // removed while they're used by a container, which may belong to another
+    // environment.
+    let mut resources: Vec<clean::Resource> =
+        pruned
+            .into_iter()
+            .flat_map(|prune| prune.env.resources)
+            .collect();
+    resources.sort_by_key(|r| r.kind);
+
+    let (summary, failures) = clean::remove_all(&mut logger, resources);
+
+    // TODO Check if the prefixing command logger has an error.
+
+    report_removal(&summary, &failures)
+}
+
+// `print_table` prints `rows` under `header`, with each column padded to the
+// width of its widest cell.
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: [&str; N]| {
+        let line: Vec<String> =
+            cells
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect();
+
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(header);
+    for row in rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+// `print_prunes` prints the environments that `gc` removes as a table,
+// followed by their total size.
+fn print_prunes(pruned: &[gc::Prune]) {
+    let now = SystemTime::now();
+
+    let rows: Vec<[String; 5]> =
+        pruned
+            .iter()
+            .map(|prune| {
+                let last_use =
+                    prune.env.last_use.map_or("unknown".to_string(), |t| {
+                        let age = now.duration_since(t).unwrap_or_default();
+
+                        format!("{} ago", base_refresh::format_age(age))
+                    });
+
+                [
+                    prune.env.project.clone(),
+                    prune.env.env_name.clone(),
+                    last_use,
+                    build_context::format_size(prune.env.size),
+                    prune.reason.to_string(),
+                ]
+            })
+            .collect();
+
+    let header = ["PROJECT", "ENVIRONMENT", "LAST USE", "SIZE", "REASON"];
+    print_table(header, &rows);
+
+    let total: u64 = pruned.iter().map(|prune| prune.env.size).sum();
+    println!("Total: {}", build_context::format_size(total));
+}
+
+fn cache(dock_file_name: &str, args: &ArgMatches) -> i32 {
+    let (action, sub_args) =
+        match args.subcommand() {
+            Some(("ls", _)) => {
+                return cache_ls(dock_file_name);
+            },
+            Some((action, sub_args)) => {
+                (action, sub_args)
+            },
+            None => {
+                // `subcommand_required` is set for `cache`, so this shouldn't
+                // be reached.
+                panic!("no `cache` subcommand was given");
+            },
+        };
+
+    let mut stdout = io::stdout();
+
+    let debug = sub_args.is_present(DEBUG_FLAG);
+    let mut logger =
+        if debug {
+            let logger = PrefixingCmdLogger::new(
+                &mut stdout,
+                b"[$] ",
+                Prefixer::new(b"[>] "),
+                Prefixer::new(b"[!] "),
+            );
+            let timing_logger = TimingPrefixingCmdLogger::new(logger, b"[@] ");
+
+            CmdLoggers::Debugging(timing_logger)
+        } else {
+            CmdLoggers::Capturing(CapturingCmdLogger::new())
+        };
+
+    // `ENV_FLAG` and `CACHE_NAME_FLAG` are required arguments of the `cache`
+    // subcommands other than `ls`, so we can safely `unwrap` their values.
+    let vol = cache::CacheVolumeRef{
+        env_name: sub_args.value_of(ENV_FLAG).unwrap(),
+        name: sub_args.value_of(CACHE_NAME_FLAG).unwrap(),
+    };
+    let opts = BuildOpts{
+        cache_tag: DEFAULT_CACHE_TAG,
+        platform: None,
+        pull_only: false,
+    };
+
+    let result =
+        match action {
+            "reset" => {
+                cache::reset(&mut logger, dock_file_name, &vol, &opts, !debug)
+            },
+            "export" | "import" => {
+                // `ARCHIVE_FLAG` is a required argument of `export` and
+                // `import`, so we can safely `unwrap` its value.
+                let archive =
+                    Path::new(sub_args.value_of(ARCHIVE_FLAG).unwrap());
+
+                let run =
+                    if action == "export" {
+                        cache::export
+                    } else {
+                        cache::import
+                    };
+
+                run(&mut logger, dock_file_name, &vol, archive, &opts, !debug)
+            },
+            _ => {
+                // All subcommands defined for `cache` should be handled here,
+                // so this should be unreachable.
+                panic!("unexpected `cache` subcommand: '{action}'");
+            },
+        };
+
+    // TODO Check if the prefixing command logger has an error.
+
+    match result {
+        Ok(()) => {
+            0
+        },
+        Err(err) => {
+            eprintln!("{err}");
+
+            1
+        },
+    }
+}
+
+fn cache_ls(dock_file_name: &str) -> i32 {
+    let vols =
+        match cache::list(dock_file_name) {
+            Ok(vols) => {
+                vols
+            },
+            Err(err) => {
+                eprintln!("{err}");
+
+                return 1;
+            },
+        };
+
+    if vols.is_empty() {
+        return 0;
+    }
+
+    let now = SystemTime::now();
+
+    let rows: Vec<[String; 4]> =
+        vols
+            .iter()
+            .map(|vol| {
+                let size =
+                    if vol.exists {
+                        vol.size
+                            .clone()
+                            .unwrap_or_else(|| "-".to_string())
+                    } else {
+                        "not created".to_string()
+                    };
+
+                let last_use =
+                    vol.last_use.map_or("never".to_string(), |t| {
+                        let age = now.duration_since(t).unwrap_or_default();
+
+                        format!("{} ago", base_refresh::format_age(age))
+                    });
+
+                [vol.env_name.clone(), vol.name.clone(), size, last_use]
+            })
+            .collect();
+
+    print_table(["ENVIRONMENT", "NAME", "SIZE", "LAST USE"], &rows);
+
+    0
 }
@@ -12,14 +12,20 @@ use std::path::PathBuf;
 use std::process::Command;
 use std::process::ExitStatus;
 use std::process::Stdio;
+use std::thread;
+use std::thread::JoinHandle;
 
 use snafu::ResultExt;
 use snafu::Snafu;
 
+use crate::build_context;
+use crate::build_context::ContextFiles;
 use crate::canon_path::AbsPath;
 use crate::docker;
 use crate::docker::AssertRunError;
 use crate::docker::StreamRunError;
+use crate::history;
+use crate::history::RotateError;
 use crate::logging_process;
 use crate::logging_process::CommandLogger;
 use crate::logging_process::RunError;
@@ -35,6 +41,8 @@ pub fn rebuild_with_streaming_output(
     rebuild_img(
         target_img,
         cache_img,
+        0,
+        Builder::Docker,
         strs_to_os_strings(args),
         |build_args| {
             let build_result = docker::stream_run(build_args)?;
@@ -44,6 +52,32 @@ pub fn rebuild_with_streaming_output(
     )
 }
 
+// `replace` replaces `target_img` with `new_img`, handling the replaced image
+// in the same way as `rebuild`.
+pub fn replace(
+    target_img: &str,
+    cache_img: &str,
+    new_img: &str,
+    history_depth: usize,
+)
+    -> Result<(), RebuildError<(), AssertRunError>>
+{
+    // The build arguments aren't used, because `new_img` is tagged instead of
+    // being built.
+    rebuild_img(
+        target_img,
+        cache_img,
+        history_depth,
+        Builder::Docker,
+        vec![],
+        |_| {
+            docker::assert_run(["tag", new_img, target_img])?;
+
+            Ok(((), true))
+        },
+    )
+}
+
 fn strs_to_os_strings(strs: &[&str]) -> Vec<OsString> {
     strs
         .iter()
@@ -54,6 +88,8 @@ fn strs_to_os_strings(strs: &[&str]) -> Vec<OsString> {
 fn rebuild_img<F, V, E>(
     target_img: &str,
     cache_img: &str,
+    history_depth: usize,
+    builder: Builder,
     args: Vec<OsString>,
     build_img: F,
 )
@@ -71,16 +107,7 @@ where
             .output()
             .context(TagFailed)?;
 
-    let tag_flag = &format!("--tag={target_img}");
-
-    // By default, Docker removes intermediate containers after a successful
-    // build, but leaves them after a failed build. We use `--force-rm` to
-    // remove them even if the build failed. See "Container Removal" in
-    // `README.md` for more details.
-    let mut build_args: Vec<OsString> =
-        strs_to_os_strings(&["build", tag_flag, "--force-rm"]);
-
-    build_args.extend(args);
+    let build_args = build_args(builder, target_img, args);
 
     let (build_result, build_success) = build_img(build_args)
         .context(BuildNewImageFailed)?;
@@ -89,6 +116,11 @@ where
     // tagging succeeded.
     if tag_result.status.success() {
         if build_success {
+            keep_superseded_img(target_img, cache_img, history_depth)
+                .with_context(|| KeepOldImageFailed{
+                    build_result: build_result.clone(),
+                })?;
+
             docker::assert_run(["rmi", cache_img])
                 .with_context(|| RemoveOldImageFailed{
                     build_result: build_result.clone(),
@@ -105,6 +137,100 @@ where
     Ok(build_result)
 }
 
+// `keep_superseded_img` keeps `cache_img` as a previous image of
+// `target_img`, if `history_depth` is greater than zero and the rebuild
+// produced a new image.
+fn keep_superseded_img(
+    target_img: &str,
+    cache_img: &str,
+    history_depth: usize,
+)
+    -> Result<(), KeepSupersededImageError>
+{
+    if history_depth == 0 {
+        return Ok(());
+    }
+
+    let img_name =
+        match target_img.rsplit_once(':') {
+            Some((name, _)) => name,
+            None => target_img,
+        };
+
+    let output = docker::assert_run([
+        "image",
+        "inspect",
+        "--format={{.Id}}",
+        target_img,
+        cache_img,
+    ])
+        .context(InspectImagesFailed)?;
+
+    let ids = String::from_utf8_lossy(&output.stdout).into_owned();
+    let mut ids = ids.lines();
+    if ids.next() == ids.next() {
+        // The rebuild didn't change the image, so there's nothing to keep.
+        return Ok(());
+    }
+
+    history::rotate(img_name, cache_img, history_depth)
+        .context(RotateFailed)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+pub enum KeepSupersededImageError {
+    #[snafu(display("Couldn't inspect images: {}", source))]
+    InspectImagesFailed{source: AssertRunError},
+    #[snafu(display("Couldn't rotate previous images: {}", source))]
+    RotateFailed{source: RotateError},
+}
+
+// `Builder` is the Docker command used to build images.
+#[derive(Clone, Copy)]
+pub enum Builder {
+    // `Docker` builds images with `docker build`, which uses BuildKit by
+    // default since Docker 23.0.
+    Docker,
+    // `Buildx` builds images with `docker buildx build`, which allows
+    // non-default builder instances, and cache exports, to be used.
+    Buildx,
+}
+
+// `build_args` returns the arguments used to run `docker` to build
+// `target_img` with `builder`, with `args` as additional arguments.
+pub fn build_args<I, S>(builder: Builder, target_img: &str, args: I)
+    -> Vec<OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<OsString>,
+{
+    let tag_flag = &format!("--tag={target_img}");
+
+    let mut build_args: Vec<OsString> =
+        match builder {
+            // By default, Docker removes intermediate containers after a
+            // successful build, but leaves them after a failed build. We use
+            // `--force-rm` to remove them even if the build failed. See
+            // "Container Removal" in `README.md` for more details.
+            Builder::Docker => {
+                strs_to_os_strings(&["build", tag_flag, "--force-rm"])
+            },
+            // BuildKit doesn't create intermediate containers, so
+            // `--force-rm` isn't needed. `--load` is used so that the image
+            // is loaded into the local image store, where it can be tagged
+            // and run, regardless of the driver used by the builder.
+            Builder::Buildx => {
+                strs_to_os_strings(&["buildx", "build", tag_flag, "--load"])
+            },
+        };
+
+    build_args.extend(args.into_iter().map(Into::into));
+
+    build_args
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Snafu)]
 pub enum RebuildError<T, E>
@@ -115,6 +241,8 @@ where
     TagFailed{source: IoError},
     #[snafu(display("Couldn't build a new Docker image: {}", source))]
     BuildNewImageFailed{source: E},
+    #[snafu(display("Couldn't keep the old Docker image: {}", source))]
+    KeepOldImageFailed{source: KeepSupersededImageError, build_result: T},
     #[snafu(display("Couldn't remove the old Docker image: {}", source))]
     RemoveOldImageFailed{source: AssertRunError, build_result: T},
     #[snafu(display("Couldn't replace tag on Docker: {}", source))]
@@ -122,8 +250,31 @@ where
 }
 
 pub enum DockerContext {
-    Empty{dockerfile: File},
+    Empty{dockerfile: AbsPath},
     Dir{path: AbsPath, dockerfile: AbsPath},
+    // `Filtered` sends `files` to `docker build` as a tar stream, along with
+    // `dockerfile`.
+    Filtered{files: ContextFiles, dockerfile: AbsPath},
+}
+
+impl DockerContext {
+    pub fn dockerfile(&self) -> &AbsPath {
+        match self {
+            Self::Empty{dockerfile}
+                | Self::Dir{dockerfile, ..}
+                | Self::Filtered{dockerfile, ..} => dockerfile,
+        }
+    }
+
+    // `with_dockerfile` returns this context with its Dockerfile replaced by
+    // `dockerfile`.
+    pub fn with_dockerfile(self, dockerfile: AbsPath) -> Self {
+        match self {
+            Self::Empty{..} => Self::Empty{dockerfile},
+            Self::Dir{path, ..} => Self::Dir{path, dockerfile},
+            Self::Filtered{files, ..} => Self::Filtered{files, dockerfile},
+        }
+    }
 }
 
 pub fn rebuild(
@@ -132,44 +283,26 @@ pub fn rebuild(
     cache_img: &str,
     context: DockerContext,
     extra_args: &[&str],
+    history_depth: usize,
+    builder: Builder,
 )
-    -> Result<ExitStatus, RebuildError<ExitStatus, RunError>>
+    -> Result<ExitStatus, RebuildError<ExitStatus, BuildError>>
 {
-    let stdin;
-    let mut args;
-    match context {
-        DockerContext::Empty{dockerfile} => {
-            stdin = Stdio::from(dockerfile);
-            args = vec![OsString::from("-")];
-        },
-        DockerContext::Dir{path, dockerfile} => {
-            stdin = Stdio::null();
-
-            let mut file_arg = OsString::from("--file=");
-            file_arg.push(PathBuf::from(dockerfile).as_os_str());
-            args = vec![file_arg, PathBuf::from(path).into_os_string()];
-        },
-    }
+    let input = context_input(context)
+        .context(PrepareContextFailed)
+        .context(BuildNewImageFailed)?;
 
+    let mut args = input.args.clone();
     args.extend(strs_to_os_strings(extra_args));
 
     rebuild_img(
         target_img,
         cache_img,
+        history_depth,
+        builder,
         args,
         |build_args| {
-            let build_args: Vec<&OsStr> =
-                build_args
-                    .iter()
-                    .map(OsStr::new)
-                    .collect();
-
-            let build_result = logging_process::run(
-                logger,
-                OsStr::new("docker"),
-                &build_args,
-                stdin,
-            )?;
+            let build_result = run_build(logger, &build_args, input)?;
 
             let success = build_result.success();
 
@@ -177,3 +310,132 @@ pub fn rebuild(
         },
     )
 }
+
+// `build` builds `target_img` from `context`, without keeping or replacing the
+// existing image, if any.
+pub fn build(
+    logger: &mut dyn CommandLogger,
+    target_img: &str,
+    context: DockerContext,
+    extra_args: &[&str],
+    builder: Builder,
+)
+    -> Result<ExitStatus, BuildError>
+{
+    let input = context_input(context)
+        .context(PrepareContextFailed)?;
+
+    let mut args = input.args.clone();
+    args.extend(strs_to_os_strings(extra_args));
+
+    let build_args = build_args(builder, target_img, args);
+
+    run_build(logger, &build_args, input)
+}
+
+// `ContextInput` is the STDIN and the arguments used to pass a context to
+// `docker build`. `writer` is the thread that writes the context to STDIN, if
+// any.
+struct ContextInput {
+    stdin: Stdio,
+    args: Vec<OsString>,
+    writer: Option<JoinHandle<Result<(), IoError>>>,
+}
+
+fn context_input(context: DockerContext) -> Result<ContextInput, IoError> {
+    match context {
+        DockerContext::Empty{dockerfile} => {
+            let dockerfile = File::open(PathBuf::from(dockerfile))?;
+
+            Ok(ContextInput{
+                stdin: Stdio::from(dockerfile),
+                args: vec![OsString::from("-")],
+                writer: None,
+            })
+        },
+        DockerContext::Dir{path, dockerfile} => {
+            let mut file_arg = OsString::from("--file=");
+            file_arg.push(PathBuf::from(dockerfile).as_os_str());
+
+            let path = PathBuf::from(path).into_os_string();
+
+            Ok(ContextInput{
+                stdin: Stdio::null(),
+                args: vec![file_arg, path],
+                writer: None,
+            })
+        },
+        DockerContext::Filtered{files, dockerfile} => {
+            let dockerfile = PathBuf::from(dockerfile);
+            let mut tar = build_context::spawn_tar(&files, &dockerfile)?;
+
+            let tar_stdout = tar.take_stdout()
+                .ok_or_else(|| IoError::other("couldn't bind to `tar`"))?;
+
+            // We pass the list of files to `tar` in a separate thread, because
+            // `docker build` reads the context while we wait for it to finish.
+            let writer = thread::spawn(move || tar.finish());
+
+            let file_arg =
+                format!("--file={}", build_context::DOCKERFILE_NAME);
+
+            Ok(ContextInput{
+                stdin: Stdio::from(tar_stdout),
+                args: strs_to_os_strings(&[&file_arg, "-"]),
+                writer: Some(writer),
+            })
+        },
+    }
+}
+
+// `run_build` runs `docker` with `build_args`, passing the context from
+// `input`.
+fn run_build(
+    logger: &mut dyn CommandLogger,
+    build_args: &[OsString],
+    input: ContextInput,
+)
+    -> Result<ExitStatus, BuildError>
+{
+    let build_args: Vec<&OsStr> =
+        build_args
+            .iter()
+            .map(OsStr::new)
+            .collect();
+
+    let status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &build_args,
+        input.stdin,
+    )
+        .context(RunBuildFailed)?;
+
+    if let Some(writer) = input.writer {
+        let write_result = writer.join()
+            .map_err(|_| BuildError::JoinContextWriterFailed)?;
+
+        // If the build failed then the context may not have been read in
+        // full, so we return the status of the build instead of the error
+        // from writing the context.
+        if status.success() {
+            write_result
+                .context(WriteContextFailed)?;
+        }
+    }
+
+    Ok(status)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum BuildError {
+    #[snafu(display("Couldn't prepare the context: {}", source))]
+    PrepareContextFailed{source: IoError},
+    #[snafu(display("Couldn't run `docker build`: {}", source))]
+    RunBuildFailed{source: RunError},
+    #[snafu(display("Couldn't write the context: {}", source))]
+    WriteContextFailed{source: IoError},
+    #[snafu(display("(Dev Err) Couldn't join the context writer thread"))]
+    JoinContextWriterFailed,
+}
@@ -0,0 +1,325 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::canon_path::AbsPath;
+use crate::cmd_loggers::CapturingCmdLogger;
+use crate::run_in;
+use crate::run_in::BuildOpts;
+use crate::run_in::DockConfig;
+use crate::run_in::EnvBuildOrderError;
+use crate::run_in::EnvDepsError;
+use crate::run_in::FindAndParseDockConfigError;
+use crate::run_in::RebuildDisplay;
+use crate::spinner;
+use crate::spinner::Board;
+use crate::spinner::SpinError;
+
+pub struct EnvResult {
+    pub env_name: String,
+    pub outcome: Outcome,
+}
+
+pub enum Outcome {
+    Rebuilt{duration: Duration},
+    // `log_path` is the path of the saved build log, if the rebuild was
+    // unsuccessful.
+    Failed{duration: Duration, msg: String, log_path: Option<PathBuf>},
+    // `Skipped` environments weren't rebuilt because `dep`, which they depend
+    // on, wasn't rebuilt.
+    Skipped{dep: String},
+}
+
+impl Outcome {
+    pub fn is_rebuilt(&self) -> bool {
+        matches!(self, Outcome::Rebuilt{..})
+    }
+}
+
+// `rebuild_all` rebuilds `env_names`, or all environments if `env_names` is
+// empty, along with the environments that they depend on. At most `jobs`
+// environments are rebuilt at a time, and an environment is only rebuilt
+// after the environments that it depends on have been rebuilt successfully.
+// The results are returned in build order.
+pub fn rebuild_all(
+    dock_file_name: &str,
+    env_names: &[&str],
+    opts: &BuildOpts,
+    jobs: usize,
+    show_board: bool,
+)
+    -> Result<Vec<EnvResult>, RebuildAllError>
+{
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let mut roots: Vec<&str> =
+        if env_names.is_empty() {
+            conf.environments.keys().map(String::as_str).collect()
+        } else {
+            env_names.to_vec()
+        };
+    // We sort the environments so that the build order is stable.
+    roots.sort_unstable();
+
+    for name in &roots {
+        conf.environments.get(*name)
+            .context(EnvironmentNotFound{name: *name})?;
+    }
+
+    let order = run_in::env_build_order(&dock_dir, &conf, &roots)
+        .context(EnvBuildOrderFailed)?;
+
+    let mut deps = vec![];
+    for env_name in &order {
+        let env = &conf.environments[*env_name];
+        let env_deps = run_in::env_deps(&dock_dir, &conf, env_name, env)
+            .context(EnvDepsFailed{name: *env_name})?;
+
+        let dep_idxs =
+            env_deps
+                .iter()
+                .filter_map(|dep| order.iter().position(|e| e == dep))
+                .collect::<Vec<usize>>();
+
+        deps.push(dep_idxs);
+    }
+
+    let board = Board::new(&order);
+    for i in 0..order.len() {
+        board.set(i, "waiting".to_string(), false);
+    }
+
+    let build = Build{
+        dock_dir: &dock_dir,
+        conf: &conf,
+        opts,
+        order: &order,
+        deps: &deps,
+    };
+
+    let outcomes =
+        if show_board {
+            spinner::spin_board(&board, || build.run(jobs, Some(&board)))
+                .context(SpinFailed)?
+        } else {
+            build.run(jobs, None)
+        };
+
+    let results =
+        order
+            .iter()
+            .zip(outcomes)
+            .map(|(env_name, outcome)| EnvResult{
+                env_name: (*env_name).to_string(),
+                outcome,
+            })
+            .collect();
+
+    Ok(results)
+}
+
+#[derive(Debug, Snafu)]
+pub enum RebuildAllError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+    #[snafu(display("Couldn't determine the build order: {}", source))]
+    EnvBuildOrderFailed{source: EnvBuildOrderError},
+    #[snafu(display(
+        "Couldn't get the dependencies of '{}': {}",
+        name,
+        source,
+    ))]
+    EnvDepsFailed{source: EnvDepsError, name: String},
+    #[snafu(display("{}", source))]
+    SpinFailed{source: SpinError},
+}
+
+// `Build` holds the environments to rebuild, in build order, where `deps[i]`
+// contains the indices of the environments that `order[i]` depends on.
+struct Build<'a> {
+    dock_dir: &'a AbsPath,
+    conf: &'a DockConfig,
+    opts: &'a BuildOpts<'a>,
+    order: &'a [&'a str],
+    deps: &'a [Vec<usize>],
+}
+
+// `Finished` is sent by a rebuild thread when its rebuild finishes. `result`
+// contains the ID of the rebuilt image, or a description of the failure and
+// the path of the saved build log, if any.
+struct Finished {
+    idx: usize,
+    result: Result<String, (String, Option<PathBuf>)>,
+    duration: Duration,
+}
+
+impl Build<'_> {
+    // `run` returns the outcome for each environment in `order`.
+    fn run(&self, jobs: usize, board: Option<&Board>) -> Vec<Outcome> {
+        let mut outcomes: Vec<Option<Outcome>> =
+            self.order.iter().map(|_| None).collect();
+        let mut img_ids = HashMap::new();
+        let mut pending: Vec<usize> = (0..self.order.len()).collect();
+        let mut running = 0;
+
+        let (sender, receiver) = mpsc::channel::<Finished>();
+
+        thread::scope(|scope| {
+            loop {
+                let mut j = 0;
+                while j < pending.len() && running < jobs {
+                    let i = pending[j];
+
+                    let failed_dep = self.deps[i].iter().find(|&&d| {
+                        outcomes[d].as_ref().is_some_and(|o| !o.is_rebuilt())
+                    });
+                    if let Some(&d) = failed_dep {
+                        let dep = self.order[d].to_string();
+                        let outcome = Outcome::Skipped{dep};
+                        self.report(board, i, &outcome);
+                        outcomes[i] = Some(outcome);
+                        pending.remove(j);
+                        continue;
+                    }
+
+                    if !self.deps[i].iter().all(|&d| outcomes[d].is_some()) {
+                        j += 1;
+                        continue;
+                    }
+
+                    if let Some(board) = board {
+                        board.set(i, "rebuilding".to_string(), true);
+                    }
+
+                    let sender = sender.clone();
+                    let img_ids = img_ids.clone();
+                    scope.spawn(move || {
+                        let start = Instant::now();
+                        let mut logger = CapturingCmdLogger::new();
+                        let display =
+                            match board {
+                                Some(board) => {
+                                    RebuildDisplay::Board{board, i}
+                                },
+                                None => {
+                                    RebuildDisplay::Plain
+                                },
+                            };
+                        let result = run_in::rebuild_env(
+                            &mut logger,
+                            self.dock_dir,
+                            self.conf,
+                            self.order[i],
+                            self.opts,
+                            &img_ids,
+                            &display,
+                        );
+
+                        // The receiver is only dropped after all rebuilds
+                        // have finished, so we ignore send errors.
+                        let _ = sender.send(Finished{
+                            idx: i,
+                            result: result.map_err(|e| {
+                                let log_path =
+                                    e.build_log_path()
+                                        .map(|(_, path)| path.to_path_buf());
+
+                                (e.to_string(), log_path)
+                            }),
+                            duration: start.elapsed(),
+                        });
+                    });
+
+                    running += 1;
+                    pending.remove(j);
+                }
+
+                if running == 0 {
+                    break;
+                }
+
+                let Ok(finished) = receiver.recv() else {
+                    break;
+                };
+                running -= 1;
+
+                let i = finished.idx;
+                let duration = finished.duration;
+                let outcome =
+                    match finished.result {
+                        Ok(img_id) => {
+                            img_ids.insert(self.order[i].to_string(), img_id);
+
+                            Outcome::Rebuilt{duration}
+                        },
+                        Err((msg, log_path)) => {
+                            Outcome::Failed{duration, msg, log_path}
+                        },
+                    };
+                self.report(board, i, &outcome);
+                outcomes[i] = Some(outcome);
+            }
+        });
+
+        outcomes
+            .into_iter()
+            .map(|outcome| {
+                // Every environment is either rebuilt or skipped before the
+                // loop above exits.
+                outcome
+                    .unwrap_or_else(|| Outcome::Skipped{dep: String::new()})
+            })
+            .collect()
+    }
+
+    // `report` shows the outcome for `order[i]` on `board` if it's being
+    // shown, and prints it otherwise.
+    fn report(&self, board: Option<&Board>, i: usize, outcome: &Outcome) {
+        let status = status(outcome);
+        if let Some(board) = board {
+            board.set(i, status, false);
+        } else {
+            println!("{}: {}", self.order[i], status);
+        }
+    }
+}
+
+// `status` returns a short description of `outcome`.
+pub fn status(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Rebuilt{duration} => {
+            format!("rebuilt in {}", format_duration(*duration))
+        },
+        Outcome::Failed{duration, ..} => {
+            format!("failed after {}", format_duration(*duration))
+        },
+        Outcome::Skipped{dep} => {
+            format!("skipped ('{dep}' wasn't rebuilt)")
+        },
+    }
+}
+
+pub fn format_duration(d: Duration) -> String {
+    format!("{:.1}s", d.as_secs_f64())
+}
@@ -0,0 +1,316 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Sharing of environment images through a registry.
+//!
+//! Images are pushed to `<registry>/<image>:<content hash>`, so that an image
+//! is only pulled in place of a local rebuild if it was built from the same
+//! Dockerfile, parent images, Git context commit and platform.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::process::Stdio;
+
+use snafu::OptionExt;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::docker;
+use crate::docker::AssertRunError;
+use crate::logging_process;
+use crate::logging_process::CommandLogger;
+use crate::logging_process::RunError;
+use crate::rebuild;
+use crate::rebuild::RebuildError;
+use crate::run_in;
+use crate::run_in::BuildOpts;
+use crate::run_in::FindAndParseDockConfigError;
+use crate::run_in::RebuildDisplay;
+use crate::run_in::RunInError;
+
+// `remote_image` returns the name of the image of `img_name` in `registry`
+// with the content hash `content_hash`.
+pub fn remote_image(registry: &str, img_name: &str, content_hash: &str)
+    -> String
+{
+    let tag = content_hash.strip_prefix("sha256:").unwrap_or(content_hash);
+
+    format!("{}/{img_name}:{tag}", registry.trim_end_matches('/'))
+}
+
+// `content_hash` returns the content hash of `img`, or `None` if `img`
+// doesn't exist or doesn't have a content hash.
+pub fn content_hash(img: &str) -> Option<String> {
+    let format_arg = format!(
+        "--format={{{{index .Config.Labels \"{}\"}}}}",
+        run_in::CONTENT_HASH_LABEL,
+    );
+    let output = docker::assert_run(["image", "inspect", &format_arg, img])
+        .ok()?;
+
+    let hash = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if hash.is_empty() || hash == "<no value>" {
+        return None;
+    }
+
+    Some(hash)
+}
+
+// `pull` pulls `remote_img` and uses it to replace `target_img`, in the same
+// way that a rebuild replaces `target_img`. It returns `false` if
+// `remote_img` couldn't be pulled, for example, because it hasn't been pushed
+// or because the registry can't be reached.
+pub fn pull(
+    logger: &mut dyn CommandLogger,
+    remote_img: &str,
+    target_img: &str,
+    cache_img: &str,
+    history_depth: usize,
+)
+    -> Result<bool, PullError>
+{
+    let pull_args = [OsStr::new("pull"), OsStr::new(remote_img)];
+    let status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &pull_args,
+        Stdio::null(),
+    )
+        .context(RunPullFailed)?;
+
+    if !status.success() {
+        return Ok(false);
+    }
+
+    rebuild::replace(target_img, cache_img, remote_img, history_depth)
+        .context(ReplaceImageFailed{img: target_img})?;
+
+    // We remove the registry name of the pulled image so that it isn't left
+    // behind by `dock clean`; the image itself is kept as `target_img`.
+    docker::assert_run(["rmi", remote_img])
+        .context(UntagPulledImageFailed{img: remote_img})?;
+
+    Ok(true)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum PullError {
+    #[snafu(display("Couldn't run `docker pull`: {}", source))]
+    RunPullFailed{source: RunError},
+    #[snafu(display(
+        "Couldn't replace '{}' with the pulled image: {}",
+        img,
+        source,
+    ))]
+    ReplaceImageFailed{
+        source: RebuildError<(), AssertRunError>,
+        img: String,
+    },
+    #[snafu(display("Couldn't untag '{}': {}", img, source))]
+    UntagPulledImageFailed{source: AssertRunError, img: String},
+}
+
+// `push_envs` pushes the current images of `env_names`, or of all
+// environments if `env_names` is empty, to the registry of the project, and
+// returns the names of the pushed images. `platform` selects the images that
+// were built for a platform other than the configured platform of each
+// environment.
+pub fn push_envs(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    env_names: &[&str],
+    platform: Option<&str>,
+)
+    -> Result<Vec<String>, PushEnvsError>
+{
+    let (_, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(FindAndParseDockConfigFailed{dock_file_name})?;
+
+    let registry = conf.registry.as_ref()
+        .context(RegistryNotSet)?;
+
+    let mut env_names: Vec<&str> =
+        if env_names.is_empty() {
+            conf.environments.keys().map(String::as_str).collect()
+        } else {
+            env_names.to_vec()
+        };
+    env_names.sort_unstable();
+
+    let mut pushed = vec![];
+    for env_name in env_names {
+        let env = conf.environments.get(env_name)
+            .context(EnvironmentNotFound{name: env_name})?;
+
+        let img_name =
+            run_in::platform_image_name(&conf, env_name, env, platform);
+
+        let remote_img = push(logger, registry, &img_name)
+            .context(PushFailed{name: env_name})?;
+
+        pushed.push(remote_img);
+    }
+
+    Ok(pushed)
+}
+
+#[derive(Debug, Snafu)]
+pub enum PushEnvsError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    FindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("`registry` isn't set in the Dock file"))]
+    RegistryNotSet,
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    EnvironmentNotFound{name: String},
+    #[snafu(display("Couldn't push '{}': {}", name, source))]
+    PushFailed{source: PushError, name: String},
+}
+
+// `push` pushes the current image of `img_name` to `registry`, tagged with its
+// content hash, and returns the name of the pushed image.
+fn push(logger: &mut dyn CommandLogger, registry: &str, img_name: &str)
+    -> Result<String, PushError>
+{
+    let img = format!("{img_name}:latest");
+    let content_hash = content_hash(&img)
+        .context(ContentHashNotFound{img: &img})?;
+
+    let remote_img = remote_image(registry, img_name, &content_hash);
+    docker::assert_run(["tag", &img, &remote_img])
+        .context(TagRemoteImageFailed{img: &remote_img})?;
+
+    let push_args = [OsStr::new("push"), OsStr::new(&remote_img)];
+    let push_result = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &push_args,
+        Stdio::null(),
+    );
+
+    // We remove the registry name of the image whether or not the push
+    // succeeded, so that it isn't left behind by `dock clean`.
+    let untag_result = docker::assert_run(["rmi", &remote_img]);
+
+    let status = push_result
+        .context(RunPushFailed)?;
+    if !status.success() {
+        return Err(PushError::PushUnsuccessful{img: remote_img});
+    }
+
+    untag_result
+        .context(UntagRemoteImageFailed{img: &remote_img})?;
+
+    Ok(remote_img)
+}
+
+#[derive(Debug, Snafu)]
+pub enum PushError {
+    #[snafu(display(
+        "'{}' doesn't exist or doesn't have a content hash; rebuild it before \
+         pushing it",
+        img,
+    ))]
+    ContentHashNotFound{img: String},
+    #[snafu(display("Couldn't tag '{}': {}", img, source))]
+    TagRemoteImageFailed{source: AssertRunError, img: String},
+    #[snafu(display("Couldn't run `docker push`: {}", source))]
+    RunPushFailed{source: RunError},
+    #[snafu(display("`docker push` of '{}' was unsuccessful", img))]
+    PushUnsuccessful{img: String},
+    #[snafu(display("Couldn't untag '{}': {}", img, source))]
+    UntagRemoteImageFailed{source: AssertRunError, img: String},
+}
+
+// `pull_envs` pulls the images of `env_names`, or of all environments if
+// `env_names` is empty, along with the images of the environments that they
+// depend on, from the registry of the project, without rebuilding them.
+// `opts.pull_only` should be set.
+pub fn pull_envs(
+    logger: &mut dyn CommandLogger,
+    dock_file_name: &str,
+    env_names: &[&str],
+    opts: &BuildOpts,
+)
+    -> Result<(), PullEnvsError>
+{
+    let (dock_dir, conf) = run_in::find_and_parse_dock_config(dock_file_name)
+        .context(PullFindAndParseDockConfigFailed{dock_file_name})?;
+
+    if conf.registry.is_none() {
+        return Err(PullEnvsError::PullRegistryNotSet);
+    }
+
+    let mut roots: Vec<&str> =
+        if env_names.is_empty() {
+            conf.environments.keys().map(String::as_str).collect()
+        } else {
+            env_names.to_vec()
+        };
+    roots.sort_unstable();
+
+    let order = run_in::env_build_order(&dock_dir, &conf, &roots)
+        .context(PullEnvBuildOrderFailed)?;
+
+    let mut img_ids = HashMap::default();
+    for env_name in order {
+        let img_id = run_in::rebuild_env(
+            logger,
+            &dock_dir,
+            &conf,
+            env_name,
+            opts,
+            &img_ids,
+            &RebuildDisplay::Plain,
+        )
+            .context(PullEnvFailed)?;
+
+        img_ids.insert(env_name.to_string(), img_id);
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum PullEnvsError {
+    #[snafu(display(
+        "Couldn't find and parse '{}': {}",
+        dock_file_name,
+        source,
+    ))]
+    PullFindAndParseDockConfigFailed{
+        source: FindAndParseDockConfigError,
+        dock_file_name: String,
+    },
+    #[snafu(display("`registry` isn't set in the Dock file"))]
+    PullRegistryNotSet,
+    #[snafu(display("Couldn't determine the pull order: {}", source))]
+    PullEnvBuildOrderFailed{source: run_in::EnvBuildOrderError},
+    #[snafu(display("{}", source))]
+    PullEnvFailed{source: RunInError},
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_image_uses_content_hash_as_tag() {
+        let img = remote_image(
+            "localhost:5000/",
+            "org/proj.build",
+            "sha256:0123abcd",
+        );
+
+        assert_eq!(img, "localhost:5000/org/proj.build:0123abcd");
+    }
+}
@@ -3,6 +3,7 @@
 // licence that can be found in the LICENCE file.
 
 use std::char;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::env;
 use std::env::VarError;
@@ -14,41 +15,82 @@ use std::fs::File;
 use std::io::Error as IoError;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::ExitStatus;
+use std::process;
 use std::process::Output;
 use std::process::Stdio;
 use std::str;
 use std::str::Utf8Error;
+use std::time::SystemTime;
 
 use serde::Deserialize;
 use serde_yaml::Error as SerdeYamlError;
 use serde_yaml::Value;
+use sha2::Digest;
+use sha2::Sha256;
 use snafu::OptionExt;
 use snafu::ResultExt;
 use snafu::Snafu;
 
+use crate::base_refresh::BaseRefresh;
+use crate::base_refresh::ParseTtlError;
+use crate::build_context;
+use crate::build_context::ContextFilter;
+use crate::build_context::ListError;
+use crate::build_log;
+use crate::build_progress::StepCmdLogger;
 use crate::canon_path::AbsPath;
 use crate::canon_path::NewAbsPathError;
 use crate::canon_path::NewRelPathError;
 use crate::canon_path::RelPath;
 use crate::cmd_loggers::CapturingCmdLogger;
+use crate::cmd_loggers::TeeCmdLogger;
 use crate::cmd_loggers::TimingPrefixingCmdLogger;
+use crate::context_source;
+use crate::context_source::ParseContextSourceError;
+use crate::context_source::ResolveContextError;
+use crate::docker;
+use crate::docker::AssertRunError as DockerAssertRunError;
+use crate::dockerfile;
 use crate::fs;
 use crate::fs::FindAndOpenFileError;
+use crate::gc;
+use crate::history;
+use crate::hostpaths;
+use crate::hostpaths::DOCK_HOSTPATHS_VAR_NAME;
+use crate::hostpaths::Hostpaths;
+use crate::hostpaths::HostpathsError;
+use crate::interpolate;
+use crate::interpolate::InterpolateError;
+use crate::labels;
+use crate::lock::ImageLock;
+use crate::lock::LockError;
+use crate::lockfile;
+use crate::lockfile::PinDockerfileError;
 use crate::logging_process;
 use crate::logging_process::CmdLoggerMsg;
 use crate::logging_process::CommandLogger;
 use crate::logging_process::RunError as LoggingProcessRunError;
 use crate::option::OptionResultExt;
 use crate::rebuild;
+use crate::rebuild::BuildError;
+use crate::rebuild::Builder;
 use crate::rebuild::DockerContext;
 use crate::rebuild::RebuildError;
+use crate::registry;
+use crate::registry::PullError;
 use crate::spinner;
+use crate::spinner::Board;
 use crate::spinner::SpinError;
-use crate::trie::InsertError;
-use crate::trie::Trie;
+use crate::spinner::Status;
+use crate::staged_run;
+use crate::staged_run::Artifact;
+use crate::staged_run::ProjectCopy;
+use crate::staged_run::StagedRun;
+use crate::staged_run::StagedRunError;
 
 #[derive(Deserialize)]
 pub struct DockConfig {
@@ -56,6 +98,7 @@ pub struct DockConfig {
     pub organisation: String,
     pub project: String,
     pub default_shell_env: String,
+    pub registry: Option<String>,
     pub environments: HashMap<String, DockEnvironmentConfig>
 }
 
@@ -63,15 +106,76 @@ pub struct DockConfig {
 // or to read them as `String`s and parse them directly.
 #[derive(Deserialize)]
 pub struct DockEnvironmentConfig {
-    pub context: Option<PathBuf>,
+    pub context: Option<String>,
+    pub context_include: Option<Vec<String>>,
+    pub context_exclude: Option<Vec<String>>,
+    pub context_git_tracked: Option<bool>,
     pub workdir: Option<String>,
-    pub build_args: Option<Vec<String>>,
+    pub build_args: Option<DockEnvironmentBuildArgsConfig>,
+    pub build_secrets: Option<DockEnvironmentBuildSecretsConfig>,
     pub run_args: Option<Vec<String>>,
     pub env: Option<HashMap<String, String>>,
     pub cache_volumes: Option<HashMap<String, PathBuf>>,
     pub mounts: Option<HashMap<PathBuf, PathBuf>>,
     pub mount_local: Option<Vec<DockEnvironmentMountLocalConfig>>,
     pub shell: Option<PathBuf>,
+    pub follow_cwd: Option<bool>,
+    pub artifacts: Option<HashMap<PathBuf, DockEnvironmentArtifactConfig>>,
+    pub image_history: Option<usize>,
+    pub builder: Option<DockEnvironmentBuilderConfig>,
+    pub cache_from: Option<Vec<String>>,
+    pub cache_to: Option<Vec<String>>,
+    pub ssh: Option<Vec<String>>,
+    pub target: Option<String>,
+    pub platform: Option<String>,
+    pub base_refresh: Option<String>,
+    pub depends_on_env: Option<DockEnvironmentDependsOnConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DockEnvironmentDependsOnConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+// `DockEnvironmentBuildArgsConfig` is either a list of raw `docker build`
+// arguments, or a map from build argument names to values.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DockEnvironmentBuildArgsConfig {
+    Raw(Vec<String>),
+    Map(BTreeMap<String, String>),
+}
+
+// `DockEnvironmentBuildSecretsConfig` is either a list of raw BuildKit
+// `--secret` specifications, or a map from secret IDs to their sources.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DockEnvironmentBuildSecretsConfig {
+    Raw(Vec<String>),
+    Map(BTreeMap<String, DockEnvironmentBuildSecretConfig>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DockEnvironmentBuildSecretConfig {
+    File(PathBuf),
+    Env(String),
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DockEnvironmentBuilderConfig {
+    Docker,
+    Buildx,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DockEnvironmentArtifactConfig {
+    Path(PathBuf),
+    Detailed{path: PathBuf, optional: Option<bool>},
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
@@ -101,10 +205,17 @@ impl CommandLogger for CmdLoggers<'_> {
     }
 }
 
+// `DEFAULT_CACHE_TAG` is the tag that the previous image of an environment
+// has while the environment is rebuilt, unless another tag is given.
+pub const DEFAULT_CACHE_TAG: &str = "cached";
+
+pub const CONTENT_HASH_LABEL: &str = "dock.content_hash";
+pub const PLATFORM_LABEL: &str = "dock.platform";
+
 pub fn run_in(
     // NOTE We would ideally take `logger` as `dyn CommandLogger`, but this
     // type can't be shared between threads safely, which is required by
-    // `spinner::spin`.
+    // `spinner::spin_status`.
     logger: &mut dyn CommandLogger,
     dock_file_name: &str,
     maybe_env_name: Option<&str>,
@@ -123,52 +234,52 @@ pub fn run_in(
     let env = conf.environments.get(env_name)
         .context(EnvironmentNotFound{name: env_name})?;
 
-    let img_name = image_name(&conf.organisation, &conf.project, env_name);
-    let target_img = img_name.clone() + ":latest";
-    let cache_img = img_name + ":" + &rebuild.cache_tag;
+    let img_name = platform_image_name(&conf, env_name, env, args.platform);
+    let target_img = img_name + ":latest";
 
     if let RebuildAction::Run = rebuild.action {
-        let env_context =
-            env.context
-                .as_ref()
-                .and_maybe_then(|path| RelPath::try_from(path.clone()))
-                .context(RelPathFromContextPathFailed)?;
-
-        let build_args = env.build_args.clone().unwrap_or_default();
-
-        let build_args: Vec<&str> =
-            build_args
-                .iter()
-                .map(AsRef::as_ref)
-                .collect();
+        let opts = BuildOpts{
+            cache_tag: &rebuild.cache_tag,
+            platform: args.platform,
+            pull_only: false,
+        };
 
-        let mut rebuild = || rebuild_for_run_in(
+        rebuild_with_deps(
             logger,
             &dock_dir,
+            &conf,
             env_name,
-            env_context.as_ref(),
-            &target_img,
-            &cache_img,
-            &build_args,
-        );
-
-        if show_rebuild_spinner {
-            let rebuild_msg = format!("Rebuilding '{target_img}'");
-            spinner::spin(
-                rebuild_msg,
-                rebuild,
-            )
-                .context(SpinFailed)?
-                .context(SpinnerRebuildForRunInFailed)?;
-        } else {
-            rebuild()
-                .context(RebuildForRunInFailed)?;
-        }
+            &opts,
+            show_rebuild_spinner,
+        )?;
     }
 
+    record_env_use(&conf, &dock_dir, env_name);
+
     let vol_name_prefix =
         cache_vol_name_prefix(&conf.organisation, &conf.project, env_name);
 
+    // The container and the cache volumes are labelled so that they can be
+    // found by `dock clean`.
+    let label_args = labels::label_args(&conf, &dock_dir, env_name);
+
+    // Artifacts defined by the environment are only extracted for commands,
+    // and not for shells.
+    let env_artifacts =
+        if shell.is_some() {
+            None
+        } else {
+            env.artifacts.as_ref()
+        };
+
+    let artifacts = artifacts(&dock_dir, env_artifacts, args.artifacts)
+        .context(ParseArtifactsFailed)?;
+
+    // We create, start and remove the container in separate steps if files
+    // need to be copied to or from the container, instead of replacing the
+    // current process with `docker run`.
+    let staged = args.hermetic || !artifacts.is_empty();
+
     let mut run_args = to_strings(&["run"]);
 
     if let Some(mut shell) = shell {
@@ -179,13 +290,25 @@ pub fn run_in(
         run_args.push(format!("--entrypoint={}", shell.display()));
     }
 
+    if let Some(platform) = env_platform(env, args.platform) {
+        run_args.push(format!("--platform={platform}"));
+    }
+
+    run_args.extend(label_args.iter().cloned());
+    run_args.push(labels::container_marker_arg());
+
     let main_run_args =
         prepare_run_in_args(
             logger,
             env,
             &dock_dir,
-            &vol_name_prefix,
+            &CacheVolumeNaming{
+                name_prefix: &vol_name_prefix,
+                labels: &label_args,
+            },
             &target_img,
+            args.hermetic,
+            staged,
         )
             .context(PrepareRunInArgsFailed)?;
 
@@ -197,6 +320,18 @@ pub fn run_in(
 
     run_args.extend(to_strings(args.command));
 
+    if staged {
+        return handle_staged_run_in(
+            logger,
+            &dock_dir,
+            env,
+            &vol_name_prefix,
+            &run_args[1..],
+            args,
+            &artifacts,
+        );
+    }
+
     // TODO Perform the side effects of `prepare_run_cache_volumes_args` here.
 
     let prog = OsStr::new("docker");
@@ -219,6 +354,136 @@ pub fn run_in(
 pub struct Args<'a> {
     pub docker: &'a [&'a str],
     pub command: &'a [&'a str],
+    // `hermetic` copies the Git-tracked files of the project into the
+    // container instead of bind-mounting the project directory, and only
+    // copies the declared `artifacts` back out.
+    pub hermetic: bool,
+    // `artifacts` are artifacts to extract in addition to those defined by
+    // the environment, in the form `<container-path>:<host-path>`, with an
+    // optional `:optional` suffix.
+    pub artifacts: &'a [&'a str],
+    // `platform` overrides the `platform` of the environment.
+    pub platform: Option<&'a str>,
+}
+
+fn handle_staged_run_in(
+    logger: &mut dyn CommandLogger,
+    dock_dir: &AbsPath,
+    env: &DockEnvironmentConfig,
+    vol_name_prefix: &str,
+    create_args: &[String],
+    args: &Args,
+    artifacts: &[Artifact],
+)
+    -> Result<ExitStatus, RunInError>
+{
+    let mut project_copy = None;
+    if args.hermetic {
+        let workdir = env.workdir.as_ref()
+            .context(HermeticWorkdirNotSet)?;
+
+        project_copy = Some(ProjectCopy{src: dock_dir, dst: workdir});
+    }
+
+    let container_name =
+        format!("{vol_name_prefix}.run.{}", process::id());
+
+    let staged_run = StagedRun{
+        container_name: &container_name,
+        create_args,
+        interactive: args.docker.contains(&"--interactive"),
+        project_copy,
+        artifacts,
+    };
+
+    staged_run::run(logger, &staged_run)
+        .context(StagedRunFailed)
+}
+
+fn artifacts(
+    dock_dir: &AbsPath,
+    env_artifacts: Option<&HashMap<PathBuf, DockEnvironmentArtifactConfig>>,
+    raw_arg_artifacts: &[&str],
+)
+    -> Result<Vec<Artifact>, ParseArtifactsError>
+{
+    type ArtifactConf = DockEnvironmentArtifactConfig;
+
+    let mut specs = vec![];
+
+    if let Some(env_artifacts) = env_artifacts {
+        for (src, conf) in env_artifacts {
+            let (dst, optional) =
+                match conf {
+                    ArtifactConf::Path(dst) => {
+                        (dst.clone(), false)
+                    },
+                    ArtifactConf::Detailed{path, optional} => {
+                        (path.clone(), optional.unwrap_or(false))
+                    },
+                };
+
+            specs.push((src.clone(), dst, optional));
+        }
+    }
+
+    for raw_artifact in raw_arg_artifacts {
+        let spec = parse_artifact_arg(raw_artifact)
+            .context(InvalidArtifactArg{arg: *raw_artifact})?;
+
+        specs.push(spec);
+    }
+
+    let mut artifacts = vec![];
+    for (src, raw_dst, optional) in specs {
+        let dst = RelPath::try_from(raw_dst.clone())
+            .context(RelPathFromArtifactPathFailed{path: raw_dst})?;
+
+        artifacts.push(Artifact{src, dst: dock_dir.concat(&dst), optional});
+    }
+
+    Ok(artifacts)
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseArtifactsError {
+    #[snafu(display("Invalid artifact '{}': {}", arg, source))]
+    InvalidArtifactArg{source: ParseArtifactArgError, arg: String},
+    #[snafu(display(
+        "Couldn't get artifact path '{}' as a relative path: {}",
+        path.display(),
+        source,
+    ))]
+    RelPathFromArtifactPathFailed{source: NewRelPathError, path: PathBuf},
+}
+
+fn parse_artifact_arg(raw: &str)
+    -> Result<(PathBuf, PathBuf, bool), ParseArtifactArgError>
+{
+    let (raw, optional) =
+        match raw.strip_suffix(":optional") {
+            Some(raw) => (raw, true),
+            None => (raw, false),
+        };
+
+    let (src, dst) = raw.split_once(':')
+        .context(MissingArtifactSeparator)?;
+
+    if src.is_empty() || dst.is_empty() {
+        return Err(ParseArtifactArgError::EmptyArtifactPath);
+    }
+
+    Ok((PathBuf::from(src), PathBuf::from(dst), optional))
+}
+
+#[derive(Debug, Snafu)]
+pub enum ParseArtifactArgError {
+    #[snafu(display(
+        "expected the form `<container-path>:<host-path>[:optional]`",
+    ))]
+    MissingArtifactSeparator,
+    #[snafu(display("artifact paths can't be empty"))]
+    EmptyArtifactPath,
 }
 
 pub struct Rebuild {
@@ -231,6 +496,16 @@ pub enum RebuildAction {
     Skip,
 }
 
+// `BuildOpts` are the options for rebuilding the images of environments.
+pub struct BuildOpts<'a> {
+    pub cache_tag: &'a str,
+    // `platform` overrides the `platform` of each environment that's rebuilt.
+    pub platform: Option<&'a str>,
+    // `pull_only` pulls images from the registry of the project instead of
+    // rebuilding them, and fails if an image can't be pulled.
+    pub pull_only: bool,
+}
+
 // TODO The following variants don't need to contain `dock_file_name` as a
 // field because it's passed to the `run_with_extra_prefix_args`, but we
 // include it for now for simplicity.
@@ -248,16 +523,53 @@ pub enum RunInError {
     #[snafu(display("Dock environment '{}' isn't defined", name))]
     EnvironmentNotFound{name: String},
     #[snafu(display(
-        "Couldn't get path to the context directory as a relative path: {}",
+        "Invalid `base_refresh` for '{}': {}",
+        name,
+        source,
+    ))]
+    ParseBaseRefreshFailed{source: ParseTtlError, name: String},
+    #[snafu(display("Couldn't lock '{}' for rebuilding: {}", img, source))]
+    LockImageFailed{source: LockError, img: String},
+    #[snafu(display("Couldn't mark '{}' as rebuilt: {}", img, source))]
+    MarkRebuiltFailed{source: IoError, img: String},
+    #[snafu(display("Couldn't determine the build order: {}", source))]
+    EnvBuildOrderFailed{source: EnvBuildOrderError},
+    #[snafu(display(
+        "Couldn't get the dependencies of '{}': {}",
+        name,
+        source,
+    ))]
+    EnvDepsFailed{source: EnvDepsError, name: String},
+    #[snafu(display(
+        "Couldn't get the ID of the rebuilt image '{}': {}",
+        img,
         source,
     ))]
-    RelPathFromContextPathFailed{source: NewRelPathError},
+    InspectRebuiltImageFailed{source: DockerAssertRunError, img: String},
     #[snafu(display("{}", source))]
     SpinFailed{source: SpinError},
+    #[snafu(display("Couldn't prepare input for `docker build`: {}", source))]
+    NewDockerContextFailed{source: NewDockerContextError},
+    #[snafu(display("Couldn't pin the base images: {}", source))]
+    PinDockerfileFailed{source: PinDockerfileError},
     #[snafu(display("{}", source))]
-    SpinnerRebuildForRunInFailed{source: RebuildForRunInError},
+    WritePlatformDockerfileFailed{source: PlatformDockerfileError},
+    #[snafu(display("Couldn't hash the build context: {}", source))]
+    HashContextFailed{source: ContextHashInputsError},
     #[snafu(display("{}", source))]
     RebuildForRunInFailed{source: RebuildForRunInError},
+    #[snafu(display(
+        "Rebuild of '{}' failed; its output was saved to '{}'",
+        img,
+        log_path.display(),
+    ))]
+    BuildFailed{img: String, env_name: String, log_path: PathBuf},
+    #[snafu(display(
+        "Couldn't save the build log to '{}': {}",
+        path.display(),
+        source,
+    ))]
+    WriteBuildLogFailed{source: IoError, path: PathBuf},
     #[snafu(display(
         "Couldn't prepare arguments for `docker run`: {}",
         source,
@@ -265,12 +577,172 @@ pub enum RunInError {
     PrepareRunInArgsFailed{source: PrepareRunInArgsError},
     #[snafu(display("`exec` failed: {}", source))]
     ExecFailed{source: IoError},
+    #[snafu(display("`workdir` is required for hermetic runs"))]
+    HermeticWorkdirNotSet,
+    #[snafu(display("{}", source))]
+    ParseArtifactsFailed{source: ParseArtifactsError},
+    #[snafu(display("{}", source))]
+    StagedRunFailed{source: StagedRunError},
+}
+
+impl RunInError {
+    // `build_log_path` returns the path of the saved build log, if this error
+    // is the result of an unsuccessful rebuild.
+    pub fn build_log_path(&self) -> Option<(&str, &Path)> {
+        match self {
+            Self::BuildFailed{env_name, log_path, ..} => {
+                Some((env_name, log_path))
+            },
+            _ => {
+                None
+            },
+        }
+    }
 }
 
 pub fn image_name(org: &str, proj: &str, env_name: &str) -> String {
     format!("{org}/{proj}.{env_name}")
 }
 
+// `platform_image_name` returns the name of the image of `env_name` when it's
+// built for `platform`. Images that are built for a platform other than the
+// `platform` of `env` have the platform appended to their names, so that they
+// don't replace the images for the configured platform.
+pub fn platform_image_name(
+    conf: &DockConfig,
+    env_name: &str,
+    env: &DockEnvironmentConfig,
+    platform: Option<&str>,
+)
+    -> String
+{
+    let img_name = image_name(&conf.organisation, &conf.project, env_name);
+
+    match platform {
+        Some(p) if env.platform.as_deref() != Some(p) => {
+            // Environment names can't contain `-`, so the suffixed name can't
+            // be the name of the image of another environment.
+            format!("{img_name}-{}", p.replace('/', "-"))
+        },
+        _ => {
+            img_name
+        },
+    }
+}
+
+// `replace_platform_images` returns `dockerfile` with the images of the
+// environments of `conf` that are used in its `FROM` instructions replaced by
+// their images for `platform`, so that an environment that's built for
+// `platform` is built from the images that were built for the same platform.
+pub fn replace_platform_images(
+    conf: &DockConfig,
+    dockerfile: &str,
+    platform: Option<&str>,
+)
+    -> String
+{
+    let img_prefix = format!("{}/{}.", conf.organisation, conf.project);
+
+    dockerfile::replace_from_images(dockerfile, |img| {
+        let name = strip_image_tag(img);
+        let dep = name.strip_prefix(&img_prefix)?;
+        let dep_env = conf.environments.get(dep)?;
+
+        let platform_name = platform_image_name(conf, dep, dep_env, platform);
+        if platform_name == name {
+            return None;
+        }
+
+        Some(format!("{platform_name}{}", &img[name.len()..]))
+    })
+}
+
+// `write_platform_dockerfile` writes a copy of the Dockerfile at
+// `dockerfile_path` in which the images of other environments are replaced
+// using `replace_platform_images`, and returns its path. It returns `None` if
+// no images need to be replaced.
+fn write_platform_dockerfile(
+    conf: &DockConfig,
+    img_name: &str,
+    dockerfile_path: &AbsPath,
+    platform: Option<&str>,
+)
+    -> Result<Option<AbsPath>, PlatformDockerfileError>
+{
+    if platform.is_none() {
+        return Ok(None);
+    }
+
+    let dockerfile = std_fs::read(PathBuf::from(dockerfile_path.clone()))
+        .context(ReadSourceDockerfileFailed{path: dockerfile_path.clone()})?;
+    let dockerfile = String::from_utf8_lossy(&dockerfile);
+
+    let replaced = replace_platform_images(conf, &dockerfile, platform);
+    let from_images = dockerfile::from_images(&dockerfile);
+    if dockerfile::from_images(&replaced) == from_images {
+        return Ok(None);
+    }
+
+    // The Dockerfile is named after the image, which includes the platform,
+    // so that builds for different platforms don't share it.
+    let name = img_name.rsplit('/').next().unwrap_or(img_name);
+    let platform_dir =
+        build_log::project_cache_dir(&conf.organisation, &conf.project)
+            .join("platform");
+    let platform_path = platform_dir.join(format!("{name}.Dockerfile"));
+
+    std_fs::create_dir_all(&platform_dir)
+        .context(WriteReplacedDockerfileFailed{path: platform_dir})?;
+    std_fs::write(&platform_path, replaced)
+        .context(WriteReplacedDockerfileFailed{path: platform_path.clone()})?;
+
+    let platform_path = AbsPath::try_from(platform_path)
+        .context(ReplacedDockerfileAsAbsPathFailed)?;
+
+    Ok(Some(platform_path))
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum PlatformDockerfileError {
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    ReadSourceDockerfileFailed{source: IoError, path: AbsPath},
+    #[snafu(display(
+        "Couldn't write the platform Dockerfile to '{}': {}",
+        path.display(),
+        source,
+    ))]
+    WriteReplacedDockerfileFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't get the path of the platform Dockerfile as an absolute \
+         path: {}",
+        source,
+    ))]
+    ReplacedDockerfileAsAbsPathFailed{source: NewAbsPathError},
+}
+
+// `platform_image_names_pattern` returns a `reference` filter for
+// `docker image ls` that matches the names of the images of `img_name` that
+// were built for other platforms.
+pub fn platform_image_names_pattern(img_name: &str) -> String {
+    format!("{img_name}-*")
+}
+
+// `env_platform` returns the platform that `env` is built for and run on,
+// where `platform` overrides the `platform` of `env`.
+pub fn env_platform<'a>(
+    env: &'a DockEnvironmentConfig,
+    platform: Option<&'a str>,
+)
+    -> Option<&'a str>
+{
+    platform.or(env.platform.as_deref())
+}
+
 pub fn find_and_parse_dock_config(dock_file_name: &str)
     -> Result<(AbsPath, DockConfig), FindAndParseDockConfigError>
 {
@@ -366,123 +838,1433 @@ fn parse_dock_config(file: File) -> Result<DockConfig, ParseDockConfigError> {
 }
 
 #[derive(Debug, Snafu)]
-pub enum ParseDockConfigError {
-    #[snafu(display("Couldn't parse: {}", source))]
-    ParseYamlFailed{source: SerdeYamlError},
-    #[snafu(display("Only `schema_version` 0.1 is currently supported"))]
-    UnsupportedSchemaVersion,
-    #[snafu(display("Missing `schema_version` field"))]
-    MissingSchemaVersion,
-    #[snafu(display("Parsed YAML didn't conform to schema: {}", source))]
-    ParseSchemaFailed{source: SerdeYamlError},
+pub enum ParseDockConfigError {
+    #[snafu(display("Couldn't parse: {}", source))]
+    ParseYamlFailed{source: SerdeYamlError},
+    #[snafu(display("Only `schema_version` 0.1 is currently supported"))]
+    UnsupportedSchemaVersion,
+    #[snafu(display("Missing `schema_version` field"))]
+    MissingSchemaVersion,
+    #[snafu(display("Parsed YAML didn't conform to schema: {}", source))]
+    ParseSchemaFailed{source: SerdeYamlError},
+}
+
+// `rebuild_with_deps` rebuilds the image for `env_name`, after rebuilding the
+// images of the environments that it depends on, so that it isn't built from
+// stale images.
+pub fn rebuild_with_deps(
+    logger: &mut dyn CommandLogger,
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    opts: &BuildOpts,
+    show_rebuild_spinner: bool,
+)
+    -> Result<(), RunInError>
+{
+    let build_order = env_build_order(dock_dir, conf, &[env_name])
+        .context(EnvBuildOrderFailed)?;
+
+    let display =
+        if show_rebuild_spinner {
+            RebuildDisplay::Spinner
+        } else {
+            RebuildDisplay::Plain
+        };
+
+    let mut img_ids = HashMap::new();
+    for build_env_name in build_order {
+        let img_id = rebuild_env(
+            logger,
+            dock_dir,
+            conf,
+            build_env_name,
+            opts,
+            &img_ids,
+            &display,
+        )?;
+
+        img_ids.insert(build_env_name.to_string(), img_id);
+    }
+
+    Ok(())
+}
+
+// `rebuild_env` rebuilds the image for `env_name`, and returns the ID of the
+// rebuilt image. `img_ids` must contain the image IDs of the environments
+// that `env_name` depends on.
+pub fn rebuild_env(
+    logger: &mut dyn CommandLogger,
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    opts: &BuildOpts,
+    img_ids: &HashMap<String, String>,
+    display: &RebuildDisplay,
+)
+    -> Result<String, RunInError>
+{
+    let env = conf.environments.get(env_name)
+        .context(EnvironmentNotFound{name: env_name})?;
+
+    let platform = env_platform(env, opts.platform);
+    let img_name = platform_image_name(conf, env_name, env, opts.platform);
+    let target_img = img_name.clone() + ":latest";
+    let cache_img = img_name.clone() + ":" + opts.cache_tag;
+
+    let deps = env_deps(dock_dir, conf, env_name, env)
+        .context(EnvDepsFailed{name: env_name})?;
+
+    let base_refresh =
+        env.base_refresh
+            .as_ref()
+            .and_maybe_then(|raw| BaseRefresh::new(conf, &img_name, raw))
+            .context(ParseBaseRefreshFailed{name: env_name})?;
+
+    let mut content_hash_inputs =
+        parent_hash_inputs(&deps, img_ids, platform);
+
+    // Concurrent rebuilds of the same image race on its tags, so we only
+    // allow one rebuild of an image at a time. If another process rebuilt the
+    // image while we were waiting then we use its image instead of
+    // rebuilding.
+    let mut lock = ImageLock::lock(&img_name, || {
+        display.notify(format!(
+            "Waiting for another rebuild of '{target_img}' to finish...",
+        ));
+    })
+        .context(LockImageFailed{img: img_name.clone()})?;
+
+    if !lock.rebuilt_by_other() {
+        // We prepare the context before the spinner is shown, so that a
+        // warning about its size isn't overwritten by the spinner.
+        let (docker_context, context_inputs) = prepare_docker_context(
+            dock_dir,
+            conf,
+            env_name,
+            env,
+            &img_name,
+            opts.platform,
+        )?;
+        content_hash_inputs.extend(context_inputs);
+
+        let maybe_context_summary =
+            context_summary(&docker_context, env_name, display);
+
+        let mut label_args = labels::label_args(conf, dock_dir, env_name);
+        label_args.push(labels::image_marker_arg(&img_name));
+
+        let img_build = ImageBuild{
+            img_name: &img_name,
+            img: &target_img,
+            cache_img: &cache_img,
+            platform,
+            content_hash_inputs: &content_hash_inputs,
+            registry: conf.registry.as_deref(),
+            pull_only: opts.pull_only,
+            base_refresh: base_refresh.as_ref(),
+            labels: &label_args,
+        };
+
+        let rebuild = |logger: &mut dyn CommandLogger| rebuild_for_run_in(
+            logger,
+            dock_dir,
+            env,
+            docker_context,
+            &img_build,
+        );
+
+        run_rebuild(
+            logger,
+            conf,
+            env_name,
+            &target_img,
+            maybe_context_summary,
+            display,
+            rebuild,
+        )?;
+
+        lock.mark_rebuilt()
+            .context(MarkRebuiltFailed{img: img_name})?;
+    }
+
+    let inspect_args = ["image", "inspect", "--format={{.Id}}", &target_img];
+    let output = docker::assert_run(inspect_args)
+        .context(InspectRebuiltImageFailed{img: target_img.clone()})?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+// `RebuildDisplay` defines how the progress of a rebuild, and notices about
+// it, are shown to the user.
+pub enum RebuildDisplay<'a> {
+    // `Plain` doesn't show the progress of the rebuild, and prints notices to
+    // STDERR.
+    Plain,
+    // `Spinner` shows a spinner, along with the current build step, while the
+    // image is rebuilt, and prints notices to STDERR before the spinner is
+    // shown.
+    Spinner,
+    // `Board` shows the current build step on line `i` of `board`, and adds
+    // notices to the notes of `board`, so that they aren't overwritten when
+    // `board` is redrawn.
+    Board{board: &'a Board, i: usize},
+}
+
+impl RebuildDisplay<'_> {
+    // `notify` shows `msg` to the user without garbling the progress of the
+    // rebuild.
+    fn notify(&self, msg: String) {
+        match self {
+            Self::Plain | Self::Spinner => {
+                eprintln!("{msg}");
+            },
+            Self::Board{board, ..} => {
+                board.note(msg);
+            },
+        }
+    }
+}
+
+// `parent_hash_inputs` returns the content hash inputs for the images of
+// `deps`, whose IDs are in `img_ids`, and for `platform`. The content hash of
+// an image covers the images of the environments that it depends on, so that
+// it changes when a parent image changes, and its platform, so that images
+// for different platforms have different content hashes.
+fn parent_hash_inputs(
+    deps: &[String],
+    img_ids: &HashMap<String, String>,
+    platform: Option<&str>,
+)
+    -> Vec<String>
+{
+    let parent_ids: BTreeMap<&str, &str> =
+        deps
+            .iter()
+            .filter_map(|dep| {
+                img_ids.get(dep).map(|id| (dep.as_str(), id.as_str()))
+            })
+            .collect();
+    let mut inputs: Vec<String> =
+        parent_ids
+            .iter()
+            .map(|(parent, id)| format!("{parent}={id}"))
+            .collect();
+
+    // Environment names can't contain `:`, so this input can't be confused
+    // with a parent image.
+    if let Some(platform) = platform {
+        inputs.push(format!("build:platform={platform}"));
+    }
+
+    inputs
+}
+
+// `prepare_docker_context` returns the Docker context for rebuilding the
+// image of `env_name`, along with its content hash inputs. The Dockerfile of
+// the context has its base images pinned, and the images of the environments
+// that it's built from replaced by their images for `platform_override`, if
+// necessary.
+fn prepare_docker_context(
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    env: &DockEnvironmentConfig,
+    img_name: &str,
+    platform_override: Option<&str>,
+)
+    -> Result<(DockerContext, Vec<String>), RunInError>
+{
+    let dockerfile_path = dockerfile_path(dock_dir, env_name);
+    let (docker_context, maybe_context_commit) = new_docker_context(
+        dock_dir,
+        conf,
+        env,
+        dockerfile_path.clone(),
+    )
+        .context(NewDockerContextFailed)?;
+
+    // If the project has a lock file then the environment is built from a
+    // copy of its Dockerfile with its base images pinned. The content hash
+    // covers the pinned Dockerfile, so it changes when a pin changes.
+    let maybe_pinned_dockerfile = lockfile::pin_dockerfile(
+        dock_dir,
+        conf,
+        env_name,
+        img_name,
+        &dockerfile_path,
+    )
+        .context(PinDockerfileFailed)?;
+    let docker_context =
+        match maybe_pinned_dockerfile {
+            Some(pinned) => docker_context.with_dockerfile(pinned),
+            None => docker_context,
+        };
+
+    // If the environment is built for a platform other than its configured
+    // one then the images of the environments that it's built from are
+    // replaced by their images for the same platform.
+    let maybe_platform_dockerfile = write_platform_dockerfile(
+        conf,
+        img_name,
+        docker_context.dockerfile(),
+        platform_override,
+    )
+        .context(WritePlatformDockerfileFailed)?;
+    let docker_context =
+        match maybe_platform_dockerfile {
+            Some(path) => docker_context.with_dockerfile(path),
+            None => docker_context,
+        };
+
+    // The content hash also covers the context, so that it changes when the
+    // context is updated. The content hash is only used to find the image in
+    // the registry, so the files of the context, which may take a while to
+    // read, are only hashed if the project has a registry.
+    let context_inputs = context_hash_inputs(
+        &docker_context,
+        maybe_context_commit.as_deref(),
+        conf.registry.is_some(),
+    )
+        .context(HashContextFailed)?;
+
+    Ok((docker_context, context_inputs))
+}
+
+// `context_summary` returns a summary of `docker_context` if it's filtered,
+// and uses `display` to warn the user if the context is large.
+fn context_summary(
+    docker_context: &DockerContext,
+    env_name: &str,
+    display: &RebuildDisplay,
+)
+    -> Option<String>
+{
+    let DockerContext::Filtered{files, ..} = docker_context else {
+        return None;
+    };
+
+    if files.size > build_context::SIZE_WARNING_THRESHOLD {
+        display.notify(format!(
+            "Warning: the context of '{env_name}' contains {}; consider \
+             narrowing it using `context_include` or `context_exclude`",
+            files.summary(),
+        ));
+    }
+
+    Some(files.summary())
+}
+
+// `run_rebuild` runs `rebuild`, showing its progress using `display`, and
+// writes the output of `rebuild` to the build log of `env_name` if the
+// rebuild of `img` is unsuccessful.
+fn run_rebuild<F>(
+    logger: &mut dyn CommandLogger,
+    conf: &DockConfig,
+    env_name: &str,
+    img: &str,
+    maybe_context_summary: Option<String>,
+    display: &RebuildDisplay,
+    rebuild: F,
+)
+    -> Result<(), RunInError>
+where
+    F: FnOnce(&mut dyn CommandLogger) -> Result<(), RebuildForRunInError>,
+{
+    // We capture the output of the rebuild so that it can be saved if the
+    // rebuild fails.
+    let mut tee = TeeCmdLogger::new(logger);
+
+    // The context summary is logged so that it's included in the debug
+    // output and in the build log.
+    if let Some(summary) = maybe_context_summary {
+        let msg = format!("Sending filtered context: {summary}\n");
+        tee.log(CmdLoggerMsg::StderrWrite(msg.as_bytes()));
+    }
+
+    let result =
+        match display {
+            RebuildDisplay::Plain => {
+                rebuild(&mut tee)
+            },
+            RebuildDisplay::Spinner => {
+                // We show the current build step next to the spinner, if it
+                // can be found in the build output.
+                let rebuild_msg = format!("Rebuilding '{img}'");
+                let status = Status::new(rebuild_msg.clone());
+                let mut step_logger = StepCmdLogger::new(&mut tee, |step| {
+                    status.set(format!("{rebuild_msg} {step}"));
+                });
+
+                spinner::spin_status(
+                    &status,
+                    || rebuild(&mut step_logger),
+                )
+                    .context(SpinFailed)?
+            },
+            RebuildDisplay::Board{board, i} => {
+                let mut step_logger = StepCmdLogger::new(&mut tee, |step| {
+                    board.set(*i, format!("rebuilding {step}"), true);
+                });
+
+                rebuild(&mut step_logger)
+            },
+        };
+
+    match result {
+        Ok(()) => {
+            Ok(())
+        },
+        Err(RebuildForRunInError::RebuildUnsuccessful{img}) => {
+            let log_path = build_log::last_build_log_path(
+                &conf.organisation,
+                &conf.project,
+                env_name,
+            );
+            build_log::write(&log_path, &tee.capture.chunks)
+                .context(WriteBuildLogFailed{path: log_path.clone()})?;
+
+            Err(RunInError::BuildFailed{
+                img,
+                env_name: env_name.to_string(),
+                log_path,
+            })
+        },
+        Err(source) => {
+            Err(RunInError::RebuildForRunInFailed{source})
+        },
+    }
+}
+
+// `env_build_order` returns `env_names` and the environments that they depend
+// on, directly or indirectly, in the order that they should be built.
+pub fn env_build_order<'a>(
+    dock_dir: &AbsPath,
+    conf: &'a DockConfig,
+    env_names: &[&str],
+)
+    -> Result<Vec<&'a str>, EnvBuildOrderError>
+{
+    let mut order = vec![];
+    let mut visiting = vec![];
+    for env_name in env_names {
+        visit_env(dock_dir, conf, env_name, &mut visiting, &mut order)?;
+    }
+
+    Ok(order)
+}
+
+fn visit_env<'a>(
+    dock_dir: &AbsPath,
+    conf: &'a DockConfig,
+    env_name: &str,
+    visiting: &mut Vec<String>,
+    order: &mut Vec<&'a str>,
+)
+    -> Result<(), EnvBuildOrderError>
+{
+    if order.contains(&env_name) {
+        return Ok(());
+    }
+
+    if visiting.iter().any(|name| name == env_name) {
+        let mut cycle = visiting.clone();
+        cycle.push(env_name.to_string());
+
+        return Err(EnvBuildOrderError::DependencyCycle{cycle});
+    }
+
+    let (env_name, env) = conf.environments.get_key_value(env_name)
+        .context(DependencyNotFound{name: env_name})?;
+
+    let deps = env_deps(dock_dir, conf, env_name, env)
+        .context(GetEnvDepsFailed{name: env_name})?;
+
+    visiting.push(env_name.clone());
+    for dep in &deps {
+        visit_env(dock_dir, conf, dep, visiting, order)?;
+    }
+    visiting.pop();
+
+    order.push(env_name);
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnvBuildOrderError {
+    #[snafu(display(
+        "Environments depend on each other: {}",
+        cycle.join(" -> "),
+    ))]
+    DependencyCycle{cycle: Vec<String>},
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    DependencyNotFound{name: String},
+    #[snafu(display(
+        "Couldn't get the dependencies of '{}': {}",
+        name,
+        source,
+    ))]
+    GetEnvDepsFailed{source: EnvDepsError, name: String},
+}
+
+// `env_deps` returns the environments that `env_name` depends on, which are
+// the environments listed in `depends_on_env`, along with the environments
+// whose images are used in `FROM` instructions in the Dockerfile for
+// `env_name`.
+pub fn env_deps(
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    env: &DockEnvironmentConfig,
+)
+    -> Result<Vec<String>, EnvDepsError>
+{
+    let mut deps: Vec<String> =
+        match &env.depends_on_env {
+            None => {
+                vec![]
+            },
+            Some(DockEnvironmentDependsOnConfig::One(dep)) => {
+                vec![dep.clone()]
+            },
+            Some(DockEnvironmentDependsOnConfig::Many(deps)) => {
+                deps.clone()
+            },
+        };
+
+    let dockerfile_path = dockerfile_path(dock_dir, env_name);
+    let dockerfile = std_fs::read(PathBuf::from(dockerfile_path.clone()))
+        .context(ReadEnvDockerfileFailed{path: dockerfile_path})?;
+
+    let img_prefix = format!("{}/{}.", conf.organisation, conf.project);
+    let dockerfile = String::from_utf8_lossy(&dockerfile);
+    for img in dockerfile::from_images(&dockerfile) {
+        let img = strip_image_tag(&img);
+        if let Some(dep) = img.strip_prefix(&img_prefix) {
+            let known = conf.environments.contains_key(dep);
+            if known && !deps.iter().any(|d| d == dep) {
+                deps.push(dep.to_string());
+            }
+        }
+    }
+
+    Ok(deps)
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnvDepsError {
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    ReadEnvDockerfileFailed{source: IoError, path: AbsPath},
+}
+
+// `strip_image_tag` removes the tag and digest, if any, from `img`.
+fn strip_image_tag(img: &str) -> &str {
+    let img =
+        match img.split_once('@') {
+            Some((img, _)) => img,
+            None => img,
+        };
+
+    // A `:` before the last `/` is part of a registry host, rather than a
+    // tag.
+    let name_start = img.rfind('/').map_or(0, |i| i + 1);
+    match img[name_start..].find(':') {
+        Some(i) => &img[..name_start + i],
+        None => img,
+    }
+}
+
+pub fn dockerfile_path(dock_dir: &AbsPath, env_name: &str) -> AbsPath {
+    // TODO Consider the fact that `env_name` may contain `/`; it may be worth
+    // adding an `EnvName` type with validation in its constructor.
+    let dockerfile_name = OsString::from(format!("{env_name}.Dockerfile"));
+
+    dock_dir.concat(&rel_path_from_component(dockerfile_name))
+}
+
+// `ImageBuild` defines a rebuild of `img`, whose previous image is tagged as
+// `cache_img` for the duration of the rebuild. If `registry` is set then the
+// image is pulled from `registry`, if possible, instead of being rebuilt.
+struct ImageBuild<'a> {
+    img_name: &'a str,
+    img: &'a str,
+    cache_img: &'a str,
+    platform: Option<&'a str>,
+    content_hash_inputs: &'a [String],
+    registry: Option<&'a str>,
+    pull_only: bool,
+    base_refresh: Option<&'a BaseRefresh>,
+    labels: &'a [String],
+}
+
+fn rebuild_for_run_in(
+    logger: &mut dyn CommandLogger,
+    dock_dir: &AbsPath,
+    env: &DockEnvironmentConfig,
+    docker_context: DockerContext,
+    img_build: &ImageBuild,
+)
+    -> Result<(), RebuildForRunInError>
+{
+    let ImageBuild{img, cache_img, platform, content_hash_inputs, ..} =
+        *img_build;
+
+    let dockerfile_path = docker_context.dockerfile().clone();
+
+    let dockerfile = std_fs::read(PathBuf::from(dockerfile_path.clone()))
+        .context(ReadDockerfileFailed{path: dockerfile_path})?;
+
+    // We label the image with the hash of its Dockerfile so that previous
+    // images can be identified by `dock history`. The label is deterministic,
+    // so it doesn't prevent an unchanged build from producing the same image.
+    let hash_label = format!(
+        "--label={}=sha256:{:x}",
+        history::DOCKERFILE_HASH_LABEL,
+        Sha256::digest(&dockerfile),
+    );
+
+    let env_build_args = env_build_args(env)
+        .context(EnvBuildArgsFailed)?;
+
+    let buildkit_args = buildkit_args(dock_dir, env)
+        .context(BuildkitArgsFailed)?;
+
+    // The content hash also covers `content_hash_inputs` and the build
+    // settings that affect the content of the image, so that it changes when
+    // one of them changes, even if the Dockerfile doesn't.
+    let build_inputs = build_hash_inputs(env, &env_build_args);
+    let content_hash = content_hash(
+        &dockerfile,
+        content_hash_inputs.iter().chain(&build_inputs),
+    );
+    let content_hash_label =
+        format!("--label={CONTENT_HASH_LABEL}={content_hash}");
+
+    let history_depth = env.image_history.unwrap_or(0);
+
+    let registry_args = registry_build_args(
+        logger,
+        img_build,
+        &content_hash,
+        history_depth,
+    )?;
+    let registry_args =
+        match registry_args {
+            Some(registry_args) => {
+                registry_args
+            },
+            None => {
+                return Ok(());
+            },
+        };
+
+    let now = SystemTime::now();
+    let (base_args, pulled_bases) =
+        base_refresh_build_args(logger, img_build, &dockerfile, now)?;
+    let platform_args = platform_build_args(platform);
+
+    let mut args: Vec<&str> =
+        env_build_args
+            .iter()
+            .chain(&buildkit_args)
+            .chain(&platform_args)
+            .chain(&registry_args)
+            .chain(&base_args)
+            .chain(img_build.labels)
+            .map(AsRef::as_ref)
+            .collect();
+    args.push(&hash_label);
+    args.push(&content_hash_label);
+
+    let builder = env_builder(env);
+
+    let status = rebuild::rebuild(
+        logger,
+        img,
+        cache_img,
+        docker_context,
+        &args,
+        history_depth,
+        builder,
+    )
+        .context(RebuildFailed{img: img.to_string()})?;
+
+    if !status.success() {
+        let img = img.to_string();
+
+        return Err(RebuildForRunInError::RebuildUnsuccessful{img});
+    }
+
+    if let (Some(refresh), true) = (img_build.base_refresh, pulled_bases) {
+        refresh.record_pull(now)
+            .context(RecordBasePullFailed{
+                path: refresh.last_pull_path.clone(),
+            })?;
+    }
+
+    // We ignore the status code returned "by the build step" because there
+    // isn't anything to distinguish it from a status code returned "by the run
+    // step".
+    Ok(())
+}
+
+// `content_hash` returns the content hash of an image built from
+// `dockerfile`, which also covers `inputs`.
+fn content_hash<'a>(
+    dockerfile: &[u8],
+    inputs: impl Iterator<Item = &'a String>,
+)
+    -> String
+{
+    let mut hasher = Sha256::new();
+    hasher.update(dockerfile);
+    for input in inputs {
+        hasher.update(format!("\0{input}"));
+    }
+
+    format!("sha256:{:x}", hasher.finalize())
+}
+
+// `registry_build_args` pulls the image of `img_build` from its registry, if
+// it has one and the current image doesn't have `content_hash`, and returns
+// the extra arguments for building the image locally. `None` is returned if
+// the image doesn't need to be built, because it was pulled, or because it's
+// up to date and only pulls were requested.
+fn registry_build_args(
+    logger: &mut dyn CommandLogger,
+    img_build: &ImageBuild,
+    content_hash: &str,
+    history_depth: usize,
+)
+    -> Result<Option<Vec<String>>, RebuildForRunInError>
+{
+    let Some(registry) = img_build.registry else {
+        return Ok(Some(vec![]));
+    };
+    let img = img_build.img;
+
+    let remote_img =
+        registry::remote_image(registry, img_build.img_name, content_hash);
+
+    // We only try to pull the image if the current image has a different
+    // content hash, so that the registry isn't queried on every run.
+    if registry::content_hash(img).as_deref() == Some(content_hash) {
+        if img_build.pull_only {
+            return Ok(None);
+        }
+    } else {
+        let pulled = registry::pull(
+            logger,
+            &remote_img,
+            img,
+            img_build.cache_img,
+            history_depth,
+        )
+            .context(PullFailed)?;
+
+        if pulled {
+            return Ok(None);
+        }
+        if img_build.pull_only {
+            return Err(RebuildForRunInError::NotInRegistry{img: remote_img});
+        }
+    }
+
+    // Images that are built locally include their build cache, so that
+    // rebuilds of a pulled image can use its layers as a cache.
+    Ok(Some(vec![
+        "--build-arg=BUILDKIT_INLINE_CACHE=1".to_string(),
+        format!("--cache-from={remote_img}"),
+    ]))
+}
+
+// `base_refresh_build_args` refreshes the base images of `dockerfile`, if
+// `img_build` requests it and they're due to be refreshed, and returns the
+// extra arguments for building the image, along with whether the base images
+// were pulled.
+fn base_refresh_build_args(
+    logger: &mut dyn CommandLogger,
+    img_build: &ImageBuild,
+    dockerfile: &[u8],
+    now: SystemTime,
+)
+    -> Result<(Vec<String>, bool), RebuildForRunInError>
+{
+    let Some(refresh) = img_build.base_refresh else {
+        return Ok((vec![], false));
+    };
+    let dockerfile = String::from_utf8_lossy(dockerfile);
+
+    refresh.prepare(logger, &dockerfile, img_build.platform, now)
+        .context(RefreshBaseImagesFailed)
+}
+
+// `build_hash_inputs` returns the content hash inputs for the build settings
+// of `env` that affect the content of its image. `env_build_args` are hashed
+// after interpolation, so that the hash changes when an interpolated value
+// changes. Only the IDs of build secrets are hashed, so that their values
+// can't be recovered from the hash. Cache sources and destinations aren't
+// hashed because they only affect the speed of the build, and local paths in
+// other settings aren't hashed so that the hash doesn't depend on the
+// location of the project.
+fn build_hash_inputs(env: &DockEnvironmentConfig, env_build_args: &[String])
+    -> Vec<String>
+{
+    let mut inputs: Vec<String> =
+        env_build_args
+            .iter()
+            .map(|arg| format!("build:arg={arg}"))
+            .collect();
+
+    if let Some(target) = &env.target {
+        inputs.push(format!("build:target={target}"));
+    }
+
+    let secret_ids: Vec<&str> =
+        match &env.build_secrets {
+            Some(DockEnvironmentBuildSecretsConfig::Raw(specs)) => {
+                specs
+                    .iter()
+                    .filter_map(|spec| {
+                        spec.split(',').find_map(|field| {
+                            field.strip_prefix("id=")
+                        })
+                    })
+                    .collect()
+            },
+            Some(DockEnvironmentBuildSecretsConfig::Map(secrets)) => {
+                secrets.keys().map(String::as_str).collect()
+            },
+            None => {
+                vec![]
+            },
+        };
+    for id in secret_ids {
+        inputs.push(format!("build:secret={id}"));
+    }
+
+    inputs
+}
+
+// `platform_build_args` returns the `docker build` arguments for building an
+// image for `platform`. The image is labelled with its platform so that
+// platform-specific images can be found by `dock clean`.
+fn platform_build_args(platform: Option<&str>) -> Vec<String> {
+    match platform {
+        Some(platform) => vec![
+            format!("--platform={platform}"),
+            format!("--label={PLATFORM_LABEL}={platform}"),
+        ],
+        None => vec![],
+    }
+}
+
+fn env_builder(env: &DockEnvironmentConfig) -> Builder {
+    match env.builder {
+        Some(DockEnvironmentBuilderConfig::Buildx) => Builder::Buildx,
+        Some(DockEnvironmentBuilderConfig::Docker) | None => Builder::Docker,
+    }
+}
+
+// `build_debug_img` builds `img` from the Dockerfile at `dockerfile_path`,
+// using the build settings of `env`, without keeping or replacing any
+// existing image. The `target` and `cache_to` settings aren't used, because
+// the Dockerfile is expected to be a truncated version of the environment's
+// Dockerfile, which may not contain the target stage.
+pub fn build_debug_img(
+    logger: &mut dyn CommandLogger,
+    dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env_name: &str,
+    dockerfile_path: AbsPath,
+    img: &str,
+    platform: Option<&str>,
+)
+    -> Result<ExitStatus, BuildDebugImgError>
+{
+    let env = conf.environments.get(env_name)
+        .context(DebugEnvironmentNotFound{name: env_name})?;
+
+    let env_build_args = env_build_args(env)
+        .context(DebugEnvBuildArgsFailed)?;
+
+    let buildkit_args = buildkit_args(dock_dir, env)
+        .context(DebugBuildkitArgsFailed)?;
+
+    let platform_args = platform_build_args(platform);
+
+    // The debug image is labelled so that it can be found by `dock clean` if
+    // it isn't removed after the debug shell exits.
+    let img_name = img.rsplit_once(':').map_or(img, |(img_name, _)| img_name);
+    let mut label_args = labels::label_args(conf, dock_dir, env_name);
+    label_args.push(labels::image_marker_arg(img_name));
+
+    let args: Vec<&str> =
+        env_build_args
+            .iter()
+            .chain(&buildkit_args)
+            .chain(&platform_args)
+            .chain(&label_args)
+            .map(AsRef::as_ref)
+            .filter(|arg: &&str| {
+                !arg.starts_with("--target=")
+                    && !arg.starts_with("--cache-to=")
+            })
+            .collect();
+
+    let (docker_context, _) =
+        new_docker_context(dock_dir, conf, env, dockerfile_path)
+            .context(NewDebugDockerContextFailed)?;
+
+    rebuild::build(logger, img, docker_context, &args, env_builder(env))
+        .context(BuildDebugImgFailed{img})
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum BuildDebugImgError {
+    #[snafu(display("Dock environment '{}' isn't defined", name))]
+    DebugEnvironmentNotFound{name: String},
+    #[snafu(display("{}", source))]
+    DebugEnvBuildArgsFailed{source: EnvBuildArgsError},
+    #[snafu(display("{}", source))]
+    DebugBuildkitArgsFailed{source: BuildkitArgsError},
+    #[snafu(display("Couldn't prepare input for `docker build`: {}", source))]
+    NewDebugDockerContextFailed{source: NewDockerContextError},
+    #[snafu(display("Couldn't build '{}': {}", img, source))]
+    BuildDebugImgFailed{source: BuildError, img: String},
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum RebuildForRunInError {
+    #[snafu(display(
+        "Couldn't read the Dockerfile '{}': {}",
+        path.display_lossy(),
+        source,
+    ))]
+    ReadDockerfileFailed{source: IoError, path: AbsPath},
+    #[snafu(display("{}", source))]
+    EnvBuildArgsFailed{source: EnvBuildArgsError},
+    #[snafu(display("{}", source))]
+    BuildkitArgsFailed{source: BuildkitArgsError},
+    #[snafu(display("Couldn't rebuild '{}': {}", img, source))]
+    RebuildFailed{
+        source: RebuildError<ExitStatus, BuildError>,
+        img: String,
+    },
+    #[snafu(display("Rebuild of '{}' returned an unsuccessful status", img))]
+    RebuildUnsuccessful{img: String},
+    #[snafu(display("Couldn't pull from the registry: {}", source))]
+    PullFailed{source: PullError},
+    #[snafu(display("'{}' couldn't be pulled from the registry", img))]
+    NotInRegistry{img: String},
+    #[snafu(display("Couldn't refresh the base images: {}", source))]
+    RefreshBaseImagesFailed{source: LoggingProcessRunError},
+    #[snafu(display(
+        "Couldn't record the base image pull in '{}': {}",
+        path.display(),
+        source,
+    ))]
+    RecordBasePullFailed{source: IoError, path: PathBuf},
+}
+
+// `buildkit_args` returns the `docker build` arguments for the BuildKit
+// settings of `env`. Relative local paths in these settings are resolved
+// relative to `dock_dir`, so that they don't depend on the current directory.
+fn buildkit_args(dock_dir: &AbsPath, env: &DockEnvironmentConfig)
+    -> Result<Vec<String>, BuildkitArgsError>
+{
+    let mut args = vec![];
+
+    let settings = [
+        ("cache-from", &env.cache_from, &["src"][..]),
+        ("cache-to", &env.cache_to, &["dest"][..]),
+    ];
+    for (flag, maybe_specs, path_keys) in settings {
+        for spec in maybe_specs.iter().flatten() {
+            let spec = resolve_spec_paths(dock_dir, spec, path_keys)
+                .context(ResolveSettingPathsFailed{flag, spec})?;
+
+            args.push(format!("--{flag}={spec}"));
+        }
+    }
+
+    // `ssh` values have the form `<id>[=<path>[,<path>]]`.
+    for spec in env.ssh.iter().flatten() {
+        let spec =
+            if let Some((id, paths)) = spec.split_once('=') {
+                let paths = paths
+                    .split(',')
+                    .map(|path| resolve_local_path(dock_dir, path))
+                    .collect::<Result<Vec<String>, _>>()
+                    .context(ResolveSettingPathsFailed{flag: "ssh", spec})?;
+
+                format!("{id}={}", paths.join(","))
+            } else {
+                spec.clone()
+            };
+
+        args.push(format!("--ssh={spec}"));
+    }
+
+    if let Some(target) = &env.target {
+        args.push(format!("--target={target}"));
+    }
+
+    if let Some(build_secrets) = &env.build_secrets {
+        let secret_args = build_secret_args(dock_dir, build_secrets)?;
+        args.extend(secret_args);
+    }
+
+    Ok(args)
+}
+
+// `build_secret_args` returns the `docker build` arguments for
+// `build_secrets`. Secrets are passed to `docker build` by file path or by
+// environment variable name, so their values are never read by `dock`, and so
+// can't appear in debugging output or error messages.
+fn build_secret_args(
+    dock_dir: &AbsPath,
+    build_secrets: &DockEnvironmentBuildSecretsConfig,
+)
+    -> Result<Vec<String>, BuildkitArgsError>
+{
+    let secrets =
+        match build_secrets {
+            DockEnvironmentBuildSecretsConfig::Raw(specs) => {
+                let mut args = vec![];
+                for spec in specs {
+                    let spec = resolve_spec_paths(dock_dir, spec, &["src"])
+                        .context(ResolveSettingPathsFailed{
+                            flag: "secret",
+                            spec,
+                        })?;
+
+                    args.push(format!("--secret={spec}"));
+                }
+
+                return Ok(args);
+            },
+            DockEnvironmentBuildSecretsConfig::Map(secrets) => {
+                secrets
+            },
+        };
+
+    let mut args = vec![];
+    for (id, conf) in secrets {
+        if id.is_empty() || id.contains(',') || id.contains('=') {
+            return Err(BuildkitArgsError::InvalidSecretId{id: id.clone()});
+        }
+
+        match conf {
+            DockEnvironmentBuildSecretConfig::File(path) => {
+                let path = PathBuf::from(dock_dir.clone()).join(path);
+                if !path.is_file() {
+                    return Err(BuildkitArgsError::SecretFileNotFound{
+                        id: id.clone(),
+                        path,
+                    });
+                }
+
+                let path = path.to_str()
+                    .context(NonUtf8SecretPath{id, path: path.clone()})?;
+
+                args.push(format!("--secret=id={id},src={path}"));
+            },
+            DockEnvironmentBuildSecretConfig::Env(var) => {
+                if env::var_os(var).is_none() {
+                    return Err(BuildkitArgsError::SecretEnvVarNotSet{
+                        id: id.clone(),
+                        var: var.clone(),
+                    });
+                }
+
+                args.push(format!("--secret=id={id},env={var}"));
+            },
+        }
+    }
+
+    Ok(args)
+}
+
+#[derive(Debug, Snafu)]
+pub enum BuildkitArgsError {
+    #[snafu(display("Couldn't resolve paths in `{}` value '{}'", flag, spec))]
+    ResolveSettingPathsFailed{
+        source: ResolveLocalPathError,
+        flag: String,
+        spec: String,
+    },
+    #[snafu(display(
+        "Build secret ID '{}' must be non-empty and can't contain `,` or `=`",
+        id,
+    ))]
+    InvalidSecretId{id: String},
+    #[snafu(display(
+        "The file '{}' for build secret '{}' doesn't exist",
+        path.display(),
+        id,
+    ))]
+    SecretFileNotFound{id: String, path: PathBuf},
+    #[snafu(display(
+        "The path '{}' for build secret '{}' isn't valid UTF-8",
+        path.display(),
+        id,
+    ))]
+    NonUtf8SecretPath{id: String, path: PathBuf},
+    #[snafu(display(
+        "The environment variable `{}` for build secret '{}' isn't set",
+        var,
+        id,
+    ))]
+    SecretEnvVarNotSet{id: String, var: String},
+}
+
+// `env_build_args` returns the `docker build` arguments defined by the
+// `build_args` of `env`. Values in the map form of `build_args` can refer to
+// host environment variables using `${NAME}`, and to the user and group IDs of
+// the current user using `${DOCK_UID}` and `${DOCK_GID}`.
+fn env_build_args(env: &DockEnvironmentConfig)
+    -> Result<Vec<String>, EnvBuildArgsError>
+{
+    let build_args =
+        match &env.build_args {
+            None => {
+                return Ok(vec![]);
+            },
+            Some(DockEnvironmentBuildArgsConfig::Raw(args)) => {
+                return Ok(args.clone());
+            },
+            Some(DockEnvironmentBuildArgsConfig::Map(build_args)) => {
+                build_args
+            },
+        };
+
+    let mut args = vec![];
+    for (name, raw_value) in build_args {
+        let value = interpolate::interpolate(raw_value, lookup_build_arg_var)
+            .context(InterpolateBuildArgFailed{name})?;
+
+        args.push(format!("--build-arg={name}={value}"));
+    }
+
+    Ok(args)
+}
+
+#[derive(Debug, Snafu)]
+pub enum EnvBuildArgsError {
+    #[snafu(display("Couldn't interpolate build arg `{}`: {}", name, source))]
+    InterpolateBuildArgFailed{
+        source: InterpolateError<LookupBuildArgVarError>,
+        name: String,
+    },
 }
 
-fn rebuild_for_run_in(
-    logger: &mut dyn CommandLogger,
-    dock_dir: &AbsPath,
-    env_name: &str,
-    maybe_context_sub_path: Option<&RelPath>,
-    img: &str,
-    cache_img: &str,
-    args: &[&str],
-)
-    -> Result<(), RebuildForRunInError>
+fn lookup_build_arg_var(name: &str)
+    -> Result<Option<String>, LookupBuildArgVarError>
 {
-    // TODO Consider the fact that `env_name` may contain `/`; it may be worth
-    // adding an `EnvName` type with validation in its constructor.
-    let dockerfile_name = OsString::from(format!("{env_name}.Dockerfile"));
-
-    let dockerfile_path =
-        dock_dir.concat(&rel_path_from_component(dockerfile_name));
+    match name {
+        "DOCK_UID" => {
+            let id = run_command("id", &["--user"])
+                .context(GetBuildArgUserIdFailed)?;
+
+            Ok(Some(id.trim_end().to_string()))
+        },
+        "DOCK_GID" => {
+            let id = run_command("id", &["--group"])
+                .context(GetBuildArgGroupIdFailed)?;
+
+            Ok(Some(id.trim_end().to_string()))
+        },
+        _ => {
+            match env::var(name) {
+                Ok(value) => {
+                    Ok(Some(value))
+                },
+                Err(VarError::NotPresent) => {
+                    Ok(None)
+                },
+                Err(VarError::NotUnicode(_)) => {
+                    Err(LookupBuildArgVarError::NonUnicodeVar)
+                },
+            }
+        },
+    }
+}
 
-    let docker_context = new_docker_context(
-        dock_dir,
-        dockerfile_path,
-        maybe_context_sub_path,
-    )
-        .context(NewDockerRebuildInputFailed)?;
+#[derive(Debug, Snafu)]
+pub enum LookupBuildArgVarError {
+    #[snafu(display("Couldn't get user ID for the active user: {}", source))]
+    GetBuildArgUserIdFailed{source: RunCommandError},
+    #[snafu(display("Couldn't get group ID for the active user: {}", source))]
+    GetBuildArgGroupIdFailed{source: RunCommandError},
+    #[snafu(display("The value isn't valid unicode"))]
+    NonUnicodeVar,
+}
 
-    let status = rebuild::rebuild(logger, img, cache_img, docker_context, args)
-        .context(RebuildFailed{img: img.to_string()})?;
+// `resolve_spec_paths` resolves the values of the `path_keys` fields of
+// `spec`, which has the form `<key>=<value>[,<key>=<value>]`.
+fn resolve_spec_paths(dock_dir: &AbsPath, spec: &str, path_keys: &[&str])
+    -> Result<String, ResolveLocalPathError>
+{
+    let mut fields = vec![];
+    for field in spec.split(',') {
+        match field.split_once('=') {
+            Some((key, value)) if path_keys.contains(&key) => {
+                let value = resolve_local_path(dock_dir, value)?;
+                fields.push(format!("{key}={value}"));
+            },
+            _ => {
+                fields.push(field.to_string());
+            },
+        }
+    }
 
-    if !status.success() {
-        let img = img.to_string();
+    Ok(fields.join(","))
+}
 
-        return Err(RebuildForRunInError::RebuildUnsuccessful{img});
+// `resolve_local_path` resolves `path` relative to `dock_dir` if it starts
+// with `./` or `../`. Other paths are returned unchanged.
+fn resolve_local_path(dock_dir: &AbsPath, path: &str)
+    -> Result<String, ResolveLocalPathError>
+{
+    if !path.starts_with("./") && !path.starts_with("../") {
+        return Ok(path.to_string());
     }
 
-    // We ignore the status code returned "by the build step" because there
-    // isn't anything to distinguish it from a status code returned "by the run
-    // step".
-    Ok(())
+    let resolved = PathBuf::from(dock_dir.clone()).join(path);
+
+    match resolved.to_str() {
+        Some(resolved) => Ok(resolved.to_string()),
+        None => Err(ResolveLocalPathError::NonUtf8Path{path: resolved}),
+    }
 }
 
-#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Snafu)]
-pub enum RebuildForRunInError {
-    #[snafu(display("Couldn't prepare input for `dock rebuild`: {}", source))]
-    NewDockerRebuildInputFailed{source: NewDockerContextError},
-    #[snafu(display("Couldn't rebuild '{}': {}", img, source))]
-    RebuildFailed{
-        source: RebuildError<ExitStatus, LoggingProcessRunError>,
-        img: String,
-    },
-    #[snafu(display("Rebuild of '{}' returned an unsuccessful status", img))]
-    RebuildUnsuccessful{img: String},
+pub enum ResolveLocalPathError {
+    #[snafu(display("'{}' isn't valid UTF-8", path.display()))]
+    NonUtf8Path{path: PathBuf},
 }
 
 fn rel_path_from_component(c: OsString) -> RelPath {
     RelPath::from(vec![c])
 }
 
+// `new_docker_context` returns the context used to build `env` from the
+// Dockerfile at `dockerfile_path`, along with the commit of the context, if
+// it's a Git repository.
 fn new_docker_context(
     dock_dir: &AbsPath,
+    conf: &DockConfig,
+    env: &DockEnvironmentConfig,
     dockerfile_path: AbsPath,
-    maybe_context_sub_path: Option<&RelPath>,
 )
-    -> Result<DockerContext, NewDockerContextError>
+    -> Result<(DockerContext, Option<String>), NewDockerContextError>
 {
-    if let Some(context_sub_path) = maybe_context_sub_path {
-        let context_path = dock_dir.concat(context_sub_path);
+    let maybe_source =
+        env.context
+            .as_ref()
+            .and_maybe_then(|raw| context_source::parse(dock_dir, raw))
+            .context(ParseContextSourceFailed)?;
+
+    let maybe_resolved =
+        maybe_source
+            .as_ref()
+            .and_maybe_then(|source| {
+                context_source::resolve(
+                    source,
+                    &conf.organisation,
+                    &conf.project,
+                )
+            })
+            .context(ResolveContextFailed)?;
+
+    match (maybe_resolved, context_filter(env)) {
+        (Some(resolved), Some(filter)) => {
+            let context_path = PathBuf::from(resolved.path);
+
+            let files = build_context::list(&context_path, &filter)
+                .context(ListContextFilesFailed{path: context_path})?;
+
+            let context =
+                DockerContext::Filtered{files, dockerfile: dockerfile_path};
+
+            Ok((context, resolved.commit))
+        },
+        (Some(resolved), None) => {
+            let context =
+                DockerContext::Dir{
+                    path: resolved.path,
+                    dockerfile: dockerfile_path,
+                };
+
+            Ok((context, resolved.commit))
+        },
+        (None, Some(_)) => {
+            Err(NewDockerContextError::ContextFilterWithoutContext)
+        },
+        (None, None) => {
+            Ok((DockerContext::Empty{dockerfile: dockerfile_path}, None))
+        },
+    }
+}
 
-        Ok(DockerContext::Dir{path: context_path, dockerfile: dockerfile_path})
-    } else {
-        let dockerfile = File::open(PathBuf::from(dockerfile_path.clone()))
-            .context(OpenDockerfileFailed{path: dockerfile_path})?;
+// `context_hash_inputs` returns the content hash inputs for `docker_context`,
+// which was checked out at `maybe_commit` if it's a Git repository. The
+// commit of a Git context identifies its files, so they're only hashed if
+// the context is filtered, because the filter may change while the commit
+// doesn't. Files ignored by the `.dockerignore` file of a local context
+// directory aren't sent to the Docker daemon, so they aren't hashed either.
+// No files are hashed if `hash_files` is `false`.
+fn context_hash_inputs(
+    docker_context: &DockerContext,
+    maybe_commit: Option<&str>,
+    hash_files: bool,
+)
+    -> Result<Vec<String>, ContextHashInputsError>
+{
+    let mut inputs = vec![];
+    if let Some(commit) = maybe_commit {
+        inputs.push(format!("context:git={commit}"));
+    }
+
+    let maybe_digest =
+        match docker_context {
+            _ if !hash_files => {
+                None
+            },
+            DockerContext::Empty{..} => {
+                None
+            },
+            DockerContext::Dir{..} if maybe_commit.is_some() => {
+                None
+            },
+            DockerContext::Dir{path, ..} => {
+                let path = PathBuf::from(path.clone());
+
+                let filter = build_context::dockerignore_filter(&path)
+                    .context(ReadDockerignoreFailed{path: path.clone()})?;
+
+                let files = build_context::list(&path, &filter)
+                    .context(ListContextFailed{path: path.clone()})?;
+
+                let digest = build_context::digest(&files)
+                    .context(DigestContextFailed{path})?;
+
+                Some(digest)
+            },
+            DockerContext::Filtered{files, ..} => {
+                let digest = build_context::digest(files)
+                    .context(DigestContextFailed{path: files.root.clone()})?;
+
+                Some(digest)
+            },
+        };
+
+    if let Some(digest) = maybe_digest {
+        inputs.push(format!("context:files={digest}"));
+    }
+
+    Ok(inputs)
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum ContextHashInputsError {
+    #[snafu(display(
+        "Couldn't read the `.dockerignore` file of '{}': {}",
+        path.display(),
+        source,
+    ))]
+    ReadDockerignoreFailed{source: IoError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't list the files in '{}': {}",
+        path.display(),
+        source,
+    ))]
+    ListContextFailed{source: ListError, path: PathBuf},
+    #[snafu(display(
+        "Couldn't hash the files in '{}': {}",
+        path.display(),
+        source,
+    ))]
+    DigestContextFailed{source: IoError, path: PathBuf},
+}
 
-        Ok(DockerContext::Empty{dockerfile})
+// `context_filter` returns the filter defined by the `context_include`,
+// `context_exclude` and `context_git_tracked` settings of `env`, if any of
+// them are set.
+fn context_filter(env: &DockEnvironmentConfig) -> Option<ContextFilter> {
+    let git_tracked = env.context_git_tracked == Some(true);
+    if env.context_include.is_none()
+        && env.context_exclude.is_none()
+        && !git_tracked
+    {
+        return None;
     }
+
+    Some(ContextFilter{
+        include: env.context_include.clone().unwrap_or_default(),
+        exclude: env.context_exclude.clone().unwrap_or_default(),
+        git_tracked,
+    })
 }
 
 #[derive(Debug, Snafu)]
 pub enum NewDockerContextError {
+    #[snafu(display("{}", source))]
+    ParseContextSourceFailed{source: ParseContextSourceError},
+    #[snafu(display("{}", source))]
+    ResolveContextFailed{source: ResolveContextError},
     #[snafu(display(
-        "Couldn't open the Dockerfile '{}': {}",
-        path.display_lossy(),
+        "`context_include`, `context_exclude` and `context_git_tracked` \
+         require `context` to be set",
+    ))]
+    ContextFilterWithoutContext,
+    #[snafu(display(
+        "Couldn't list the files in the context '{}': {}",
+        path.display(),
         source,
     ))]
-    OpenDockerfileFailed{source: IoError, path: AbsPath},
+    ListContextFilesFailed{source: ListError, path: PathBuf},
 }
 
 fn prepare_run_in_args(
     logger: &mut dyn CommandLogger,
     env: &DockEnvironmentConfig,
     dock_dir: &AbsPath,
-    vol_name_prefix: &str,
+    vol_naming: &CacheVolumeNaming,
     target_img: &str,
+    hermetic: bool,
+    staged: bool,
 )
     -> Result<Vec<String>, PrepareRunInArgsError>
 {
+    // Containers for staged runs are removed explicitly after artifacts have
+    // been copied out of them.
+    let mut run_args =
+        if staged {
+            vec![]
+        } else {
+            to_strings(&["--rm"])
+        };
+
     // We pass `--init` in order to forward signals and reap processes. TODO
     // Give concrete examples to justify providing `--init`.
     // TODO Add tests for `--init`.
-    let mut run_args = to_strings(&["--rm", "--init"]);
+    run_args.push("--init".to_string());
 
     if let Some(cache_volumes) = &env.cache_volumes {
         let args = prepare_run_cache_volumes_args(
             logger,
             cache_volumes,
-            vol_name_prefix,
+            vol_naming,
             target_img,
         )
             .context(PrepareRunInCacheVolumesArgsFailed)?;
@@ -492,8 +2274,40 @@ fn prepare_run_in_args(
 
     run_args.extend(env.run_args.clone().unwrap_or_default());
 
+    let project_dir_mounted =
+        env.mount_local
+            .as_ref()
+            .is_some_and(|mount_local| {
+                mount_local.contains(
+                    &DockEnvironmentMountLocalConfig::ProjectDir,
+                )
+            });
+
+    let follow_cwd =
+        match env.follow_cwd {
+            Some(true) if !project_dir_mounted => {
+                return Err(PrepareRunInArgsError::FollowCwdWithoutProjectDir);
+            },
+            Some(follow_cwd) => {
+                follow_cwd
+            },
+            None => {
+                project_dir_mounted
+            },
+        };
+
     if let Some(dir) = &env.workdir {
-        run_args.push(format!("--workdir={dir}"));
+        let workdir =
+            if follow_cwd {
+                let cwd = env::current_dir()
+                    .context(GetCurrentDirForWorkdirFailed)?;
+
+                workdir_for_cwd(dock_dir, dir, &cwd)
+            } else {
+                dir.clone()
+            };
+
+        run_args.push(format!("--workdir={workdir}"));
     }
 
     if let Some(env_vars) = &env.env {
@@ -502,9 +2316,11 @@ fn prepare_run_in_args(
         }
     }
 
+    // Hermetic runs don't bind-mount any paths from the project directory,
+    // so that they can't write to the project directory.
     // TODO Add tests for nested mounting.
     let mut parsed_mounts = vec![];
-    if let Some(mounts) = &env.mounts {
+    if let (Some(mounts), false) = (&env.mounts, hermetic) {
         for (rel_outer_path, inner_path) in mounts {
             let rel_outer_path =
                 RelPath::try_from(rel_outer_path.clone())
@@ -519,7 +2335,10 @@ fn prepare_run_in_args(
 
     if let Some(mount_local) = &env.mount_local {
         // TODO Add tests for nested mounting of the project directory.
-        if mount_local.contains(&DockEnvironmentMountLocalConfig::ProjectDir) {
+        let mount_project_dir =
+            mount_local.contains(&DockEnvironmentMountLocalConfig::ProjectDir);
+
+        if mount_project_dir && !hermetic {
             let raw_workdir = env.workdir.as_ref()
                 .context(WorkdirNotSet)?;
 
@@ -536,7 +2355,7 @@ fn prepare_run_in_args(
     }
 
     if !parsed_mounts.is_empty() {
-        let cur_hostpaths = hostpaths()
+        let cur_hostpaths = hostpaths::hostpaths(logger)
             .context(GetHostpathsFailed)?;
 
         let args = prepare_run_mount_args(
@@ -552,6 +2371,74 @@ fn prepare_run_in_args(
     Ok(run_args)
 }
 
+// `record_env_use` records the time that `env_name` was used, so that unused
+// environments can be found by `dock gc`, and the directory of the project,
+// so that `dock gc` can find the directory of environments whose resources
+// were created on another host. This is only bookkeeping for `dock gc`, so a
+// warning is printed if either can't be recorded.
+fn record_env_use(conf: &DockConfig, dock_dir: &AbsPath, env_name: &str) {
+    let (org, proj) = (&conf.organisation, &conf.project);
+
+    if let Err(err) = gc::record_use(org, proj, env_name, SystemTime::now()) {
+        eprintln!(
+            "warning: couldn't record the use of the environment in '{}': {}",
+            gc::last_use_path(org, proj, env_name).display(),
+            err,
+        );
+    }
+
+    if let Err(err) = gc::record_dir(org, proj, &dock_dir.display_lossy()) {
+        eprintln!(
+            "warning: couldn't record the directory of the project in '{}': \
+             {}",
+            gc::dir_path(org, proj).display(),
+            err,
+        );
+    }
+}
+
+// `workdir_for_cwd` returns the path under `workdir` that corresponds to
+// `cwd`, where `workdir` is the path that `dock_dir` is mounted at. `workdir`
+// is returned, and a warning is printed, if `cwd` isn't inside `dock_dir`.
+fn workdir_for_cwd(dock_dir: &AbsPath, workdir: &str, cwd: &Path) -> String {
+    let dock_dir = PathBuf::from(dock_dir.clone());
+
+    let rel_cwd =
+        if let Ok(rel_cwd) = cwd.strip_prefix(&dock_dir) {
+            rel_cwd
+        } else {
+            eprintln!(
+                "warning: the current directory ('{}') is outside the project \
+                 directory ('{}'), so '{}' will be used as the working \
+                 directory",
+                cwd.display(),
+                dock_dir.display(),
+                workdir,
+            );
+
+            return workdir.to_string();
+        };
+
+    if rel_cwd.as_os_str().is_empty() {
+        return workdir.to_string();
+    }
+
+    let inner_cwd = Path::new(workdir).join(rel_cwd);
+
+    if let Some(inner_cwd) = inner_cwd.to_str() {
+        inner_cwd.to_string()
+    } else {
+        eprintln!(
+            "warning: the current directory ('{}') can't be rendered as a \
+             working directory, so '{}' will be used as the working directory",
+            cwd.display(),
+            workdir,
+        );
+
+        workdir.to_string()
+    }
+}
+
 const DOCKER_SOCK_PATH: &str = "/var/run/docker.sock";
 
 #[allow(clippy::enum_variant_names)]
@@ -566,6 +2453,10 @@ pub enum PrepareRunInArgsError {
     },
     #[snafu(display("`workdir` is required when `project_dir` is mounted"))]
     WorkdirNotSet,
+    #[snafu(display("`follow_cwd` requires `project_dir` to be mounted"))]
+    FollowCwdWithoutProjectDir,
+    #[snafu(display("Couldn't get the current directory: {}", source))]
+    GetCurrentDirForWorkdirFailed{source: IoError},
     #[snafu(display(
         "Couldn't prepare \"local mount\" arguments for `docker run`: {}",
         source,
@@ -591,6 +2482,13 @@ pub enum PrepareRunInArgsError {
     PrepareRunInMountArgsFailed{source: PrepareRunInMountArgsError},
 }
 
+// `CacheVolumeNaming` defines the names and labels of the cache volumes of an
+// environment. The name of each cache volume starts with `name_prefix`.
+pub struct CacheVolumeNaming<'a> {
+    pub name_prefix: &'a str,
+    pub labels: &'a [String],
+}
+
 // TODO This method doesn't just prepare the cache volume arguments for the
 // `docker run` command, but also creates the volumes (if they don't exist) and
 // changes their permissions. This responsibility should ideally be moved to a
@@ -598,7 +2496,7 @@ pub enum PrepareRunInArgsError {
 fn prepare_run_cache_volumes_args(
     logger: &mut dyn CommandLogger,
     cache_volumes: &HashMap<String, PathBuf>,
-    vol_name_prefix: &str,
+    vol_naming: &CacheVolumeNaming,
     target_img: &str,
 )
     -> Result<Vec<String>, PrepareRunInCacheVolumesArgsError>
@@ -606,56 +2504,88 @@ fn prepare_run_cache_volumes_args(
     let mut args = vec![];
 
     for (name, path) in cache_volumes {
-        let path_abs_path = AbsPath::try_from(path.clone())
-            .context(CacheVolDirAsAbsPathFailed)?;
+        let mount_arg =
+            prepare_cache_volume(logger, vol_naming, name, path, target_img)?;
 
-        let path_cli_arg = path_abs_path.display()
-            .context(RenderCacheVolDirFailed{dir: path_abs_path})?;
+        args.push(mount_arg);
+    }
 
-        let vol_name = cache_vol_name(vol_name_prefix, name);
-        let mount_spec =
-            format!("type=volume,src={vol_name},dst={path_cli_arg}");
-        let mount_arg = format!("--mount={mount_spec}");
-
-        args.push(mount_arg.clone());
-
-        let prog = OsStr::new("docker");
-        let raw_inspect_args = &["volume", "inspect", vol_name.as_str()];
-        let inspect_args = new_os_strs(raw_inspect_args);
-        let status =
-            logging_process::run(logger, prog, &inspect_args, Stdio::null())
-                .context(CheckCacheExistenceFailed{
-                    vol_name: vol_name.clone(),
-                })?;
-
-        if status.success() {
-            // `vol_name` already exists, so we skip creating and initialising
-            // it.
-            continue;
-        }
+    Ok(args)
+}
 
-        let raw_docker_args = &[
-            "run",
-            "--rm",
-            "--user=root",
-            &mount_arg,
-            target_img,
-            "chmod",
-            // We would ideally use `--recursive` instead of `-R` in order
-            // to be more explicit, but in practice, `-R` has been found to
-            // be available in more `chmod` implementations (notably, the
-            // implementation used in `busybox`/`alpine` doesn't support
-            // `--recursive`).
-            "-R",
-            "0777",
-            &path_cli_arg,
-        ];
-        let docker_args = new_os_strs(raw_docker_args);
-        logging_process::run(logger, prog, &docker_args, Stdio::null())
-            .context(ChangeCacheOwnershipFailed{vol_name})?;
+// `prepare_cache_volume` creates the cache volume `name` of an environment, if
+// it doesn't exist, and makes it writable by all users of `target_img`, where
+// it's mounted at `path`. The `--mount` argument for the cache volume is
+// returned.
+pub fn prepare_cache_volume(
+    logger: &mut dyn CommandLogger,
+    vol_naming: &CacheVolumeNaming,
+    name: &str,
+    path: &Path,
+    target_img: &str,
+)
+    -> Result<String, PrepareRunInCacheVolumesArgsError>
+{
+    let path_abs_path = AbsPath::try_from(path.to_path_buf())
+        .context(CacheVolDirAsAbsPathFailed)?;
+
+    let path_cli_arg = path_abs_path.display()
+        .context(RenderCacheVolDirFailed{dir: path_abs_path})?;
+
+    let vol_name = cache_vol_name(vol_naming.name_prefix, name);
+    let mount_spec = format!("type=volume,src={vol_name},dst={path_cli_arg}");
+    let mount_arg = format!("--mount={mount_spec}");
+
+    let prog = OsStr::new("docker");
+    let raw_inspect_args = &["volume", "inspect", vol_name.as_str()];
+    let inspect_args = new_os_strs(raw_inspect_args);
+    let status =
+        logging_process::run(logger, prog, &inspect_args, Stdio::null())
+            .context(CheckCacheExistenceFailed{vol_name: vol_name.clone()})?;
+
+    if status.success() {
+        // `vol_name` already exists, so we skip creating and initialising it.
+        return Ok(mount_arg);
     }
 
-    Ok(args)
+    // We create the volume explicitly, instead of letting `docker run` create
+    // it, so that it's labelled.
+    let mut create_args = vec![OsStr::new("volume"), OsStr::new("create")];
+    create_args.extend(vol_naming.labels.iter().map(OsStr::new));
+    create_args.push(OsStr::new(&vol_name));
+    let status =
+        logging_process::run(logger, prog, &create_args, Stdio::null())
+            .context(CreateCacheVolumeFailed{vol_name: vol_name.clone()})?;
+
+    if !status.success() {
+        return Err(
+            PrepareRunInCacheVolumesArgsError::CreateVolumeUnsuccessful{
+                vol_name,
+            },
+        );
+    }
+
+    let container_marker_arg = labels::container_marker_arg();
+    let mut raw_docker_args = vec!["run", "--rm", "--user=root"];
+    raw_docker_args.extend(vol_naming.labels.iter().map(String::as_str));
+    raw_docker_args.push(&container_marker_arg);
+    raw_docker_args.extend([
+        &mount_arg,
+        target_img,
+        "chmod",
+        // We would ideally use `--recursive` instead of `-R` in order to be
+        // more explicit, but in practice, `-R` has been found to be available
+        // in more `chmod` implementations (notably, the implementation used
+        // in `busybox`/`alpine` doesn't support `--recursive`).
+        "-R",
+        "0777",
+        &path_cli_arg,
+    ]);
+    let docker_args = new_os_strs(&raw_docker_args);
+    logging_process::run(logger, prog, &docker_args, Stdio::null())
+        .context(ChangeCacheOwnershipFailed{vol_name})?;
+
+    Ok(mount_arg)
 }
 
 pub fn cache_vol_name_prefix(org: &str, proj: &str, env_name: &str) -> String {
@@ -695,6 +2625,20 @@ pub enum PrepareRunInCacheVolumesArgsError {
         vol_name: String,
         source: LoggingProcessRunError,
     },
+    #[snafu(display(
+        "Couldn't create the cache volume '{}': {}",
+        vol_name,
+        source,
+    ))]
+    CreateCacheVolumeFailed{
+        vol_name: String,
+        source: LoggingProcessRunError,
+    },
+    #[snafu(display(
+        "`docker volume create` of the cache volume '{}' was unsuccessful",
+        vol_name,
+    ))]
+    CreateVolumeUnsuccessful{vol_name: String},
     #[snafu(display(
         "Couldn't set the ownership of the cache volume '{}': {}",
         vol_name,
@@ -822,18 +2766,19 @@ fn prepare_run_mount_args(
     -> Result<Vec<String>, PrepareRunInMountArgsError>
 {
     let mut hostpath_cli_args = vec![];
+    let mut hostpath_mappings = vec![];
     for (rel_outer_path, inner_path) in mounts {
         let mut path = dock_dir.concat(rel_outer_path);
 
         // TODO Add `cur_hostpaths` to the error context. This ideally requires
         // `&Trie` to implement `Clone` so that a new, owned copy of
         // `cur_hostpaths` can be added to the error.
-        path = apply_hostpath(cur_hostpaths, &path)
+        path = hostpaths::apply_hostpath(cur_hostpaths, &path)
             .context(NoPathRouteOnHost{attempted_path: path})?;
 
         let host_path_cli_arg = path.display()
-            .context(RenderHostPathFailed{
-                path,
+            .with_context(|| RenderHostPathFailed{
+                path: path.clone(),
                 inner_path: (*inner_path).clone(),
             })?;
 
@@ -853,6 +2798,7 @@ fn prepare_run_mount_args(
                 },
             };
 
+        hostpath_mappings.push((PathBuf::from(path), inner_path.clone()));
         hostpath_cli_args.push((host_path_cli_arg, inner_path_cli_arg));
     }
 
@@ -865,11 +2811,7 @@ fn prepare_run_mount_args(
         args.push(format!("--mount={mount_spec}"));
     }
 
-    let rendered_hostpaths = hostpath_cli_args
-        .into_iter()
-        .map(|(host_path, inner_path)| format!("{host_path}:{inner_path}"))
-        .collect::<Vec<String>>()
-        .join(":");
+    let rendered_hostpaths = hostpaths::render(&hostpath_mappings);
 
     args.push(
         format!("--env={DOCK_HOSTPATHS_VAR_NAME}={rendered_hostpaths}")
@@ -897,226 +2839,3 @@ pub enum PrepareRunInMountArgsError {
     ))]
     InnerPathAsCliArgFailed{path: OsString},
 }
-
-const DOCK_HOSTPATHS_VAR_NAME: &str = "DOCK_HOSTPATHS";
-
-#[derive(Debug)]
-struct Hostpaths {
-    host_paths: Trie<OsString, AbsPath>,
-}
-
-impl Hostpaths {
-    fn new() -> Hostpaths {
-        Self{host_paths: Trie::new()}
-    }
-
-    fn insert(&mut self, outer_path: AbsPath, inner_path: AbsPath)
-        -> Result<(), HostpathInsertError>
-    {
-        match self.host_paths.insert(&inner_path, outer_path.clone()) {
-            Ok(()) => {
-                Ok(())
-            },
-            Err(err) => {
-                let e =
-                    match err {
-                        InsertError::EmptyKey =>
-                            // TODO These parameters can be added at a higher
-                            // level.
-                            HostpathInsertError::EmptyInnerPath{outer_path},
-                        InsertError::PrefixContainsValue =>
-                            HostpathInsertError::InnerPathAncestorHasMapping{
-                                outer_path,
-                                inner_path,
-                            },
-                        InsertError::DirAtKey =>
-                            HostpathInsertError::InnerPathDescendentHasMapping{
-                                outer_path,
-                                inner_path,
-                            },
-                    };
-
-                Err(e)
-            },
-        }
-    }
-
-    fn lookup(&self, path: &AbsPath) -> Option<AbsPath> {
-        let (prefix, host_dir) = self.host_paths.value_at_prefix(path)?;
-
-        let rel_path: Vec<OsString> =
-            path
-                .iter()
-                .skip(prefix.len())
-                .cloned()
-                .collect();
-
-        let host_path = host_dir.concat(&RelPath::from(rel_path));
-
-        Some(host_path)
-    }
-}
-
-#[derive(Debug, Snafu)]
-pub enum HostpathInsertError {
-    #[snafu(display(
-        "The path '{}' maps to an empty path",
-        outer_path.display_lossy(),
-    ))]
-    EmptyInnerPath{outer_path: AbsPath},
-    #[snafu(display(
-        "A host path maps to an ancestor of '{}' (which is mapped-to by '{}')",
-        inner_path.display_lossy(),
-        outer_path.display_lossy(),
-    ))]
-    InnerPathAncestorHasMapping{outer_path: AbsPath, inner_path: AbsPath},
-    #[snafu(display(
-        "A host path maps to a descendant of '{}' (which is mapped-to by \
-            '{}')",
-        inner_path.display_lossy(),
-        outer_path.display_lossy(),
-    ))]
-    InnerPathDescendentHasMapping{outer_path: AbsPath, inner_path: AbsPath},
-}
-
-impl TryFrom<Vec<(&str, &str)>> for Hostpaths {
-    type Error = HostpathFromPairsError;
-
-    fn try_from(pairs: Vec<(&str, &str)>) -> Result<Self, Self::Error> {
-        let mut hps = Hostpaths::new();
-
-        for (outer_path, inner_path) in pairs {
-            let abs_outer_path = AbsPath::parse(outer_path)
-                .context(ParseOuterPathFailed{
-                    outer_path: (*outer_path).to_string(),
-                    inner_path: (*inner_path).to_string(),
-                })?;
-
-            let abs_inner_path = AbsPath::parse(inner_path)
-                .context(ParseInnerPathFailed{
-                    outer_path: (*outer_path).to_string(),
-                    inner_path: (*inner_path).to_string(),
-                })?;
-
-            hps.insert(abs_outer_path, abs_inner_path)
-                .context(HostpathInsertFailed{
-                    outer_path: (*outer_path).to_string(),
-                    inner_path: (*inner_path).to_string(),
-                })?;
-        }
-
-        Ok(hps)
-    }
-}
-
-#[allow(clippy::enum_variant_names)]
-#[derive(Debug, Snafu)]
-pub enum HostpathFromPairsError {
-    #[snafu(display(
-        "Couldn't parse '{}' as an absolute path (mapped to '{}'): {}",
-        inner_path,
-        outer_path,
-        source,
-    ))]
-    ParseOuterPathFailed{
-        source: NewAbsPathError,
-        outer_path: String,
-        inner_path: String,
-    },
-    #[snafu(display(
-        "Couldn't parse '{}' as an absolute path (mapped from '{}'): {}",
-        outer_path,
-        inner_path,
-        source,
-    ))]
-    ParseInnerPathFailed{
-        source: NewAbsPathError,
-        outer_path: String,
-        inner_path: String,
-    },
-    #[snafu(display(
-        "Couldn't add hostpath mapping '{}' to '{}' to hostpaths: {}",
-        inner_path,
-        outer_path,
-        source,
-    ))]
-    HostpathInsertFailed{
-        source: HostpathInsertError,
-        outer_path: String,
-        inner_path: String,
-    },
-}
-
-fn hostpaths() -> Result<Option<Hostpaths>, HostpathsError> {
-    let raw_hostpaths =
-        match env::var(DOCK_HOSTPATHS_VAR_NAME) {
-            Ok(v) => {
-                v
-            },
-            Err(VarError::NotPresent) => {
-                return Ok(None)
-            },
-            Err(VarError::NotUnicode(value)) => {
-                return Err(HostpathsError::EnvVarIsNotUnicode{value});
-            },
-        };
-
-    let raw_hostpaths: Vec<&str> = raw_hostpaths.split(':').collect();
-
-    let pairs = pairs(&raw_hostpaths)
-        .context(UnmatchedHostpath{hostpaths: to_strings(&raw_hostpaths) })?;
-
-    let hostpaths = Hostpaths::try_from(pairs)
-        .context(CreateHostpathsFailed)?;
-
-    Ok(Some(hostpaths))
-}
-
-#[derive(Debug, Snafu)]
-pub enum HostpathsError {
-    #[snafu(display(
-        "The value of '${}' isn't unicode",
-        DOCK_HOSTPATHS_VAR_NAME,
-    ))]
-    EnvVarIsNotUnicode{value: OsString},
-    #[snafu(display(
-        "'${}' has an unmatched hostpath",
-        DOCK_HOSTPATHS_VAR_NAME,
-    ))]
-    UnmatchedHostpath{hostpaths: Vec<String>},
-    #[snafu(display(
-        "Couldn't create hostpaths from '${}': {}",
-        DOCK_HOSTPATHS_VAR_NAME,
-        source,
-    ))]
-    CreateHostpathsFailed{source: HostpathFromPairsError},
-}
-
-fn pairs<'a, T: Debug + ?Sized>(xs: &[&'a T]) -> Option<Vec<(&'a T, &'a T)>> {
-    if xs.len() % 2 == 1 {
-        return None;
-    }
-
-    let mut pairs = Vec::with_capacity(xs.len() / 2);
-
-    for pair in xs.chunks(2) {
-        if let [a, b] = pair {
-            pairs.push((*a, *b));
-        } else {
-            // `chunks(2)` should always return slices of length 2.
-            panic!("chunk didn't have length 2: {pair:?}");
-        }
-    }
-
-    Some(pairs)
-}
-
-fn apply_hostpath(maybe_hostpaths: Option<&Hostpaths>, path: &AbsPath)
-    -> Option<AbsPath>
-{
-    if let Some(hps) = maybe_hostpaths {
-        hps.lookup(path)
-    } else {
-        Some(path.clone())
-    }
-}
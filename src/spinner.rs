@@ -4,36 +4,68 @@
 
 use std::io;
 use std::io::Write;
+use std::mem;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::mpsc;
 use std::sync::mpsc::SendError;
 use std::sync::mpsc::TryRecvError;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
 
 use snafu::ResultExt;
 use snafu::Snafu;
 
-pub fn spin<F, T>(msg: String, f: F) -> Result<T, SpinError>
+// `Status` is a message that can be updated while it's shown by `spin_status`.
+#[derive(Clone)]
+pub struct Status {
+    msg: Arc<Mutex<String>>,
+}
+
+impl Status {
+    pub fn new(msg: String) -> Self {
+        Self{msg: Arc::new(Mutex::new(msg))}
+    }
+
+    pub fn set(&self, msg: String) {
+        if let Ok(mut cur) = self.msg.lock() {
+            *cur = msg;
+        }
+    }
+
+    fn get(&self) -> String {
+        self.msg.lock().map(|msg| msg.clone()).unwrap_or_default()
+    }
+}
+
+// `spin_status` shows `status`, along with the time elapsed since it was
+// called, and a spinner, while `f` runs. The line is redrawn on each tick so
+// that updates to `status` are shown.
+pub fn spin_status<F, T>(status: &Status, f: F) -> Result<T, SpinError>
 where
     F: FnOnce() -> T,
 {
     let (sender, receiver) = mpsc::channel();
+    let status = status.clone();
 
     let thread = thread::spawn(move || {
         // TODO Retrieve a reference to STDOUT as a parameter.
         let mut stdout = io::stdout();
 
-        let _ = write!(&mut stdout, "{msg}  ");
-
-        let _ = stdout.flush();
-
+        let start = Instant::now();
         let mut i = 0;
 
         // We exit the loop if the receiver receives data, or if the sender
         // disconnects.
         while let Err(TryRecvError::Empty) = receiver.try_recv() {
-            let _ =
-                write!(&mut stdout, "{}{}", BACKSPACE, CYCLE_CHARS[i]);
+            let _ = write!(
+                &mut stdout,
+                "{RESET_CURSOR}{CLEAR_LINE}{} ({}s)  {}",
+                status.get(),
+                start.elapsed().as_secs(),
+                CYCLE_CHARS[i],
+            );
 
             let _ = stdout.flush();
 
@@ -61,6 +93,162 @@ where
     Ok(result)
 }
 
+// `Board` is a set of lines that are redrawn by `spin_board`, each of which
+// has a label and a status. A spinner is shown next to active lines. Notes
+// added to the board are printed above its lines, and aren't cleared with
+// them.
+#[derive(Clone)]
+pub struct Board {
+    lines: Arc<Mutex<Vec<BoardLine>>>,
+    notes: Arc<Mutex<Vec<String>>>,
+}
+
+struct BoardLine {
+    label: String,
+    status: String,
+    active: bool,
+}
+
+impl Board {
+    pub fn new(labels: &[&str]) -> Self {
+        let lines =
+            labels
+                .iter()
+                .map(|label| BoardLine{
+                    label: (*label).to_string(),
+                    status: String::new(),
+                    active: false,
+                })
+                .collect();
+
+        Self{
+            lines: Arc::new(Mutex::new(lines)),
+            notes: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    // `set` updates the status of the line at index `i`.
+    pub fn set(&self, i: usize, status: String, active: bool) {
+        if let Ok(mut lines) = self.lines.lock() {
+            if let Some(line) = lines.get_mut(i) {
+                line.status = status;
+                line.active = active;
+            }
+        }
+    }
+
+    // `note` adds `msg` to the notes that are printed above the board.
+    pub fn note(&self, msg: String) {
+        if let Ok(mut notes) = self.notes.lock() {
+            notes.push(msg);
+        }
+    }
+
+    fn take_notes(&self) -> Vec<String> {
+        self.notes.lock()
+            .map(|mut notes| mem::take(&mut *notes))
+            .unwrap_or_default()
+    }
+
+    fn render(&self, tick: usize) -> Vec<String> {
+        let Ok(lines) = self.lines.lock() else {
+            return vec![];
+        };
+
+        let width =
+            lines
+                .iter()
+                .map(|line| line.label.chars().count())
+                .max()
+                .unwrap_or(0);
+
+        lines
+            .iter()
+            .map(|line| {
+                let c =
+                    if line.active {
+                        CYCLE_CHARS[tick % CYCLE_CHARS.len()]
+                    } else {
+                        ' '
+                    };
+
+                format!("{c} {:width$}  {}", line.label, line.status)
+            })
+            .collect()
+    }
+}
+
+// `spin_board` redraws `board` on STDOUT while `f` runs, and clears it once
+// `f` returns.
+pub fn spin_board<F, T>(board: &Board, f: F) -> Result<T, SpinError>
+where
+    F: FnOnce() -> T,
+{
+    let (sender, receiver) = mpsc::channel();
+    let board = board.clone();
+
+    let thread = thread::spawn(move || {
+        // TODO Retrieve a reference to STDOUT as a parameter.
+        let mut stdout = io::stdout();
+
+        let mut drawn = 0;
+        let mut tick = 0;
+
+        loop {
+            // We stop if the receiver receives data, or if the sender
+            // disconnects.
+            let done =
+                !matches!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+            let _ = write!(&mut stdout, "{}", cursor_up(drawn));
+            for note in board.take_notes() {
+                let _ = writeln!(&mut stdout, "{CLEAR_LINE}{note}");
+            }
+            let lines = board.render(tick);
+            for line in &lines {
+                if done {
+                    let _ = writeln!(&mut stdout, "{CLEAR_LINE}");
+                } else {
+                    let _ = writeln!(&mut stdout, "{CLEAR_LINE}{line}");
+                }
+            }
+            drawn = lines.len();
+
+            if done {
+                let _ = write!(&mut stdout, "{}", cursor_up(drawn));
+                let _ = stdout.flush();
+
+                break;
+            }
+
+            let _ = stdout.flush();
+
+            tick += 1;
+
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    let result = f();
+
+    sender.send(())
+        .context(SendEndSignalFailed)?;
+
+    if thread.join().is_err() {
+        return Err(SpinError::JoinSpinnerThreadFailed);
+    }
+
+    Ok(result)
+}
+
+fn cursor_up(n: usize) -> String {
+    if n == 0 {
+        String::new()
+    } else {
+        format!("\x1b[{n}A")
+    }
+}
+
 // Characters adapted from
 // <https://github.com/6/braille-pattern-cli-loading-indicator>.
 const CYCLE_CHARS: &[char] = &[
@@ -74,8 +262,6 @@ const CYCLE_CHARS: &[char] = &[
     '⣾',
 ];
 
-const BACKSPACE: char = '\x08';
-
 // NOTE These codes may not be portable.
 const RESET_CURSOR: &str = "\x1b[1G";
 const CLEAR_LINE: &str = "\x1b[2K";
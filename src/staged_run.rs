@@ -0,0 +1,514 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+//! Running commands in containers that are created, started and removed in
+//! separate steps.
+//!
+//! `dock run-in` usually replaces the current process with `docker run --rm`.
+//! A "staged" run instead creates the container, optionally copies the
+//! project into it, starts it and waits for it to finish, copies artifacts out
+//! of it, and then removes it. This allows files to be transferred to and from
+//! the container without using bind mounts, which may not be available in
+//! nested Docker scenarios.
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Error as IoError;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal;
+use nix::sys::signal::SaFlags;
+use nix::sys::signal::SigAction;
+use nix::sys::signal::SigHandler;
+use nix::sys::signal::SigSet;
+use nix::sys::signal::Signal;
+use snafu::ResultExt;
+use snafu::Snafu;
+
+use crate::canon_path::AbsPath;
+use crate::logging_process;
+use crate::logging_process::CmdLoggerMsg;
+use crate::logging_process::CommandLogger;
+use crate::logging_process::RunError;
+
+pub struct StagedRun<'a> {
+    pub container_name: &'a str,
+    pub create_args: &'a [String],
+    pub interactive: bool,
+    pub project_copy: Option<ProjectCopy<'a>>,
+    pub artifacts: &'a [Artifact],
+}
+
+// `ProjectCopy` defines the copying of the Git-tracked files in `src` to the
+// `dst` directory in the container.
+pub struct ProjectCopy<'a> {
+    pub src: &'a AbsPath,
+    pub dst: &'a str,
+}
+
+// `Artifact` defines the copying of `src` in the container to `dst` on the
+// host after the command has finished. A failure to copy an `optional`
+// artifact is ignored.
+#[derive(Debug)]
+pub struct Artifact {
+    pub src: PathBuf,
+    pub dst: AbsPath,
+    pub optional: bool,
+}
+
+/// Runs the command defined by `staged_run` in a new container, and returns
+/// the exit status of the command. The container is removed even if an error
+/// occurs after it has been created, or if `dock` receives `SIGINT` or
+/// `SIGTERM`; in the latter case the signal is raised again once the
+/// container has been removed.
+pub fn run(logger: &mut dyn CommandLogger, staged_run: &StagedRun)
+    -> Result<ExitStatus, StagedRunError>
+{
+    let name = staged_run.container_name;
+
+    let handlers = SignalHandlers::install()
+        .context(InstallSignalHandlersFailed)?;
+
+    let mut create_args = vec!["create", "--name", name];
+    create_args.extend(staged_run.create_args.iter().map(String::as_str));
+
+    let status = run_logged(logger, &create_args)
+        .context(CreateContainerFailed)?;
+
+    if !status.success() {
+        return Err(StagedRunError::CreateContainerUnsuccessful);
+    }
+
+    let result = run_in_created_container(logger, staged_run);
+
+    // We attempt to remove the container regardless of whether the run
+    // succeeded, but we prioritise the error from the run, if any.
+    let remove_result = run_logged(logger, &["rm", "--force", name]);
+
+    // We restore the previous signal handlers before raising the received
+    // signal, if any, so that `dock` is terminated by it as it would have
+    // been if the handlers hadn't been installed.
+    drop(handlers);
+    if let Some(sig) = received_signal() {
+        // NOTE We ignore the result because we return an `Interrupted` error
+        // below if raising the signal doesn't terminate `dock`.
+        let _ = signal::raise(sig);
+    }
+
+    let status = result?;
+
+    let remove_status = remove_result
+        .context(RemoveContainerFailed{name})?;
+
+    if !remove_status.success() {
+        return Err(StagedRunError::RemoveContainerUnsuccessful{
+            name: name.to_string(),
+        });
+    }
+
+    Ok(status)
+}
+
+#[derive(Debug, Snafu)]
+pub enum StagedRunError {
+    #[snafu(display("Couldn't install signal handlers: {}", source))]
+    InstallSignalHandlersFailed{source: Errno},
+    #[snafu(display("Couldn't create container: {}", source))]
+    CreateContainerFailed{source: RunError},
+    #[snafu(display("`docker create` returned an unsuccessful status"))]
+    CreateContainerUnsuccessful,
+    #[snafu(display(
+        "Couldn't copy the project into the container: {}",
+        source,
+    ))]
+    CopyProjectFailed{source: CopyProjectError},
+    #[snafu(display("Couldn't start container: {}", source))]
+    StartContainerFailed{source: IoError},
+    #[snafu(display("Couldn't wait for container: {}", source))]
+    WaitContainerFailed{source: IoError},
+    #[snafu(display("Interrupted by a signal"))]
+    Interrupted,
+    #[snafu(display(
+        "Couldn't copy artifact '{}' to '{}': {}",
+        src.display(),
+        dst.display_lossy(),
+        source,
+    ))]
+    CopyArtifactFailed{source: CopyArtifactError, src: PathBuf, dst: AbsPath},
+    #[snafu(display("Couldn't remove container '{}': {}", name, source))]
+    RemoveContainerFailed{source: RunError, name: String},
+    #[snafu(display(
+        "`docker rm` returned an unsuccessful status for '{}'",
+        name,
+    ))]
+    RemoveContainerUnsuccessful{name: String},
+}
+
+fn run_in_created_container(
+    logger: &mut dyn CommandLogger,
+    staged_run: &StagedRun,
+)
+    -> Result<ExitStatus, StagedRunError>
+{
+    let name = staged_run.container_name;
+
+    if received_signal().is_some() {
+        return Err(StagedRunError::Interrupted);
+    }
+
+    if let Some(project_copy) = &staged_run.project_copy {
+        copy_project(logger, name, project_copy)
+            .context(CopyProjectFailed)?;
+    }
+
+    if received_signal().is_some() {
+        return Err(StagedRunError::Interrupted);
+    }
+
+    let mut start_args = vec!["start", "--attach"];
+    if staged_run.interactive {
+        start_args.push("--interactive");
+    }
+    start_args.push(name);
+
+    log_cmd(logger, &start_args);
+
+    // The process spawned by `Command` inherits the standard file descriptors
+    // from the parent process by default.
+    let mut child =
+        Command::new("docker")
+            .args(&start_args)
+            .spawn()
+            .context(StartContainerFailed)?;
+
+    let status = loop {
+        let maybe_status = child.try_wait()
+            .context(WaitContainerFailed)?;
+
+        if let Some(status) = maybe_status {
+            break status;
+        }
+
+        // NOTE We don't wait for `docker start` to exit after a signal is
+        // received, because the main process of the container may ignore
+        // the signal that `docker start` proxies to it. `run` removes the
+        // container instead, which causes `docker start` to exit.
+        if received_signal().is_some() {
+            return Err(StagedRunError::Interrupted);
+        }
+
+        thread::sleep(WAIT_POLL_INTERVAL);
+    };
+
+    for artifact in staged_run.artifacts {
+        let result = copy_artifact(logger, name, artifact);
+
+        // We only require artifacts to exist if the command succeeded, so
+        // that the exit status of a failed command is returned instead of an
+        // error about a missing artifact.
+        if artifact.optional || !status.success() {
+            continue;
+        }
+
+        result
+            .with_context(|| CopyArtifactFailed{
+                src: artifact.src.clone(),
+                dst: artifact.dst.clone(),
+            })?;
+    }
+
+    // We return the exit status of the command so that it's preserved as the
+    // exit status of `dock`.
+    Ok(status)
+}
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// `RECEIVED_SIGNAL` is the last signal received by `record_signal`, or `0` if
+// no signal has been received.
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+const HANDLED_SIGNALS: [Signal; 2] = [Signal::SIGINT, Signal::SIGTERM];
+
+// `record_signal` records `sig` so that it can be handled by `run` after the
+// container has been removed.
+extern "C" fn record_signal(sig: libc::c_int) {
+    RECEIVED_SIGNAL.store(sig, Ordering::SeqCst);
+}
+
+fn received_signal() -> Option<Signal> {
+    let sig = RECEIVED_SIGNAL.load(Ordering::SeqCst);
+    if sig == 0 {
+        return None;
+    }
+
+    Signal::try_from(sig).ok()
+}
+
+// `SignalHandlers` installs `record_signal` as the handler for
+// `HANDLED_SIGNALS`, and restores the previous handlers when dropped.
+struct SignalHandlers {
+    prev_actions: Vec<(Signal, SigAction)>,
+}
+
+impl SignalHandlers {
+    fn install() -> Result<Self, Errno> {
+        let action = SigAction::new(
+            SigHandler::Handler(record_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+
+        let mut handlers = Self{prev_actions: vec![]};
+        for sig in HANDLED_SIGNALS {
+            // SAFETY `record_signal` only calls async-signal-safe functions.
+            let prev_action = unsafe { signal::sigaction(sig, &action) }?;
+            handlers.prev_actions.push((sig, prev_action));
+        }
+
+        Ok(handlers)
+    }
+}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        for (sig, prev_action) in &self.prev_actions {
+            // SAFETY `prev_action` was returned by a previous call to
+            // `sigaction`.
+            // NOTE We ignore the result because there's no way to recover
+            // from a failure to restore a handler.
+            let _ = unsafe { signal::sigaction(*sig, prev_action) };
+        }
+    }
+}
+
+fn copy_project(
+    logger: &mut dyn CommandLogger,
+    container_name: &str,
+    project_copy: &ProjectCopy,
+)
+    -> Result<(), CopyProjectError>
+{
+    let src = PathBuf::from(project_copy.src.clone());
+
+    let tracked_paths = git_tracked_paths(&src)?;
+
+    // We pass the list of paths to `tar` explicitly, including the ancestor
+    // directories of each path, so that `tar` doesn't include untracked files,
+    // and so that the ownership of directories is preserved in the container.
+    let mut paths = BTreeSet::new();
+    paths.insert(PathBuf::from("."));
+    for path in tracked_paths {
+        // Tracked files may have been deleted from the working tree.
+        if fs::symlink_metadata(src.join(&path)).is_err() {
+            continue;
+        }
+
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() {
+                paths.insert(ancestor.to_path_buf());
+            }
+        }
+        paths.insert(path);
+    }
+
+    let mut file_list = vec![];
+    for path in paths {
+        file_list.extend(path.as_os_str().as_bytes());
+        file_list.push(0);
+    }
+
+    let mut tar =
+        Command::new("tar")
+            .arg("--create")
+            .arg("--no-recursion")
+            .arg("--null")
+            .arg("--file=-")
+            // NOTE `--directory` must precede `--files-from` because it only
+            // applies to the paths that follow it.
+            .arg("--directory")
+            .arg(&src)
+            .arg("--files-from=-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(SpawnTarFailed)?;
+
+    // We write the file list to `tar` in a separate thread, because `tar` may
+    // block on writing its output before it has read all of its input.
+    let mut tar_stdin = tar.stdin.take()
+        .ok_or(CopyProjectError::DevErrBindTarStdinFailed)?;
+    let writer = thread::spawn(move || tar_stdin.write_all(&file_list));
+
+    let tar_stdout = tar.stdout.take()
+        .ok_or(CopyProjectError::DevErrBindTarStdoutFailed)?;
+
+    // `--archive` preserves the ownership of the files in the archive, so that
+    // the project can be written to by a container that is run as the local
+    // user.
+    let dst = format!("{container_name}:{}", project_copy.dst);
+    let cp_args = ["cp", "--archive", "-", &dst];
+    let cp_args: Vec<&OsStr> = cp_args.iter().map(OsStr::new).collect();
+    let cp_status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &cp_args,
+        Stdio::from(tar_stdout),
+    )
+        .context(RunDockerCpFailed)?;
+
+    let write_result = writer.join()
+        .map_err(|_| CopyProjectError::JoinFileListWriterFailed)?;
+
+    let tar_status = tar.wait()
+        .context(WaitTarFailed)?;
+
+    write_result
+        .context(WriteFileListFailed)?;
+
+    if !tar_status.success() {
+        return Err(CopyProjectError::TarUnsuccessful);
+    }
+
+    if !cp_status.success() {
+        return Err(CopyProjectError::DockerCpUnsuccessful);
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Snafu)]
+pub enum CopyProjectError {
+    #[snafu(display("Couldn't list Git-tracked files: {}", source))]
+    ListTrackedFilesFailed{source: IoError},
+    #[snafu(display(
+        "`git ls-files` returned an unsuccessful status (is the project in a \
+         Git repository?)",
+    ))]
+    ListTrackedFilesUnsuccessful,
+    #[snafu(display("Couldn't spawn `tar`: {}", source))]
+    SpawnTarFailed{source: IoError},
+    #[snafu(display("Couldn't write the file list to `tar`: {}", source))]
+    WriteFileListFailed{source: IoError},
+    #[snafu(display("Couldn't run `docker cp`: {}", source))]
+    RunDockerCpFailed{source: RunError},
+    #[snafu(display("Couldn't wait for `tar`: {}", source))]
+    WaitTarFailed{source: IoError},
+    #[snafu(display("`tar` returned an unsuccessful status"))]
+    TarUnsuccessful,
+    #[snafu(display("`docker cp` returned an unsuccessful status"))]
+    DockerCpUnsuccessful,
+
+    // NOTE The following are considered "developer errors"; see
+    // `logging_process::RunError` for more details.
+    #[snafu(display("(Dev Err) Couldn't bind to the `tar` STDIN"))]
+    DevErrBindTarStdinFailed,
+    #[snafu(display("(Dev Err) Couldn't bind to the `tar` STDOUT"))]
+    DevErrBindTarStdoutFailed,
+    #[snafu(display("(Dev Err) Couldn't join the file list writer thread"))]
+    JoinFileListWriterFailed,
+}
+
+fn git_tracked_paths(dir: &Path) -> Result<Vec<PathBuf>, CopyProjectError> {
+    let output =
+        Command::new("git")
+            .args(["ls-files", "-z", "--cached"])
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .context(ListTrackedFilesFailed)?;
+
+    if !output.status.success() {
+        return Err(CopyProjectError::ListTrackedFilesUnsuccessful);
+    }
+
+    let paths =
+        output.stdout
+            .split(|b| *b == 0)
+            .filter(|p| !p.is_empty())
+            .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+            .collect();
+
+    Ok(paths)
+}
+
+fn copy_artifact(
+    logger: &mut dyn CommandLogger,
+    container_name: &str,
+    artifact: &Artifact,
+)
+    -> Result<(), CopyArtifactError>
+{
+    let dst = PathBuf::from(artifact.dst.clone());
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .context(CreateArtifactDirFailed{dir: parent.to_path_buf()})?;
+    }
+
+    let mut src = OsStr::new(container_name).to_os_string();
+    src.push(":");
+    src.push(&artifact.src);
+
+    let cp_args = [OsStr::new("cp"), &src, dst.as_os_str()];
+    let status = logging_process::run(
+        logger,
+        OsStr::new("docker"),
+        &cp_args,
+        Stdio::null(),
+    )
+        .context(RunDockerCpArtifactFailed)?;
+
+    if !status.success() {
+        return Err(CopyArtifactError::DockerCpArtifactUnsuccessful);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Snafu)]
+pub enum CopyArtifactError {
+    #[snafu(display(
+        "Couldn't create directory '{}': {}",
+        dir.display(),
+        source,
+    ))]
+    CreateArtifactDirFailed{source: IoError, dir: PathBuf},
+    #[snafu(display("Couldn't run `docker cp`: {}", source))]
+    RunDockerCpArtifactFailed{source: RunError},
+    #[snafu(display(
+        "`docker cp` returned an unsuccessful status (does the artifact \
+         exist?)",
+    ))]
+    DockerCpArtifactUnsuccessful,
+}
+
+fn run_logged(logger: &mut dyn CommandLogger, args: &[&str])
+    -> Result<ExitStatus, RunError>
+{
+    let args: Vec<&OsStr> = args.iter().map(OsStr::new).collect();
+
+    logging_process::run(logger, OsStr::new("docker"), &args, Stdio::null())
+}
+
+fn log_cmd(logger: &mut dyn CommandLogger, args: &[&str]) {
+    let mut cmd_line = vec![OsStr::new("docker")];
+    cmd_line.extend(args.iter().map(OsStr::new));
+    logger.log(CmdLoggerMsg::Cmd(&cmd_line));
+}
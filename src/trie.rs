@@ -3,7 +3,6 @@
 // licence that can be found in the LICENCE file.
 
 use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::hash::Hash;
 
 use snafu::OptionExt;
@@ -16,10 +15,12 @@ pub struct Trie<K, V> {
 
 type Dir<K, V> = HashMap<K, Node<V>>;
 
+// A `Node` can contain both a value and a "directory" of child nodes, so that
+// values can be stored at a key as well as at keys that it is a prefix of.
 #[derive(Debug)]
-enum Node<V> {
-    Value(V),
-    DirIndex(usize),
+struct Node<V> {
+    value: Option<V>,
+    dir_index: Option<usize>,
 }
 
 impl<K: Clone + Eq + Hash, V> Trie<K, V> {
@@ -27,6 +28,9 @@ impl<K: Clone + Eq + Hash, V> Trie<K, V> {
         Trie{dirs: vec![HashMap::new()]}
     }
 
+    /// Inserts `value` at `key`, replacing any value that was previously
+    /// stored at `key`. Values may be stored at keys that are prefixes of
+    /// other keys.
     pub fn insert(&mut self, key: &[K], value: V) -> Result<(), InsertError> {
         let mut key_components = key.to_vec();
 
@@ -37,68 +41,66 @@ impl<K: Clone + Eq + Hash, V> Trie<K, V> {
         for k in key_components {
             let num_dirs = self.dirs.len();
 
-            let cur_dir = &mut self.dirs[dir_index];
+            let node =
+                self.dirs[dir_index]
+                    .entry(k)
+                    .or_insert(Node{value: None, dir_index: None});
 
-            if let Some(node) = cur_dir.get(&k) {
-                if let Node::DirIndex(i) = node {
-                    dir_index = *i;
-                } else {
-                    // TODO Add the prefix to the error context.
-                    return Err(InsertError::PrefixContainsValue);
-                }
+            if let Some(i) = node.dir_index {
+                dir_index = i;
             } else {
+                node.dir_index = Some(num_dirs);
                 dir_index = num_dirs;
-                cur_dir.insert(k, Node::DirIndex(dir_index));
                 self.dirs.push(HashMap::new());
             }
         }
 
-        let cur_dir = &mut self.dirs[dir_index];
-
-        match cur_dir.entry(last) {
-            Entry::Vacant(entry) => {
-                entry.insert(Node::Value(value));
+        let node =
+            self.dirs[dir_index]
+                .entry(last)
+                .or_insert(Node{value: None, dir_index: None});
 
-                Ok(())
-            },
-            Entry::Occupied(mut entry) => {
-                if let Node::DirIndex(_) = entry.get() {
-                    return Err(InsertError::DirAtKey);
-                }
-                entry.insert(Node::Value(value));
+        node.value = Some(value);
 
-                Ok(())
-            },
-        }
+        Ok(())
     }
 
-    /// Returns the prefix of `key` that leads to a value in `self`, if one
-    /// exists, with the value found at that location; otherwise returns
-    /// `None`.
+    /// Returns the longest prefix of `key` that leads to a value in `self`,
+    /// if one exists, with the value found at that location; otherwise
+    /// returns `None`.
     pub fn value_at_prefix<'a, 'b>(&'a self, key: &'b [K])
         -> Option<(Vec<&'b K>, &'a V)>
     {
         let mut dir_index = 0;
 
-        let mut prefix = vec![];
-        for k in key {
-            prefix.push(k);
-
+        let mut longest_match = None;
+        for (i, k) in key.iter().enumerate() {
             let cur_dir = &self.dirs[dir_index];
 
-            let node = cur_dir.get(k)?;
+            let node =
+                if let Some(node) = cur_dir.get(k) {
+                    node
+                } else {
+                    break;
+                };
+
+            if let Some(v) = &node.value {
+                longest_match = Some((i + 1, v));
+            }
 
-            match node {
-                Node::DirIndex(i) => {
-                    dir_index = *i;
+            match node.dir_index {
+                Some(i) => {
+                    dir_index = i;
                 },
-                Node::Value(v) => {
-                    return Some((prefix, v));
+                None => {
+                    break;
                 },
             }
         }
 
-        None
+        let (prefix_len, v) = longest_match?;
+
+        Some((key[..prefix_len].iter().collect(), v))
     }
 }
 
@@ -106,10 +108,6 @@ impl<K: Clone + Eq + Hash, V> Trie<K, V> {
 pub enum InsertError {
     #[snafu(display("The key was empty"))]
     EmptyKey,
-    #[snafu(display("There was a Trie \"directory\" at the key"))]
-    DirAtKey,
-    #[snafu(display("A value was encountered at a prefix of the key"))]
-    PrefixContainsValue,
 }
 
 #[cfg(test)]
@@ -148,8 +146,8 @@ mod tests {
     // Given (1) a `Trie` `t`
     //     AND (2) a value was inserted into `t` at `a/b`
     // When `insert` is called with `a` as the key
-    // Then (A) the result is `Err(InsertError::DirAtKey)`
-    fn test_insert_at_dir_fails() {
+    // Then (A) the result is `Ok`
+    fn test_insert_at_dir() {
         // (1)
         let mut t = Trie::new();
         // (2)
@@ -159,15 +157,15 @@ mod tests {
         let result = t.insert(&['a'], 1);
 
         // (A)
-        assert!(matches!(result, Err(InsertError::DirAtKey)));
+        assert!(result.is_ok());
     }
 
     #[test]
     // Given (1) a `Trie` `t`
     //     AND (2) a value was inserted into `t` at `a/b/c`
     // When `insert` is called with `a/b` as the key
-    // Then (A) the result is `Err(InsertError::DirAtKey)`
-    fn test_insert_at_nested_dir_fails() {
+    // Then (A) the result is `Ok`
+    fn test_insert_at_nested_dir() {
         // (1)
         let mut t = Trie::new();
         // (2)
@@ -177,14 +175,14 @@ mod tests {
         let result = t.insert(&['a', 'b'], 1);
 
         // (A)
-        assert!(matches!(result, Err(InsertError::DirAtKey)));
+        assert!(result.is_ok());
     }
 
     #[test]
     // Given (1) a `Trie` `t`
     //     AND (2) a value was inserted into `t` at `a/b`
     // When `insert` is called with `a/b/c` as the key
-    // Then (A) the result is `Err(InsertError::PrefixContainsValue)`
+    // Then (A) the result is `Ok`
     fn test_insert_past_value_node() {
         // (1)
         let mut t = Trie::new();
@@ -195,7 +193,28 @@ mod tests {
         let result = t.insert(&['a', 'b', 'c'], 1);
 
         // (A)
-        assert!(matches!(result, Err(InsertError::PrefixContainsValue)));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    // Given (1) a `Trie` `t`
+    //     AND (2) `1` was inserted into `t` at `a/b`
+    // When `insert` is called with `a/b` as the key and `2` as the value
+    // Then (A) `value_at_prefix` returns `2` for `a/b`
+    fn test_insert_replaces_value() {
+        // (1)
+        let mut t = Trie::new();
+        // (2)
+        t.insert(&['a', 'b'], 1)
+            .expect("couldn't insert value");
+
+        t.insert(&['a', 'b'], 2)
+            .expect("couldn't insert value");
+
+        let result = t.value_at_prefix(&['a', 'b']);
+
+        // (A)
+        assert_eq!(result, Some((vec![&'a', &'b'], &2)));
     }
 
     #[test]
@@ -345,4 +364,48 @@ mod tests {
         // (A)
         assert_eq!(result, Some((vec![&'a', &'y'], &2)));
     }
+
+    #[test]
+    // Given (1) a `Trie` `t`
+    //     AND (2) `1` was inserted into `t` at `a`
+    //     AND (3) `2` was inserted into `t` at `a/b/c`
+    // When `value_at_prefix` is called with `a/b/c/d` as the key
+    // Then (A) the result contains a reference to `a/b/c` and `2`
+    fn test_value_at_prefix_returns_longest_prefix() {
+        // (1)
+        let mut t = Trie::new();
+        // (2)
+        t.insert(&['a'], 1)
+            .expect("couldn't insert value");
+        // (3)
+        t.insert(&['a', 'b', 'c'], 2)
+            .expect("couldn't insert value");
+
+        let result = t.value_at_prefix(&['a', 'b', 'c', 'd']);
+
+        // (A)
+        assert_eq!(result, Some((vec![&'a', &'b', &'c'], &2)));
+    }
+
+    #[test]
+    // Given (1) a `Trie` `t`
+    //     AND (2) `1` was inserted into `t` at `a`
+    //     AND (3) `2` was inserted into `t` at `a/b/c`
+    // When `value_at_prefix` is called with `a/b/x` as the key
+    // Then (A) the result contains a reference to `a` and `1`
+    fn test_value_at_prefix_falls_back_to_shorter_prefix() {
+        // (1)
+        let mut t = Trie::new();
+        // (2)
+        t.insert(&['a'], 1)
+            .expect("couldn't insert value");
+        // (3)
+        t.insert(&['a', 'b', 'c'], 2)
+            .expect("couldn't insert value");
+
+        let result = t.value_at_prefix(&['a', 'b', 'x']);
+
+        // (A)
+        assert_eq!(result, Some((vec![&'a'], &1)));
+    }
 }
@@ -0,0 +1,239 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+
+use crate::docker;
+use crate::test_setup;
+use crate::test_setup::Definition;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines a cache volume called `test` at `/a/b`
+//     AND (3) the cache volume for `test` doesn't exist
+// When `cache ls` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT lists `test` as not created
+fn cache_ls_lists_volumes() {
+    let test_name = "cache_ls_lists_volumes";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            cache_volumes:
+              test: '/a/b'
+        "},
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{},
+        },
+    );
+    // (3)
+    docker::assert_remove_volume(&test.cache_volume_name("test"));
+
+    let cmd_result = run_test_cmd(&test.dir, &["cache", "ls"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(predicate_str::contains(format!(
+            "{test_name}  test  not created",
+        )));
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines a cache volume called `test` at `/a/b`
+//     AND (3) the Dockerfile used by `<env>` sets the user to non-root
+//     AND (4) the cache volume for `test` contains `test.txt`
+//     AND (5) `cache export <env> test <archive>` was run
+//     AND (6) `cache reset <env> test` was run
+//     AND (7) the cache volume for `test` doesn't contain `test.txt`
+// When `cache import <env> test <archive>` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the cache volume for `test` contains `test.txt`
+//     AND (D) the cache volume is writable by the user of `<env>`
+fn cache_export_and_import_restore_volume() {
+    let test_name = "cache_export_and_import_restore_volume";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            cache_volumes:
+              test: '/a/b'
+        "},
+        &Definition{
+            name: test_name,
+            // (3)
+            dockerfile_steps: indoc!{"
+                USER 10000
+            "},
+            fs: &hashmap!{},
+        },
+    );
+    docker::assert_remove_volume(&test.cache_volume_name("test"));
+    // (4)
+    let write_cmd = "echo cached > /a/b/test.txt";
+    run_test_cmd(&test.dir, &["run-in", test_name, "sh", "-c", write_cmd])
+        .code(0);
+    // (5)
+    let archive = format!("{}/test.tar.gz", test.dir);
+    run_test_cmd(&test.dir, &["cache", "export", test_name, "test", &archive])
+        .code(0);
+    // (6)
+    run_test_cmd(&test.dir, &["cache", "reset", test_name, "test"])
+        .code(0);
+    // (7)
+    run_test_cmd(&test.dir, &["run-in", test_name, "ls", "/a/b/test.txt"])
+        .failure();
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &["cache", "import", test_name, "test", &archive],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("");
+    // (C)
+    run_test_cmd(&test.dir, &["run-in", test_name, "cat", "/a/b/test.txt"])
+        .code(0)
+        .stdout("cached\n");
+    // (D)
+    run_test_cmd(&test.dir, &["run-in", test_name, "touch", "/a/b/new.txt"])
+        .code(0);
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines a cache volume called `test` at `/a/b`
+//     AND (3) the image of `<env>` doesn't contain `tar`
+//     AND (4) the cache volume for `test` contains `test.txt`
+//     AND (5) `<archive>` exists
+// When `cache import <env> test <archive>` is run
+// Then (A) the command returns 1
+//     AND (B) the command STDERR reports that `tar` couldn't be found
+//     AND (C) the cache volume for `test` contains `test.txt`
+fn cache_import_without_tar_keeps_volume() {
+    let test_name = "cache_import_without_tar_keeps_volume";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            cache_volumes:
+              test: '/a/b'
+        "},
+        &Definition{
+            name: test_name,
+            // (3)
+            dockerfile_steps: indoc!{"
+                RUN rm \"$(command -v tar)\"
+            "},
+            fs: &hashmap!{
+                // (5)
+                "test.tar.gz" => "",
+            },
+        },
+    );
+    docker::assert_remove_volume(&test.cache_volume_name("test"));
+    // (4)
+    let write_cmd = "echo cached > /a/b/test.txt";
+    run_test_cmd(&test.dir, &["run-in", test_name, "sh", "-c", write_cmd])
+        .code(0);
+    let archive = format!("{}/test.tar.gz", test.dir);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &["cache", "import", test_name, "test", &archive],
+    );
+
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stderr(predicate_str::contains("`tar` couldn't be found in"));
+    // (C)
+    run_test_cmd(&test.dir, &["run-in", test_name, "cat", "/a/b/test.txt"])
+        .code(0)
+        .stdout("cached\n");
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines a cache volume called `test` at `/a/b`
+//     AND (3) the cache volume for `test` contains `test.txt`
+//     AND (4) `<archive>` isn't a gzipped tarball
+// When `cache import <env> test <archive>` is run
+// Then (A) the command returns 1
+//     AND (B) the command STDERR reports that `<archive>` couldn't be read
+//     AND (C) the cache volume for `test` contains `test.txt`
+fn cache_import_of_corrupt_archive_keeps_volume() {
+    let test_name = "cache_import_of_corrupt_archive_keeps_volume";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            cache_volumes:
+              test: '/a/b'
+        "},
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{
+                // (4)
+                "test.tar.gz" => "not a tarball",
+            },
+        },
+    );
+    docker::assert_remove_volume(&test.cache_volume_name("test"));
+    // (3)
+    let write_cmd = "echo cached > /a/b/test.txt";
+    run_test_cmd(&test.dir, &["run-in", test_name, "sh", "-c", write_cmd])
+        .code(0);
+    let archive = format!("{}/test.tar.gz", test.dir);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &["cache", "import", test_name, "test", &archive],
+    );
+
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stderr(predicate_str::contains(format!(
+            "`tar` couldn't read '{archive}'",
+        )));
+    // (C)
+    run_test_cmd(&test.dir, &["run-in", test_name, "cat", "/a/b/test.txt"])
+        .code(0)
+        .stdout("cached\n");
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    cmd.assert()
+}
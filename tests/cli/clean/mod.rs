@@ -11,6 +11,8 @@ use crate::test_setup;
 
 use crate::assert_cmd::assert::Assert;
 use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+use crate::predicates::prelude::PredicateBooleanExt;
 
 #[test]
 // Given (1) the dock file defines environments called `<env1>` and `<env2>`
@@ -22,7 +24,7 @@ use crate::assert_cmd::Command as AssertCommand;
 // When `clean` is run
 // Then (A) the command is successful
 //     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` doesn't exist
 //     AND (E) the image for `<env2>` doesn't exist
 //     AND (F) the cache volume for `<env1>` doesn't exist
@@ -51,7 +53,7 @@ fn clean_removes_images_and_volumes() {
         // (B)
         .stderr("")
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 2 images and 2 volumes\n");
     // (D)
     docker::assert_image_doesnt_exist(&setup.env1_img_tagged_name);
     // (E)
@@ -63,11 +65,13 @@ fn clean_removes_images_and_volumes() {
 }
 
 fn create_templates_dir(dir: &str, test_name: &str) -> TestSetup {
+    // Each test uses its own project, because `clean` removes all of the
+    // labelled resources of the project, and tests are run concurrently.
     let test_dock_yaml = formatdoc!{
         "
             schema_version: '0.1'
             organisation: org
-            project: proj
+            project: {test_name}
             default_shell_env: {test_name}1
 
             environments:
@@ -108,11 +112,11 @@ fn create_templates_dir(dir: &str, test_name: &str) -> TestSetup {
 
     TestSetup{
         env1: format!("{test_name}1"),
-        env1_img_tagged_name: format!("org/proj.{test_name}1:latest"),
-        env1_cache_vol: format!("org.proj.{test_name}1.cache.test"),
+        env1_img_tagged_name: format!("org/{test_name}.{test_name}1:latest"),
+        env1_cache_vol: format!("org.{test_name}.{test_name}1.cache.test"),
         env2: format!("{test_name}2"),
-        env2_img_tagged_name: format!("org/proj.{test_name}2:latest"),
-        env2_cache_vol: format!("org.proj.{test_name}2.cache.test"),
+        env2_img_tagged_name: format!("org/{test_name}.{test_name}2:latest"),
+        env2_cache_vol: format!("org.{test_name}.{test_name}2.cache.test"),
     }
 }
 
@@ -161,7 +165,7 @@ fn assert_noop_run(dir: &str, env: &str) {
 // When `clean` is run
 // Then (A) the command is successful
 //     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` doesn't exist
 //     AND (E) the image for `<env2>` doesn't exist
 //     AND (F) the cache volume for `<env1>` doesn't exist
@@ -189,7 +193,7 @@ fn clean_ignores_missing_images_and_volumes() {
         // (B)
         .stderr("")
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 1 image and 1 volume\n");
     // (D)
     docker::assert_image_doesnt_exist(&setup.env1_img_tagged_name);
     // (E)
@@ -210,7 +214,7 @@ fn clean_ignores_missing_images_and_volumes() {
 // When `clean --skip-images` is run
 // Then (A) the command is successful
 //     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` exists
 //     AND (E) the image for `<env2>` exists
 //     AND (F) the cache volume for `<env1>` doesn't exist
@@ -239,7 +243,58 @@ fn clean_skip_images() {
         // (B)
         .stderr("")
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 0 images and 2 volumes\n");
+    // (D)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (E)
+    docker::assert_image_exists(&setup.env2_img_tagged_name);
+    // (F)
+    docker::assert_volume_doesnt_exist(&setup.env1_cache_vol);
+    // (G)
+    docker::assert_volume_doesnt_exist(&setup.env2_cache_vol);
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<env1>` and `<env2>`
+//     AND (2) `<env1>` and `<env2>` define cache volumes called `test`
+//     AND (3) the image for `<env1>` exists
+//     AND (4) the cache volume for `<env1>` exists
+//     AND (5) the image for `<env2>` exists
+//     AND (6) the cache volume for `<env2>` exists
+// When `clean --volume test` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT summarises the removed resources
+//     AND (D) the image for `<env1>` exists
+//     AND (E) the image for `<env2>` exists
+//     AND (F) the cache volume for `<env1>` doesn't exist
+//     AND (G) the cache volume for `<env2>` doesn't exist
+fn clean_volume_keeps_images() {
+    let test_name = "clean_volume_keeps_images";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let setup = create_templates_dir(&root_test_dir, test_name);
+    assert_noop_run(&root_test_dir, &setup.env1);
+    // (3)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (4)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+    assert_noop_run(&root_test_dir, &setup.env2);
+    // (5)
+    docker::assert_image_exists(&setup.env2_img_tagged_name);
+    // (6)
+    docker::assert_volume_exists(&setup.env2_cache_vol);
+
+    let cmd_result =
+        run_test_cmd(&root_test_dir, &["clean", "--volume", "test"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("Removed 0 containers, 0 images and 2 volumes\n");
     // (D)
     docker::assert_image_exists(&setup.env1_img_tagged_name);
     // (E)
@@ -260,7 +315,7 @@ fn clean_skip_images() {
 // When `clean --skip-volumes` is run
 // Then (A) the command is successful
 //     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` doesn't exist
 //     AND (E) the image for `<env2>` doesn't exist
 //     AND (F) the cache volume for `<env1>` exists
@@ -290,7 +345,7 @@ fn clean_skip_volumes() {
         // (B)
         .stderr("")
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 2 images and 0 volumes\n");
     // (D)
     docker::assert_image_doesnt_exist(&setup.env1_img_tagged_name);
     // (E)
@@ -308,9 +363,9 @@ fn clean_skip_volumes() {
 //     AND (4) the cache volume for `<env1>` exists
 //     AND (5) a container for the image for `<env1>` exists
 // When `clean` is run
-// Then (A) the command is successful
-//     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+// Then (A) the command returns 1
+//     AND (B) the command STDERR reports that the image couldn't be removed
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` exists
 //     AND (E) the cache volume for `<env1>` doesn't exist
 fn clean_image_in_use() {
@@ -330,13 +385,14 @@ fn clean_image_in_use() {
 
     cmd_result
         // (A)
-        .code(0)
-        // TODO STDERR should contain a message to indicate that the image
-        // couldn't be removed.
+        .code(1)
         // (B)
-        .stderr("")
+        .stderr(predicate_str::contains(format!(
+            "Couldn't remove image '{}': ",
+            setup.env1_img_tagged_name,
+        )))
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 0 images and 1 volume\n");
     // (D)
     docker::assert_image_exists(&setup.env1_img_tagged_name);
     // (E)
@@ -351,11 +407,12 @@ fn clean_image_in_use() {
 //     AND (5) a container for the image for `<env1>` exists
 //     AND (6) the container uses the cache volume for `<env1>`
 // When `clean` is run
-// Then (A) the command is successful
-//     AND (B) the command STDERR is empty
-//     AND (C) the command STDOUT is empty
+// Then (A) the command returns 1
+//     AND (B) the command STDERR reports that the image and the cache volume
+//         couldn't be removed
+//     AND (C) the command STDOUT summarises the removed resources
 //     AND (D) the image for `<env1>` exists
-//     AND (E) the cache volume for `<env1>` doesn't exist
+//     AND (E) the cache volume for `<env1>` exists
 fn clean_volume_in_use() {
     let test_name = "clean_volume_in_use";
     let root_test_dir = test_setup::assert_create_root_dir(test_name);
@@ -378,17 +435,241 @@ fn clean_volume_in_use() {
 
     let cmd_result = run_test_cmd(&root_test_dir, &["clean"]);
 
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stderr(
+            predicate_str::contains(format!(
+                "Couldn't remove image '{}': ",
+                setup.env1_img_tagged_name,
+            ))
+                .and(predicate_str::contains(format!(
+                    "Couldn't remove volume '{}': ",
+                    setup.env1_cache_vol,
+                ))),
+        )
+        // (C)
+        .stdout("Removed 0 containers, 0 images and 0 volumes\n");
+    // (D)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (E)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<env1>` and `<env2>`
+//     AND (2) `<env1>` and `<env2>` define cache volumes
+//     AND (3) the image for `<env1>` exists
+//     AND (4) the cache volume for `<env1>` exists
+//     AND (5) the image for `<env2>` exists
+//     AND (6) the cache volume for `<env2>` exists
+//     AND (7) `<env2>` is removed from the dock file
+// When `clean` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT summarises the removed resources
+//     AND (D) the image for `<env1>` doesn't exist
+//     AND (E) the image for `<env2>` doesn't exist
+//     AND (F) the cache volume for `<env1>` doesn't exist
+//     AND (G) the cache volume for `<env2>` doesn't exist
+fn clean_removes_resources_of_removed_env() {
+    let test_name = "clean_removes_resources_of_removed_env";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let setup = create_templates_dir(&root_test_dir, test_name);
+    assert_noop_run(&root_test_dir, &setup.env1);
+    // (3)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (4)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+    assert_noop_run(&root_test_dir, &setup.env2);
+    // (5)
+    docker::assert_image_exists(&setup.env2_img_tagged_name);
+    // (6)
+    docker::assert_volume_exists(&setup.env2_cache_vol);
+    // (7)
+    let test_dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: {test_name}
+            default_shell_env: {test_name}1
+
+            environments:
+              {test_name}1:
+                cache_volumes: {{ test: /test }}
+        ",
+        test_name = test_name,
+    };
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{"dock.yaml" => test_dock_yaml.as_str()},
+    );
+
+    let cmd_result = run_test_cmd(&root_test_dir, &["clean"]);
+
     cmd_result
         // (A)
         .code(0)
-        // TODO STDERR should contain a message to indicate that the image
-        // couldn't be removed.
         // (B)
         .stderr("")
         // (C)
-        .stdout("");
+        .stdout("Removed 0 containers, 2 images and 2 volumes\n");
+    // (D)
+    docker::assert_image_doesnt_exist(&setup.env1_img_tagged_name);
+    // (E)
+    docker::assert_image_doesnt_exist(&setup.env2_img_tagged_name);
+    // (F)
+    docker::assert_volume_doesnt_exist(&setup.env1_cache_vol);
+    // (G)
+    docker::assert_volume_doesnt_exist(&setup.env2_cache_vol);
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<env1>` and `<env2>`
+//     AND (2) `<env1>` and `<env2>` define cache volumes
+//     AND (3) the image for `<env1>` exists
+//     AND (4) the cache volume for `<env1>` exists
+//     AND (5) the image for `<env2>` exists
+//     AND (6) the cache volume for `<env2>` exists
+// When `clean <env2>` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT summarises the removed resources
+//     AND (D) the image for `<env1>` exists
+//     AND (E) the image for `<env2>` doesn't exist
+//     AND (F) the cache volume for `<env1>` exists
+//     AND (G) the cache volume for `<env2>` doesn't exist
+fn clean_selects_env() {
+    let test_name = "clean_selects_env";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let setup = create_templates_dir(&root_test_dir, test_name);
+    assert_noop_run(&root_test_dir, &setup.env1);
+    // (3)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (4)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+    assert_noop_run(&root_test_dir, &setup.env2);
+    // (5)
+    docker::assert_image_exists(&setup.env2_img_tagged_name);
+    // (6)
+    docker::assert_volume_exists(&setup.env2_cache_vol);
+
+    let cmd_result = run_test_cmd(&root_test_dir, &["clean", &setup.env2]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("Removed 0 containers, 1 image and 1 volume\n");
+    // (D)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (E)
+    docker::assert_image_doesnt_exist(&setup.env2_img_tagged_name);
+    // (F)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+    // (G)
+    docker::assert_volume_doesnt_exist(&setup.env2_cache_vol);
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<env1>` and `<env2>`
+//     AND (2) `<env1>` and `<env2>` define cache volumes
+//     AND (3) the image for `<env1>` exists
+//     AND (4) the cache volume for `<env1>` exists
+// When `clean --dry-run --skip-images` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT lists the cache volume for `<env1>`
+//     AND (D) the image for `<env1>` exists
+//     AND (E) the cache volume for `<env1>` exists
+fn clean_dry_run() {
+    let test_name = "clean_dry_run";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let setup = create_templates_dir(&root_test_dir, test_name);
+    assert_noop_run(&root_test_dir, &setup.env1);
+    // (3)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (4)
+    docker::assert_volume_exists(&setup.env1_cache_vol);
+
+    let cmd_result = run_test_cmd(
+        &root_test_dir,
+        &["clean", "--dry-run", "--skip-images"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(predicate_str::contains(format!(
+            "volume     {}  ",
+            setup.env1_cache_vol,
+        )));
     // (D)
     docker::assert_image_exists(&setup.env1_img_tagged_name);
     // (E)
     docker::assert_volume_exists(&setup.env1_cache_vol);
 }
+
+#[test]
+// Given (1) the dock file defines an environment called `<env1>`
+//     AND (2) `<env1>` defines a cache volume
+//     AND (3) the image for `<env1>` exists
+//     AND (4) an image `<child>` built from the image for `<env1>` by
+//         `docker build` exists
+// When `clean` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT summarises the removed resources
+//     AND (D) the image for `<env1>` doesn't exist
+//     AND (E) `<child>` exists
+fn clean_keeps_images_built_from_dock_images() {
+    let test_name = "clean_keeps_images_built_from_dock_images";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let setup = create_templates_dir(&root_test_dir, test_name);
+    assert_noop_run(&root_test_dir, &setup.env1);
+    // (3)
+    docker::assert_image_exists(&setup.env1_img_tagged_name);
+    // (4)
+    let child_img_tagged_name = format!("{test_name}_child:latest");
+    let child_dockerfile =
+        format!("FROM {}\n", setup.env1_img_tagged_name);
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{"child.Dockerfile" => child_dockerfile.as_str()},
+    );
+    assert_run::assert_run_in_dir(
+        &root_test_dir,
+        "docker",
+        &[
+            "build",
+            "--file=child.Dockerfile",
+            &format!("--tag={child_img_tagged_name}"),
+            ".",
+        ],
+    );
+
+    let cmd_result = run_test_cmd(&root_test_dir, &["clean"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("Removed 0 containers, 1 image and 1 volume\n");
+    // (D)
+    docker::assert_image_doesnt_exist(&setup.env1_img_tagged_name);
+    // (E)
+    docker::assert_image_exists(&child_img_tagged_name);
+    docker::assert_remove_image(&child_img_tagged_name);
+}
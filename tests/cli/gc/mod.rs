@@ -0,0 +1,98 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+use std::fs;
+
+use regex::escape;
+
+use crate::docker;
+use crate::test_setup;
+use crate::test_setup::Definition;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) the image for `<env>` exists
+//     AND (3) the directory of the dock file was removed
+// When `gc --missing-dir --dry-run` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT reports `<env>` as having a missing directory
+//     AND (D) the image for `<env>` exists
+fn gc_dry_run_reports_env_with_missing_dir() {
+    let test_name = "gc_dry_run_reports_env_with_missing_dir";
+    // (1)
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        dockerfile_steps: "",
+        fs: &hashmap!{},
+    });
+    run_test_cmd(&test.dir, &["run-in", test_name, "true"])
+        .code(0);
+    // (2)
+    docker::assert_image_exists(&test.image_tagged_name);
+    // (3)
+    fs::remove_dir_all(&test.dir)
+        .expect("couldn't remove the test directory");
+
+    let cmd_result =
+        run_test_cmd(env!("HOME"), &["gc", "--missing-dir", "--dry-run"]);
+
+    let env_pattern = format!(
+        "(?m)^\\S+ +{} +.* +project directory is missing$",
+        escape(test_name),
+    );
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(
+            predicate_str::is_match(env_pattern)
+                .expect("couldn't generate a pattern match"),
+        );
+    // (D)
+    docker::assert_image_exists(&test.image_tagged_name);
+}
+
+#[test]
+// Given (1) no pruning criteria are given
+// When `gc` is run
+// Then (A) the command returns 1
+//     AND (B) the command STDOUT is empty
+//     AND (C) the command STDERR lists the pruning criteria
+fn gc_requires_criteria() {
+    let cmd_result = run_test_cmd(env!("HOME"), &["gc"]);
+
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stdout("")
+        // (C)
+        .stderr(
+            "At least one of `--max-age`, `--max-size` or `--missing-dir` is \
+             required\n",
+        );
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    cmd.assert()
+}
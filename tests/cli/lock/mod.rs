@@ -0,0 +1,102 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+
+use regex::escape;
+
+use crate::test_setup;
+use crate::test_setup::Definition;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) the lock file doesn't pin any base images
+// When `lock --check` is run
+// Then (A) the command returns 1
+//     AND (B) the command STDOUT is empty
+//     AND (C) the command STDERR reports the unpinned base image of `<env>`
+fn lock_check_reports_unpinned_base_image() {
+    let test_name = "lock_check_reports_unpinned_base_image";
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        dockerfile_steps: "",
+        // (2)
+        fs: &hashmap!{
+            "dock.lock" => "environments: {}\n",
+        },
+    });
+
+    let cmd_result = run_test_cmd(&test.dir, &["lock", "--check"]);
+
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stdout("")
+        // (C)
+        .stderr(formatdoc!{
+            "
+                'dock.lock' is out of date:
+                  '{base_img}' of '{test_name}' isn't pinned
+                Run `dock lock` to update it
+            ",
+            base_img = test_setup::TEST_BASE_IMG,
+            test_name = test_name,
+        });
+}
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) the lock file doesn't exist
+// When `lock` is run
+// Then (A) the command is successful
+//     AND (B) the command STDOUT reports the pinned base image of `<env>`
+//     AND (C) `lock --check` is successful
+fn lock_pins_base_image() {
+    let test_name = "lock_pins_base_image";
+    // (1) (2)
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        dockerfile_steps: "",
+        fs: &hashmap!{},
+    });
+
+    let cmd_result = run_test_cmd(&test.dir, &["lock"]);
+
+    let pinned_pattern = format!(
+        "^Pinned '{}' to 'sha256:[0-9a-f]{{64}}'\n$",
+        escape(test_setup::TEST_BASE_IMG),
+    );
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout(
+            predicate_str::is_match(pinned_pattern)
+                .expect("couldn't generate a pattern match"),
+        );
+    // (C)
+    run_test_cmd(&test.dir, &["lock", "--check"])
+        .code(0)
+        .stderr("");
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    cmd.assert()
+}
@@ -2,8 +2,15 @@
 // Use of this source code is governed by an MIT
 // licence that can be found in the LICENCE file.
 
+mod cache;
 mod clean;
+mod gc;
 mod init;
+mod lock;
+mod outdated;
 pub mod rebuild;
+mod rebuild_all;
+mod registry;
+mod rollback;
 mod run_in;
 mod shell;
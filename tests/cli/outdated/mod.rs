@@ -0,0 +1,107 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+
+use crate::docker;
+use crate::test_setup;
+use crate::test_setup::Definition;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) `<env>` defines a `base_refresh` of 7 days
+//     AND (3) the base images of `<env>` have never been pulled by `dock`
+// When `outdated` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT lists `<env>` as never pulled
+fn outdated_lists_env_that_was_never_pulled() {
+    let test_name = "outdated_lists_env_that_was_never_pulled";
+    // (1) (2)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        "base_refresh: 7d",
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{},
+        },
+    );
+    // (3)
+    let cache_dir = test_setup::assert_create_dir(test.dir.clone(), "cache");
+
+    let cmd_result = run_test_cmd(&test.dir, &cache_dir, &["outdated"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(formatdoc!{
+            "
+                {env:<width$}  LAST PULL  BASE_REFRESH
+                {test_name}  never      1w
+            ",
+            env = "ENVIRONMENT",
+            width = test_name.len(),
+            test_name = test_name,
+        });
+}
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) `<env>` defines a `base_refresh` of 7 days
+//     AND (3) the base images of `<env>` have never been pulled by `dock`
+//     AND (4) `<env>` was rebuilt
+// When `outdated` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT is empty
+fn outdated_skips_env_after_rebuild() {
+    let test_name = "outdated_skips_env_after_rebuild";
+    // (1) (2)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        "base_refresh: 7d",
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+    // (3)
+    let cache_dir = test_setup::assert_create_dir(test.dir.clone(), "cache");
+    // (4)
+    run_test_cmd(&test.dir, &cache_dir, &["run-in", test_name, "true"])
+        .code(0);
+
+    let cmd_result = run_test_cmd(&test.dir, &cache_dir, &["outdated"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("");
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, cache_dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+    cmd.env("XDG_CACHE_HOME", cache_dir);
+
+    cmd.assert()
+}
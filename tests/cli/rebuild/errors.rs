@@ -127,3 +127,31 @@ fn prefix_tag_argument() {
         // (C)
         .stderr(format!("unsupported argument: `{arg}`\n"));
 }
+
+#[test]
+// Given a valid Dockerfile
+// When the `rebuild` subcommand is run with a combined `-qt` argument
+// Then (A) the command fails
+//     AND (B) the command STDOUT is empty
+//     AND (C) the command STDERR contains an error message
+fn combined_short_tag_argument() {
+    // (1)
+    let test = test_setup::assert_apply(&Definition{
+        name: "combined_short_tag_argument",
+        dockerfile_steps: "",
+        fs: &hashmap!{},
+    });
+    let mut cmd = new_test_cmd(test.dir, &test.image_tagged_name);
+    let flag = "-qt";
+    cmd.args([flag, &test.image_tagged_name]);
+
+    let cmd_result = cmd.assert();
+
+    cmd_result
+        // (A)
+        .failure()
+        // (B)
+        .stdout("")
+        // (C)
+        .stderr(format!("unsupported argument: `{flag}`\n"));
+}
@@ -0,0 +1,118 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+
+use crate::docker;
+use crate::test_setup;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+use crate::predicates::str::RegexPredicate;
+
+#[test]
+// Given (1) the dock file defines environments called `<parent>`, `<child>`
+//         and `<broken>`
+//     AND (2) `<child>` is built from the image for `<parent>`
+//     AND (3) the Dockerfile for `<broken>` fails
+//     AND (4) the images for the environments don't exist
+// When `rebuild-all --jobs 2` is run
+// Then (A) the command exits with 1
+//     AND (B) the command STDOUT lists `<parent>` and `<child>` as rebuilt
+//     AND (C) the command STDOUT lists `<broken>` as failed
+//     AND (D) the images for `<parent>` and `<child>` exist
+fn rebuild_all_rebuilds_envs_in_dependency_order() {
+    let test_name = "rebuild_all_rebuilds_envs_in_dependency_order";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    let parent = format!("{test_name}_parent");
+    let child = format!("{test_name}_child");
+    let broken = format!("{test_name}_broken");
+    // (1)
+    let dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: proj
+            default_shell_env: {child}
+
+            environments:
+              {parent}: {{}}
+              {child}: {{}}
+              {broken}: {{}}
+        ",
+        parent = parent,
+        child = child,
+        broken = broken,
+    };
+    let parent_dockerfile =
+        format!("FROM {}\n", test_setup::TEST_BASE_IMG);
+    // (2)
+    let child_dockerfile = format!("FROM org/proj.{parent}:latest\n");
+    // (3)
+    let broken_dockerfile = formatdoc!{
+        "
+            FROM {test_base_img}
+
+            RUN false
+        ",
+        test_base_img = test_setup::TEST_BASE_IMG,
+    };
+    let parent_dockerfile_name = format!("{parent}.Dockerfile");
+    let child_dockerfile_name = format!("{child}.Dockerfile");
+    let broken_dockerfile_name = format!("{broken}.Dockerfile");
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{
+            "dock.yaml" => dock_yaml.as_str(),
+            parent_dockerfile_name.as_str() => parent_dockerfile.as_str(),
+            child_dockerfile_name.as_str() => child_dockerfile.as_str(),
+            broken_dockerfile_name.as_str() => broken_dockerfile.as_str(),
+        },
+    );
+    let parent_img = format!("org/proj.{parent}:latest");
+    let child_img = format!("org/proj.{child}:latest");
+    // (4)
+    docker::assert_remove_image(&child_img);
+    docker::assert_remove_image(&parent_img);
+    docker::assert_remove_image(&format!("org/proj.{broken}:latest"));
+
+    let cmd_result =
+        run_test_cmd(&root_test_dir, &["rebuild-all", "--jobs", "2"]);
+
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stdout(predicate_match(&format!("\n{parent} +rebuilt ")))
+        .stdout(predicate_match(&format!("\n{child} +rebuilt ")))
+        // (C)
+        .stdout(predicate_match(&format!("\n{broken} +failed ")));
+    // (D)
+    docker::assert_image_exists(&parent_img);
+    docker::assert_image_exists(&child_img);
+}
+
+// TODO Duplicated from `tests/cli/run_in/success.rs`.
+fn predicate_match(s: &str) -> RegexPredicate {
+    predicate_str::is_match(s)
+        .unwrap_or_else(|e| panic!(
+            "couldn't generate a pattern match for '{s}': {e}",
+        ))
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    cmd.assert()
+}
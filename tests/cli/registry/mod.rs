@@ -0,0 +1,218 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+use std::process::Command;
+
+use crate::docker;
+use crate::test_setup;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+use crate::predicates::prelude::predicate::str as predicate_str;
+use crate::predicates::prelude::PredicateBooleanExt;
+use crate::predicates::str::RegexPredicate;
+
+const REGISTRY_CONTAINER_NAME: &str = "dock_test_registry";
+const REGISTRY: &str = "localhost:5000";
+
+#[test]
+// Given (1) a local registry is running
+//     AND (2) the dock file defines an environment called `<env>`
+//     AND (3) the dock file uses the local registry
+//     AND (4) the image for `<env>` was rebuilt and pushed to the registry
+//     AND (5) the local image for `<env>` doesn't exist
+// When `run-in --debug <env> true` is run
+// Then (A) the command is successful
+//     AND (B) the image for `<env>` is pulled from the registry
+//     AND (C) the image for `<env>` isn't rebuilt
+//     AND (D) the image for `<env>` exists
+fn run_in_pulls_pushed_image() {
+    let test_name = "run_in_pulls_pushed_image";
+    // (1)
+    assert_start_registry();
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (2) (3)
+    let dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: proj
+            default_shell_env: {test_name}
+            registry: {REGISTRY}
+
+            environments:
+              {test_name}: {{}}
+        ",
+        test_name = test_name,
+        REGISTRY = REGISTRY,
+    };
+    let dockerfile = format!(
+        "FROM {}\nRUN echo {test_name} > /test.txt\n",
+        test_setup::TEST_BASE_IMG,
+    );
+    let dockerfile_name = format!("{test_name}.Dockerfile");
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{
+            "dock.yaml" => dock_yaml.as_str(),
+            dockerfile_name.as_str() => dockerfile.as_str(),
+        },
+    );
+    let img = format!("org/proj.{test_name}:latest");
+    docker::assert_remove_image(&img);
+    // (4)
+    run_test_cmd(&root_test_dir, &["run-in", test_name, "true"])
+        .code(0);
+    run_test_cmd(&root_test_dir, &["push", test_name])
+        .code(0)
+        .stdout(predicate_match(&format!(
+            "^Pushed '{REGISTRY}/org/proj.{test_name}:[0-9a-f]{{64}}'\n$",
+        )));
+    // (5)
+    docker::assert_remove_image(&img);
+
+    let cmd_result = run_test_cmd(
+        &root_test_dir,
+        &["run-in", "--debug", test_name, "true"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout(predicate_match(&format!(
+            r"\[\$\] docker pull {REGISTRY}/org/proj\.{test_name}:",
+        )))
+        // (C)
+        .stdout(predicate_match(r"\[\$\] docker build").not());
+    // (D)
+    docker::assert_image_exists(&img);
+}
+
+#[test]
+// Given (1) a local registry is running
+//     AND (2) the dock file defines an environment called `<env>`
+//     AND (3) the dock file uses the local registry
+//     AND (4) `<env>` uses the `ctx` directory as its context
+//     AND (5) `<env>`'s Dockerfile copies `ctx/a.txt` into the image
+//     AND (6) the image for `<env>` was rebuilt and pushed to the registry
+//     AND (7) the local image for `<env>` doesn't exist
+//     AND (8) the content of `ctx/a.txt` was changed
+// When `run-in <env> cat /a.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDOUT contains the new content of `ctx/a.txt`
+fn run_in_doesnt_pull_image_with_changed_context() {
+    let test_name = "run_in_doesnt_pull_image_with_changed_context";
+    // (1)
+    assert_start_registry();
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (2) (3) (4)
+    let dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: proj
+            default_shell_env: {test_name}
+            registry: {REGISTRY}
+
+            environments:
+              {test_name}:
+                context: ./ctx
+        ",
+        test_name = test_name,
+        REGISTRY = REGISTRY,
+    };
+    // (5)
+    let dockerfile = format!(
+        "FROM {}\nCOPY a.txt /a.txt\n",
+        test_setup::TEST_BASE_IMG,
+    );
+    let dockerfile_name = format!("{test_name}.Dockerfile");
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{
+            "dock.yaml" => dock_yaml.as_str(),
+            dockerfile_name.as_str() => dockerfile.as_str(),
+            "ctx/a.txt" => "old\n",
+        },
+    );
+    let img = format!("org/proj.{test_name}:latest");
+    docker::assert_remove_image(&img);
+    // (6)
+    run_test_cmd(&root_test_dir, &["run-in", test_name, "true"])
+        .code(0);
+    run_test_cmd(&root_test_dir, &["push", test_name])
+        .code(0);
+    // (7)
+    docker::assert_remove_image(&img);
+    // (8)
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{"ctx/a.txt" => "new\n"},
+    );
+
+    let cmd_result = run_test_cmd(
+        &root_test_dir,
+        &["run-in", test_name, "cat", "/a.txt"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout("new\n");
+}
+
+// `assert_start_registry` starts a local registry, if it isn't already
+// running.
+fn assert_start_registry() {
+    let start_status =
+        Command::new("docker")
+            .args(["start", REGISTRY_CONTAINER_NAME])
+            .output()
+            .expect("couldn't run `docker start`")
+            .status;
+
+    if start_status.success() {
+        return;
+    }
+
+    let run_status =
+        Command::new("docker")
+            .args([
+                "run",
+                "--detach",
+                "--publish=5000:5000",
+                &format!("--name={REGISTRY_CONTAINER_NAME}"),
+                "registry:2",
+            ])
+            .status()
+            .expect("couldn't run `docker run`");
+
+    assert!(run_status.success(), "couldn't start the test registry");
+}
+
+// TODO Duplicated from `tests/cli/run_in/success.rs`.
+fn predicate_match(s: &str) -> RegexPredicate {
+    predicate_str::is_match(s)
+        .unwrap_or_else(|e| panic!(
+            "couldn't generate a pattern match for '{s}': {e}",
+        ))
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    cmd.assert()
+}
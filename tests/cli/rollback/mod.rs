@@ -0,0 +1,118 @@
+// Copyright 2024 Sean Kelleher. All rights reserved.
+// Use of this source code is governed by an MIT
+// licence that can be found in the LICENCE file.
+
+use std::env;
+
+use crate::docker;
+use crate::test_setup;
+
+use crate::assert_cmd::assert::Assert;
+use crate::assert_cmd::Command as AssertCommand;
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` keeps one previous image
+//     AND (3) `<env>` was built from a Dockerfile that writes `v1`
+//     AND (4) `<env>` was rebuilt from a Dockerfile that writes `v2`
+// When `rollback <env>` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT is empty
+//     AND (D) `run-in --skip-rebuild <env>` runs in the `v1` image
+//     AND (E) the `v2` image is kept as `prev-1`
+fn rollback_restores_previous_image() {
+    let test_name = "rollback_restores_previous_image";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    let img_name = format!("org/proj.{test_name}");
+    docker::assert_remove_image(&format!("{img_name}:latest"));
+    docker::assert_remove_image(&format!("{img_name}:prev-1"));
+    // (1) (2) (3)
+    write_fs_state(&root_test_dir, test_name, "v1");
+    assert_cat_test_file(&root_test_dir, test_name, &[], "v1\n");
+    // (4)
+    write_fs_state(&root_test_dir, test_name, "v2");
+    assert_cat_test_file(&root_test_dir, test_name, &[], "v2\n");
+
+    let cmd_result = run_test_cmd(&root_test_dir, &["rollback", test_name]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("");
+    // (D)
+    assert_cat_test_file(&root_test_dir, test_name, &["-R"], "v1\n");
+    // (E)
+    docker::assert_image_exists(&format!("{img_name}:prev-1"));
+}
+
+fn write_fs_state(dir: &str, test_name: &str, vsn: &str) {
+    let test_dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: proj
+            default_shell_env: {test_name}
+
+            environments:
+              {test_name}:
+                image_history: 1
+        ",
+        test_name = test_name,
+    };
+    let test_dockerfile_name = test_name.to_string() + ".Dockerfile";
+    let test_dockerfile = formatdoc!{
+        "
+            FROM {test_base_img}
+
+            RUN echo '{vsn}' > /test.txt
+        ",
+        test_base_img = test_setup::TEST_BASE_IMG,
+        vsn = vsn,
+    };
+    let fs_state = &hashmap!{
+        "dock.yaml" => test_dock_yaml.as_str(),
+        test_dockerfile_name.as_str() => test_dockerfile.as_str(),
+    };
+    test_setup::assert_write_fs_state(dir, fs_state);
+}
+
+fn assert_cat_test_file(
+    dir: &str,
+    env: &str,
+    flags: &[&str],
+    expected: &str,
+) {
+    let mut args = vec!["run-in"];
+    args.extend(flags);
+    args.extend([env, "cat", "/test.txt"]);
+
+    run_test_cmd(dir, &args)
+        .code(0)
+        .stdout(expected.to_string());
+}
+
+// TODO Mostly duplicated from `crate::cli::run_in::success::run_test_cmd`.
+fn run_test_cmd(dir: &str, args: &[&str]) -> Assert {
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(args);
+    cmd.current_dir(dir);
+    cmd.env_clear();
+
+    // We set `HOME` because if unset then Docker BuildKit will create a
+    // `.docker` directory in the working directory during builds.
+    cmd.env("HOME", env!("HOME"));
+
+    if let Ok(v) = env::var(DOCK_HOSTPATHS_VAR_NAME) {
+        cmd.env(DOCK_HOSTPATHS_VAR_NAME, v);
+    }
+
+    cmd.assert()
+}
+
+// TODO Duplicated from `crate::cli::run_in::success::run_test_cmd`.
+const DOCK_HOSTPATHS_VAR_NAME: &str = "DOCK_HOSTPATHS";
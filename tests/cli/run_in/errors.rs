@@ -11,6 +11,7 @@ use crate::test_setup::Definition;
 use super::success;
 use super::success::TestDefinition;
 
+use crate::assert_cmd::Command as AssertCommand;
 use crate::predicates::prelude::predicate;
 use crate::predicates::prelude::predicate::str as predicate_str;
 use crate::predicates::str::RegexPredicate;
@@ -20,9 +21,10 @@ use crate::predicates::str::RegexPredicate;
 //     AND (2) the Dockerfile used by `<env>` has a step that fails
 // When `run-in <env> true` is run
 // Then (A) the command returns an exit code of 1
-//     AND (B) the command STDERR contains the `docker build` STDERR
-//     AND (C) the command STDOUT contains the `docker build` STDOUT
+//     AND (B) the command STDERR contains the end of the `docker build` STDERR
+//     AND (C) the command STDERR refers to `logs <env> --last-build`
 //     AND (D) the target image doesn't exist
+//     AND (E) `logs <env> --last-build` outputs the `docker build` STDERR
 fn run_in_with_build_failure() {
     let test_name = "run_with_build_failure";
     // (1)
@@ -42,17 +44,29 @@ fn run_in_with_build_failure() {
     // Docker client. This message is correct when using
     // `Docker Engine - Community` version `23.0.3` as the Docker client.
     let exp = "ERROR: executor failed running \\[/bin/sh -c exit 2\\]";
-    let cmd_result =
-        cmd_result
-            // (A)
-            .code(1)
-            // (B)
-            .stderr(predicate_match(exp));
-    let stdout = rebuild_success::new_str_from_cmd_stderr(&cmd_result);
-    // (C)
-    rebuild_success::assert_docker_build_stderr(stdout);
+    cmd_result
+        // (A)
+        .code(1)
+        // (B)
+        .stderr(predicate_match(exp))
+        // (C)
+        .stderr(predicate_str::contains(
+            format!("`dock logs {test_name} --last-build`"),
+        ));
     // (D)
     docker::assert_image_doesnt_exist(&test.image_tagged_name);
+
+    let mut cmd = AssertCommand::cargo_bin(env!("CARGO_PKG_NAME"))
+        .expect("couldn't create command for package binary");
+    cmd.args(["logs", test_name, "--last-build"]);
+    cmd.current_dir(&test.dir);
+    cmd.env_clear();
+    cmd.env("HOME", env!("HOME"));
+    let logs_result = cmd.assert().code(0);
+    let stdout = str::from_utf8(&logs_result.get_output().stdout)
+        .expect("couldn't decode STDOUT");
+    // (E)
+    rebuild_success::assert_docker_build_stderr(stdout);
 }
 
 // TODO Duplicated from `tests/cli/run_in/success.rs`.
@@ -152,9 +166,10 @@ fn build_with_file_outside_context_directory() {
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
 //     AND (2) the `<env>` context path starts with `..`
+//     AND (3) the context directory doesn't exist
 // When `run-in <env> true` is run
 // Then (A) the command returns an exit code of 1
-//     AND (B) the command STDERR indicates the invalid path
+//     AND (B) the command STDERR indicates the missing directory
 //     AND (B) the command STDOUT is empty
 //     AND (D) the target image doesn't exist
 //     AND (E) no containers exist for the target image
@@ -168,6 +183,7 @@ fn context_starts_with_path_traversal() {
         "},
         &Definition{
             name: test_name,
+            // (3)
             fs: &hashmap!{},
             dockerfile_steps: indoc!{""},
         },
@@ -180,9 +196,7 @@ fn context_starts_with_path_traversal() {
         // (A)
         .code(1)
         // (B)
-        .stderr(predicate_str::ends_with(
-            "The relative path didn't start with `.`\n",
-        ))
+        .stderr(predicate_str::ends_with("/dir' doesn't exist\n"))
         // (C)
         .stdout("");
     // (D)
@@ -193,7 +207,7 @@ fn context_starts_with_path_traversal() {
 
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
-//     AND (2) the `<env>` context path contains a `..` component
+//     AND (2) the `<env>` context path doesn't start with `.`, `..` or `/`
 // When `run-in <env> true` is run
 // Then (A) the command returns an exit code of 1
 //     AND (B) the command STDERR indicates the invalid path
@@ -223,7 +237,7 @@ fn context_contains_path_traversal() {
         .code(1)
         // (B)
         .stderr(predicate_str::ends_with(
-            "The relative path didn't start with `.`\n",
+            "The context path 'dir/../dir' must start with `.`, `..` or `/`\n",
         ))
         // (C)
         .stdout("");
@@ -236,9 +250,10 @@ fn context_contains_path_traversal() {
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
 //     AND (2) the `<env>` context path starts with `/`
+//     AND (3) the context directory doesn't exist
 // When `run-in <env> true` is run
 // Then (A) the command returns an exit code of 1
-//     AND (B) the command STDERR indicates the invalid path
+//     AND (B) the command STDERR indicates the missing directory
 //     AND (B) the command STDOUT is empty
 //     AND (D) the target image doesn't exist
 //     AND (E) no containers exist for the target image
@@ -252,6 +267,7 @@ fn context_contains_absolute_path() {
         "},
         &Definition{
             name: test_name,
+            // (3)
             fs: &hashmap!{},
             dockerfile_steps: indoc!{""},
         },
@@ -265,7 +281,7 @@ fn context_contains_absolute_path() {
         .code(1)
         // (B)
         .stderr(predicate_str::ends_with(
-            "The relative path didn't start with `.`\n",
+            "The context directory '/dir' doesn't exist\n",
         ))
         // (C)
         .stdout("");
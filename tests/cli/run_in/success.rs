@@ -4,8 +4,14 @@
 
 use std::env;
 use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
+use std::process::Command as StdCommand;
 use std::process::ExitStatus;
+use std::process::Stdio;
 use std::str;
 use std::string::FromUtf8Error;
 use std::thread;
@@ -20,9 +26,13 @@ use crate::test_setup::References;
 
 use crate::assert_cmd::assert::Assert;
 use crate::assert_cmd::Command as AssertCommand;
+use crate::nix::sys::signal;
+use crate::nix::sys::signal::Signal;
 use crate::nix::sys::time::TimeVal;
 use crate::nix::sys::time::TimeValLike;
+use crate::nix::unistd::Pid;
 use crate::predicates::prelude::predicate::str as predicate_str;
+use crate::predicates::prelude::PredicateBooleanExt;
 use crate::predicates::str::RegexPredicate;
 
 #[test]
@@ -116,6 +126,50 @@ fn run_in_uses_correct_image() {
     docker::assert_image_exists(&test.image_tagged_name);
 }
 
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>`'s Dockerfile defines `dev` and `release` stages
+//     AND (3) `<env>` defines `target` as `dev`
+// When `run-in <env> cat /stage.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains the name of the `dev` stage
+fn target_selects_build_stage() {
+    let test_name = "target_selects_build_stage";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (3)
+        "target: dev",
+        &Definition{
+            name: test_name,
+            // (2)
+            dockerfile_steps: &formatdoc!{
+                "
+                    FROM {base_img} AS dev
+                    RUN echo dev > /stage.txt
+
+                    FROM {base_img} AS release
+                    RUN echo release > /stage.txt
+                ",
+                base_img = test_setup::TEST_BASE_IMG,
+            },
+            fs: &hashmap!{},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result =
+        run_test_cmd(&test.dir, &[test_name, "cat", "/stage.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("dev\n");
+}
+
 #[test]
 // Given (1) the dock file defines an empty environment called `<env>`
 // When `run-in <env> sh -c 'exit 2'` is run
@@ -234,6 +288,300 @@ fn build_with_nested_directory_as_context() {
         .stdout(test_name);
 }
 
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` uses a sibling of the project directory as the context
+//     AND (3) the sibling directory contains `test.txt`
+//     AND (4) `<env>`'s Dockerfile copies `test.txt`
+// When `run-in <env> cat test.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains the contents of `test.txt`
+fn build_with_sibling_directory_as_context() {
+    let test_name = "build_with_sibling_directory_as_context";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            context: ../build_with_sibling_directory_as_context.common
+        "},
+        &Definition{
+            name: test_name,
+            fs: &hashmap!{
+                // (3)
+                "../build_with_sibling_directory_as_context.common/test.txt" =>
+                    test_name,
+            },
+            // (4)
+            dockerfile_steps: indoc!{"
+                COPY test.txt /
+            "},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result = run_test_cmd(&test.dir, &[test_name, "cat", "test.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(test_name);
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` uses the current directory as the context
+//     AND (3) `<env>` includes `src` and excludes `*.bak` files
+//     AND (4) the current directory contains included and excluded files
+//     AND (5) `<env>`'s Dockerfile copies the context to `/ctx`
+// When `run-in <env> find . -type f` is run in `/ctx`
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT lists the included files, without the
+//         Dockerfile or the generated `.dockerignore`
+fn build_with_filtered_context() {
+    let test_name = "build_with_filtered_context";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2) (3)
+        indoc!{"
+            context: .
+            context_include:
+            - src
+            context_exclude:
+            - '**/*.bak'
+        "},
+        &Definition{
+            name: test_name,
+            // (4)
+            fs: &hashmap!{
+                "src/a.txt" => test_name,
+                "src/a.txt.bak" => test_name,
+                "test.txt" => test_name,
+            },
+            // (5)
+            dockerfile_steps: indoc!{"
+                COPY . /ctx
+                WORKDIR /ctx
+            "},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &[test_name, "sh", "-c", "find . -type f | sort"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("./src/a.txt\n");
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` uses the `main` branch of a local Git repository as
+//         its context, through a `file://` URL
+//     AND (3) `<env>`'s Dockerfile copies `a.txt` from the context
+//     AND (4) `run-in <env> cat /a.txt` was run
+//     AND (5) a commit that changes `a.txt` was added to `main`
+// When `run-in <env> cat /a.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDOUT contains the new content of `a.txt`
+//     AND (C) the content hash label of the image for `<env>` changed
+fn build_with_git_context_follows_ref() {
+    let test_name = "build_with_git_context_follows_ref";
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        fs: &hashmap!{
+            "repo/a.txt" => "old\n",
+        },
+        // (3)
+        dockerfile_steps: "COPY a.txt /a.txt",
+    });
+    // (2)
+    let repo_dir = format!("{}/repo", test.dir);
+    let commit = |msg: &str| {
+        assert_run::assert_run_in_dir(&repo_dir, "git", &["add", "a.txt"]);
+        assert_run::assert_run_in_dir(
+            &repo_dir,
+            "git",
+            &[
+                "-c",
+                "user.name=dock",
+                "-c",
+                "user.email=dock@example.com",
+                "commit",
+                "--quiet",
+                &format!("--message={msg}"),
+            ],
+        );
+    };
+    assert_run::assert_run_in_dir(&repo_dir, "git", &["init", "--quiet"]);
+    commit("Add a.txt");
+    assert_run::assert_run_in_dir(&repo_dir, "git", &["branch", "-M", "main"]);
+    // (1)
+    let dock_yaml = test_setup::render_dock_file(
+        "0.1",
+        test_name,
+        &format!("context: git+file://{repo_dir}#main"),
+    );
+    test_setup::assert_write_fs_state(
+        &test.dir,
+        &hashmap!{"dock.yaml" => dock_yaml.as_str()},
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+    // (4)
+    run_test_cmd(&test.dir, &[test_name, "cat", "/a.txt"])
+        .code(0)
+        .stdout("old\n");
+    let old_hash = assert_content_hash_label(&test.image_tagged_name);
+    // (5)
+    test_setup::assert_write_fs_state(
+        &repo_dir,
+        &hashmap!{"a.txt" => "new\n"},
+    );
+    commit("Update a.txt");
+
+    let cmd_result = run_test_cmd(&test.dir, &[test_name, "cat", "/a.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout("new\n");
+    // (C)
+    let new_hash = assert_content_hash_label(&test.image_tagged_name);
+    assert_ne!(old_hash, new_hash);
+}
+
+// `assert_content_hash_label` returns the value of the content hash label of
+// `img`.
+fn assert_content_hash_label(img: &str) -> String {
+    let hash = assert_run::assert_run_stdout(
+        "docker",
+        &[
+            "image",
+            "inspect",
+            r#"--format={{index .Config.Labels "dock.content_hash"}}"#,
+            img,
+        ],
+    );
+    let hash = hash.trim_end();
+    assert!(hash.starts_with("sha256:"), "unexpected content hash: {hash}");
+
+    hash.to_string()
+}
+
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) `<env>` doesn't define a `platform`
+//     AND (3) the image for `<env>` doesn't exist
+// When `run-in --platform=linux/amd64 <env> uname -m` is run
+// Then (A) the command is successful
+//     AND (B) the command STDOUT contains the architecture of the platform
+//     AND (C) the image for `linux/amd64` exists
+//     AND (D) the image for `<env>` doesn't exist
+fn run_with_platform_override() {
+    let test_name = "run_with_platform_override";
+    // (1) (2)
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        dockerfile_steps: "",
+        fs: &hashmap!{},
+    });
+    let platform_img =
+        test.image_tagged_name.replace(":latest", "-linux-amd64:latest");
+    // (3)
+    docker::assert_remove_image(&test.image_tagged_name);
+    docker::assert_remove_image(&platform_img);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &["--platform=linux/amd64", test_name, "uname", "-m"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout("x86_64\n");
+    // (C)
+    docker::assert_image_exists(&platform_img);
+    // (D)
+    docker::assert_image_doesnt_exist(&test.image_tagged_name);
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<env>` and
+//     `<parent>`
+//     AND (2) neither environment defines a `platform`
+//     AND (3) `<env>`'s Dockerfile is built from the image of `<parent>`
+//     AND (4) the images for both environments don't exist
+// When `run-in --platform=linux/amd64 <env> uname -m` is run
+// Then (A) the command is successful
+//     AND (B) the command STDOUT contains the architecture of the platform
+//     AND (C) the images for `linux/amd64` exist for both environments
+//     AND (D) the image for `<parent>` doesn't exist
+fn run_with_platform_override_uses_parent_platform_image() {
+    let test_name = "run_with_platform_override_uses_parent_platform_image";
+    let parent_name = format!("{test_name}_parent");
+    let img = test_setup::test_image_tagged_name(test_name);
+    let parent_img = test_setup::test_image_tagged_name(&parent_name);
+    let platform_img = img.replace(":latest", "-linux-amd64:latest");
+    let parent_platform_img =
+        parent_img.replace(":latest", "-linux-amd64:latest");
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    // (1) (2)
+    let dock_yaml = format!(
+        "{}  {parent_name}: {{}}\n",
+        test_setup::render_dock_file("0.1", test_name, "{}"),
+    );
+    // (3)
+    let dockerfile = format!("FROM {parent_img}\nRUN echo {test_name}\n");
+    let parent_dockerfile = format!(
+        "FROM {}\nRUN echo {parent_name}\n",
+        test_setup::TEST_BASE_IMG,
+    );
+    let dockerfile_name = format!("{test_name}.Dockerfile");
+    let parent_dockerfile_name = format!("{parent_name}.Dockerfile");
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{
+            "dock.yaml" => dock_yaml.as_str(),
+            dockerfile_name.as_str() => dockerfile.as_str(),
+            parent_dockerfile_name.as_str() => parent_dockerfile.as_str(),
+        },
+    );
+    // (4)
+    for img in [&img, &parent_img, &platform_img, &parent_platform_img] {
+        docker::assert_remove_image(img);
+    }
+
+    let cmd_result = run_test_cmd(
+        &root_test_dir,
+        &["--platform=linux/amd64", test_name, "uname", "-m"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stdout("x86_64\n");
+    // (C)
+    docker::assert_image_exists(&platform_img);
+    docker::assert_image_exists(&parent_platform_img);
+    // (D)
+    docker::assert_image_doesnt_exist(&parent_img);
+}
+
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
 //     AND (2) `<env>` uses the current directory as the context
@@ -799,6 +1147,297 @@ fn project_dir() {
         .stdout(test_name.to_owned());
 }
 
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines `workdir` as `/a/b`
+//     AND (3) `<env>` enables `project_dir`
+//     AND (4) the current directory contains `dir/test.txt`
+// When `run-in <env> sh -c 'pwd && cat test.txt'` is run in `dir`
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains `/a/b/dir` and the contents of
+//         `dir/test.txt`
+fn project_dir_follows_cwd() {
+    let test_name = "project_dir_follows_cwd";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2) (3)
+        indoc!{"
+            workdir: '/a/b'
+            mount_local:
+            - project_dir
+        "},
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            // (4)
+            fs: &hashmap!{"dir/test.txt" => test_name},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result = run_test_cmd_from_subdir(
+        &test.dir,
+        Path::new("dir"),
+        &[test_name, "sh", "-c", "pwd && cat test.txt"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(format!("/a/b/dir\n{test_name}"));
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines `workdir` as `/a/b`
+//     AND (3) `<env>` enables `project_dir`
+//     AND (4) `<env>` defines `/a/b/out.txt` as an artifact at `./out.txt`
+//     AND (5) the current directory is a Git repository that tracks
+//         `tracked.txt`
+//     AND (6) the current directory contains an untracked `untracked.txt`
+// When `run-in --hermetic <env> sh -c 'ls && echo ok > out.txt'` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT only lists `tracked.txt`
+//     AND (D) `out.txt` was copied to the current directory
+fn hermetic_copies_tracked_files_and_artifacts() {
+    let test_name = "hermetic_copies_tracked_files_and_artifacts";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2) (3) (4)
+        indoc!{"
+            workdir: '/a/b'
+            mount_local:
+            - project_dir
+            artifacts:
+              /a/b/out.txt: ./out.txt
+        "},
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{
+                "tracked.txt" => test_name,
+                "untracked.txt" => test_name,
+            },
+        },
+    );
+    // (5) (6)
+    assert_run::assert_run_in_dir(&test.dir, "git", &["init", "--quiet"]);
+    assert_run::assert_run_in_dir(&test.dir, "git", &["add", "tracked.txt"]);
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &["--hermetic", test_name, "sh", "-c", "ls && echo ok > out.txt"],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("tracked.txt\n");
+    // (D)
+    let out = fs::read_to_string(Path::new(&test.dir).join("out.txt"))
+        .expect("couldn't read artifact");
+    assert_eq!(out, "ok\n");
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `run-in --hermetic <env> sleep 60` is running
+// When `SIGTERM` is sent to `dock`
+// Then (A) `dock` is terminated by `SIGTERM`
+//     AND (B) no containers exist for the image of `<env>`
+fn hermetic_removes_container_on_signal() {
+    let test_name = "hermetic_removes_container_on_signal";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        "",
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+    // (2)
+    let mut child =
+        StdCommand::new(test_setup::test_bin())
+            .args(["run-in", "--hermetic", test_name, "sleep", "60"])
+            .current_dir(&test.dir)
+            .env_clear()
+            .env("HOME", env!("HOME"))
+            .spawn()
+            .expect("couldn't spawn `dock`");
+    let filter = format!("--filter=ancestor={}", test.image_tagged_name);
+    let mut running = false;
+    for _ in 0..600 {
+        let ids = assert_run::assert_run_stdout_lines(
+            "docker",
+            &["ps", "--quiet", &filter],
+        );
+        if !ids.is_empty() {
+            running = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(running, "the container wasn't started");
+
+    let pid = i32::try_from(child.id())
+        .expect("couldn't convert PID");
+    signal::kill(Pid::from_raw(pid), Signal::SIGTERM)
+        .expect("couldn't send `SIGTERM` to `dock`");
+
+    let status = child.wait()
+        .expect("couldn't wait for `dock`");
+
+    // (A)
+    assert_eq!(status.signal(), Some(Signal::SIGTERM as i32));
+    // (B)
+    docker::assert_no_containers_from_image(&test.image_tagged_name);
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>`'s Dockerfile takes several seconds to build
+//     AND (3) the target image defined by `<env>` doesn't exist
+//     AND (4) `run-in --debug <env> true` has started rebuilding `<env>`
+// When `run-in --debug <env> true` is run again
+// Then (A) both commands are successful
+//     AND (B) the second command waits for the first rebuild to finish
+//     AND (C) the second command doesn't rebuild the image
+fn concurrent_rebuild_reuses_image() {
+    let test_name = "concurrent_rebuild_reuses_image";
+    // (1)
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        // (2)
+        dockerfile_steps: "RUN sleep 5",
+        fs: &hashmap!{},
+    });
+    // (3)
+    docker::assert_remove_image(&test.image_tagged_name);
+    let new_cmd = || {
+        let mut cmd = StdCommand::new(test_setup::test_bin());
+        cmd.args(["run-in", "--debug", test_name, "true"])
+            .current_dir(&test.dir)
+            .env_clear()
+            .env("HOME", env!("HOME"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        cmd
+    };
+    // (4)
+    let mut first = new_cmd().spawn()
+        .expect("couldn't spawn the first `dock`");
+    let first_stdout = first.stdout.take()
+        .expect("couldn't take the STDOUT of the first `dock`");
+    let mut first_stdout = BufReader::new(first_stdout);
+    let mut line = String::new();
+    while !line.starts_with("[$] docker build") {
+        line.clear();
+        let n = first_stdout.read_line(&mut line)
+            .expect("couldn't read the STDOUT of the first `dock`");
+        assert!(n > 0, "the first `dock` didn't rebuild the image");
+    }
+    // We read the rest of the output in a separate thread so that the first
+    // `dock` doesn't block on writing it.
+    let first_reader = thread::spawn(move || {
+        let mut rest = String::new();
+        first_stdout.read_to_string(&mut rest)
+            .map(|_| rest)
+    });
+
+    let second = new_cmd().output()
+        .expect("couldn't run the second `dock`");
+
+    let first_status = first.wait()
+        .expect("couldn't wait for the first `dock`");
+    first_reader.join()
+        .expect("couldn't join the reader thread")
+        .expect("couldn't read the STDOUT of the first `dock`");
+    // (A)
+    assert!(first_status.success(), "the first `dock` failed");
+    assert!(second.status.success(), "the second `dock` failed");
+    // (B)
+    let second_stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(
+        second_stderr.contains("Waiting for another rebuild of"),
+        "unexpected STDERR: {second_stderr}",
+    );
+    // (C)
+    let second_stdout = String::from_utf8_lossy(&second.stdout);
+    assert!(
+        !second_stdout.contains("[$] docker build"),
+        "unexpected STDOUT: {second_stdout}",
+    );
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines `/out/a.txt` as an artifact at `./a.txt`
+//     AND (3) `<env>` defines `/out/missing.txt` as an optional artifact
+// When `run-in --artifact=/out/b.txt:./b.txt <env> sh -c '...'` is run
+//     AND the command writes `/out/a.txt` and `/out/b.txt`
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT is empty
+//     AND (D) `a.txt` and `b.txt` were copied to the current directory
+fn artifacts_are_extracted() {
+    let test_name = "artifacts_are_extracted";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2) (3)
+        indoc!{"
+            artifacts:
+              /out/a.txt: ./a.txt
+              /out/missing.txt:
+                path: ./missing.txt
+                optional: true
+        "},
+        &Definition{
+            name: test_name,
+            dockerfile_steps: "",
+            fs: &hashmap!{},
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result = run_test_cmd(
+        &test.dir,
+        &[
+            "--artifact=/out/b.txt:./b.txt",
+            test_name,
+            "sh",
+            "-c",
+            "mkdir /out && echo a > /out/a.txt && echo b > /out/b.txt",
+        ],
+    );
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout("");
+    // (D)
+    for (name, contents) in [("a.txt", "a\n"), ("b.txt", "b\n")] {
+        let out = fs::read_to_string(Path::new(&test.dir).join(name))
+            .expect("couldn't read artifact");
+        assert_eq!(out, contents);
+    }
+    assert!(!Path::new(&test.dir).join("missing.txt").exists());
+}
+
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
 //     AND (2) `<env>` defines a cache volume called `test` at `/a/b`
@@ -967,6 +1606,163 @@ fn run_in_with_build_args() {
         .stdout("test-value\n");
 }
 
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` maps the `TEST_VALUE` build argument to a value that
+//         refers to `${DOCK_UID}`
+//     AND (3) `<env>`'s Dockerfile saves `TEST_VALUE` in `/test.txt`
+// When `run-in <env> cat /test.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains the interpolated `TEST_VALUE`
+fn run_in_with_build_arg_map() {
+    let test_name = "run_in_with_build_arg_map";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            build_args:
+              TEST_VALUE: 'uid-${DOCK_UID}'
+        "},
+        &Definition{
+            name: test_name,
+            fs: &hashmap!{},
+            // (3)
+            dockerfile_steps: "
+                ARG TEST_VALUE
+                RUN echo \"$TEST_VALUE\" > /test.txt
+            ",
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+    let uid = assert_run::assert_run_stdout("id", &["--user"]);
+
+    let cmd_result = run_test_cmd(&test.dir, &[test_name, "cat", "/test.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(format!("uid-{uid}"));
+}
+
+#[test]
+// Given (1) the dock file defines an environment called `<env>`
+//     AND (2) `<env>` defines a `token` build secret from `secret.txt`
+//     AND (3) `<env>`'s Dockerfile saves the length of `token` in `/len.txt`
+// When `run-in -D <env> cat /len.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains the length of `token`
+//     AND (D) the command STDOUT doesn't contain the value of `token`
+fn run_in_with_build_secret() {
+    let test_name = "run_in_with_build_secret";
+    let secret = "s3cr3t-t0k3n";
+    // (1)
+    let test = test_setup::assert_apply_with_dock_yaml(
+        // (2)
+        indoc!{"
+            build_secrets:
+              token:
+                file: ./secret.txt
+        "},
+        &Definition{
+            name: test_name,
+            fs: &hashmap!{"secret.txt" => secret},
+            // (3)
+            dockerfile_steps: "
+                RUN --mount=type=secret,id=token,required=true \\
+                    wc -c < /run/secrets/token > /len.txt
+            ",
+        },
+    );
+    docker::assert_remove_image(&test.image_tagged_name);
+
+    let cmd_result =
+        run_test_cmd(&test.dir, &["-D", test_name, "cat", "/len.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(predicate_match(&format!("(?m)^{}$", secret.len())))
+        // (D)
+        .stdout(predicate_str::contains(secret).not());
+}
+
+#[test]
+// Given (1) the dock file defines environments called `<parent>` and `<child>`
+//     AND (2) `<child>`'s Dockerfile is built `FROM` the image for `<parent>`
+//     AND (3) the images for `<parent>` and `<child>` don't exist
+// When `run-in <child> cat /parent.txt` is run
+// Then (A) the command is successful
+//     AND (B) the command STDERR is empty
+//     AND (C) the command STDOUT contains the file created by `<parent>`
+//     AND (D) the image for `<parent>` exists
+fn run_in_rebuilds_parent_env_first() {
+    let test_name = "run_in_rebuilds_parent_env_first";
+    let root_test_dir = test_setup::assert_create_root_dir(test_name);
+    let parent = format!("{test_name}_parent");
+    let child = format!("{test_name}_child");
+    // (1)
+    let dock_yaml = formatdoc!{
+        "
+            schema_version: '0.1'
+            organisation: org
+            project: proj
+            default_shell_env: {child}
+
+            environments:
+              {parent}: {{}}
+              {child}: {{}}
+        ",
+        parent = parent,
+        child = child,
+    };
+    let parent_dockerfile = formatdoc!{
+        "
+            FROM {test_base_img}
+
+            RUN echo '{test_name}' > /parent.txt
+        ",
+        test_base_img = test_setup::TEST_BASE_IMG,
+        test_name = test_name,
+    };
+    // (2)
+    let child_dockerfile = format!("FROM org/proj.{parent}:latest\n");
+    let parent_dockerfile_name = format!("{parent}.Dockerfile");
+    let child_dockerfile_name = format!("{child}.Dockerfile");
+    test_setup::assert_write_fs_state(
+        &root_test_dir,
+        &hashmap!{
+            "dock.yaml" => dock_yaml.as_str(),
+            parent_dockerfile_name.as_str() => parent_dockerfile.as_str(),
+            child_dockerfile_name.as_str() => child_dockerfile.as_str(),
+        },
+    );
+    let parent_img = format!("org/proj.{parent}:latest");
+    // (3)
+    docker::assert_remove_image(&format!("org/proj.{child}:latest"));
+    docker::assert_remove_image(&parent_img);
+
+    let cmd_result =
+        run_test_cmd(&root_test_dir, &[&child, "cat", "/parent.txt"]);
+
+    cmd_result
+        // (A)
+        .code(0)
+        // (B)
+        .stderr("")
+        // (C)
+        .stdout(format!("{test_name}\n"));
+    // (D)
+    docker::assert_image_exists(&parent_img);
+}
+
 #[test]
 // Given (1) the dock file defines an environment called `<env>`
 //     AND (2) `<env>` has a `<script>` that checks if all streams are TTYs
@@ -36,6 +36,60 @@ fn shell_uses_correct_image() {
     pty.expect_eof();
 }
 
+#[test]
+// Given (1) the dock file defines an empty environment called `<env>`
+//     AND (2) the Dockerfile for `<env>` creates a test file
+//     AND (3) the Dockerfile for `<env>` then runs a command that fails
+// When `shell --build-failure <env>` is run
+// Then (A) the shell starts before the failed command
+//     AND (B) the test file exists in the shell
+//     AND (C) the failed command is in the shell history
+fn shell_build_failure_starts_before_failed_step() {
+    let test_name = "shell_build_failure_starts_before_failed_step";
+    // (1)
+    let test = test_setup::assert_apply_with_empty_dock_yaml(&Definition{
+        name: test_name,
+        dockerfile_steps: &formatdoc!{
+            "
+                RUN echo '{test_name}' > test.txt
+                RUN test -f /nonexistent
+            ",
+            test_name = test_name,
+        },
+        fs: &hashmap!{},
+    });
+    let debug_img = test.image_tagged_name.replace(":latest", ":debug");
+    let args = &["shell", "--build-failure", test_name];
+    let mut pty = unsafe { new_test_cmd(args, &test.dir) };
+
+    defer!{
+        docker::assert_kill_image_container(&debug_img);
+    };
+
+    // (A)
+    pty.expect("Starting a shell before `RUN test -f /nonexistent`");
+
+    pty.expect("# ");
+
+    pty.send("cat 'test.txt'\n");
+
+    // (B)
+    pty.expect(test_name);
+
+    pty.expect("# ");
+
+    pty.send("history\n");
+
+    // (C)
+    pty.expect("test -f /nonexistent");
+
+    pty.expect("# ");
+
+    pty.send("exit\n");
+
+    pty.expect_eof();
+}
+
 unsafe fn set_up(test_name: &str, cmd_args: &[&str])
     -> (References, Expecter)
 {
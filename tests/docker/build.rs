@@ -20,6 +20,7 @@ impl DockerBuild {
     // NOTE This parse depends on the specific STDERR returned by the Docker
     // client. This parse able to handle `Docker Engine - Community` version
     // `23.0.3` of the Docker client.
+    #[allow(clippy::regex_creation_in_loops)]
     pub fn parse_from_stderr(lines: &mut LineMatcher)
         -> Result<Option<DockerBuild>, AssertError>
     {